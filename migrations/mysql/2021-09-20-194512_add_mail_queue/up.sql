@@ -0,0 +1,12 @@
+CREATE TABLE mail_queue (
+  uuid            CHAR(36)     NOT NULL PRIMARY KEY,
+  recipient       VARCHAR(255) NOT NULL,
+  subject         TEXT         NOT NULL,
+  body_html       MEDIUMTEXT   NOT NULL,
+  body_text       MEDIUMTEXT   NOT NULL,
+  status          INTEGER      NOT NULL,
+  attempts        INTEGER      NOT NULL,
+  last_error      TEXT,
+  created_at      DATETIME     NOT NULL,
+  next_attempt_at DATETIME     NOT NULL
+);
@@ -0,0 +1,13 @@
+CREATE TABLE webauthn_credentials (
+  uuid                  CHAR(36)     NOT NULL PRIMARY KEY,
+  user_uuid             CHAR(36)     NOT NULL REFERENCES users (uuid),
+  name                  TEXT         NOT NULL,
+  credential_id         VARCHAR(255) NOT NULL UNIQUE,
+  credential            TEXT         NOT NULL,
+  supports_prf          BOOLEAN      NOT NULL,
+  encrypted_user_key    TEXT,
+  encrypted_public_key  TEXT,
+  encrypted_private_key TEXT,
+  created_at            DATETIME     NOT NULL,
+  last_used_at          DATETIME
+);
@@ -0,0 +1,4 @@
+ALTER TABLE devices ADD COLUMN previous_refresh_token TEXT;
+ALTER TABLE devices ADD COLUMN refresh_token_issued_at DATETIME;
+ALTER TABLE devices ADD COLUMN session_started_at DATETIME;
+ALTER TABLE devices ADD COLUMN twofactor_remember_issued_at DATETIME;
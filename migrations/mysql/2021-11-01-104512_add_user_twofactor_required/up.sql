@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN twofactor_required_at DATETIME;
@@ -0,0 +1,14 @@
+CREATE TABLE login_history (
+  uuid           CHAR(36)     NOT NULL PRIMARY KEY,
+  user_uuid      CHAR(36)     NOT NULL REFERENCES users (uuid),
+  device_uuid    CHAR(36)     NOT NULL,
+  device_name    TEXT         NOT NULL,
+  device_type    INTEGER      NOT NULL,
+  ip             TEXT         NOT NULL,
+  method         INTEGER      NOT NULL,
+  success        BOOLEAN      NOT NULL,
+  twofactor_type INTEGER,
+  created_at     DATETIME     NOT NULL
+);
+
+CREATE INDEX login_history_user_created_idx ON login_history (user_uuid, created_at);
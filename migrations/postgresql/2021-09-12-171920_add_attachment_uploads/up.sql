@@ -0,0 +1,6 @@
+CREATE TABLE attachment_uploads (
+  attachment_id CHAR(36)  NOT NULL PRIMARY KEY REFERENCES attachments (id),
+  file_hash     TEXT,
+  created_at    TIMESTAMP NOT NULL,
+  updated_at    TIMESTAMP NOT NULL
+);
@@ -0,0 +1,18 @@
+CREATE TABLE broadcasts (
+  uuid       CHAR(36)  NOT NULL PRIMARY KEY,
+  subject    TEXT      NOT NULL,
+  body       TEXT      NOT NULL,
+  recipients TEXT      NOT NULL,
+  created_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE broadcast_recipients (
+  broadcast_uuid CHAR(36)     NOT NULL REFERENCES broadcasts (uuid),
+  email          VARCHAR(255) NOT NULL,
+  status         INTEGER      NOT NULL,
+  last_error     TEXT,
+  updated_at     TIMESTAMP    NOT NULL,
+  PRIMARY KEY (broadcast_uuid, email)
+);
+
+ALTER TABLE mail_queue ADD COLUMN broadcast_uuid CHAR(36);
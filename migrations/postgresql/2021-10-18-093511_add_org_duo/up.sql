@@ -0,0 +1 @@
+ALTER TABLE organizations ADD COLUMN duo_data TEXT;
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN server_role INTEGER;
@@ -0,0 +1,20 @@
+ALTER TABLE organizations
+  ADD COLUMN scim_token TEXT;
+
+ALTER TABLE users_organizations
+  ADD COLUMN external_id TEXT;
+
+CREATE TABLE groups (
+  uuid        TEXT     NOT NULL PRIMARY KEY,
+  org_uuid    TEXT     NOT NULL REFERENCES organizations (uuid),
+  name        TEXT     NOT NULL,
+  external_id TEXT,
+  created_at  DATETIME NOT NULL,
+  updated_at  DATETIME NOT NULL
+);
+
+CREATE TABLE groups_users (
+  group_uuid               TEXT NOT NULL REFERENCES groups (uuid),
+  users_organizations_uuid TEXT NOT NULL REFERENCES users_organizations (uuid),
+  PRIMARY KEY (group_uuid, users_organizations_uuid)
+);
@@ -0,0 +1,3 @@
+ALTER TABLE users ADD COLUMN attachment_limit BIGINT;
+
+ALTER TABLE organizations ADD COLUMN attachment_limit BIGINT;
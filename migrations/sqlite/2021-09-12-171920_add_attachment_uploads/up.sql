@@ -0,0 +1,6 @@
+CREATE TABLE attachment_uploads (
+  attachment_id TEXT     NOT NULL PRIMARY KEY REFERENCES attachments (id),
+  file_hash     TEXT,
+  created_at    DATETIME NOT NULL,
+  updated_at    DATETIME NOT NULL
+);
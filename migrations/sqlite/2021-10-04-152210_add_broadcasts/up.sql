@@ -0,0 +1,18 @@
+CREATE TABLE broadcasts (
+  uuid       TEXT     NOT NULL PRIMARY KEY,
+  subject    TEXT     NOT NULL,
+  body       TEXT     NOT NULL,
+  recipients TEXT     NOT NULL,
+  created_at DATETIME NOT NULL
+);
+
+CREATE TABLE broadcast_recipients (
+  broadcast_uuid TEXT     NOT NULL REFERENCES broadcasts (uuid),
+  email          TEXT     NOT NULL,
+  status         INTEGER  NOT NULL,
+  last_error     TEXT,
+  updated_at     DATETIME NOT NULL,
+  PRIMARY KEY (broadcast_uuid, email)
+);
+
+ALTER TABLE mail_queue ADD COLUMN broadcast_uuid TEXT;
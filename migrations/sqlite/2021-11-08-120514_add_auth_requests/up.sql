@@ -0,0 +1,16 @@
+CREATE TABLE auth_requests (
+  uuid                      TEXT     NOT NULL PRIMARY KEY,
+  user_uuid                 TEXT     NOT NULL REFERENCES users (uuid),
+  request_device_identifier TEXT     NOT NULL,
+  device_type               INTEGER  NOT NULL,
+  request_ip                TEXT     NOT NULL,
+  response_device_id        TEXT,
+  access_code               TEXT     NOT NULL,
+  public_key                TEXT     NOT NULL,
+  enc_key                   TEXT,
+  master_password_hash      TEXT,
+  approved                  BOOLEAN,
+  creation_date             DATETIME NOT NULL,
+  response_date             DATETIME,
+  authentication_date       DATETIME
+);
@@ -0,0 +1,19 @@
+CREATE TABLE admins (
+  uuid           TEXT     NOT NULL PRIMARY KEY,
+  name           TEXT     NOT NULL UNIQUE,
+  password_hash  TEXT     NOT NULL,
+  totp_secret    TEXT,
+  totp_last_used INTEGER  NOT NULL DEFAULT 0,
+  created_at     DATETIME NOT NULL,
+  last_login_at  DATETIME
+);
+
+CREATE TABLE admin_actions (
+  uuid       TEXT     NOT NULL PRIMARY KEY,
+  admin_uuid TEXT,
+  admin_name TEXT     NOT NULL,
+  action     TEXT     NOT NULL,
+  target     TEXT,
+  ip         TEXT     NOT NULL,
+  created_at DATETIME NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN twofactor_reminded_at DATETIME;
@@ -1,24 +1,41 @@
 use once_cell::sync::Lazy;
 use serde::de::DeserializeOwned;
 use serde_json::Value;
-use std::{env, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    io::Cursor,
+    time::Duration,
+};
 
+use chrono::{NaiveDateTime, Utc};
+use data_encoding::BASE32;
 use rocket::{
-    http::{Cookie, Cookies, SameSite, Status},
+    http::{ContentType, Cookie, Cookies, SameSite, Status},
     request::{self, FlashMessage, Form, FromRequest, Outcome, Request},
-    response::{content::Html, Flash, Redirect},
+    response::{content::Html, Flash, Redirect, Response},
     Route,
 };
 use rocket_contrib::json::Json;
 
 use crate::{
-    api::{ApiResult, EmptyResult, JsonResult, NumberOrString},
-    auth::{decode_admin, encode_jwt, generate_admin_claims, ClientIp},
+    api::{
+        core::{
+            delete_user_account,
+            two_factor::{
+                authenticator::{check_totp_code, TotpCheck},
+                twofactor_enrolment, TwoFactorEnrolment,
+            },
+        },
+        ApiResult, EmptyResult, JsonResult, NumberOrString,
+    },
+    auth::{decode_admin, encode_jwt, generate_admin_claims, ClientIp, ServerRoleHeaders},
     config::ConfigBuilder,
-    db::{backup_database, get_sql_server_version, models::*, DbConn, DbConnType},
+    crypto,
+    db::{backup_database, get_sql_server_version, models::*, DbConn, DbConnType, DbPool},
     error::{Error, MapResult},
     mail,
-    util::{format_naive_datetime_local, get_display_size, get_reqwest_client, is_running_in_docker},
+    util::{format_date, format_naive_datetime_local, get_display_size, get_reqwest_client, is_running_in_docker},
     CONFIG,
 };
 
@@ -36,11 +53,14 @@ pub fn routes() -> Vec<Route> {
         invite_user,
         logout,
         delete_user,
+        restore_user,
         deauth_user,
         disable_user,
         enable_user,
         remove_2fa,
+        update_user_attachment_limit,
         update_user_org_type,
+        update_user_server_role,
         update_revision_users,
         post_config,
         delete_config,
@@ -49,8 +69,32 @@ pub fn routes() -> Vec<Route> {
         users_overview,
         organizations_overview,
         delete_organization,
+        update_org_attachment_limit,
+        storage_overview,
+        get_storage_json,
+        get_storage_csv,
+        mail_queue_overview,
+        get_mail_queue_json,
+        resend_queued_mail,
+        discard_queued_mail,
+        email_preview,
+        render_email_preview,
+        broadcast_overview,
+        preview_broadcast,
+        send_broadcast,
+        get_broadcast_json,
         diagnostics,
-        get_diagnostics_config
+        get_diagnostics_config,
+        run_fsck,
+        purge_icon_cache,
+        rotate_jwt_keys,
+        admins_overview,
+        create_admin,
+        delete_admin,
+        remove_admin_totp,
+        generate_admin_totp,
+        activate_admin_totp,
+        change_admin_password
     ]
 }
 
@@ -75,6 +119,13 @@ fn admin_disabled() -> &'static str {
 const COOKIE_NAME: &str = "BWRS_ADMIN";
 const ADMIN_PATH: &str = "/admin";
 
+/// Subject of the admin JWTs of the sessions opened with the admin token, the others have the UUID of the admin account.
+const ADMIN_TOKEN_SUBJECT: &str = "admin_panel";
+/// Min length of the passwords of the admin accounts.
+const ADMIN_PASSWORD_MIN_LENGTH: usize = 12;
+/// Number of entries shown from the admin action log.
+const ADMIN_ACTIONS_LIMIT: i64 = 500;
+
 const BASE_TEMPLATE: &str = "admin/base";
 const VERSION: Option<&str> = option_env!("BWRS_VERSION");
 
@@ -82,6 +133,17 @@ fn admin_path() -> String {
     format!("{}{}", CONFIG.domain_path(), ADMIN_PATH)
 }
 
+pub fn purge_admin_actions(pool: DbPool) {
+    debug!("Purging admin actions");
+    if let Ok(conn) = pool.get() {
+        if let Err(e) = AdminAction::purge_old(&conn) {
+            error!("Error purging admin actions: {:#?}", e);
+        }
+    } else {
+        error!("Failed to get DB connection while purging admin actions")
+    }
+}
+
 struct Referer(Option<String>);
 
 impl<'a, 'r> FromRequest<'a, 'r> for Referer {
@@ -153,6 +215,9 @@ fn admin_login(flash: Option<FlashMessage>) -> ApiResult<Html<String>> {
 #[derive(FromForm)]
 struct LoginForm {
     token: String,
+    // Name and TOTP code of an admin account, the token is then its password
+    name: Option<String>,
+    totp: Option<String>,
 }
 
 #[post("/", data = "<data>")]
@@ -161,34 +226,102 @@ fn post_admin_login(
     mut cookies: Cookies,
     ip: ClientIp,
     referer: Referer,
+    conn: DbConn,
 ) -> Result<Redirect, Flash<Redirect>> {
     let data = data.into_inner();
+    let name = data.name.as_deref().map(str::trim).filter(|name| !name.is_empty());
+
+    let admin = match name {
+        None => {
+            // If the token is invalid, redirect to login page
+            if !_validate_token(&data.token) {
+                error!("Invalid admin token. IP: {}", ip.ip);
+                return Err(Flash::error(Redirect::to(admin_url(referer)), "Invalid admin token, please try again."));
+            }
+            None
+        }
+        Some(name) => match _validate_admin(name, &data.token, data.totp.as_deref(), &conn) {
+            Ok(admin) => Some(admin),
+            Err(reason) => {
+                error!("Invalid admin login for '{}': {}. IP: {}", name, reason, ip.ip);
+                return Err(Flash::error(
+                    Redirect::to(admin_url(referer)),
+                    "Invalid admin credentials, please try again.",
+                ));
+            }
+        },
+    };
 
-    // If the token is invalid, redirect to login page
-    if !_validate_token(&data.token) {
-        error!("Invalid admin token. IP: {}", ip.ip);
-        Err(Flash::error(Redirect::to(admin_url(referer)), "Invalid admin token, please try again."))
-    } else {
-        // If the token received is valid, generate JWT and save it as a cookie
-        let claims = generate_admin_claims();
-        let jwt = encode_jwt(&claims);
-
-        let cookie = Cookie::build(COOKIE_NAME, jwt)
-            .path(admin_path())
-            .max_age(time::Duration::minutes(20))
-            .same_site(SameSite::Strict)
-            .http_only(true)
-            .finish();
-
-        cookies.add(cookie);
-        Ok(Redirect::to(admin_url(referer)))
-    }
+    // If the credentials are valid, generate JWT and save it as a cookie
+    let subject = admin.as_ref().map_or_else(|| ADMIN_TOKEN_SUBJECT.to_string(), |admin| admin.uuid.clone());
+    let claims = generate_admin_claims(subject);
+    let jwt = encode_jwt(&claims);
+
+    let cookie = Cookie::build(COOKIE_NAME, jwt)
+        .path(admin_path())
+        .max_age(time::Duration::minutes(20))
+        .same_site(SameSite::Strict)
+        .http_only(true)
+        .finish();
+
+    cookies.add(cookie);
+
+    let token = AdminToken {
+        admin,
+        user: None,
+        role: ServerRole::SuperAdmin,
+        ip,
+    };
+    token.log_action("Log in", None, &conn);
+    Ok(Redirect::to(admin_url(referer)))
 }
 
 fn _validate_token(token: &str) -> bool {
     match CONFIG.admin_token().as_ref() {
         None => false,
-        Some(t) => crate::crypto::ct_eq(t.trim(), token.trim()),
+        Some(t) => match crypto::verify_password_phc(token.trim(), t.trim()) {
+            Some(valid) => valid,
+            // Not a hash, the token is in plain text
+            None => crypto::ct_eq(t.trim(), token.trim()),
+        },
+    }
+}
+
+fn _validate_admin(name: &str, password: &str, totp: Option<&str>, conn: &DbConn) -> Result<Admin, &'static str> {
+    let mut admin = match Admin::find_by_name(name, conn) {
+        Some(admin) => admin,
+        None => {
+            Admin::check_dummy_password(password);
+            return Err("unknown admin");
+        }
+    };
+
+    if !admin.check_valid_password(password) {
+        return Err("invalid password");
+    }
+
+    if admin.totp_secret.is_some() {
+        let totp = totp.map(str::trim).filter(|totp| !totp.is_empty()).ok_or("missing TOTP code")?;
+        let secret = admin.totp_secret.clone().unwrap_or_default();
+        match validate_admin_totp(&secret, totp, admin.totp_last_used) {
+            Some(time_step) => admin.totp_last_used = time_step,
+            None => return Err("invalid TOTP code"),
+        }
+    }
+
+    admin.last_login_at = Some(Utc::now().naive_utc());
+    admin.save(conn).map_err(|_| "error saving the admin")?;
+    Ok(admin)
+}
+
+/// Returns the time step of the code if it's valid and more recent than `last_used`
+fn validate_admin_totp(secret: &str, code: &str, last_used: i32) -> Option<i32> {
+    let decoded_secret = BASE32.decode(secret.as_bytes()).ok()?;
+    let code: u64 = code.parse().ok()?;
+
+    match check_totp_code(&decoded_secret, code, last_used, Utc::now().timestamp()) {
+        TotpCheck::Valid(time_step) => Some(time_step),
+        TotpCheck::Reused | TotpCheck::Invalid => None,
     }
 }
 
@@ -234,7 +367,8 @@ impl AdminTemplateData {
 }
 
 #[get("/", rank = 1)]
-fn admin_page(_token: AdminToken, _conn: DbConn) -> ApiResult<Html<String>> {
+fn admin_page(token: AdminToken, _conn: DbConn) -> ApiResult<Html<String>> {
+    token.require_role(ServerRole::SuperAdmin)?;
     let text = AdminTemplateData::new().render()?;
     Ok(Html(text))
 }
@@ -254,7 +388,8 @@ fn get_user_or_404(uuid: &str, conn: &DbConn) -> ApiResult<User> {
 }
 
 #[post("/invite", data = "<data>")]
-fn invite_user(data: Json<InviteData>, _token: AdminToken, conn: DbConn) -> JsonResult {
+fn invite_user(data: Json<InviteData>, token: AdminToken, conn: DbConn) -> JsonResult {
+    token.require_role(ServerRole::UserAdmin)?;
     let data: InviteData = data.into_inner();
     let email = data.email.clone();
     if User::find_by_mail(&data.email, &conn).is_some() {
@@ -267,7 +402,16 @@ fn invite_user(data: Json<InviteData>, _token: AdminToken, conn: DbConn) -> Json
     // See: https://github.com/rust-lang/rust/issues/31436
     (|| {
         if CONFIG.mail_enabled() {
-            mail::send_invite(&user.email, &user.uuid, None, None, &CONFIG.invitation_org_name(), None)?;
+            mail::send_invite(
+                &user.email,
+                user.language.as_deref(),
+                &user.uuid,
+                None,
+                None,
+                &CONFIG.invitation_org_name(),
+                None,
+                &conn,
+            )?;
         } else {
             let invitation = Invitation::new(data.email);
             invitation.save(&conn)?;
@@ -277,15 +421,19 @@ fn invite_user(data: Json<InviteData>, _token: AdminToken, conn: DbConn) -> Json
     })()
     .map_err(|e| e.with_code(Status::InternalServerError.code))?;
 
+    token.log_action("Invite user", Some(user.email.clone()), &conn);
     Ok(Json(user.to_json(&conn)))
 }
 
 #[post("/test/smtp", data = "<data>")]
-fn test_smtp(data: Json<InviteData>, _token: AdminToken) -> EmptyResult {
+fn test_smtp(data: Json<InviteData>, token: AdminToken, conn: DbConn) -> EmptyResult {
+    token.require_role(ServerRole::SuperAdmin)?;
     let data: InviteData = data.into_inner();
 
     if CONFIG.mail_enabled() {
-        mail::send_test(&data.email)
+        mail::send_test(&data.email)?;
+        token.log_action("Send test email", Some(data.email), &conn);
+        Ok(())
     } else {
         err!("Mail is not enabled")
     }
@@ -308,6 +456,7 @@ fn get_users_json(_token: AdminToken, conn: DbConn) -> Json<Value> {
 #[get("/users/overview")]
 fn users_overview(_token: AdminToken, conn: DbConn) -> ApiResult<Html<String>> {
     let users = User::get_all(&conn);
+    let last_logins = LoginHistory::find_last_logins(&conn);
     let dt_fmt = "%Y-%m-%d %H:%M:%S %Z";
     let users_json: Vec<Value> = users
         .iter()
@@ -315,13 +464,39 @@ fn users_overview(_token: AdminToken, conn: DbConn) -> ApiResult<Html<String>> {
             let mut usr = u.to_json(&conn);
             usr["cipher_count"] = json!(Cipher::count_owned_by_user(&u.uuid, &conn));
             usr["attachment_count"] = json!(Attachment::count_by_user(&u.uuid, &conn));
-            usr["attachment_size"] = json!(get_display_size(Attachment::size_by_user(&u.uuid, &conn) as i32));
+            usr["attachment_size"] = json!(get_display_size(Attachment::size_by_user(&u.uuid, &conn)));
+            usr["attachment_limit"] = json!(u.attachment_limit);
+            usr["attachment_limit_custom"] = json!(u.attachment_limit.is_some());
+            usr["attachment_limit_size"] = json!(u.effective_attachment_limit().map(|l| get_display_size(l * 1024)));
             usr["user_enabled"] = json!(u.enabled);
+            usr["server_role"] = json!(u.server_role);
+            usr["server_role_name"] = json!(u.server_role().map(ServerRole::name));
+            usr["deleted"] = json!(u.deleted_at.is_some());
+            usr["purge_date"] = json!(u.purge_date().map(|dt| format_naive_datetime_local(&dt, dt_fmt)));
             usr["created_at"] = json!(format_naive_datetime_local(&u.created_at, dt_fmt));
             usr["last_active"] = match u.last_active(&conn) {
                 Some(dt) => json!(format_naive_datetime_local(&dt, dt_fmt)),
                 None => json!("Never"),
             };
+            match last_logins.get(&u.uuid) {
+                Some(login) => {
+                    usr["last_login"] = json!(format_naive_datetime_local(&login.created_at, dt_fmt));
+                    usr["last_login_ip"] = json!(login.ip);
+                }
+                None => usr["last_login"] = json!("Never"),
+            }
+
+            let (twofactor_pending, twofactor_overdue, twofactor_deadline) = match twofactor_enrolment(u, &conn) {
+                TwoFactorEnrolment::Pending(deadline) => (true, false, Some(deadline)),
+                TwoFactorEnrolment::Overdue(deadline) => (false, true, Some(deadline)),
+                _ => (false, false, None),
+            };
+            usr["twofactor_pending"] = json!(twofactor_pending);
+            usr["twofactor_overdue"] = json!(twofactor_overdue);
+            // Without a start date, the grace period starts at the next login or reminder
+            usr["twofactor_deadline"] = json!(
+                u.twofactor_required_at.and(twofactor_deadline).map(|dt| format_naive_datetime_local(&dt, dt_fmt))
+            );
             usr
         })
         .collect();
@@ -338,44 +513,149 @@ fn get_user_json(uuid: String, _token: AdminToken, conn: DbConn) -> JsonResult {
 }
 
 #[post("/users/<uuid>/delete")]
-fn delete_user(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+fn delete_user(uuid: String, token: AdminToken, conn: DbConn) -> EmptyResult {
     let user = get_user_or_404(&uuid, &conn)?;
-    user.delete(&conn)
+    token.require_role_on(ServerRole::UserAdmin, &user)?;
+    let email = user.email.clone();
+
+    // Deleting a user who is already deleted removes them without waiting for the end of the grace period
+    if user.deleted_at.is_some() {
+        token.require_role_on(ServerRole::SuperAdmin, &user)?;
+        user.delete(&conn)?;
+        token.log_action("Permanently delete user", Some(email), &conn);
+    } else {
+        delete_user_account(user, &conn)?;
+        token.log_action("Delete user", Some(email), &conn);
+    }
+    Ok(())
+}
+
+#[post("/users/<uuid>/restore")]
+fn restore_user(uuid: String, token: AdminToken, conn: DbConn) -> EmptyResult {
+    let mut user = get_user_or_404(&uuid, &conn)?;
+    token.require_role_on(ServerRole::UserAdmin, &user)?;
+    if user.deleted_at.is_none() {
+        err!("The user isn't deleted")
+    }
+    user.restore();
+
+    user.save(&conn)?;
+    token.log_action("Restore deleted user", Some(user.email), &conn);
+    Ok(())
 }
 
 #[post("/users/<uuid>/deauth")]
-fn deauth_user(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+fn deauth_user(uuid: String, token: AdminToken, conn: DbConn) -> EmptyResult {
     let mut user = get_user_or_404(&uuid, &conn)?;
+    token.require_role_on(ServerRole::UserAdmin, &user)?;
     Device::delete_all_by_user(&user.uuid, &conn)?;
     user.reset_security_stamp();
 
-    user.save(&conn)
+    user.save(&conn)?;
+    token.log_action("Deauthorize user sessions", Some(user.email), &conn);
+    Ok(())
 }
 
 #[post("/users/<uuid>/disable")]
-fn disable_user(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+fn disable_user(uuid: String, token: AdminToken, conn: DbConn) -> EmptyResult {
     let mut user = get_user_or_404(&uuid, &conn)?;
+    token.require_role_on(ServerRole::UserAdmin, &user)?;
     Device::delete_all_by_user(&user.uuid, &conn)?;
     user.reset_security_stamp();
     user.enabled = false;
 
-    user.save(&conn)
+    user.save(&conn)?;
+    token.log_action("Disable user", Some(user.email), &conn);
+    Ok(())
 }
 
 #[post("/users/<uuid>/enable")]
-fn enable_user(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+fn enable_user(uuid: String, token: AdminToken, conn: DbConn) -> EmptyResult {
     let mut user = get_user_or_404(&uuid, &conn)?;
+    token.require_role_on(ServerRole::UserAdmin, &user)?;
+    if user.deleted_at.is_some() {
+        err!("The user is deleted, restore it instead")
+    }
     user.enabled = true;
 
-    user.save(&conn)
+    user.save(&conn)?;
+    token.log_action("Enable user", Some(user.email), &conn);
+    Ok(())
 }
 
 #[post("/users/<uuid>/remove-2fa")]
-fn remove_2fa(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+fn remove_2fa(uuid: String, token: AdminToken, conn: DbConn) -> EmptyResult {
     let mut user = get_user_or_404(&uuid, &conn)?;
+    token.require_role_on(ServerRole::UserAdmin, &user)?;
     TwoFactor::delete_all_by_user(&user.uuid, &conn)?;
     user.totp_recover = None;
-    user.save(&conn)
+    user.save(&conn)?;
+
+    token.log_action("Remove user 2FA", Some(user.email), &conn);
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+struct AttachmentLimitData {
+    /// Limit in KB, or `None` to use the global limit
+    attachment_limit: Option<i64>,
+}
+
+fn validate_attachment_limit(limit: Option<i64>) -> EmptyResult {
+    if let Some(limit) = limit {
+        if limit < 0 {
+            err!("The attachment limit can't be negative")
+        }
+    }
+    Ok(())
+}
+
+#[post("/users/<uuid>/attachment_limit", data = "<data>")]
+fn update_user_attachment_limit(
+    uuid: String,
+    data: Json<AttachmentLimitData>,
+    token: AdminToken,
+    conn: DbConn,
+) -> EmptyResult {
+    let data: AttachmentLimitData = data.into_inner();
+    validate_attachment_limit(data.attachment_limit)?;
+
+    let mut user = get_user_or_404(&uuid, &conn)?;
+    token.require_role_on(ServerRole::UserAdmin, &user)?;
+    user.attachment_limit = data.attachment_limit;
+    user.save(&conn)?;
+
+    token.log_action("Change user attachment limit", Some(user.email), &conn);
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+struct ServerRoleData {
+    /// `None` to remove the role of the user
+    server_role: Option<NumberOrString>,
+}
+
+#[post("/users/<uuid>/server_role", data = "<data>")]
+fn update_user_server_role(uuid: String, data: Json<ServerRoleData>, token: AdminToken, conn: DbConn) -> EmptyResult {
+    token.require_role(ServerRole::SuperAdmin)?;
+    let data: ServerRoleData = data.into_inner();
+
+    let new_role = match data.server_role {
+        Some(role) => match ServerRole::from_str(&role.into_string()) {
+            Some(role) => Some(role),
+            None => err!("Invalid server role"),
+        },
+        None => None,
+    };
+
+    let mut user = get_user_or_404(&uuid, &conn)?;
+    token.require_role_on(ServerRole::SuperAdmin, &user)?;
+    user.server_role = new_role.map(|role| role as i32);
+    user.save(&conn)?;
+
+    let target = format!("{}: {}", user.email, new_role.map_or("None", ServerRole::name));
+    token.log_action("Change user server role", Some(target), &conn);
+    Ok(())
 }
 
 #[derive(Deserialize, Debug)]
@@ -386,13 +666,15 @@ struct UserOrgTypeData {
 }
 
 #[post("/users/org_type", data = "<data>")]
-fn update_user_org_type(data: Json<UserOrgTypeData>, _token: AdminToken, conn: DbConn) -> EmptyResult {
+fn update_user_org_type(data: Json<UserOrgTypeData>, token: AdminToken, conn: DbConn) -> EmptyResult {
+    token.require_role(ServerRole::SuperAdmin)?;
     let data: UserOrgTypeData = data.into_inner();
 
     let mut user_to_edit = match UserOrganization::find_by_user_and_org(&data.user_uuid, &data.org_uuid, &conn) {
         Some(user) => user,
         None => err!("The specified user isn't member of the organization"),
     };
+    token.require_role_on(ServerRole::SuperAdmin, &get_user_or_404(&data.user_uuid, &conn)?)?;
 
     let new_type = match UserOrgType::from_str(&data.user_type.into_string()) {
         Some(new_type) => new_type as i32,
@@ -409,12 +691,23 @@ fn update_user_org_type(data: Json<UserOrgTypeData>, _token: AdminToken, conn: D
     }
 
     user_to_edit.atype = new_type as i32;
-    user_to_edit.save(&conn)
+    user_to_edit.save(&conn)?;
+
+    token.log_action(
+        "Change organization member type",
+        Some(format!("{} in {}", data.user_uuid, data.org_uuid)),
+        &conn,
+    );
+    Ok(())
 }
 
 #[post("/users/update_revision")]
-fn update_revision_users(_token: AdminToken, conn: DbConn) -> EmptyResult {
-    User::update_all_revisions(&conn)
+fn update_revision_users(token: AdminToken, conn: DbConn) -> EmptyResult {
+    token.require_role(ServerRole::UserAdmin)?;
+    User::update_all_revisions(&conn)?;
+
+    token.log_action("Force clients to resync", None, &conn);
+    Ok(())
 }
 
 #[get("/organizations/overview")]
@@ -427,7 +720,10 @@ fn organizations_overview(_token: AdminToken, conn: DbConn) -> ApiResult<Html<St
             org["user_count"] = json!(UserOrganization::count_by_org(&o.uuid, &conn));
             org["cipher_count"] = json!(Cipher::count_by_org(&o.uuid, &conn));
             org["attachment_count"] = json!(Attachment::count_by_org(&o.uuid, &conn));
-            org["attachment_size"] = json!(get_display_size(Attachment::size_by_org(&o.uuid, &conn) as i32));
+            org["attachment_size"] = json!(get_display_size(Attachment::size_by_org(&o.uuid, &conn)));
+            org["attachment_limit"] = json!(o.attachment_limit);
+            org["attachment_limit_custom"] = json!(o.attachment_limit.is_some());
+            org["attachment_limit_size"] = json!(o.effective_attachment_limit().map(|l| get_display_size(l * 1024)));
             org
         })
         .collect();
@@ -437,9 +733,512 @@ fn organizations_overview(_token: AdminToken, conn: DbConn) -> ApiResult<Html<St
 }
 
 #[post("/organizations/<uuid>/delete")]
-fn delete_organization(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+fn delete_organization(uuid: String, token: AdminToken, conn: DbConn) -> EmptyResult {
+    token.require_role(ServerRole::SuperAdmin)?;
     let org = Organization::find_by_uuid(&uuid, &conn).map_res("Organization doesn't exist")?;
-    org.delete(&conn)
+    let name = org.name.clone();
+    org.delete(&conn)?;
+
+    token.log_action("Delete organization", Some(name), &conn);
+    Ok(())
+}
+
+#[post("/organizations/<uuid>/attachment_limit", data = "<data>")]
+fn update_org_attachment_limit(
+    uuid: String,
+    data: Json<AttachmentLimitData>,
+    token: AdminToken,
+    conn: DbConn,
+) -> EmptyResult {
+    token.require_role(ServerRole::SuperAdmin)?;
+    let data: AttachmentLimitData = data.into_inner();
+    validate_attachment_limit(data.attachment_limit)?;
+
+    let mut org = Organization::find_by_uuid(&uuid, &conn).map_res("Organization doesn't exist")?;
+    org.attachment_limit = data.attachment_limit;
+    org.save(&conn)?;
+
+    token.log_action("Change organization attachment limit", Some(org.name), &conn);
+    Ok(())
+}
+
+/// Storage used by a single user or organization.
+#[derive(Default)]
+struct StorageUsage {
+    owner_type: &'static str,
+    id: String,
+    name: String,
+    email: String,
+    attachment_count: usize,
+    attachment_size: i64,
+    send_file_count: usize,
+    send_file_size: i64,
+    expired_send_count: usize,
+    trash_count: usize,
+    trash_size: i64,
+    last_active: Option<NaiveDateTime>,
+}
+
+impl StorageUsage {
+    fn for_user(user: &User, last_active: Option<NaiveDateTime>) -> Self {
+        Self {
+            owner_type: "user",
+            id: user.uuid.clone(),
+            name: user.name.clone(),
+            email: user.email.clone(),
+            last_active,
+            ..Default::default()
+        }
+    }
+
+    fn for_org(org: &Organization) -> Self {
+        Self {
+            owner_type: "organization",
+            id: org.uuid.clone(),
+            name: org.name.clone(),
+            email: org.billing_email.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn total_size(&self) -> i64 {
+        self.attachment_size + self.send_file_size + self.trash_size
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "type": self.owner_type,
+            "id": self.id,
+            "name": self.name,
+            "email": self.email,
+            "attachment_count": self.attachment_count,
+            "attachment_size": self.attachment_size,
+            "send_file_count": self.send_file_count,
+            "send_file_size": self.send_file_size,
+            "expired_send_count": self.expired_send_count,
+            "trash_count": self.trash_count,
+            "trash_size": self.trash_size,
+            "total_size": self.total_size(),
+            "last_active": self.last_active.as_ref().map(format_date),
+        })
+    }
+
+    fn to_csv_row(&self) -> String {
+        let fields = [
+            self.owner_type.to_string(),
+            self.id.clone(),
+            self.name.clone(),
+            self.email.clone(),
+            self.attachment_count.to_string(),
+            self.attachment_size.to_string(),
+            self.send_file_count.to_string(),
+            self.send_file_size.to_string(),
+            self.expired_send_count.to_string(),
+            self.trash_count.to_string(),
+            self.trash_size.to_string(),
+            self.total_size().to_string(),
+            self.last_active.as_ref().map(format_date).unwrap_or_default(),
+        ];
+        fields.iter().map(|f| csv_escape(f)).collect::<Vec<String>>().join(",")
+    }
+}
+
+const STORAGE_CSV_HEADER: &str = "type,id,name,email,attachment_count,attachment_size,send_file_count,send_file_size,\
+                                  expired_send_count,trash_count,trash_size,total_size,last_active";
+
+/// Quotes a CSV field when needed, and neutralizes values which spreadsheets would interpret as formulas.
+fn csv_escape(field: &str) -> String {
+    let field = if field.starts_with(&['=', '+', '-', '@'][..]) {
+        format!("'{}", field)
+    } else {
+        field.to_string()
+    };
+
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+/// Returns the storage usage of all the users and organizations, the largest first.
+/// Everything is loaded at once, so the number of queries doesn't depend on the number of users.
+/// Only the owners of the ciphers are loaded, not their data.
+fn get_storage_usage(conn: &DbConn) -> Vec<StorageUsage> {
+    aggregate_storage_usage(
+        &User::get_all(conn),
+        &Organization::get_all(conn),
+        Cipher::get_all_ownerships(conn),
+        Attachment::get_all(conn),
+        Send::get_all(conn),
+        &Device::find_last_active_by_users(conn),
+        &Utc::now().naive_utc(),
+    )
+}
+
+fn aggregate_storage_usage(
+    users: &[User],
+    orgs: &[Organization],
+    ciphers: Vec<CipherOwnership>,
+    attachments: Vec<Attachment>,
+    sends: Vec<Send>,
+    last_active: &HashMap<String, NaiveDateTime>,
+    now: &NaiveDateTime,
+) -> Vec<StorageUsage> {
+    let mut usage: HashMap<String, StorageUsage> = users
+        .iter()
+        .map(|u| (u.uuid.clone(), StorageUsage::for_user(u, last_active.get(&u.uuid).copied())))
+        .chain(orgs.iter().map(|o| (o.uuid.clone(), StorageUsage::for_org(o))))
+        .collect();
+
+    // Items of an organization belong to it, even when they also have a user set
+    let mut cipher_owners = HashMap::new();
+    for cipher in ciphers {
+        let owner = match cipher.organization_uuid.as_ref().or_else(|| cipher.user_uuid.as_ref()) {
+            Some(owner) => owner,
+            None => continue,
+        };
+        if let Some(owner_usage) = usage.get_mut(owner) {
+            if cipher.deleted_at.is_some() {
+                owner_usage.trash_count += 1;
+            }
+            // Organizations don't have devices, so use the last change to one of their items
+            if owner_usage.owner_type == "organization" {
+                owner_usage.last_active = owner_usage.last_active.max(Some(cipher.updated_at));
+            }
+            cipher_owners.insert(cipher.uuid, (owner.clone(), cipher.deleted_at.is_some()));
+        }
+    }
+
+    for attachment in attachments {
+        if let Some((owner, trashed)) = cipher_owners.get(&attachment.cipher_uuid) {
+            if let Some(owner_usage) = usage.get_mut(owner) {
+                if *trashed {
+                    owner_usage.trash_size += attachment.file_size as i64;
+                } else {
+                    owner_usage.attachment_count += 1;
+                    owner_usage.attachment_size += attachment.file_size as i64;
+                }
+            }
+        }
+    }
+
+    for send in sends {
+        let owner_usage = match send.organization_uuid.as_ref().or_else(|| send.user_uuid.as_ref()) {
+            Some(owner) => usage.get_mut(owner),
+            None => None,
+        };
+        if let Some(owner_usage) = owner_usage {
+            if let Some(size) = send.file_size() {
+                owner_usage.send_file_count += 1;
+                owner_usage.send_file_size += size;
+            }
+            if send.is_expired(now) {
+                owner_usage.expired_send_count += 1;
+            }
+        }
+    }
+
+    let mut usage: Vec<StorageUsage> = usage.into_iter().map(|(_, u)| u).collect();
+    usage.sort_by(|a, b| b.total_size().cmp(&a.total_size()).then_with(|| a.name.cmp(&b.name)));
+    usage
+}
+
+#[get("/storage/overview")]
+fn storage_overview(_token: AdminToken, conn: DbConn) -> ApiResult<Html<String>> {
+    let dt_fmt = "%Y-%m-%d %H:%M:%S %Z";
+    let usage_json: Vec<Value> = get_storage_usage(&conn)
+        .iter()
+        .map(|u| {
+            let mut json = u.to_json();
+            for field in &["attachment_size", "send_file_size", "trash_size", "total_size"] {
+                json[format!("{}_name", field)] = json!(get_display_size(json[field].as_i64().unwrap_or_default()));
+            }
+            json["last_active_name"] = match u.last_active {
+                Some(dt) => json!(format_naive_datetime_local(&dt, dt_fmt)),
+                None => json!("Never"),
+            };
+            json
+        })
+        .collect();
+
+    let text = AdminTemplateData::with_data("admin/storage", json!(usage_json)).render()?;
+    Ok(Html(text))
+}
+
+#[get("/storage")]
+fn get_storage_json(_token: AdminToken, conn: DbConn) -> Json<Value> {
+    let usage_json: Vec<Value> = get_storage_usage(&conn).iter().map(StorageUsage::to_json).collect();
+    Json(Value::Array(usage_json))
+}
+
+#[get("/storage/csv")]
+fn get_storage_csv(_token: AdminToken, conn: DbConn) -> Response<'static> {
+    let mut csv = format!("{}\n", STORAGE_CSV_HEADER);
+    for usage in get_storage_usage(&conn) {
+        csv.push_str(&usage.to_csv_row());
+        csv.push('\n');
+    }
+
+    let file_name = format!("vaultwarden-storage-{}.csv", Utc::now().format("%Y%m%d"));
+    Response::build()
+        .header(ContentType::CSV)
+        .raw_header("Content-Disposition", format!("attachment; filename=\"{}\"", file_name))
+        .sized_body(Cursor::new(csv))
+        .finalize()
+}
+
+#[get("/mail/overview")]
+fn mail_queue_overview(_token: AdminToken, conn: DbConn) -> ApiResult<Html<String>> {
+    let dt_fmt = "%Y-%m-%d %H:%M:%S %Z";
+    let mails_json: Vec<Value> = QueuedMail::find_all(&conn)
+        .iter()
+        .map(|m| {
+            let mut mail = m.to_json();
+            mail["failed"] = json!(m.status == MailStatus::Failed as i32);
+            mail["created_at"] = json!(format_naive_datetime_local(&m.created_at, dt_fmt));
+            mail["next_attempt_at"] = json!(format_naive_datetime_local(&m.next_attempt_at, dt_fmt));
+            mail
+        })
+        .collect();
+
+    let text = AdminTemplateData::with_data("admin/mail_queue", json!(mails_json)).render()?;
+    Ok(Html(text))
+}
+
+#[get("/mail")]
+fn get_mail_queue_json(_token: AdminToken, conn: DbConn) -> Json<Value> {
+    let mails_json: Vec<Value> = QueuedMail::find_all(&conn).iter().map(QueuedMail::to_json).collect();
+    Json(Value::Array(mails_json))
+}
+
+#[post("/mail/<uuid>/resend")]
+fn resend_queued_mail(uuid: String, token: AdminToken, conn: DbConn) -> EmptyResult {
+    token.require_role(ServerRole::UserAdmin)?;
+    let mut mail = QueuedMail::find_by_uuid(&uuid, &conn).map_res("Queued email doesn't exist")?;
+    mail.status = MailStatus::Pending as i32;
+    mail.attempts = 0;
+    mail.next_attempt_at = Utc::now().naive_utc();
+    mail.save(&conn)?;
+
+    if let Some(ref broadcast_uuid) = mail.broadcast_uuid {
+        BroadcastRecipient::update_status(broadcast_uuid, &mail.recipient, BroadcastStatus::Pending, None, &conn)?;
+    }
+
+    mail::wake_queue_worker();
+    token.log_action("Resend queued email", Some(mail.recipient), &conn);
+    Ok(())
+}
+
+#[post("/mail/<uuid>/discard")]
+fn discard_queued_mail(uuid: String, token: AdminToken, conn: DbConn) -> EmptyResult {
+    token.require_role(ServerRole::UserAdmin)?;
+    let mail = QueuedMail::find_by_uuid(&uuid, &conn).map_res("Queued email doesn't exist")?;
+    let recipient = mail.recipient.clone();
+    mail.delete(&conn)?;
+
+    token.log_action("Discard queued email", Some(recipient), &conn);
+    Ok(())
+}
+
+#[get("/email/preview")]
+fn email_preview(_token: AdminToken) -> ApiResult<Html<String>> {
+    let page_data = json!({
+        "templates": mail::EMAIL_TEMPLATES,
+        "languages": mail::EMAIL_LANGUAGES,
+    });
+
+    let text = AdminTemplateData::with_data("admin/email_preview", page_data).render()?;
+    Ok(Html(text))
+}
+
+#[get("/email/preview/<name>/<lang>")]
+fn render_email_preview(name: String, lang: String, _token: AdminToken) -> JsonResult {
+    if !mail::EMAIL_TEMPLATES.contains(&name.as_str()) {
+        err!("Unknown email template")
+    }
+    if !mail::EMAIL_LANGUAGES.contains(&lang.as_str()) {
+        err!("Unsupported language")
+    }
+
+    // Sample values for the variables of all the templates
+    let data = json!({
+        "url": CONFIG.domain(),
+        "hint": "The name of my first pet",
+        "user_id": crate::util::get_uuid(),
+        "email": "user@example.com",
+        "token": "123456",
+        "org_name": "Example Organization",
+        "org_id": crate::util::get_uuid(),
+        "org_user_id": crate::util::get_uuid(),
+        "device": "Firefox",
+        "ip": "192.0.2.1",
+        "datetime": crate::util::format_datetime_local(&chrono::Local::now(), "%A, %B %_d, %Y at %r %Z"),
+        "deadline": crate::util::format_datetime_local(&(chrono::Local::now() + chrono::Duration::days(7)), "%A, %B %_d, %Y at %r %Z"),
+        "purge_date": crate::util::format_datetime_local(&(chrono::Local::now() + chrono::Duration::days(30)), "%A, %B %_d, %Y at %r %Z"),
+        "name": "user@example.com",
+        "count": 5,
+        "minutes": 15,
+        "percent": 90,
+        "limit_reached": false,
+        "used": "900 MB",
+        "limit": "1 GB",
+    });
+
+    let (subject, body_html, body_text) = mail::render_text(&format!("email/{}", name), Some(&lang), &data)?;
+    Ok(Json(json!({
+        "Subject": subject,
+        "BodyHtml": body_html,
+        "BodyText": body_text,
+    })))
+}
+
+#[derive(Deserialize)]
+struct BroadcastData {
+    subject: String,
+    body: String,
+    /// One of "all", "enabled", "organizations" or "no_2fa"
+    recipients: String,
+    organizations: Option<Vec<String>>,
+}
+
+/// Returns the users selected as recipients of a broadcast, and a description of the selection.
+fn get_broadcast_recipients(data: &BroadcastData, conn: &DbConn) -> ApiResult<(Vec<User>, String)> {
+    let mut member_uuids = HashSet::new();
+    let description = match data.recipients.as_str() {
+        "all" => String::from("All users"),
+        "enabled" => String::from("Enabled users"),
+        "no_2fa" => String::from("Users without two-step login"),
+        "organizations" => {
+            let mut org_names = Vec::new();
+            for org_uuid in data.organizations.as_deref().unwrap_or_default() {
+                let org = Organization::find_by_uuid(org_uuid, conn).map_res("Organization doesn't exist")?;
+                for member in UserOrganization::find_by_org(&org.uuid, conn) {
+                    if member.status != UserOrgStatus::Invited as i32 {
+                        member_uuids.insert(member.user_uuid);
+                    }
+                }
+                org_names.push(org.name);
+            }
+            if org_names.is_empty() {
+                err!("No organizations selected")
+            }
+            format!("Members of {}", org_names.join(", "))
+        }
+        _ => err!("Invalid recipients"),
+    };
+
+    let has_2fa = |user: &User| !TwoFactor::find_by_user(&user.uuid, conn).is_empty();
+    let users = filter_broadcast_recipients(User::get_all(conn), &data.recipients, has_2fa, &member_uuids);
+    Ok((users, description))
+}
+
+/// Selects the recipients of a broadcast to one of the groups of `BroadcastData::recipients`.
+/// Users who haven't finished their registration yet are never included.
+fn filter_broadcast_recipients(
+    users: Vec<User>,
+    recipients: &str,
+    has_2fa: impl Fn(&User) -> bool,
+    member_uuids: &HashSet<String>,
+) -> Vec<User> {
+    users
+        .into_iter()
+        // Deleted users are only kept to be restored, they don't get any email besides the purge reminder
+        .filter(|u| !u.password_hash.is_empty() && u.deleted_at.is_none())
+        .filter(|u| match recipients {
+            "all" => true,
+            "enabled" => u.enabled,
+            "no_2fa" => u.enabled && !has_2fa(u),
+            "organizations" => u.enabled && member_uuids.contains(&u.uuid),
+            _ => false,
+        })
+        .collect()
+}
+
+#[get("/broadcast/overview")]
+fn broadcast_overview(_token: AdminToken, conn: DbConn) -> ApiResult<Html<String>> {
+    let organizations: Vec<Value> = Organization::get_all(&conn)
+        .iter()
+        .map(|o| {
+            json!({
+                "Id": o.uuid,
+                "Name": o.name,
+            })
+        })
+        .collect();
+
+    let dt_fmt = "%Y-%m-%d %H:%M:%S %Z";
+    let broadcasts: Vec<Value> = Broadcast::find_all(&conn)
+        .iter()
+        .map(|b| {
+            let mut broadcast = b.to_json(&conn);
+            broadcast["created_at"] = json!(format_naive_datetime_local(&b.created_at, dt_fmt));
+            broadcast
+        })
+        .collect();
+
+    let page_data = json!({
+        "organizations": organizations,
+        "broadcasts": broadcasts,
+    });
+
+    let text = AdminTemplateData::with_data("admin/broadcast", page_data).render()?;
+    Ok(Html(text))
+}
+
+#[post("/broadcast/preview", data = "<data>")]
+fn preview_broadcast(data: Json<BroadcastData>, _token: AdminToken, conn: DbConn) -> JsonResult {
+    let data = data.into_inner();
+    let (users, _) = get_broadcast_recipients(&data, &conn)?;
+
+    // Preview the email of the first recipient, or of an example user when there are none
+    let example_user = User::new(String::from("user@example.com"));
+    let user = users.first().unwrap_or(&example_user);
+    let (subject, body_html, body_text) = mail::render_broadcast(&data.subject, &data.body, user)?;
+
+    Ok(Json(json!({
+        "RecipientCount": users.len(),
+        "Subject": subject,
+        "BodyHtml": body_html,
+        "BodyText": body_text,
+    })))
+}
+
+#[post("/broadcast", data = "<data>")]
+fn send_broadcast(data: Json<BroadcastData>, token: AdminToken, conn: DbConn) -> JsonResult {
+    token.require_role(ServerRole::SuperAdmin)?;
+    if !CONFIG.mail_enabled() {
+        err!("Email is not configured")
+    }
+
+    let data = data.into_inner();
+    if data.subject.trim().is_empty() || data.body.trim().is_empty() {
+        err!("The subject and the body can't be empty")
+    }
+
+    let (users, description) = get_broadcast_recipients(&data, &conn)?;
+    if users.is_empty() {
+        err!("No users match the selected recipients")
+    }
+    // Catch errors in the templates before anything is sent
+    mail::render_broadcast(&data.subject, &data.body, &users[0])?;
+
+    let broadcast = Broadcast::new(data.subject, data.body, description);
+    mail::send_broadcast(&broadcast, &users, &conn)?;
+
+    token.log_action("Send broadcast", Some(broadcast.subject.clone()), &conn);
+    Ok(Json(broadcast.to_json(&conn)))
+}
+
+#[get("/broadcast/<uuid>")]
+fn get_broadcast_json(uuid: String, _token: AdminToken, conn: DbConn) -> JsonResult {
+    let broadcast = Broadcast::find_by_uuid(&uuid, &conn).map_res("Broadcast doesn't exist")?;
+    let recipients: Vec<Value> =
+        BroadcastRecipient::find_by_broadcast(&uuid, &conn).iter().map(BroadcastRecipient::to_json).collect();
+
+    let mut broadcast_json = broadcast.to_json(&conn);
+    broadcast_json["RecipientList"] = json!(recipients);
+    Ok(Json(broadcast_json))
 }
 
 #[derive(Deserialize)]
@@ -541,6 +1340,30 @@ fn diagnostics(_token: AdminToken, ip_header: IpHeader, conn: DbConn) -> ApiResu
         _ => "",
     };
 
+    let icon_cache = crate::api::icons::icon_cache_stats();
+    let icon_cache_max_size = match icon_cache.max_size {
+        0 => "Unlimited".to_string(),
+        max_size => get_display_size(max_size as i64),
+    };
+    let icon_cache_oldest_access = icon_cache.oldest_access.map(|time| {
+        format_naive_datetime_local(&chrono::DateTime::<Utc>::from(time).naive_utc(), "%Y-%m-%d %H:%M:%S %Z")
+    });
+
+    let jwt_keys: Vec<Value> = crate::jwt_keys::key_metadata()
+        .into_iter()
+        .map(|key| {
+            let format_time = |time: i64| {
+                format_naive_datetime_local(&NaiveDateTime::from_timestamp(time, 0), "%Y-%m-%d %H:%M:%S %Z")
+            };
+            json!({
+                "kid": key.kid,
+                "algorithm": key.algorithm.as_str(),
+                "created_at": format_time(key.created_at),
+                "retired_at": key.retired_at.map(format_time),
+            })
+        })
+        .collect();
+
     let diagnostics_json = json!({
         "dns_resolved": dns_resolved,
         "latest_release": latest_release,
@@ -555,10 +1378,17 @@ fn diagnostics(_token: AdminToken, ip_header: IpHeader, conn: DbConn) -> ApiResu
         "ip_header_name": ip_header_name,
         "ip_header_config": &CONFIG.ip_header(),
         "uses_proxy": uses_proxy,
+        "blocked_requests": crate::http_client::blocked_requests(),
         "db_type": *DB_TYPE,
         "db_version": get_sql_server_version(&conn),
         "admin_url": format!("{}/diagnostics", admin_url(Referer(None))),
         "overrides": &CONFIG.get_overrides().join(", "),
+        "icon_cache_icons": icon_cache.icons,
+        "icon_cache_misses": icon_cache.misses,
+        "icon_cache_size": get_display_size(icon_cache.size as i64),
+        "icon_cache_max_size": icon_cache_max_size,
+        "icon_cache_oldest_access": icon_cache_oldest_access,
+        "jwt_keys": jwt_keys,
         "server_time_local": Local::now().format("%Y-%m-%d %H:%M:%S %Z").to_string(),
         "server_time": Utc::now().format("%Y-%m-%d %H:%M:%S UTC").to_string(), // Run the date/time check as the last item to minimize the difference
     });
@@ -573,55 +1403,566 @@ fn get_diagnostics_config(_token: AdminToken) -> Json<Value> {
     Json(support_json)
 }
 
+#[derive(Deserialize)]
+struct FsckData {
+    repair: bool,
+    confirm: Option<String>,
+}
+
+/// Text the admin has to type to confirm a repair, as it removes data.
+const FSCK_REPAIR_CONFIRMATION: &str = "REPAIR";
+
+#[post("/diagnostics/fsck", data = "<data>")]
+fn run_fsck(data: Json<FsckData>, token: AdminToken, conn: DbConn) -> JsonResult {
+    let data = data.into_inner();
+    let repair = data.repair;
+    if repair {
+        token.require_role(ServerRole::SuperAdmin)?;
+        if data.confirm.as_deref() != Some(FSCK_REPAIR_CONFIRMATION) {
+            err_code!("The repair has to be confirmed", Status::BadRequest.code);
+        }
+    }
+    let report = crate::fsck::run(repair, &conn);
+
+    if repair {
+        token.log_action("Repair database and files", None, &conn);
+    }
+    Ok(Json(report.to_json()))
+}
+
+#[derive(Deserialize)]
+struct IconCachePurgeData {
+    domain: Option<String>,
+}
+
+#[post("/diagnostics/icon_cache/purge", data = "<data>")]
+fn purge_icon_cache(data: Json<IconCachePurgeData>, token: AdminToken, conn: DbConn) -> JsonResult {
+    token.require_role(ServerRole::SuperAdmin)?;
+    let data = data.into_inner();
+    let domain = data.domain.as_deref().filter(|domain| !domain.trim().is_empty());
+    let removed = crate::api::icons::purge_cached_icons(domain)?;
+
+    match domain {
+        Some(domain) => info!("Admin purged the cached icon of {}", domain),
+        None => info!("Admin purged the icon cache, {} files removed", removed),
+    }
+    token.log_action("Purge icon cache", domain.map(String::from), &conn);
+    Ok(Json(json!({ "removed": removed })))
+}
+
 #[post("/config", data = "<data>")]
-fn post_config(data: Json<ConfigBuilder>, _token: AdminToken) -> EmptyResult {
+fn post_config(data: Json<ConfigBuilder>, token: AdminToken, conn: DbConn) -> EmptyResult {
+    token.require_role(ServerRole::SuperAdmin)?;
     let data: ConfigBuilder = data.into_inner();
-    CONFIG.update_config(data)
+    CONFIG.update_config(data)?;
+
+    token.log_action("Change configuration", None, &conn);
+    Ok(())
 }
 
 #[post("/config/delete")]
-fn delete_config(_token: AdminToken) -> EmptyResult {
-    CONFIG.delete_user_config()
+fn delete_config(token: AdminToken, conn: DbConn) -> EmptyResult {
+    token.require_role(ServerRole::SuperAdmin)?;
+    CONFIG.delete_user_config()?;
+
+    token.log_action("Reset configuration", None, &conn);
+    Ok(())
 }
 
 #[post("/config/backup_db")]
-fn backup_db(_token: AdminToken, conn: DbConn) -> EmptyResult {
+fn backup_db(token: AdminToken, conn: DbConn) -> EmptyResult {
+    token.require_role(ServerRole::SuperAdmin)?;
     if *CAN_BACKUP {
-        backup_database(&conn)
+        backup_database(&conn)?;
+        token.log_action("Back up database", None, &conn);
+        Ok(())
     } else {
         err!("Can't back up current DB (Only SQLite supports this feature)");
     }
 }
 
-pub struct AdminToken {}
+pub struct AdminToken {
+    /// The admin account, `None` when logged in with the admin token or when it's disabled
+    admin: Option<Admin>,
+    /// The user, when a user with a server role called the endpoint with their login JWT
+    user: Option<User>,
+    role: ServerRole,
+    ip: ClientIp,
+}
+
+impl AdminToken {
+    /// Name of the admin in the action log
+    fn name(&self) -> String {
+        if let Some(ref user) = self.user {
+            return format!("{} ({})", user.email, self.role.name());
+        }
+        match self.admin {
+            Some(ref admin) => admin.name.clone(),
+            None if CONFIG.disable_admin_token() => String::from("(external authentication)"),
+            None => String::from("(admin token)"),
+        }
+    }
+
+    /// Fails unless the admin has at least the given role, the admin panel logins have all of them
+    fn require_role(&self, role: ServerRole) -> EmptyResult {
+        if self.role < role {
+            err_code!(
+                format!("You need to be {} to do this", role.name()),
+                format!("User: {}", self.name()),
+                Status::Forbidden.code
+            )
+        }
+        Ok(())
+    }
+
+    /// Like `require_role`, but also fails when a server role user acts on a user with the same or a higher role
+    fn require_role_on(&self, role: ServerRole, target: &User) -> EmptyResult {
+        self.require_role(role)?;
+        if self.user.is_some() && target.server_role().map_or(false, |target_role| target_role >= self.role) {
+            err_code!(
+                "You can't manage a user with the same or a higher server role",
+                format!("User: {}, target: {}", self.name(), target.email),
+                Status::Forbidden.code
+            )
+        }
+        Ok(())
+    }
+
+    /// Records an action done by this admin in the admin action log
+    fn log_action(&self, action: &str, target: Option<String>, conn: &DbConn) {
+        let admin_uuid = self.admin.as_ref().map(|admin| admin.uuid.clone());
+        let entry = AdminAction::new(admin_uuid, self.name(), action, target, self.ip.ip.to_string());
+        info!(
+            "Admin action by {}: {}{}. IP: {}",
+            entry.admin_name,
+            entry.action,
+            entry.target.as_ref().map(|t| format!(" ({})", t)).unwrap_or_default(),
+            entry.ip
+        );
+
+        if let Err(e) = entry.save(conn) {
+            error!("Error saving admin action: {:#?}", e);
+        }
+    }
+}
 
 impl<'a, 'r> FromRequest<'a, 'r> for AdminToken {
     type Error = &'static str;
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
-        if CONFIG.disable_admin_token() {
-            Outcome::Success(AdminToken {})
-        } else {
-            let mut cookies = request.cookies();
+        let ip = match request.guard::<ClientIp>() {
+            Outcome::Success(ip) => ip,
+            _ => err_handler!("Error getting Client IP"),
+        };
 
-            let access_token = match cookies.get(COOKIE_NAME) {
-                Some(cookie) => cookie.value(),
-                None => return Outcome::Forward(()), // If there is no cookie, redirect to login
-            };
+        if CONFIG.disable_admin_token() {
+            return Outcome::Success(AdminToken {
+                admin: None,
+                user: None,
+                role: ServerRole::SuperAdmin,
+                ip,
+            });
+        }
 
-            let ip = match request.guard::<ClientIp>() {
-                Outcome::Success(ip) => ip.ip,
-                _ => err_handler!("Error getting Client IP"),
-            };
+        let mut cookies = request.cookies();
+
+        let access_token = match cookies.get(COOKIE_NAME) {
+            Some(cookie) => cookie.value(),
+            // Without the admin cookie, the request can come from a user with a server role
+            None if request.headers().contains("Authorization") => {
+                return match request.guard::<ServerRoleHeaders>() {
+                    Outcome::Success(headers) => Outcome::Success(AdminToken {
+                        admin: None,
+                        user: Some(headers.user),
+                        role: headers.role,
+                        ip,
+                    }),
+                    Outcome::Failure(f) => Outcome::Failure(f),
+                    Outcome::Forward(_) => Outcome::Forward(()),
+                };
+            }
+            None => return Outcome::Forward(()), // If there is no cookie, redirect to login
+        };
 
-            if decode_admin(access_token).is_err() {
+        let claims = match decode_admin(access_token) {
+            Ok(claims) => claims,
+            Err(_) => {
                 // Remove admin cookie
                 cookies.remove(Cookie::named(COOKIE_NAME));
-                error!("Invalid or expired admin JWT. IP: {}.", ip);
+                error!("Invalid or expired admin JWT. IP: {}.", ip.ip);
                 return Outcome::Forward(());
             }
+        };
 
-            Outcome::Success(AdminToken {})
+        if claims.sub == ADMIN_TOKEN_SUBJECT {
+            return Outcome::Success(AdminToken {
+                admin: None,
+                user: None,
+                role: ServerRole::SuperAdmin,
+                ip,
+            });
+        }
+
+        // The sessions of an admin account end as soon as it's deleted
+        let conn = match request.guard::<DbConn>() {
+            Outcome::Success(conn) => conn,
+            _ => err_handler!("Error getting DB"),
+        };
+
+        match Admin::find_by_uuid(&claims.sub, &conn) {
+            Some(admin) => Outcome::Success(AdminToken {
+                admin: Some(admin),
+                user: None,
+                role: ServerRole::SuperAdmin,
+                ip,
+            }),
+            None => {
+                cookies.remove(Cookie::named(COOKIE_NAME));
+                error!("Admin account {} doesn't exist anymore. IP: {}.", claims.sub, ip.ip);
+                Outcome::Forward(())
+            }
         }
     }
 }
+
+#[post("/diagnostics/jwt/rotate")]
+fn rotate_jwt_keys(token: AdminToken, conn: DbConn) -> JsonResult {
+    token.require_role(ServerRole::SuperAdmin)?;
+    let kid = crate::jwt_keys::rotate_keys()?;
+    info!("Admin rotated the JWT signing key, the new key is {}", kid);
+    token.log_action("Rotate JWT signing key", Some(kid.clone()), &conn);
+    Ok(Json(json!({ "kid": kid })))
+}
+
+#[get("/admins/overview")]
+fn admins_overview(token: AdminToken, conn: DbConn) -> ApiResult<Html<String>> {
+    let dt_fmt = "%Y-%m-%d %H:%M:%S %Z";
+    let current_uuid = token.admin.as_ref().map(|admin| admin.uuid.clone());
+
+    let admins_json: Vec<Value> = Admin::get_all(&conn)
+        .iter()
+        .map(|a| {
+            let mut admin = a.to_json();
+            admin["created_at"] = json!(format_naive_datetime_local(&a.created_at, dt_fmt));
+            admin["last_login_at"] = match a.last_login_at {
+                Some(ref dt) => json!(format_naive_datetime_local(dt, dt_fmt)),
+                None => json!("Never"),
+            };
+            admin["current"] = json!(current_uuid.as_deref() == Some(a.uuid.as_str()));
+            admin
+        })
+        .collect();
+
+    let actions_json: Vec<Value> = AdminAction::find_latest(ADMIN_ACTIONS_LIMIT, &conn)
+        .iter()
+        .map(|a| {
+            let mut action = a.to_json();
+            action["created_at"] = json!(format_naive_datetime_local(&a.created_at, dt_fmt));
+            action
+        })
+        .collect();
+
+    let page_data = json!({
+        "admins": admins_json,
+        "actions": actions_json,
+        "current_admin": token.admin.as_ref().map(Admin::to_json),
+        "password_min_length": ADMIN_PASSWORD_MIN_LENGTH,
+    });
+
+    let text = AdminTemplateData::with_data("admin/admins", page_data).render()?;
+    Ok(Html(text))
+}
+
+fn validate_admin_password(password: &str) -> EmptyResult {
+    if password.chars().count() < ADMIN_PASSWORD_MIN_LENGTH {
+        err!(format!("The password must have at least {} characters", ADMIN_PASSWORD_MIN_LENGTH))
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct CreateAdminData {
+    name: String,
+    password: String,
+}
+
+#[post("/admins", data = "<data>")]
+fn create_admin(data: Json<CreateAdminData>, token: AdminToken, conn: DbConn) -> JsonResult {
+    token.require_role(ServerRole::SuperAdmin)?;
+    let data = data.into_inner();
+    let name = data.name.trim();
+
+    // The name is part of the TOTP URI, keep it simple
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || "._-@".contains(c)) {
+        err!("The name can only contain letters, numbers and . _ - @")
+    }
+    if Admin::find_by_name(name, &conn).is_some() {
+        err_code!("An admin with this name already exists", Status::Conflict.code)
+    }
+    validate_admin_password(&data.password)?;
+
+    let admin = Admin::new(name.to_string(), &data.password);
+    admin.save(&conn)?;
+
+    token.log_action("Create admin", Some(admin.name.clone()), &conn);
+    Ok(Json(admin.to_json()))
+}
+
+#[post("/admins/<uuid>/delete")]
+fn delete_admin(uuid: String, token: AdminToken, conn: DbConn) -> EmptyResult {
+    token.require_role(ServerRole::SuperAdmin)?;
+    if token.admin.as_ref().map(|admin| admin.uuid == uuid).unwrap_or(false) {
+        err!("You can't delete your own admin account")
+    }
+
+    let admin = Admin::find_by_uuid(&uuid, &conn).map_res("Admin doesn't exist")?;
+    let name = admin.name.clone();
+    admin.delete(&conn)?;
+
+    token.log_action("Delete admin", Some(name), &conn);
+    Ok(())
+}
+
+#[post("/admins/<uuid>/totp/remove")]
+fn remove_admin_totp(uuid: String, token: AdminToken, conn: DbConn) -> EmptyResult {
+    token.require_role(ServerRole::SuperAdmin)?;
+    let mut admin = Admin::find_by_uuid(&uuid, &conn).map_res("Admin doesn't exist")?;
+    admin.totp_secret = None;
+    admin.totp_last_used = 0;
+    admin.save(&conn)?;
+
+    token.log_action("Remove admin TOTP", Some(admin.name), &conn);
+    Ok(())
+}
+
+/// The admin account of the session, as the admin token doesn't have TOTP or a password to change
+fn current_admin(token: &AdminToken, conn: &DbConn) -> ApiResult<Admin> {
+    match token.admin {
+        Some(ref admin) => Admin::find_by_uuid(&admin.uuid, conn).map_res("Admin doesn't exist"),
+        None => err!("Log in with an admin account to change its settings"),
+    }
+}
+
+#[post("/admins/totp/generate")]
+fn generate_admin_totp(token: AdminToken, conn: DbConn) -> JsonResult {
+    let admin = current_admin(&token, &conn)?;
+    let secret = BASE32.encode(&crypto::get_random(vec![0u8; 20]));
+
+    Ok(Json(json!({
+        "secret": secret,
+        "uri": format!("otpauth://totp/Vaultwarden%20Admin:{}?secret={}&issuer=Vaultwarden%20Admin", admin.name, secret),
+    })))
+}
+
+#[derive(Deserialize)]
+struct ActivateTotpData {
+    secret: String,
+    code: String,
+}
+
+#[post("/admins/totp/activate", data = "<data>")]
+fn activate_admin_totp(data: Json<ActivateTotpData>, token: AdminToken, conn: DbConn) -> EmptyResult {
+    let data = data.into_inner();
+    let mut admin = current_admin(&token, &conn)?;
+
+    let secret = data.secret.trim().to_uppercase();
+    match BASE32.decode(secret.as_bytes()) {
+        Ok(decoded) if decoded.len() == 20 => {}
+        _ => err!("Invalid TOTP secret"),
+    }
+
+    admin.totp_last_used = match validate_admin_totp(&secret, data.code.trim(), 0) {
+        Some(time_step) => time_step,
+        None => err!("Invalid TOTP code"),
+    };
+    admin.totp_secret = Some(secret);
+    admin.save(&conn)?;
+
+    token.log_action("Set up admin TOTP", Some(admin.name), &conn);
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct AdminPasswordData {
+    password: String,
+}
+
+#[post("/admins/password", data = "<data>")]
+fn change_admin_password(data: Json<AdminPasswordData>, token: AdminToken, conn: DbConn) -> EmptyResult {
+    let data = data.into_inner();
+    let mut admin = current_admin(&token, &conn)?;
+    validate_admin_password(&data.password)?;
+
+    admin.set_password(&data.password);
+    admin.save(&conn)?;
+
+    token.log_action("Change admin password", Some(admin.name), &conn);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(user_role: Option<ServerRole>) -> AdminToken {
+        let user = user_role.map(|role| {
+            let mut user = User::new(String::from("admin@example.com"));
+            user.server_role = Some(role as i32);
+            user
+        });
+        AdminToken {
+            admin: None,
+            user,
+            role: user_role.unwrap_or(ServerRole::SuperAdmin),
+            ip: ClientIp {
+                ip: "127.0.0.1".parse().unwrap(),
+            },
+        }
+    }
+
+    fn target(role: Option<ServerRole>) -> User {
+        let mut user = User::new(String::from("target@example.com"));
+        user.server_role = role.map(|role| role as i32);
+        user
+    }
+
+    #[test]
+    fn test_require_role_on() {
+        let user_admin = token(Some(ServerRole::UserAdmin));
+        assert!(user_admin.require_role_on(ServerRole::UserAdmin, &target(None)).is_ok());
+        assert!(user_admin.require_role_on(ServerRole::UserAdmin, &target(Some(ServerRole::Auditor))).is_ok());
+        assert!(user_admin.require_role_on(ServerRole::UserAdmin, &target(Some(ServerRole::UserAdmin))).is_err());
+        assert!(user_admin.require_role_on(ServerRole::UserAdmin, &target(Some(ServerRole::SuperAdmin))).is_err());
+        assert!(user_admin.require_role_on(ServerRole::SuperAdmin, &target(None)).is_err());
+
+        let auditor = token(Some(ServerRole::Auditor));
+        assert!(auditor.require_role_on(ServerRole::UserAdmin, &target(None)).is_err());
+
+        // The admin token and the admin accounts can manage everyone
+        let admin = token(None);
+        assert!(admin.require_role_on(ServerRole::SuperAdmin, &target(Some(ServerRole::SuperAdmin))).is_ok());
+    }
+
+    fn broadcast_users() -> Vec<User> {
+        let user = |name: &str, enabled: bool| {
+            let mut user = User::new(format!("{}@example.com", name));
+            user.uuid = String::from(name);
+            user.password_hash = vec![1, 2, 3];
+            user.enabled = enabled;
+            user
+        };
+
+        let mut invited = user("invited", true);
+        invited.password_hash = Vec::new();
+        let mut deleted = user("deleted", false);
+        deleted.deleted_at = Some(Utc::now().naive_utc());
+        vec![user("enabled", true), user("disabled", false), user("2fa", true), invited, deleted]
+    }
+
+    #[test]
+    fn test_filter_broadcast_recipients() {
+        let has_2fa = |u: &User| u.uuid == "2fa";
+        let members: HashSet<String> = vec![String::from("disabled"), String::from("2fa")].into_iter().collect();
+        let select = |recipients: &str| -> Vec<String> {
+            filter_broadcast_recipients(broadcast_users(), recipients, has_2fa, &members)
+                .into_iter()
+                .map(|u| u.uuid)
+                .collect()
+        };
+
+        assert_eq!(select("all"), vec!["enabled", "disabled", "2fa"]);
+        assert_eq!(select("enabled"), vec!["enabled", "2fa"]);
+        assert_eq!(select("no_2fa"), vec!["enabled"]);
+        assert_eq!(select("organizations"), vec!["2fa"]);
+        assert!(select("unknown").is_empty());
+    }
+
+    #[test]
+    fn test_aggregate_storage_usage() {
+        let now = Utc::now().naive_utc();
+        let hour = chrono::Duration::hours(1);
+
+        let mut alice = User::new(String::from("alice@example.com"));
+        alice.uuid = String::from("alice");
+        alice.name = String::from("Alice");
+        let mut bob = User::new(String::from("bob@example.com"));
+        bob.uuid = String::from("bob");
+        bob.name = String::from("Bob");
+        let mut org = Organization::new(String::from("Org"), String::from("billing@example.com"), None, None);
+        org.uuid = String::from("org");
+
+        let cipher = |uuid: &str, user: Option<&str>, org: Option<&str>, trashed: bool| CipherOwnership {
+            uuid: String::from(uuid),
+            user_uuid: user.map(String::from),
+            organization_uuid: org.map(String::from),
+            updated_at: now - hour * 2,
+            deleted_at: if trashed {
+                Some(now)
+            } else {
+                None
+            },
+        };
+        let mut org_cipher = cipher("org-cipher", None, Some("org"), false);
+        org_cipher.updated_at = now - hour;
+        let ciphers = vec![
+            cipher("alice-cipher", Some("alice"), None, false),
+            cipher("alice-trashed", Some("alice"), None, true),
+            org_cipher,
+            // Items moved to an organization keep their user
+            cipher("org-shared", Some("alice"), Some("org"), false),
+            cipher("unknown-owner", Some("deleted-user"), None, false),
+        ];
+
+        let attachment = |id: &str, cipher_uuid: &str, size: i32| {
+            Attachment::new(String::from(id), String::from(cipher_uuid), String::from(id), size, None)
+        };
+        let attachments = vec![
+            attachment("a1", "alice-cipher", 100),
+            attachment("a2", "alice-cipher", 50),
+            attachment("a3", "alice-trashed", 30),
+            attachment("a4", "org-cipher", 1000),
+            attachment("a5", "org-shared", 200),
+            attachment("a6", "unknown-owner", 999),
+            attachment("a7", "missing-cipher", 999),
+        ];
+
+        let send = |atype: SendType, data: &str, user: Option<&str>, org: Option<&str>, deletion_date| {
+            let mut send = Send::new(atype as i32, String::new(), String::from(data), String::new(), deletion_date);
+            send.user_uuid = user.map(String::from);
+            send.organization_uuid = org.map(String::from);
+            send
+        };
+        let sends = vec![
+            send(SendType::File, r#"{"Size":20}"#, Some("alice"), None, now + hour),
+            send(SendType::Text, r#"{"Text":"text"}"#, Some("alice"), None, now - hour),
+            send(SendType::File, r#"{"Size":"5"}"#, None, Some("org"), now + hour),
+        ];
+
+        let last_active: HashMap<String, NaiveDateTime> = vec![(String::from("alice"), now)].into_iter().collect();
+        let usage = aggregate_storage_usage(&[alice, bob], &[org], ciphers, attachments, sends, &last_active, &now);
+
+        let ids: Vec<&str> = usage.iter().map(|u| u.id.as_str()).collect();
+        assert_eq!(ids, vec!["org", "alice", "bob"]);
+
+        let org = &usage[0];
+        assert_eq!(org.owner_type, "organization");
+        assert_eq!((org.attachment_count, org.attachment_size), (2, 1200));
+        assert_eq!((org.send_file_count, org.send_file_size), (1, 5));
+        assert_eq!((org.trash_count, org.trash_size, org.expired_send_count), (0, 0, 0));
+        assert_eq!(org.total_size(), 1205);
+        assert_eq!(org.last_active, Some(now - hour));
+
+        let alice = &usage[1];
+        assert_eq!(alice.owner_type, "user");
+        assert_eq!((alice.attachment_count, alice.attachment_size), (2, 150));
+        assert_eq!((alice.trash_count, alice.trash_size), (1, 30));
+        assert_eq!((alice.send_file_count, alice.send_file_size), (1, 20));
+        assert_eq!(alice.expired_send_count, 1);
+        assert_eq!(alice.total_size(), 200);
+        assert_eq!(alice.last_active, Some(now));
+
+        let bob = &usage[2];
+        assert_eq!(bob.total_size(), 0);
+        assert_eq!(bob.last_active, None);
+    }
+}
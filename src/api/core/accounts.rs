@@ -1,4 +1,4 @@
-use chrono::Utc;
+use chrono::{Duration, NaiveDateTime, Utc};
 use rocket_contrib::json::Json;
 use serde_json::Value;
 
@@ -6,10 +6,15 @@ use crate::{
     api::{EmptyResult, JsonResult, JsonUpcase, Notify, NumberOrString, PasswordData, UpdateType},
     auth::{decode_delete, decode_invite, decode_verify_email, Headers},
     crypto,
-    db::{models::*, DbConn},
+    db::{models::*, DbConn, DbPool},
     mail, CONFIG,
 };
 
+use super::quota::QuotaOwner;
+
+/// Max number of entries returned from the login history.
+const LOGIN_HISTORY_LIMIT: i64 = 100;
+
 pub fn routes() -> Vec<rocket::Route> {
     routes![
         register,
@@ -34,6 +39,7 @@ pub fn routes() -> Vec<rocket::Route> {
         password_hint,
         prelogin,
         verify_password,
+        get_login_history,
     ]
 }
 
@@ -135,12 +141,12 @@ fn register(data: JsonUpcase<RegisterData>, conn: DbConn) -> EmptyResult {
 
     if CONFIG.mail_enabled() {
         if CONFIG.signups_verify() {
-            if let Err(e) = mail::send_welcome_must_verify(&user.email, &user.uuid) {
+            if let Err(e) = mail::send_welcome_must_verify(&user.email, user.language.as_deref(), &user.uuid, &conn) {
                 error!("Error sending welcome email: {:#?}", e);
             }
 
             user.last_verifying_at = Some(user.created_at);
-        } else if let Err(e) = mail::send_welcome(&user.email) {
+        } else if let Err(e) = mail::send_welcome(&user.email, user.language.as_deref(), &conn) {
             error!("Error sending welcome email: {:#?}", e);
         }
     }
@@ -150,14 +156,15 @@ fn register(data: JsonUpcase<RegisterData>, conn: DbConn) -> EmptyResult {
 
 #[get("/accounts/profile")]
 fn profile(headers: Headers, conn: DbConn) -> Json<Value> {
-    Json(headers.user.to_json(&conn))
+    let mut profile = headers.user.to_json(&conn);
+    QuotaOwner::User(headers.user).add_storage_json(&mut profile, &conn);
+    Json(profile)
 }
 
 #[derive(Deserialize, Debug)]
 #[allow(non_snake_case)]
 struct ProfileData {
-    #[serde(rename = "Culture")]
-    _Culture: String, // Ignored, always use en-US
+    Culture: String,
     MasterPasswordHint: Option<String>,
     Name: String,
 }
@@ -178,6 +185,9 @@ fn post_profile(data: JsonUpcase<ProfileData>, headers: Headers, conn: DbConn) -
         Some(ref h) if h.is_empty() => None,
         _ => data.MasterPasswordHint,
     };
+    if let Some(language) = mail::profile_email_language(&data.Culture) {
+        user.language = Some(language.to_string());
+    }
     user.save(&conn)?;
     Ok(Json(user.to_json(&conn)))
 }
@@ -335,6 +345,9 @@ fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, conn: DbConn, nt:
     user.private_key = Some(data.PrivateKey);
     user.reset_security_stamp();
 
+    // The passkeys wrap the old user key, so they can only be used to log in until their keys are set again
+    WebAuthnCredential::clear_encrypted_keys_by_user(&user.uuid, &conn)?;
+
     user.save(&conn)
 }
 
@@ -379,7 +392,7 @@ fn post_email_token(data: JsonUpcase<EmailTokenData>, headers: Headers, conn: Db
     let token = crypto::generate_token(6)?;
 
     if CONFIG.mail_enabled() {
-        if let Err(e) = mail::send_change_email(&data.NewEmail, &token) {
+        if let Err(e) = mail::send_change_email(&data.NewEmail, user.language.as_deref(), &token, &conn) {
             error!("Error sending change-email email: {:#?}", e);
         }
     }
@@ -448,14 +461,14 @@ fn post_email(data: JsonUpcase<ChangeEmailData>, headers: Headers, conn: DbConn)
 }
 
 #[post("/accounts/verify-email")]
-fn post_verify_email(headers: Headers, _conn: DbConn) -> EmptyResult {
+fn post_verify_email(headers: Headers, conn: DbConn) -> EmptyResult {
     let user = headers.user;
 
     if !CONFIG.mail_enabled() {
         err!("Cannot verify email address");
     }
 
-    if let Err(e) = mail::send_verify_email(&user.email, &user.uuid) {
+    if let Err(e) = mail::send_verify_email(&user.email, user.language.as_deref(), &user.uuid, &conn) {
         error!("Error sending verify_email email: {:#?}", e);
     }
 
@@ -509,7 +522,7 @@ fn post_delete_recover(data: JsonUpcase<DeleteRecoverData>, conn: DbConn) -> Emp
 
     if CONFIG.mail_enabled() {
         if let Some(user) = user {
-            if let Err(e) = mail::send_delete_account(&user.email, &user.uuid) {
+            if let Err(e) = mail::send_delete_account(&user.email, user.language.as_deref(), &user.uuid, &conn) {
                 error!("Error sending delete account email: {:#?}", e);
             }
         }
@@ -546,7 +559,7 @@ fn post_delete_recover_token(data: JsonUpcase<DeleteRecoverTokenData>, conn: DbC
     if claims.sub != user.uuid {
         err!("Invalid claim");
     }
-    user.delete(&conn)
+    delete_user_account(user, &conn)
 }
 
 #[post("/accounts/delete", data = "<data>")]
@@ -563,7 +576,22 @@ fn delete_account(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn
         err!("Invalid password")
     }
 
-    user.delete(&conn)
+    delete_user_account(user, &conn)
+}
+
+/// Deletes the user, or only marks them as deleted during `USER_DELETION_GRACE_DAYS`.
+pub fn delete_user_account(mut user: User, conn: &DbConn) -> EmptyResult {
+    if CONFIG.user_deletion_grace_days() == 0 {
+        return user.delete(conn);
+    }
+
+    user.soft_delete(conn)?;
+    if let (true, Some(purge_date)) = (CONFIG.mail_enabled(), user.purge_date()) {
+        if let Err(e) = mail::send_account_deleted(&user.email, user.language.as_deref(), &purge_date, conn) {
+            error!("Error sending account deleted email: {:#?}", e);
+        }
+    }
+    Ok(())
 }
 
 #[get("/accounts/revision-date")]
@@ -610,7 +638,7 @@ fn password_hint(data: JsonUpcase<PasswordHintData>, conn: DbConn) -> EmptyResul
         Some(user) => {
             let hint: Option<String> = user.password_hint;
             if CONFIG.mail_enabled() {
-                mail::send_password_hint(email, hint)?;
+                mail::send_password_hint(email, user.language.as_deref(), hint, &conn)?;
                 Ok(())
             } else if let Some(hint) = hint {
                 err!(format!("Your password hint is: {}", hint));
@@ -658,3 +686,129 @@ fn verify_password(data: JsonUpcase<VerifyPasswordData>, headers: Headers, _conn
 
     Ok(())
 }
+
+#[get("/accounts/login-history")]
+fn get_login_history(headers: Headers, conn: DbConn) -> Json<Value> {
+    let history_json: Vec<Value> = LoginHistory::find_by_user(&headers.user.uuid, LOGIN_HISTORY_LIMIT, &conn)
+        .iter()
+        .map(LoginHistory::to_json)
+        .collect();
+
+    Json(json!({
+        "Data": history_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    }))
+}
+
+pub fn purge_login_history(pool: DbPool) {
+    debug!("Purging login history");
+    if let Ok(conn) = pool.get() {
+        if let Err(e) = LoginHistory::purge_old(&conn) {
+            error!("Error purging login history: {:#?}", e);
+        }
+    } else {
+        error!("Failed to get DB connection while purging login history")
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum DeletedUserAction {
+    Wait,
+    /// Send the reminder, with the date when the user will be removed
+    Remind(NaiveDateTime),
+    Purge,
+}
+
+/// What the purge job does with a deleted user: remove it once the grace period is over, and remind it once,
+/// `reminder_days` before that. A reminder of 0 days disables it.
+fn deleted_user_action(
+    deleted_at: NaiveDateTime,
+    purge_reminded: bool,
+    now: NaiveDateTime,
+    grace_days: u64,
+    reminder_days: u64,
+) -> DeletedUserAction {
+    let purge_date = deleted_at + Duration::days(grace_days as i64);
+    if purge_date <= now {
+        DeletedUserAction::Purge
+    } else if reminder_days > 0 && !purge_reminded && now >= purge_date - Duration::days(reminder_days as i64) {
+        DeletedUserAction::Remind(purge_date)
+    } else {
+        DeletedUserAction::Wait
+    }
+}
+
+/// Removes the deleted users whose grace period is over, and reminds the others by email before it ends.
+pub fn purge_deleted_users(pool: DbPool) {
+    debug!("Purging deleted users");
+    if let Ok(conn) = pool.get() {
+        let now = Utc::now().naive_utc();
+
+        for mut user in User::find_deleted(&conn) {
+            let deleted_at = match user.deleted_at {
+                Some(deleted_at) => deleted_at,
+                None => continue,
+            };
+
+            match deleted_user_action(
+                deleted_at,
+                user.purge_reminded,
+                now,
+                CONFIG.user_deletion_grace_days(),
+                CONFIG.user_purge_reminder_days(),
+            ) {
+                DeletedUserAction::Purge => {
+                    let email = user.email.clone();
+                    match user.delete(&conn) {
+                        Ok(()) => info!("Deleted user {} purged", email),
+                        Err(e) => error!("Error purging deleted user {}: {:#?}", email, e),
+                    }
+                }
+                DeletedUserAction::Remind(purge_date) if CONFIG.mail_enabled() => {
+                    if let Err(e) =
+                        mail::send_account_purge_reminder(&user.email, user.language.as_deref(), &purge_date, &conn)
+                    {
+                        error!("Error sending account purge reminder email: {:#?}", e);
+                        continue;
+                    }
+                    user.purge_reminded = true;
+                    if let Err(e) = user.save(&conn) {
+                        error!("Error saving user {}: {:#?}", user.email, e);
+                    }
+                }
+                DeletedUserAction::Remind(_) | DeletedUserAction::Wait => (),
+            }
+        }
+    } else {
+        error!("Failed to get DB connection while purging deleted users")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deleted_user_action() {
+        let now = Utc::now().naive_utc();
+
+        // Without a grace period, the users are removed right away
+        assert_eq!(deleted_user_action(now, false, now, 0, 7), DeletedUserAction::Purge);
+
+        let deleted_at = now - Duration::days(10);
+        assert_eq!(deleted_user_action(deleted_at, false, now, 30, 7), DeletedUserAction::Wait);
+        assert_eq!(deleted_user_action(deleted_at, false, now, 30, 0), DeletedUserAction::Wait);
+
+        // Reminded once in the last days of the grace period
+        let deleted_at = now - Duration::days(25);
+        let purge_date = deleted_at + Duration::days(30);
+        assert_eq!(deleted_user_action(deleted_at, false, now, 30, 7), DeletedUserAction::Remind(purge_date));
+        assert_eq!(deleted_user_action(deleted_at, true, now, 30, 7), DeletedUserAction::Wait);
+        assert_eq!(deleted_user_action(deleted_at, false, now, 30, 0), DeletedUserAction::Wait);
+
+        // Removed once the grace period is over, reminded or not
+        assert_eq!(deleted_user_action(deleted_at, true, purge_date, 30, 7), DeletedUserAction::Purge);
+        assert_eq!(deleted_user_action(deleted_at, false, now, 20, 7), DeletedUserAction::Purge);
+    }
+}
@@ -0,0 +1,290 @@
+//
+// Login with device: a new device asks to log in, and one of the logged in devices of the user approves it
+// by sending the user key, encrypted with the public key of the new device.
+//
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use rocket::Route;
+use rocket_contrib::json::Json;
+use serde_json::Value;
+
+use crate::{
+    api::{JsonResult, JsonUpcase, Notify, UpdateType},
+    auth::{ClientDeviceType, ClientIp, Headers},
+    db::{
+        models::{AuthRequest, User},
+        DbConn, DbPool,
+    },
+};
+
+/// Max number of pending requests of a user, to avoid flooding their devices.
+const MAX_PENDING_REQUESTS: usize = 5;
+/// Max number of requests created from the same IP address in `RATE_LIMIT_WINDOW`, as they send notifications.
+const MAX_REQUESTS_PER_IP: u32 = 10;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+/// Max number of unsaved requests kept in memory, the oldest are dropped first.
+const MAX_UNSAVED_REQUESTS: usize = 10_000;
+
+/// Start of the current rate limit window of each IP address, and the number of requests made in it.
+static REQUESTS_PER_IP: Lazy<Mutex<HashMap<IpAddr, (Instant, u32)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Requests created for unknown or disabled users, or for users with too many pending requests. They can't be saved
+/// without a user, but are kept until they expire so that polling them answers like for a pending request,
+/// and the requests can't be used to find out which emails have an account.
+static UNSAVED_REQUESTS: Lazy<Mutex<HashMap<String, AuthRequest>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Counts a new request from the IP address, returns false when it made too many in the current window.
+fn check_rate_limit(ip: IpAddr, now: Instant) -> bool {
+    let mut requests = REQUESTS_PER_IP.lock().unwrap();
+    // Forget the windows which are over, so that the map doesn't keep growing
+    requests.retain(|_, (start, _)| now.saturating_duration_since(*start) < RATE_LIMIT_WINDOW);
+
+    let (_, count) = requests.entry(ip).or_insert((now, 0));
+    *count += 1;
+    *count <= MAX_REQUESTS_PER_IP
+}
+
+/// Keeps a request which is never answered, and returns its JSON like for a saved one.
+fn add_unsaved_request(auth_request: AuthRequest) -> Value {
+    let json = auth_request.to_json();
+
+    let mut requests = UNSAVED_REQUESTS.lock().unwrap();
+    requests.retain(|_, r| !r.is_expired());
+    if requests.len() >= MAX_UNSAVED_REQUESTS {
+        if let Some(oldest) = requests.values().min_by_key(|r| r.creation_date).map(|r| r.uuid.clone()) {
+            requests.remove(&oldest);
+        }
+    }
+    requests.insert(auth_request.uuid.clone(), auth_request);
+
+    json
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![post_auth_request, get_auth_request, put_auth_request, get_auth_request_response, get_auth_requests,]
+}
+
+pub fn purge_auth_requests(pool: DbPool) {
+    debug!("Purging auth requests");
+    if let Ok(conn) = pool.get() {
+        if let Err(e) = AuthRequest::purge_expired(&conn) {
+            error!("Error purging auth requests: {:#?}", e);
+        }
+    } else {
+        error!("Failed to get DB connection while purging auth requests")
+    }
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct AuthRequestData {
+    Email: String,
+    PublicKey: String,
+    DeviceIdentifier: String,
+    AccessCode: String,
+}
+
+#[post("/auth-requests", data = "<data>")]
+fn post_auth_request(
+    data: JsonUpcase<AuthRequestData>,
+    device_type: ClientDeviceType,
+    ip: ClientIp,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let data: AuthRequestData = data.into_inner().data;
+
+    if !check_rate_limit(ip.ip, Instant::now()) {
+        err!("Too many login requests, try again later", format!("IP: {}.", ip.ip))
+    }
+
+    let auth_request = AuthRequest::new(
+        String::new(),
+        data.DeviceIdentifier,
+        device_type.atype,
+        ip.ip.to_string(),
+        &data.AccessCode,
+        data.PublicKey,
+    );
+
+    // Unknown and disabled users get the same response, a request which is never answered, so that this
+    // can't be used to find out which emails have an account
+    let user = match User::find_by_mail(&data.Email, &conn) {
+        Some(user) if user.enabled => user,
+        _ => {
+            debug!("Login request for unknown or disabled user {}. IP: {}", data.Email, ip.ip);
+            return Ok(Json(add_unsaved_request(auth_request)));
+        }
+    };
+
+    let pending = AuthRequest::find_by_user(&user.uuid, &conn)
+        .into_iter()
+        .filter(|r| r.approved.is_none() && !r.is_expired())
+        .count();
+    if pending >= MAX_PENDING_REQUESTS {
+        warn!("Too many pending login requests for user {}. IP: {}", user.email, ip.ip);
+        return Ok(Json(add_unsaved_request(auth_request)));
+    }
+
+    let auth_request = AuthRequest {
+        user_uuid: user.uuid,
+        ..auth_request
+    };
+    auth_request.save(&conn)?;
+
+    nt.send_auth_request(UpdateType::AuthRequest, &auth_request);
+
+    info!("Login request {} created for user {}. IP: {}", auth_request.uuid, user.email, ip.ip);
+    Ok(Json(auth_request.to_json()))
+}
+
+#[get("/auth-requests/<uuid>")]
+fn get_auth_request(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+    match AuthRequest::find_by_uuid_and_user(&uuid, &headers.user.uuid, &conn) {
+        Some(auth_request) => Ok(Json(auth_request.to_json())),
+        None => err!("Login request doesn't exist"),
+    }
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct AuthResponseData {
+    Key: Option<String>,
+    MasterPasswordHash: Option<String>,
+    RequestApproved: bool,
+}
+
+#[put("/auth-requests/<uuid>", data = "<data>")]
+fn put_auth_request(
+    uuid: String,
+    data: JsonUpcase<AuthResponseData>,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let data: AuthResponseData = data.into_inner().data;
+
+    let mut auth_request = match AuthRequest::find_by_uuid_and_user(&uuid, &headers.user.uuid, &conn) {
+        Some(auth_request) => auth_request,
+        None => err!("Login request doesn't exist"),
+    };
+
+    if auth_request.approved.is_some() {
+        err!("Login request was already answered")
+    }
+    if auth_request.is_expired() {
+        err!("Login request has expired")
+    }
+
+    if data.RequestApproved {
+        auth_request.enc_key = match data.Key {
+            Some(key) => Some(key),
+            None => err!("The encrypted key is required to approve a login request"),
+        };
+        auth_request.master_password_hash = data.MasterPasswordHash;
+    }
+    auth_request.approved = Some(data.RequestApproved);
+    auth_request.response_device_id = Some(headers.device.uuid.clone());
+    auth_request.response_date = Some(Utc::now().naive_utc());
+    auth_request.save(&conn)?;
+
+    nt.send_auth_request(UpdateType::AuthRequestResponse, &auth_request);
+
+    info!(
+        "Login request {} of user {} {} by device {}",
+        auth_request.uuid,
+        headers.user.email,
+        if data.RequestApproved {
+            "approved"
+        } else {
+            "denied"
+        },
+        headers.device.uuid
+    );
+    Ok(Json(auth_request.to_json()))
+}
+
+#[get("/auth-requests/<uuid>/response?<code>")]
+fn get_auth_request_response(uuid: String, code: String, ip: ClientIp, conn: DbConn) -> JsonResult {
+    let saved = AuthRequest::find_by_uuid(&uuid, &conn);
+    let unsaved = UNSAVED_REQUESTS.lock().unwrap();
+    match saved.as_ref().or_else(|| unsaved.get(&uuid)) {
+        Some(auth_request) if auth_request.check_access_code(&code) => {
+            if auth_request.is_expired() {
+                err!("Login request has expired")
+            }
+            Ok(Json(auth_request.to_json()))
+        }
+        _ => err!("Login request doesn't exist", format!("IP: {}. Request: {}.", ip.ip, uuid)),
+    }
+}
+
+#[get("/auth-requests")]
+fn get_auth_requests(headers: Headers, conn: DbConn) -> Json<Value> {
+    let auth_requests_json: Vec<Value> = AuthRequest::find_by_user(&headers.user.uuid, &conn)
+        .iter()
+        .filter(|r| r.approved.is_none() && !r.is_expired())
+        .map(AuthRequest::to_json)
+        .collect();
+
+    Json(json!({
+        "Data": auth_requests_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_rate_limit() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other_ip: IpAddr = "192.0.2.2".parse().unwrap();
+        let now = Instant::now();
+
+        for _ in 0..MAX_REQUESTS_PER_IP {
+            assert!(check_rate_limit(ip, now));
+        }
+        assert!(!check_rate_limit(ip, now));
+        assert!(check_rate_limit(other_ip, now));
+
+        // A new window starts once the current one is over
+        assert!(check_rate_limit(ip, now + RATE_LIMIT_WINDOW));
+    }
+
+    #[test]
+    fn test_add_unsaved_request() {
+        let request = |access_code: &str| {
+            AuthRequest::new(
+                String::new(),
+                String::from("device"),
+                0,
+                String::from("192.0.2.1"),
+                access_code,
+                String::from("key"),
+            )
+        };
+
+        let auth_request = request("code");
+        let uuid = auth_request.uuid.clone();
+        let json = add_unsaved_request(auth_request);
+        assert_eq!(json["Id"], uuid.as_str());
+        assert_eq!(json["RequestApproved"], Value::Null);
+
+        // Kept for the response endpoint, like a pending request
+        let requests = UNSAVED_REQUESTS.lock().unwrap();
+        let unsaved = requests.get(&uuid).unwrap();
+        assert!(unsaved.check_access_code("code"));
+        assert!(!unsaved.check_access_code("other"));
+        assert_eq!(unsaved.approved, None);
+    }
+}
@@ -1,5 +1,7 @@
 use std::collections::{HashMap, HashSet};
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 use chrono::{NaiveDateTime, Utc};
 use rocket::{http::ContentType, request::Form, Data, Route};
@@ -7,15 +9,18 @@ use rocket_contrib::json::Json;
 use serde_json::Value;
 
 use multipart::server::{save::SavedData, Multipart, SaveResult};
+use once_cell::sync::Lazy;
 
 use crate::{
-    api::{self, EmptyResult, JsonResult, JsonUpcase, Notify, PasswordData, UpdateType},
+    api::{self, ApiResult, EmptyResult, JsonResult, JsonUpcase, Notify, PasswordData, UpdateType},
     auth::Headers,
     crypto,
     db::{models::*, DbConn, DbPool},
     CONFIG,
 };
 
+use super::quota::QuotaOwner;
+
 pub fn routes() -> Vec<Route> {
     // Note that many routes have an `admin` variant; this seems to be
     // because the stored procedure that upstream Bitwarden uses to determine
@@ -41,6 +46,8 @@ pub fn routes() -> Vec<Route> {
         get_attachment,
         post_attachment_v2,
         post_attachment_v2_data,
+        get_attachment_upload,
+        patch_attachment_upload,
         post_attachment,       // legacy
         post_attachment_admin, // legacy
         post_attachment_share,
@@ -88,6 +95,59 @@ pub fn purge_trashed_ciphers(pool: DbPool) {
     }
 }
 
+pub fn purge_incomplete_uploads(pool: DbPool) {
+    debug!("Purging incomplete attachment uploads");
+    let conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => {
+            error!("Failed to get DB connection while purging incomplete attachment uploads");
+            return;
+        }
+    };
+
+    // Uploads which haven't received any data for a while are considered abandoned
+    for upload in AttachmentUpload::find_stale(&AttachmentUpload::stale_limit(), &conn) {
+        let result = match Attachment::find_by_id(&upload.attachment_id, &conn) {
+            Some(attachment) => {
+                info!("Deleting abandoned upload of attachment {}", attachment.id);
+                attachment.delete(&conn)
+            }
+            None => upload.delete(&conn),
+        };
+        if let Err(e) = result {
+            error!("Error deleting abandoned attachment upload: {:#?}", e);
+        }
+    }
+
+    // A missing or empty attachments folder is most likely not mounted, removing the attachments whose data
+    // can't be found would then remove all of them
+    if !crate::fsck::has_attachment_files(&CONFIG.attachments_folder()) {
+        warn!("The attachments folder is missing or empty, attachments without data are not purged");
+        return;
+    }
+
+    // Attachments without data and without a pending upload can't be downloaded, so they are removed.
+    // These can be left behind by interrupted v2 uploads from before uploads were tracked.
+    let pending: HashSet<String> = AttachmentUpload::find_all(&conn).into_iter().map(|u| u.attachment_id).collect();
+    for attachment in Attachment::get_all(&conn) {
+        if pending.contains(&attachment.id) || Path::new(&attachment.get_file_path()).exists() {
+            continue;
+        }
+
+        warn!(
+            "Deleting attachment {} of cipher {}, its data was never uploaded",
+            attachment.id, attachment.cipher_uuid
+        );
+        if let Err(e) = attachment.delete(&conn) {
+            error!("Error deleting orphaned attachment: {:#?}", e);
+            continue;
+        }
+        if let Some(cipher) = Cipher::find_by_uuid(&attachment.cipher_uuid, &conn) {
+            cipher.update_users_revision(&conn);
+        }
+    }
+}
+
 #[derive(FromForm, Default)]
 struct SyncData {
     #[form(field = "excludeDomains")]
@@ -783,6 +843,8 @@ struct AttachmentRequestData {
     Key: String,
     FileName: String,
     FileSize: i32,
+    // Optional hex encoded SHA-256 of the encrypted data, verified once the upload is complete
+    FileHash: Option<String>,
     // We check org owner/admin status via is_write_accessible_to_user(),
     // so we can just ignore this field.
     //
@@ -814,12 +876,35 @@ fn post_attachment_v2(
         err!("Cipher is not write accessible")
     }
 
-    let attachment_id = crypto::generate_attachment_id();
     let data: AttachmentRequestData = data.into_inner().data;
+
+    if data.FileSize < 0 {
+        err!("Invalid attachment size")
+    }
+
+    if let Some(ref hash) = data.FileHash {
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            err!("Invalid attachment hash, expected a hex encoded SHA-256")
+        }
+    }
+
+    // Reject uploads which won't fit in the storage quota before receiving any data
+    match QuotaOwner::for_cipher(&cipher, &conn).map(|owner| owner.storage_left(0, &conn)) {
+        Some(Ok(Some(left))) if data.FileSize as u64 > left => {
+            err!("Attachment storage limit exceeded with this file")
+        }
+        Some(Err(e)) => err!(e),
+        _ => (),
+    }
+
+    let attachment_id = crypto::generate_attachment_id();
     let attachment =
         Attachment::new(attachment_id.clone(), cipher.uuid.clone(), data.FileName, data.FileSize, Some(data.Key));
     attachment.save(&conn).expect("Error saving attachment");
 
+    // Keep track of the upload, so that it can be resumed and cleaned up if it's abandoned
+    AttachmentUpload::new(attachment_id.clone(), data.FileHash).save(&conn)?;
+
     let url = format!("/ciphers/{}/attachment/{}", cipher.uuid, attachment_id);
 
     Ok(Json(json!({ // AttachmentUploadDataResponseModel
@@ -865,34 +950,18 @@ fn save_attachment(
         Some(a) => a.file_size as i64, // v2 API
     };
 
-    let size_limit = if let Some(ref user_uuid) = cipher.user_uuid {
-        match CONFIG.user_attachment_limit() {
-            Some(0) => err_discard!("Attachments are disabled", data),
-            Some(limit_kb) => {
-                let left = (limit_kb * 1024) - Attachment::size_by_user(user_uuid, conn) + size_adjust;
-                if left <= 0 {
-                    err_discard!("Attachment storage limit reached! Delete some attachments to free up space", data)
-                }
-                Some(left as u64)
-            }
-            None => None,
-        }
-    } else if let Some(ref org_uuid) = cipher.organization_uuid {
-        match CONFIG.org_attachment_limit() {
-            Some(0) => err_discard!("Attachments are disabled", data),
-            Some(limit_kb) => {
-                let left = (limit_kb * 1024) - Attachment::size_by_org(org_uuid, conn) + size_adjust;
-                if left <= 0 {
-                    err_discard!("Attachment storage limit reached! Delete some attachments to free up space", data)
-                }
-                Some(left as u64)
-            }
-            None => None,
-        }
-    } else {
-        err_discard!("Cipher is neither owned by a user nor an organization", data);
+    let quota_owner = match QuotaOwner::for_cipher(&cipher, conn) {
+        Some(owner) => owner,
+        None => err_discard!("Cipher is neither owned by a user nor an organization", data),
     };
 
+    let size_limit = match quota_owner.storage_left(size_adjust, conn) {
+        Ok(size_limit) => size_limit,
+        Err(e) => err_discard!(e, data),
+    };
+    // Used to check if this upload crosses one of the quota warning thresholds
+    let used_before = quota_owner.storage_used(conn) - size_adjust;
+
     let mut params = content_type.params();
     let boundary_pair = params.next().expect("No boundary provided");
     let boundary = boundary_pair.1;
@@ -907,7 +976,6 @@ fn save_attachment(
         .foreach_entry(|mut field| {
             match &*field.headers.name {
                 "key" => {
-                    use std::io::Read;
                     let mut key_buffer = String::new();
                     if field.data.read_to_string(&mut key_buffer).is_ok() {
                         attachment_key = Some(key_buffer);
@@ -927,7 +995,8 @@ fn save_attachment(
                         Some(attachment) => attachment.id.clone(), // v2 API
                         None => crypto::generate_attachment_id(),  // Legacy API
                     };
-                    path = base_path.join(&file_id);
+                    // The data is written to a partial file, which is moved into place once complete
+                    path = base_path.join(format!("{}.part", file_id));
 
                     let size =
                         match field.data.save().memory_threshold(0).size_limit(size_limit).with_path(path.clone()) {
@@ -968,6 +1037,12 @@ fn save_attachment(
                             let err_msg = "Attachment size mismatch".to_string();
                             error!("{} (expected within [{}, {}], got {})", err_msg, min_size, max_size, size);
                             error = Some(err_msg);
+                            return;
+                        }
+
+                        let upload = AttachmentUpload::find_by_attachment(&attachment.id, conn);
+                        if let Err(e) = commit_attachment_upload(attachment, upload, conn) {
+                            error = Some(e.to_string());
                         }
                     } else {
                         // Legacy API
@@ -987,6 +1062,10 @@ fn save_attachment(
                             size,
                             attachment_key.clone(),
                         );
+                        if let Err(e) = commit_attachment_upload(&attachment, None, conn) {
+                            error = Some(e.to_string());
+                            return;
+                        }
                         attachment.save(conn).expect("Error saving attachment");
                     }
                 }
@@ -1000,11 +1079,192 @@ fn save_attachment(
         err!(e);
     }
 
+    quota_owner.check_storage_usage(used_before, conn);
+
     nt.send_cipher_update(UpdateType::CipherUpdate, &cipher, &cipher.update_users_revision(conn));
 
     Ok(cipher)
 }
 
+/// Verifies the uploaded data against the hash declared in post_attachment_v2(), if any,
+/// and atomically moves it from the partial file to its final location.
+fn commit_attachment_upload(attachment: &Attachment, upload: Option<AttachmentUpload>, conn: &DbConn) -> EmptyResult {
+    let upload_path = attachment.get_upload_path();
+
+    if let Some(expected_hash) = upload.as_ref().and_then(|u| u.file_hash.as_ref()) {
+        let hash = crypto::sha256_hex_reader(std::fs::File::open(&upload_path)?)?;
+        if !crypto::ct_eq(expected_hash, &hash) {
+            std::fs::remove_file(&upload_path).ok();
+            err!("Attachment hash mismatch", format!("Expected {}, got {}", expected_hash, hash))
+        }
+    }
+
+    std::fs::rename(&upload_path, attachment.get_file_path())?;
+
+    if let Some(upload) = upload {
+        upload.delete(conn)?;
+    }
+    Ok(())
+}
+
+/// Attachments for which a chunk of a resumable upload is being written.
+static UPLOADS_IN_PROGRESS: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// Held while a chunk of a resumable upload is written, released when dropped.
+struct UploadLock(String);
+
+impl UploadLock {
+    fn acquire(attachment_id: &str) -> Option<Self> {
+        let mut uploads = UPLOADS_IN_PROGRESS.lock().unwrap();
+        if uploads.insert(attachment_id.to_string()) {
+            Some(Self(attachment_id.to_string()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        UPLOADS_IN_PROGRESS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Appends a chunk of a resumable upload to the partial file, and returns the amount of data received so far.
+/// The chunk has to start at the end of the data already received, and can't go past `file_size`.
+/// The partial file is removed if it does, as the data can't be trusted anymore.
+fn append_upload_chunk(path: &Path, offset: u64, file_size: u64, data: &mut dyn Read) -> ApiResult<u64> {
+    use std::io::{Seek, SeekFrom};
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut file = std::fs::OpenOptions::new().create(true).write(true).open(path)?;
+    let received = file.metadata()?.len();
+    // The data of the request is left unread, as it could be of any size
+    if offset != received {
+        err!(format!("Invalid upload offset, {} bytes have been received", received))
+    }
+
+    // Read at most one byte more than what is left, to detect data past the declared size
+    let remaining = file_size.saturating_sub(received);
+    file.seek(SeekFrom::Start(received))?;
+    let written = std::io::copy(&mut data.take(remaining + 1), &mut file)?;
+    if written > remaining {
+        drop(file);
+        std::fs::remove_file(path).ok();
+        err!("The attachment data is larger than the declared size, the upload has to be restarted")
+    }
+
+    Ok(received + written)
+}
+
+fn upload_status_json(attachment: &Attachment, offset: u64, complete: bool) -> Value {
+    json!({
+        "Object": "attachment-upload",
+        "AttachmentId": attachment.id,
+        "FileSize": attachment.file_size,
+        "Offset": offset,
+        "Complete": complete,
+    })
+}
+
+/// Resumable upload status. Returns the amount of data received so far, which is the offset
+/// from which an interrupted upload should continue.
+#[get("/ciphers/<uuid>/attachment/<attachment_id>/upload")]
+fn get_attachment_upload(uuid: String, attachment_id: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
+        err!("Cipher is not write accessible")
+    }
+
+    let attachment = match Attachment::find_by_id(&attachment_id, &conn) {
+        Some(attachment) if uuid == attachment.cipher_uuid => attachment,
+        Some(_) => err!("Attachment doesn't belong to cipher"),
+        None => err!("Attachment doesn't exist"),
+    };
+
+    if AttachmentUpload::find_by_attachment(&attachment.id, &conn).is_none() {
+        return Ok(Json(upload_status_json(&attachment, attachment.file_size as u64, true)));
+    }
+
+    let offset = std::fs::metadata(attachment.get_upload_path()).map(|m| m.len()).unwrap_or(0);
+    Ok(Json(upload_status_json(&attachment, offset, false)))
+}
+
+/// Resumable upload of the data content of an attachment, as an alternative to the multipart upload.
+/// The raw data is sent in one or more chunks, each one starting at `offset`, which must match the
+/// amount of data received so far. Once the size declared in post_attachment_v2() is reached,
+/// the data is verified and the attachment is committed.
+#[patch("/ciphers/<uuid>/attachment/<attachment_id>/upload?<offset>", data = "<data>")]
+fn patch_attachment_upload(
+    uuid: String,
+    attachment_id: String,
+    offset: u64,
+    data: Data,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err_discard!("Cipher doesn't exist", data),
+    };
+
+    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
+        err_discard!("Cipher is not write accessible", data)
+    }
+
+    let attachment = match Attachment::find_by_id(&attachment_id, &conn) {
+        Some(attachment) if uuid == attachment.cipher_uuid => attachment,
+        Some(_) => err_discard!("Attachment doesn't belong to cipher", data),
+        None => err_discard!("Attachment doesn't exist", data),
+    };
+
+    // Only one chunk of an upload can be written at a time, the offset check would be racy otherwise
+    let _lock = match UploadLock::acquire(&attachment.id) {
+        Some(lock) => lock,
+        None => err_discard!("Another chunk of this attachment is being uploaded", data),
+    };
+
+    let mut upload = match AttachmentUpload::find_by_attachment(&attachment.id, &conn) {
+        Some(upload) => upload,
+        None => err_discard!("Attachment upload is already complete", data),
+    };
+    // Abandoned uploads are removed by the purge job, don't let them be resumed in the meantime
+    if upload.is_stale(&AttachmentUpload::stale_limit()) {
+        err_discard!("Attachment upload has expired", data)
+    }
+
+    let offset = append_upload_chunk(
+        Path::new(&attachment.get_upload_path()),
+        offset,
+        attachment.file_size as u64,
+        &mut data.open(),
+    )?;
+    upload.save(&conn)?;
+
+    if offset < attachment.file_size as u64 {
+        return Ok(Json(upload_status_json(&attachment, offset, false)));
+    }
+
+    commit_attachment_upload(&attachment, Some(upload), &conn)?;
+
+    // The attachment was already counted in the storage usage when it was created
+    if let Some(owner) = QuotaOwner::for_cipher(&cipher, &conn) {
+        owner.check_storage_usage(owner.storage_used(&conn) - attachment.file_size as i64, &conn);
+    }
+
+    nt.send_cipher_update(UpdateType::CipherUpdate, &cipher, &cipher.update_users_revision(&conn));
+
+    Ok(Json(upload_status_json(&attachment, offset, true)))
+}
+
 /// v2 API for uploading the actual data content of an attachment.
 /// This route needs a rank specified so that Rocket prioritizes the
 /// /ciphers/<uuid>/attachment/v2 route, which would otherwise conflict
@@ -1429,3 +1689,60 @@ fn _delete_cipher_attachment_by_id(
     nt.send_cipher_update(UpdateType::CipherUpdate, &cipher, &cipher.update_users_revision(conn));
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload_test_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("vaultwarden-test-{}-{}.part", name, crypto::generate_attachment_id()));
+        std::fs::remove_file(&path).ok();
+        path
+    }
+
+    #[test]
+    fn test_append_upload_chunk_resume() {
+        let path = upload_test_path("resume");
+
+        assert_eq!(append_upload_chunk(&path, 0, 10, &mut &b"abcd"[..]).unwrap(), 4);
+        // The interrupted upload continues from the amount of data received so far
+        assert_eq!(append_upload_chunk(&path, 4, 10, &mut &b"efghij"[..]).unwrap(), 10);
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcdefghij");
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_append_upload_chunk_offset_mismatch() {
+        let path = upload_test_path("offset");
+
+        assert_eq!(append_upload_chunk(&path, 0, 10, &mut &b"abcd"[..]).unwrap(), 4);
+        assert!(append_upload_chunk(&path, 2, 10, &mut &b"xxxx"[..]).is_err());
+        assert!(append_upload_chunk(&path, 8, 10, &mut &b"xx"[..]).is_err());
+        // The data received so far is kept
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcd");
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_append_upload_chunk_too_large() {
+        let path = upload_test_path("large");
+
+        assert_eq!(append_upload_chunk(&path, 0, 6, &mut &b"abcd"[..]).unwrap(), 4);
+        assert!(append_upload_chunk(&path, 4, 6, &mut &b"efgh"[..]).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_attachment_upload_expiry() {
+        let limit = Utc::now().naive_utc() - chrono::Duration::hours(24);
+
+        let mut upload = AttachmentUpload::new(String::from("attachment"), None);
+        assert!(!upload.is_stale(&limit));
+
+        upload.updated_at = limit - chrono::Duration::minutes(1);
+        assert!(upload.is_stale(&limit));
+    }
+}
@@ -1,12 +1,17 @@
 mod accounts;
+mod auth_requests;
 mod ciphers;
 mod emergency_access;
 mod folders;
 mod organizations;
+mod quota;
 mod sends;
 pub mod two_factor;
+pub mod webauthn;
 
-pub use ciphers::purge_trashed_ciphers;
+pub use accounts::{delete_user_account, purge_deleted_users, purge_login_history};
+pub use auth_requests::purge_auth_requests;
+pub use ciphers::{purge_incomplete_uploads, purge_trashed_ciphers};
 pub use sends::purge_sends;
 
 pub fn routes() -> Vec<Route> {
@@ -15,12 +20,14 @@ pub fn routes() -> Vec<Route> {
 
     let mut routes = Vec::new();
     routes.append(&mut accounts::routes());
+    routes.append(&mut auth_requests::routes());
     routes.append(&mut ciphers::routes());
     routes.append(&mut emergency_access::routes());
     routes.append(&mut folders::routes());
     routes.append(&mut organizations::routes());
     routes.append(&mut two_factor::routes());
     routes.append(&mut sends::routes());
+    routes.append(&mut webauthn::routes());
     routes.append(&mut mod_routes);
 
     routes
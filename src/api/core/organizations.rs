@@ -10,6 +10,8 @@ use crate::{
     mail, CONFIG,
 };
 
+use super::quota::QuotaOwner;
+
 pub fn routes() -> Vec<Route> {
     routes![
         get_organization,
@@ -52,6 +54,8 @@ pub fn routes() -> Vec<Route> {
         get_plans_tax_rates,
         import,
         post_org_keys,
+        post_scim_token,
+        delete_scim_token,
     ]
 }
 
@@ -169,7 +173,11 @@ fn leave_organization(org_id: String, headers: Headers, conn: DbConn) -> EmptyRe
 #[get("/organizations/<org_id>")]
 fn get_organization(org_id: String, _headers: OwnerHeaders, conn: DbConn) -> JsonResult {
     match Organization::find_by_uuid(&org_id, &conn) {
-        Some(organization) => Ok(Json(organization.to_json())),
+        Some(organization) => {
+            let mut org_json = organization.to_json();
+            QuotaOwner::Organization(organization).add_storage_json(&mut org_json, &conn);
+            Ok(Json(org_json))
+        }
         None => err!("Can't find organization details"),
     }
 }
@@ -602,11 +610,13 @@ fn send_invite(org_id: String, data: JsonUpcase<InviteData>, headers: AdminHeade
 
             mail::send_invite(
                 email,
+                user.language.as_deref(),
                 &user.uuid,
                 Some(org_id.clone()),
                 Some(new_user.uuid),
                 &org_name,
                 Some(headers.user.email.clone()),
+                &conn,
             )?;
         }
     }
@@ -646,11 +656,13 @@ fn reinvite_user(org_id: String, user_org: String, headers: AdminHeaders, conn:
     if CONFIG.mail_enabled() {
         mail::send_invite(
             &user.email,
+            user.language.as_deref(),
             &user.uuid,
             Some(org_id),
             Some(user_org.uuid),
             &org_name,
             Some(headers.user.email),
+            &conn,
         )?;
     } else {
         let invitation = Invitation::new(user.email);
@@ -717,10 +729,12 @@ fn accept_invite(_org_id: String, _org_user_id: String, data: JsonUpcase<AcceptD
         };
         if let Some(invited_by_email) = &claims.invited_by_email {
             // User was invited to an organization, so they must be confirmed manually after acceptance
-            mail::send_invite_accepted(&claims.email, invited_by_email, &org_name)?;
+            let lang = User::find_by_mail(invited_by_email, &conn).and_then(|u| u.language);
+            mail::send_invite_accepted(&claims.email, invited_by_email, lang.as_deref(), &org_name, &conn)?;
         } else {
             // User was invited from /admin, so they are automatically confirmed
-            mail::send_invite_confirmed(&claims.email, &org_name)?;
+            let lang = User::find_by_mail(&claims.email, &conn).and_then(|u| u.language);
+            mail::send_invite_confirmed(&claims.email, lang.as_deref(), &org_name, &conn)?;
         }
     }
 
@@ -761,11 +775,11 @@ fn confirm_invite(
             Some(org) => org.name,
             None => err!("Error looking up organization."),
         };
-        let address = match User::find_by_uuid(&user_to_confirm.user_uuid, &conn) {
-            Some(user) => user.email,
+        let user = match User::find_by_uuid(&user_to_confirm.user_uuid, &conn) {
+            Some(user) => user,
             None => err!("Error looking up user."),
         };
-        mail::send_invite_confirmed(&address, &org_name)?;
+        mail::send_invite_confirmed(&user.email, user.language.as_deref(), &org_name, &conn)?;
     }
 
     user_to_confirm.save(&conn)
@@ -1063,7 +1077,7 @@ fn put_policy(
                     let org = Organization::find_by_uuid(&user_org.org_uuid, &conn).unwrap();
                     let user = User::find_by_uuid(&user_org.user_uuid, &conn).unwrap();
 
-                    mail::send_2fa_removed_from_org(&user.email, &org.name)?;
+                    mail::send_2fa_removed_from_org(&user.email, user.language.as_deref(), &org.name, &conn)?;
                 }
                 user_org.delete(&conn)?;
             }
@@ -1222,11 +1236,13 @@ fn import(org_id: String, data: JsonUpcase<OrgImportData>, headers: Headers, con
 
                     mail::send_invite(
                         &user_data.Email,
+                        user.language.as_deref(),
                         &user.uuid,
                         Some(org_id.clone()),
                         Some(new_org_user.uuid),
                         &org_name,
                         Some(headers.user.email.clone()),
+                        &conn,
                     )?;
                 }
             }
@@ -1246,3 +1262,50 @@ fn import(org_id: String, data: JsonUpcase<OrgImportData>, headers: Headers, con
 
     Ok(())
 }
+
+// Generates a new SCIM token for the organization, invalidating the previous one.
+// Only a hash of the token is stored, so it is returned only once.
+#[post("/organizations/<org_id>/scim-token", data = "<data>")]
+fn post_scim_token(org_id: String, data: JsonUpcase<PasswordData>, headers: OwnerHeaders, conn: DbConn) -> JsonResult {
+    let data: PasswordData = data.into_inner().data;
+
+    if !headers.user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    let mut org = match Organization::find_by_uuid(&org_id, &conn) {
+        Some(org) => org,
+        None => err!("Organization not found"),
+    };
+
+    let token = org.regenerate_scim_token();
+    org.save(&conn)?;
+
+    Ok(Json(json!({
+        "Token": token,
+        "ScimUrl": format!("{}/scim/v2/{}", CONFIG.domain(), org.uuid),
+        "Object": "scimToken",
+    })))
+}
+
+#[delete("/organizations/<org_id>/scim-token", data = "<data>")]
+fn delete_scim_token(
+    org_id: String,
+    data: JsonUpcase<PasswordData>,
+    headers: OwnerHeaders,
+    conn: DbConn,
+) -> EmptyResult {
+    let data: PasswordData = data.into_inner().data;
+
+    if !headers.user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    let mut org = match Organization::find_by_uuid(&org_id, &conn) {
+        Some(org) => org,
+        None => err!("Organization not found"),
+    };
+
+    org.scim_token = None;
+    org.save(&conn)
+}
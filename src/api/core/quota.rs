@@ -0,0 +1,177 @@
+//
+// Attachment storage quotas
+//
+// The limits are stored in KB, like the global `user_attachment_limit` and `org_attachment_limit`
+// config values, which are used for users and organizations without an explicit limit.
+//
+use serde_json::Value;
+
+use crate::{
+    db::{models::*, DbConn},
+    mail,
+    util::get_display_size,
+    CONFIG,
+};
+
+/// Usage percentages at which a warning email is sent, from highest to lowest.
+const WARNING_THRESHOLDS: [i64; 2] = [100, 80];
+
+/// The user or organization whose quota applies to an upload.
+pub enum QuotaOwner {
+    User(User),
+    Organization(Organization),
+}
+
+impl QuotaOwner {
+    /// Attachments count against the quota of the owner of their cipher.
+    pub fn for_cipher(cipher: &Cipher, conn: &DbConn) -> Option<Self> {
+        if let Some(ref user_uuid) = cipher.user_uuid {
+            User::find_by_uuid(user_uuid, conn).map(QuotaOwner::User)
+        } else if let Some(ref org_uuid) = cipher.organization_uuid {
+            Organization::find_by_uuid(org_uuid, conn).map(QuotaOwner::Organization)
+        } else {
+            None
+        }
+    }
+
+    fn limit_kb(&self) -> Option<i64> {
+        match self {
+            QuotaOwner::User(user) => user.effective_attachment_limit(),
+            QuotaOwner::Organization(org) => org.effective_attachment_limit(),
+        }
+    }
+
+    /// Bytes of storage in use by attachments, and for users also by the files of their sends.
+    pub fn storage_used(&self, conn: &DbConn) -> i64 {
+        match self {
+            QuotaOwner::User(user) => user_storage_used(&user.uuid, conn),
+            QuotaOwner::Organization(org) => Attachment::size_by_org(&org.uuid, conn),
+        }
+    }
+
+    /// Returns the amount of bytes that can still be uploaded, or `None` if there is no limit.
+    /// `size_adjust` is added to the result, to account for attachments which are already stored in the database.
+    pub fn storage_left(&self, size_adjust: i64, conn: &DbConn) -> Result<Option<u64>, &'static str> {
+        storage_left(self.limit_kb(), self.storage_used(conn) - size_adjust)
+    }
+
+    /// Sends a warning if the last upload made the usage cross one of the warning thresholds.
+    /// For organizations, the warning is sent to all the owners.
+    pub fn check_storage_usage(&self, used_before: i64, conn: &DbConn) {
+        let limit_kb = match self.limit_kb() {
+            Some(limit_kb) if limit_kb > 0 => limit_kb,
+            _ => return,
+        };
+
+        let used_after = self.storage_used(conn);
+        let percent = match crossed_threshold(limit_kb * 1024, used_before, used_after) {
+            Some(percent) => percent,
+            None => return,
+        };
+
+        match self {
+            QuotaOwner::User(user) => send_storage_warning(user, "your account", percent, used_after, limit_kb, conn),
+            QuotaOwner::Organization(org) => {
+                for user_org in UserOrganization::find_by_org_and_type(&org.uuid, UserOrgType::Owner as i32, conn) {
+                    if let Some(owner) = User::find_by_uuid(&user_org.user_uuid, conn) {
+                        send_storage_warning(&owner, &org.name, percent, used_after, limit_kb, conn);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Adds the storage usage summary to a profile or organization response.
+    pub fn add_storage_json(&self, json: &mut Value, conn: &DbConn) {
+        let used = self.storage_used(conn);
+        let limit = self.limit_kb().map(|l| l * 1024);
+
+        json["Storage"] = json!(used);
+        json["StorageName"] = json!(get_display_size(used));
+        json["MaxStorage"] = json!(limit);
+        json["MaxStorageName"] = json!(limit.map(get_display_size));
+        json["MaxStorageGb"] = json!(limit.map(max_storage_gb));
+        json["StoragePercent"] = json!(limit.filter(|l| *l > 0).map(|l| (used * 100 / l).min(100)));
+    }
+}
+
+/// The limit in GB shown by the clients, rounded up so that a limit below 1 GB isn't shown as 0.
+fn max_storage_gb(limit: i64) -> i64 {
+    const GB: i64 = 1024 * 1024 * 1024;
+    (limit + GB - 1) / GB
+}
+
+/// Bytes of storage in use by the attachments and the send files of a user.
+pub fn user_storage_used(user_uuid: &str, conn: &DbConn) -> i64 {
+    Attachment::size_by_user(user_uuid, conn) + Send::size_by_user(user_uuid, conn)
+}
+
+/// Returns the amount of bytes left from `limit_kb`, `None` if there is no limit, or an error if the limit is reached.
+pub fn storage_left(limit_kb: Option<i64>, used: i64) -> Result<Option<u64>, &'static str> {
+    match limit_kb {
+        Some(0) => Err("Attachments are disabled"),
+        Some(limit_kb) => {
+            let left = (limit_kb * 1024) - used;
+            if left <= 0 {
+                return Err("Attachment storage limit reached! Delete some attachments to free up space");
+            }
+            Ok(Some(left as u64))
+        }
+        None => Ok(None),
+    }
+}
+
+fn send_storage_warning(user: &User, name: &str, percent: i64, used: i64, limit_kb: i64, conn: &DbConn) {
+    if !CONFIG.mail_enabled() {
+        return;
+    }
+
+    let used = get_display_size(used);
+    let limit = get_display_size(limit_kb * 1024);
+    if let Err(e) =
+        mail::send_storage_quota_warning(&user.email, user.language.as_deref(), name, percent, &used, &limit, conn)
+    {
+        error!("Error sending storage quota warning email: {:#?}", e);
+    }
+}
+
+/// Returns the highest warning threshold that was crossed going from `before` to `after` bytes used.
+fn crossed_threshold(limit: i64, before: i64, after: i64) -> Option<i64> {
+    if limit <= 0 {
+        return None;
+    }
+
+    WARNING_THRESHOLDS.iter().copied().find(|t| before * 100 < limit * t && after * 100 >= limit * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crossed_threshold() {
+        assert_eq!(crossed_threshold(1000, 0, 500), None);
+        assert_eq!(crossed_threshold(1000, 700, 800), Some(80));
+        assert_eq!(crossed_threshold(1000, 800, 900), None);
+        assert_eq!(crossed_threshold(1000, 900, 1000), Some(100));
+        assert_eq!(crossed_threshold(1000, 0, 1000), Some(100));
+        assert_eq!(crossed_threshold(1000, 1000, 1200), None);
+        assert_eq!(crossed_threshold(0, 0, 1000), None);
+    }
+
+    #[test]
+    fn test_storage_left() {
+        assert_eq!(storage_left(None, 1024), Ok(None));
+        assert_eq!(storage_left(Some(2), 1024), Ok(Some(1024)));
+        assert!(storage_left(Some(1), 1024).is_err());
+        assert!(storage_left(Some(0), 0).is_err());
+    }
+
+    #[test]
+    fn test_max_storage_gb() {
+        assert_eq!(max_storage_gb(0), 0);
+        assert_eq!(max_storage_gb(100 * 1024 * 1024), 1);
+        assert_eq!(max_storage_gb(1024 * 1024 * 1024), 1);
+        assert_eq!(max_storage_gb(1024 * 1024 * 1024 + 1), 2);
+    }
+}
@@ -14,6 +14,8 @@ use crate::{
     CONFIG,
 };
 
+use super::quota::{self, QuotaOwner};
+
 const SEND_INACCESSIBLE_MSG: &str = "Send does not exist or is no longer available";
 
 pub fn routes() -> Vec<rocket::Route> {
@@ -169,16 +171,15 @@ fn post_send_file(data: Data, content_type: &ContentType, headers: Headers, conn
     // Get the file length and add an extra 5% to avoid issues
     const SIZE_525_MB: u64 = 550_502_400;
 
-    let size_limit = match CONFIG.user_attachment_limit() {
-        Some(0) => err!("File uploads are disabled"),
-        Some(limit_kb) => {
-            let left = (limit_kb * 1024) - Attachment::size_by_user(&headers.user.uuid, &conn);
-            if left <= 0 {
-                err!("Attachment storage limit reached! Delete some attachments to free up space")
-            }
-            std::cmp::Ord::max(left as u64, SIZE_525_MB)
-        }
-        None => SIZE_525_MB,
+    if headers.user.effective_attachment_limit() == Some(0) {
+        err!("File uploads are disabled")
+    }
+
+    let used = quota::user_storage_used(&headers.user.uuid, &conn);
+    let size_limit = match quota::storage_left(headers.user.effective_attachment_limit(), used) {
+        Ok(Some(left)) => std::cmp::Ord::min(left, SIZE_525_MB),
+        Ok(None) => SIZE_525_MB,
+        Err(e) => err!(e),
     };
 
     // Create the Send
@@ -190,6 +191,8 @@ fn post_send_file(data: Data, content_type: &ContentType, headers: Headers, conn
     }
 
     let file_path = Path::new(&CONFIG.sends_folder()).join(&send.uuid).join(&file_id);
+    // The data is written to a partial file, which is moved into place once complete
+    let upload_path = file_path.with_extension("part");
 
     // Read the data entry and save the file
     let mut data_entry = match mpart.read_entry()? {
@@ -198,28 +201,29 @@ fn post_send_file(data: Data, content_type: &ContentType, headers: Headers, conn
         None => err!("No model entry present"),
     };
 
-    let size = match data_entry.data.save().memory_threshold(0).size_limit(size_limit).with_path(&file_path) {
+    let size = match data_entry.data.save().memory_threshold(0).size_limit(size_limit).with_path(&upload_path) {
         SaveResult::Full(SavedData::File(_, size)) => size as i32,
         SaveResult::Full(other) => {
-            std::fs::remove_file(&file_path).ok();
+            std::fs::remove_file(&upload_path).ok();
             err!(format!("Attachment is not a file: {:?}", other));
         }
         SaveResult::Partial(_, reason) => {
-            std::fs::remove_file(&file_path).ok();
+            std::fs::remove_file(&upload_path).ok();
             err!(format!("Attachment storage limit exceeded with this file: {:?}", reason));
         }
         SaveResult::Error(e) => {
-            std::fs::remove_file(&file_path).ok();
+            std::fs::remove_file(&upload_path).ok();
             err!(format!("Error: {:?}", e));
         }
     };
+    std::fs::rename(&upload_path, &file_path)?;
 
     // Set ID and sizes
     let mut data_value: Value = serde_json::from_str(&send.data)?;
     if let Some(o) = data_value.as_object_mut() {
         o.insert(String::from("Id"), Value::String(file_id));
         o.insert(String::from("Size"), Value::Number(size.into()));
-        o.insert(String::from("SizeName"), Value::String(crate::util::get_display_size(size)));
+        o.insert(String::from("SizeName"), Value::String(crate::util::get_display_size(size as i64)));
     }
     send.data = serde_json::to_string(&data_value)?;
 
@@ -227,6 +231,8 @@ fn post_send_file(data: Data, content_type: &ContentType, headers: Headers, conn
     send.save(&conn)?;
     nt.send_user_update(UpdateType::SyncSendCreate, &headers.user);
 
+    QuotaOwner::User(headers.user).check_storage_usage(used, &conn);
+
     Ok(Json(send.to_json()))
 }
 
@@ -118,8 +118,6 @@ pub fn validate_totp_code_str(
 }
 
 pub fn validate_totp_code(user_uuid: &str, totp_code: u64, secret: &str, ip: &ClientIp, conn: &DbConn) -> EmptyResult {
-    use oath::{totp_raw_custom_time, HashType};
-
     let decoded_secret = match BASE32.decode(secret.as_bytes()) {
         Ok(s) => s,
         Err(_) => err!("Invalid TOTP secret"),
@@ -132,7 +130,38 @@ pub fn validate_totp_code(user_uuid: &str, totp_code: u64, secret: &str, ip: &Cl
 
     // Get the current system time in UNIX Epoch (UTC)
     let current_time = chrono::Utc::now();
-    let current_timestamp = current_time.timestamp();
+
+    match check_totp_code(&decoded_secret, totp_code, twofactor.last_used, current_time.timestamp()) {
+        TotpCheck::Valid(time_step) => {
+            // Save the last used time step so only totp time steps higher then this one are allowed.
+            // This will also save a newly created twofactor if the code is correct.
+            twofactor.last_used = time_step;
+            twofactor.save(conn)
+        }
+        TotpCheck::Reused => {
+            warn!("This or a TOTP code within the allowed time drift has already been used!");
+            err!(format!("Invalid TOTP code! Server time: {} IP: {}", current_time.format("%F %T UTC"), ip.ip));
+        }
+        // Else no valide code received, deny access
+        TotpCheck::Invalid => {
+            err!(format!("Invalid TOTP code! Server time: {} IP: {}", current_time.format("%F %T UTC"), ip.ip))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TotpCheck {
+    /// The code is valid, with the time step to save as last used
+    Valid(i32),
+    /// The code matches a time step which isn't after the last used one
+    Reused,
+    Invalid,
+}
+
+/// Checks a TOTP code at the given time. Only the time steps after `last_used` are accepted, so that a code
+/// can't be used twice, and one step back and forward is allowed unless the time drift is disabled.
+pub fn check_totp_code(decoded_secret: &[u8], totp_code: u64, last_used: i32, timestamp: i64) -> TotpCheck {
+    use oath::{totp_raw_custom_time, HashType};
 
     // The amount of steps back and forward in time
     // Also check if we need to disable time drifted TOTP codes.
@@ -140,30 +169,49 @@ pub fn validate_totp_code(user_uuid: &str, totp_code: u64, secret: &str, ip: &Cl
     let steps = !CONFIG.authenticator_disable_time_drift() as i64;
 
     for step in -steps..=steps {
-        let time_step = current_timestamp / 30i64 + step;
+        let time_step = timestamp / 30i64 + step;
         // We need to calculate the time offsite and cast it as an i128.
         // Else we can't do math with it on a default u64 variable.
-        let time = (current_timestamp + step * 30i64) as u64;
-        let generated = totp_raw_custom_time(&decoded_secret, 6, 0, 30, time, &HashType::SHA1);
+        let time = (timestamp + step * 30i64) as u64;
+        let generated = totp_raw_custom_time(decoded_secret, 6, 0, 30, time, &HashType::SHA1);
 
-        // Check the the given code equals the generated and if the time_step is larger then the one last used.
-        if generated == totp_code && time_step > twofactor.last_used as i64 {
+        if generated == totp_code {
+            // Check if the time_step is larger then the one last used.
+            if time_step <= last_used as i64 {
+                return TotpCheck::Reused;
+            }
             // If the step does not equals 0 the time is drifted either server or client side.
             if step != 0 {
                 info!("TOTP Time drift detected. The step offset is {}", step);
             }
-
-            // Save the last used time step so only totp time steps higher then this one are allowed.
-            // This will also save a newly created twofactor if the code is correct.
-            twofactor.last_used = time_step as i32;
-            twofactor.save(conn)?;
-            return Ok(());
-        } else if generated == totp_code && time_step <= twofactor.last_used as i64 {
-            warn!("This or a TOTP code within {} steps back and forward has already been used!", steps);
-            err!(format!("Invalid TOTP code! Server time: {} IP: {}", current_time.format("%F %T UTC"), ip.ip));
+            return TotpCheck::Valid(time_step as i32);
         }
     }
+    TotpCheck::Invalid
+}
 
-    // Else no valide code received, deny access
-    err!(format!("Invalid TOTP code! Server time: {} IP: {}", current_time.format("%F %T UTC"), ip.ip));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oath::{totp_raw_custom_time, HashType};
+
+    #[test]
+    fn test_check_totp_code() {
+        let secret = BASE32.decode(b"JBSWY3DPEHPK3PXP").unwrap();
+        let timestamp = 1_600_000_000;
+        let time_step = (timestamp / 30) as i32;
+        let code = |time: i64| totp_raw_custom_time(&secret, 6, 0, 30, time as u64, &HashType::SHA1);
+
+        assert_eq!(check_totp_code(&secret, code(timestamp), 0, timestamp), TotpCheck::Valid(time_step));
+        assert_eq!(check_totp_code(&secret, code(timestamp), time_step - 1, timestamp), TotpCheck::Valid(time_step));
+        assert_eq!(check_totp_code(&secret, code(timestamp) ^ 1, 0, timestamp), TotpCheck::Invalid);
+
+        // A code can't be replayed, nor can an older one be used after it
+        assert_eq!(check_totp_code(&secret, code(timestamp), time_step, timestamp), TotpCheck::Reused);
+        assert_eq!(check_totp_code(&secret, code(timestamp - 30), time_step, timestamp), TotpCheck::Reused);
+
+        // One step of time drift is allowed by default
+        assert_eq!(check_totp_code(&secret, code(timestamp + 30), 0, timestamp), TotpCheck::Valid(time_step + 1));
+        assert_eq!(check_totp_code(&secret, code(timestamp - 60), 0, timestamp), TotpCheck::Invalid);
+    }
 }
@@ -1,23 +1,25 @@
 use chrono::Utc;
 use data_encoding::BASE64;
+use once_cell::sync::Lazy;
 use rocket::Route;
 use rocket_contrib::json::Json;
 
 use crate::{
     api::{core::two_factor::_generate_recover_code, ApiResult, EmptyResult, JsonResult, JsonUpcase, PasswordData},
-    auth::Headers,
+    auth::{Headers, OwnerHeaders},
     crypto,
     db::{
-        models::{TwoFactor, TwoFactorType, User},
+        models::{Organization, TwoFactor, TwoFactorType, User},
         DbConn,
     },
     error::MapResult,
-    util::get_reqwest_client,
+    http_client::HttpClient,
+    util::get_reqwest_client_builder,
     CONFIG,
 };
 
 pub fn routes() -> Vec<Route> {
-    routes![get_duo, activate_duo, activate_duo_put,]
+    routes![get_duo, activate_duo, activate_duo_put, get_org_duo, activate_org_duo, activate_org_duo_put,]
 }
 
 #[derive(Serialize, Deserialize)]
@@ -38,6 +40,9 @@ impl DuoData {
             _ => None,
         }
     }
+    fn organization(org: &Organization) -> Option<Self> {
+        org.duo_data.as_deref().and_then(|data| serde_json::from_str(data).ok())
+    }
     fn msg(s: &str) -> Self {
         Self {
             host: s.into(),
@@ -123,6 +128,39 @@ fn get_duo(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> Js
     Ok(Json(json))
 }
 
+#[post("/organizations/<org_id>/two-factor/get-duo", data = "<data>")]
+fn get_org_duo(org_id: String, data: JsonUpcase<PasswordData>, headers: OwnerHeaders, conn: DbConn) -> JsonResult {
+    let data: PasswordData = data.into_inner().data;
+
+    if !headers.user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    let org = match Organization::find_by_uuid(&org_id, &conn) {
+        Some(org) => org,
+        None => err!("Organization not found"),
+    };
+
+    let json = match DuoData::organization(&org) {
+        Some(data) => {
+            let data = data.obscure();
+            json!({
+                "Enabled": true,
+                "Host": data.host,
+                "SecretKey": data.sk,
+                "IntegrationKey": data.ik,
+                "Object": "twoFactorDuo"
+            })
+        }
+        None => json!({
+            "Enabled": false,
+            "Object": "twoFactorDuo"
+        }),
+    };
+
+    Ok(Json(json))
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case, dead_code)]
 struct EnableDuoData {
@@ -189,12 +227,74 @@ fn activate_duo_put(data: JsonUpcase<EnableDuoData>, headers: Headers, conn: DbC
     activate_duo(data, headers, conn)
 }
 
+#[post("/organizations/<org_id>/two-factor/duo", data = "<data>")]
+fn activate_org_duo(
+    org_id: String,
+    data: JsonUpcase<EnableDuoData>,
+    headers: OwnerHeaders,
+    conn: DbConn,
+) -> JsonResult {
+    let data: EnableDuoData = data.into_inner().data;
+
+    if !headers.user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    // The global keys are only meant for personal use, organizations need their own
+    if !check_duo_fields_custom(&data) {
+        err!("The Duo host, integration key and secret key are required")
+    }
+
+    let mut org = match Organization::find_by_uuid(&org_id, &conn) {
+        Some(org) => org,
+        None => err!("Organization not found"),
+    };
+
+    let data: DuoData = data.into();
+    duo_api_request("GET", "/auth/v2/check", "", &data).map_res("Failed to validate Duo credentials")?;
+    org.duo_data = Some(serde_json::to_string(&data)?);
+    org.save(&conn)?;
+
+    let data = data.obscure();
+    Ok(Json(json!({
+        "Enabled": true,
+        "Host": data.host,
+        "SecretKey": data.sk,
+        "IntegrationKey": data.ik,
+        "Object": "twoFactorDuo"
+    })))
+}
+
+#[put("/organizations/<org_id>/two-factor/duo", data = "<data>")]
+fn activate_org_duo_put(
+    org_id: String,
+    data: JsonUpcase<EnableDuoData>,
+    headers: OwnerHeaders,
+    conn: DbConn,
+) -> JsonResult {
+    activate_org_duo(org_id, data, headers, conn)
+}
+
+// The Duo host can be set by the users
+static CLIENT: Lazy<HttpClient> = Lazy::new(|| HttpClient::new(get_reqwest_client_builder));
+
 fn duo_api_request(method: &str, path: &str, params: &str, data: &DuoData) -> EmptyResult {
+    duo_api_send(&CLIENT, &format!("https://{}", &data.host), method, path, params, data)
+}
+
+fn duo_api_send(
+    client: &HttpClient,
+    base_url: &str,
+    method: &str,
+    path: &str,
+    params: &str,
+    data: &DuoData,
+) -> EmptyResult {
     use reqwest::{header, Method};
     use std::str::FromStr;
 
     // https://duo.com/docs/authapi#api-details
-    let url = format!("https://{}{}", &data.host, path);
+    let url = format!("{}{}", base_url, path);
     let date = Utc::now().to_rfc2822();
     let username = &data.ik;
     let fields = [&date, method, &data.host, path, params];
@@ -202,14 +302,13 @@ fn duo_api_request(method: &str, path: &str, params: &str, data: &DuoData) -> Em
 
     let m = Method::from_str(method).unwrap_or_default();
 
-    let client = get_reqwest_client();
-
     client
-        .request(m, &url)
-        .basic_auth(username, Some(password))
-        .header(header::USER_AGENT, "vaultwarden:Duo/1.0 (Rust)")
-        .header(header::DATE, date)
-        .send()?
+        .send(m, &url, |request| {
+            request
+                .basic_auth(username, Some(&password))
+                .header(header::USER_AGENT, "vaultwarden:Duo/1.0 (Rust)")
+                .header(header::DATE, &date)
+        })?
         .error_for_status()?;
 
     Ok(())
@@ -256,14 +355,25 @@ fn get_duo_keys_email(email: &str, conn: &DbConn) -> ApiResult<(String, String,
 }
 
 pub fn generate_duo_signature(email: &str, conn: &DbConn) -> ApiResult<(String, String)> {
-    let now = Utc::now().timestamp();
-
     let (ik, sk, ak, host) = get_duo_keys_email(email, conn)?;
 
-    let duo_sign = sign_duo_values(&sk, email, &ik, DUO_PREFIX, now + DUO_EXPIRE);
-    let app_sign = sign_duo_values(&ak, email, &ik, APP_PREFIX, now + APP_EXPIRE);
+    Ok((sign_duo_request(email, &ik, &sk, &ak), host))
+}
+
+/// Same as `generate_duo_signature`, with the Duo keys of the organization.
+pub fn generate_org_duo_signature(email: &str, org: &Organization) -> ApiResult<(String, String)> {
+    let data = DuoData::organization(org).map_res("Can't fetch the Duo keys of the organization")?;
 
-    Ok((format!("{}:{}", duo_sign, app_sign), host))
+    Ok((sign_duo_request(email, &data.ik, &data.sk, &CONFIG.get_duo_akey()), data.host))
+}
+
+fn sign_duo_request(email: &str, ik: &str, sk: &str, ak: &str) -> String {
+    let now = Utc::now().timestamp();
+
+    let duo_sign = sign_duo_values(sk, email, ik, DUO_PREFIX, now + DUO_EXPIRE);
+    let app_sign = sign_duo_values(ak, email, ik, APP_PREFIX, now + APP_EXPIRE);
+
+    format!("{}:{}", duo_sign, app_sign)
 }
 
 fn sign_duo_values(key: &str, email: &str, ikey: &str, prefix: &str, expire: i64) -> String {
@@ -278,6 +388,18 @@ pub fn validate_duo_login(email: &str, response: &str, conn: &DbConn) -> EmptyRe
     // comparison with auth_user below.
     let email = &email.to_lowercase();
 
+    let (ik, sk, ak, _host) = get_duo_keys_email(email, conn)?;
+    validate_duo_response(email, response, &ik, &sk, &ak)
+}
+
+/// Same as `validate_duo_login`, with the Duo keys of the organization.
+pub fn validate_org_duo_login(email: &str, response: &str, org: &Organization) -> EmptyResult {
+    let data = DuoData::organization(org).map_res("Can't fetch the Duo keys of the organization")?;
+
+    validate_duo_response(&email.to_lowercase(), response, &data.ik, &data.sk, &CONFIG.get_duo_akey())
+}
+
+fn validate_duo_response(email: &str, response: &str, ik: &str, sk: &str, ak: &str) -> EmptyResult {
     let split: Vec<&str> = response.split(':').collect();
     if split.len() != 2 {
         err!("Invalid response length");
@@ -288,10 +410,8 @@ pub fn validate_duo_login(email: &str, response: &str, conn: &DbConn) -> EmptyRe
 
     let now = Utc::now().timestamp();
 
-    let (ik, sk, ak, _host) = get_duo_keys_email(email, conn)?;
-
-    let auth_user = parse_duo_values(&sk, auth_sig, &ik, AUTH_PREFIX, now)?;
-    let app_user = parse_duo_values(&ak, app_sig, &ik, APP_PREFIX, now)?;
+    let auth_user = parse_duo_values(sk, auth_sig, ik, AUTH_PREFIX, now)?;
+    let app_user = parse_duo_values(ak, app_sig, ik, APP_PREFIX, now)?;
 
     if !crypto::ct_eq(&auth_user, app_user) || !crypto::ct_eq(&auth_user, email) {
         err!("Error validating duo authentication")
@@ -354,3 +474,112 @@ fn parse_duo_values(key: &str, val: &str, ikey: &str, prefix: &str, time: i64) -
 
     Ok(username.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    fn test_data(host: &str, sk: &str) -> DuoData {
+        DuoData {
+            host: host.to_string(),
+            ik: "DIXXXXXXXXXXXXXXXXXX".to_string(),
+            sk: sk.to_string(),
+        }
+    }
+
+    /// Answers every request like the Duo API, checking the signature with the given secret key.
+    fn stub_duo_server(sk: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let server_host = host.clone();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut parts = request_line.split_whitespace();
+                let (method, path) = (parts.next().unwrap().to_string(), parts.next().unwrap().to_string());
+
+                let (mut date, mut authorization) = (String::new(), String::new());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    let (name, value) = line.split_at(line.find(':').unwrap());
+                    match name.to_lowercase().as_ref() {
+                        "date" => date = value[1..].trim().to_string(),
+                        "authorization" => authorization = value[1..].trim().to_string(),
+                        _ => (),
+                    }
+                }
+
+                let data = test_data(&server_host, sk);
+                let fields = [date.as_str(), &method, &data.host, &path, ""];
+                let credentials = format!("{}:{}", data.ik, crypto::hmac_sign(&data.sk, &fields.join("\n")));
+                let status = if authorization == format!("Basic {}", BASE64.encode(credentials.as_bytes())) {
+                    "200 OK"
+                } else {
+                    "401 Unauthorized"
+                };
+
+                let body = r#"{"stat": "OK", "response": {"time": 1}}"#;
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+
+        host
+    }
+
+    #[test]
+    fn test_duo_api_check() {
+        let host = stub_duo_server("valid-secret-key");
+        let client = HttpClient::new(get_reqwest_client_builder).block_non_global_ips(|| false);
+        let base_url = format!("http://{}", host);
+
+        let valid = test_data(&host, "valid-secret-key");
+        assert!(duo_api_send(&client, &base_url, "GET", "/auth/v2/check", "", &valid).is_ok());
+
+        let invalid = test_data(&host, "wrong-secret-key");
+        assert!(duo_api_send(&client, &base_url, "GET", "/auth/v2/check", "", &invalid).is_err());
+    }
+
+    #[test]
+    fn test_duo_response() {
+        let (ik, sk, ak) = ("DIXXXXXXXXXXXXXXXXXX", "organization-secret-key", "application-secret-key");
+        let email = "user@example.com";
+
+        // The signature for the Duo frame, and the response Duo would send back after a successful login
+        let request = sign_duo_request(email, ik, sk, ak);
+        let app_sig = request.split(':').nth(1).unwrap();
+        let expire = Utc::now().timestamp() + DUO_EXPIRE;
+        let response = format!("{}:{}", sign_duo_values(sk, email, ik, AUTH_PREFIX, expire), app_sig);
+
+        assert!(validate_duo_response(email, &response, ik, sk, ak).is_ok());
+        assert!(validate_duo_response("other@example.com", &response, ik, sk, ak).is_err());
+        assert!(validate_duo_response(email, &response, ik, "other-secret-key", ak).is_err());
+
+        // The request signature can't be used as a response
+        assert!(validate_duo_response(email, &request, ik, sk, ak).is_err());
+
+        let expired = format!("{}:{}", sign_duo_values(sk, email, ik, AUTH_PREFIX, expire - 2 * DUO_EXPIRE), app_sig);
+        assert!(validate_duo_response(email, &expired, ik, sk, ak).is_err());
+    }
+}
@@ -7,7 +7,7 @@ use crate::{
     auth::Headers,
     crypto,
     db::{
-        models::{TwoFactor, TwoFactorType},
+        models::{TwoFactor, TwoFactorType, User},
         DbConn,
     },
     error::{Error, MapResult},
@@ -31,8 +31,6 @@ struct SendEmailLoginData {
 fn send_email_login(data: JsonUpcase<SendEmailLoginData>, conn: DbConn) -> EmptyResult {
     let data: SendEmailLoginData = data.into_inner().data;
 
-    use crate::db::models::User;
-
     // Get the user
     let user = match User::find_by_mail(&data.Email, &conn) {
         Some(user) => user,
@@ -65,7 +63,13 @@ pub fn send_token(user_uuid: &str, conn: &DbConn) -> EmptyResult {
     twofactor.data = twofactor_data.to_json();
     twofactor.save(conn)?;
 
-    mail::send_token(&twofactor_data.email, &twofactor_data.last_token.map_res("Token is empty")?)?;
+    let lang = User::find_by_uuid(user_uuid, conn).and_then(|user| user.language);
+    mail::send_token(
+        &twofactor_data.email,
+        lang.as_deref(),
+        &twofactor_data.last_token.map_res("Token is empty")?,
+        conn,
+    )?;
 
     Ok(())
 }
@@ -128,7 +132,12 @@ fn send_email(data: JsonUpcase<SendEmailData>, headers: Headers, conn: DbConn) -
     let twofactor = TwoFactor::new(user.uuid, TwoFactorType::EmailVerificationChallenge, twofactor_data.to_json());
     twofactor.save(&conn)?;
 
-    mail::send_token(&twofactor_data.email, &twofactor_data.last_token.map_res("Token is empty")?)?;
+    mail::send_token(
+        &twofactor_data.email,
+        user.language.as_deref(),
+        &twofactor_data.last_token.map_res("Token is empty")?,
+        &conn,
+    )?;
 
     Ok(())
 }
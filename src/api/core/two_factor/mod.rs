@@ -1,13 +1,14 @@
+use chrono::{Duration, NaiveDateTime, Utc};
 use data_encoding::BASE32;
 use rocket::Route;
 use rocket_contrib::json::Json;
 use serde_json::Value;
 
 use crate::{
-    api::{JsonResult, JsonUpcase, NumberOrString, PasswordData},
-    auth::Headers,
+    api::{ApiResult, JsonResult, JsonUpcase, NumberOrString, PasswordData},
+    auth::{AdminHeaders, Headers, OwnerHeaders},
     crypto,
-    db::{models::*, DbConn},
+    db::{models::*, DbConn, DbPool},
     mail, CONFIG,
 };
 
@@ -19,7 +20,16 @@ pub mod webauthn;
 pub mod yubikey;
 
 pub fn routes() -> Vec<Route> {
-    let mut routes = routes![get_twofactor, get_recover, recover, disable_twofactor, disable_twofactor_put,];
+    let mut routes = routes![
+        get_twofactor,
+        get_recover,
+        recover,
+        disable_twofactor,
+        disable_twofactor_put,
+        get_org_twofactor,
+        disable_org_twofactor,
+        disable_org_twofactor_put,
+    ];
 
     routes.append(&mut authenticator::routes());
     routes.append(&mut duo::routes());
@@ -138,7 +148,7 @@ fn disable_twofactor(data: JsonUpcase<DisableTwoFactorData>, headers: Headers, c
             if user_org.atype < UserOrgType::Admin {
                 if CONFIG.mail_enabled() {
                     let org = Organization::find_by_uuid(&user_org.org_uuid, &conn).unwrap();
-                    mail::send_2fa_removed_from_org(&user.email, &org.name)?;
+                    mail::send_2fa_removed_from_org(&user.email, user.language.as_deref(), &org.name, &conn)?;
                 }
                 user_org.delete(&conn)?;
             }
@@ -156,3 +166,204 @@ fn disable_twofactor(data: JsonUpcase<DisableTwoFactorData>, headers: Headers, c
 fn disable_twofactor_put(data: JsonUpcase<DisableTwoFactorData>, headers: Headers, conn: DbConn) -> JsonResult {
     disable_twofactor(data, headers, conn)
 }
+
+#[get("/organizations/<org_id>/two-factor")]
+fn get_org_twofactor(org_id: String, _headers: AdminHeaders, conn: DbConn) -> JsonResult {
+    let org = match Organization::find_by_uuid(&org_id, &conn) {
+        Some(org) => org,
+        None => err!("Organization not found"),
+    };
+
+    let mut twofactors_json = Vec::new();
+    if org.duo_data.is_some() {
+        twofactors_json.push(json!({
+            "Enabled": true,
+            "Type": TwoFactorType::OrganizationDuo as i32,
+            "Object": "twoFactorProvider"
+        }));
+    }
+
+    Ok(Json(json!({
+        "Data": twofactors_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+#[post("/organizations/<org_id>/two-factor/disable", data = "<data>")]
+fn disable_org_twofactor(
+    org_id: String,
+    data: JsonUpcase<DisableTwoFactorData>,
+    headers: OwnerHeaders,
+    conn: DbConn,
+) -> JsonResult {
+    let data: DisableTwoFactorData = data.into_inner().data;
+
+    if !headers.user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    let type_ = data.Type.into_i32()?;
+    if type_ != TwoFactorType::OrganizationDuo as i32 {
+        err!("Invalid two factor provider")
+    }
+
+    let mut org = match Organization::find_by_uuid(&org_id, &conn) {
+        Some(org) => org,
+        None => err!("Organization not found"),
+    };
+    org.duo_data = None;
+    org.save(&conn)?;
+
+    Ok(Json(json!({
+        "Enabled": false,
+        "Type": type_,
+        "Object": "twoFactorProvider"
+    })))
+}
+
+#[put("/organizations/<org_id>/two-factor/disable", data = "<data>")]
+fn disable_org_twofactor_put(
+    org_id: String,
+    data: JsonUpcase<DisableTwoFactorData>,
+    headers: OwnerHeaders,
+    conn: DbConn,
+) -> JsonResult {
+    disable_org_twofactor(org_id, data, headers, conn)
+}
+
+/// Two-step login enrolment of a user, when `REQUIRE_2FA` applies to them.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TwoFactorEnrolment {
+    NotRequired,
+    Enrolled,
+    // The user can still log in normally until the deadline
+    Pending(NaiveDateTime),
+    // The user can only log in to set up two-step login
+    Overdue(NaiveDateTime),
+}
+
+/// The enrolment status of a user, a grace period which didn't start yet is handled as if it started now.
+pub fn twofactor_enrolment(user: &User, conn: &DbConn) -> TwoFactorEnrolment {
+    if !CONFIG.is_2fa_required(&user.email) {
+        return TwoFactorEnrolment::NotRequired;
+    }
+
+    // The organization Duo is used for the users without their own two-step login
+    if !TwoFactor::find_by_user(&user.uuid, conn).is_empty()
+        || Organization::find_with_duo_by_user(&user.uuid, conn).is_some()
+    {
+        return TwoFactorEnrolment::Enrolled;
+    }
+
+    let now = Utc::now().naive_utc();
+    let deadline =
+        user.twofactor_deadline().unwrap_or_else(|| now + Duration::days(CONFIG.require_2fa_grace_period() as i64));
+    pending_enrolment(deadline, now)
+}
+
+fn pending_enrolment(deadline: NaiveDateTime, now: NaiveDateTime) -> TwoFactorEnrolment {
+    if deadline < now {
+        TwoFactorEnrolment::Overdue(deadline)
+    } else {
+        TwoFactorEnrolment::Pending(deadline)
+    }
+}
+
+/// Whether to remind a user of the deadline again. The first reminder is sent right away, then each time half of the
+/// time left at the previous one has passed, at most daily.
+fn twofactor_reminder_due(deadline: NaiveDateTime, reminded_at: Option<NaiveDateTime>, now: NaiveDateTime) -> bool {
+    match reminded_at {
+        None => true,
+        Some(reminded_at) => now - reminded_at >= Duration::days(1) && deadline - now <= (deadline - reminded_at) / 2,
+    }
+}
+
+/// Starts the grace period of the user if `REQUIRE_2FA` just started to apply to them, or clears it if it doesn't anymore.
+pub fn update_twofactor_enrolment(user: &mut User, conn: &DbConn) -> ApiResult<TwoFactorEnrolment> {
+    let required = CONFIG.is_2fa_required(&user.email);
+    if required != user.twofactor_required_at.is_some() {
+        user.twofactor_required_at = if required {
+            Some(Utc::now().naive_utc())
+        } else {
+            None
+        };
+        user.twofactor_reminded_at = None;
+        user.save(conn)?;
+    }
+
+    Ok(twofactor_enrolment(user, conn))
+}
+
+pub fn send_twofactor_reminders(pool: DbPool) {
+    debug!("Sending two-step login reminders");
+    if !CONFIG.mail_enabled() {
+        return;
+    }
+    let conn = match pool.get() {
+        Ok(conn) => conn,
+        Err(_) => {
+            error!("Failed to get DB connection while sending two-step login reminders");
+            return;
+        }
+    };
+
+    let now = Utc::now().naive_utc();
+    for mut user in User::get_all(&conn) {
+        // The grace period starts with the next login, so the users who don't log in anymore aren't reminded
+        if !user.enabled || user.twofactor_required_at.is_none() {
+            continue;
+        }
+
+        match update_twofactor_enrolment(&mut user, &conn) {
+            Ok(TwoFactorEnrolment::Pending(deadline))
+                if twofactor_reminder_due(deadline, user.twofactor_reminded_at, now) =>
+            {
+                if let Err(e) = mail::send_twofactor_required(&user.email, user.language.as_deref(), &deadline, &conn) {
+                    error!("Error sending two-step login reminder to {}: {:#?}", user.email, e);
+                    continue;
+                }
+                user.twofactor_reminded_at = Some(now);
+                if let Err(e) = user.save(&conn) {
+                    error!("Error saving the two-step login reminder of {}: {:#?}", user.email, e);
+                }
+            }
+            Ok(_) => (),
+            Err(e) => error!("Error updating the two-step login enrolment of {}: {:#?}", user.email, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_enrolment() {
+        let now = Utc::now().naive_utc();
+        let deadline = now + Duration::days(3);
+        assert_eq!(pending_enrolment(deadline, now), TwoFactorEnrolment::Pending(deadline));
+        assert_eq!(pending_enrolment(deadline, deadline), TwoFactorEnrolment::Pending(deadline));
+        assert_eq!(pending_enrolment(deadline, deadline + Duration::seconds(1)), TwoFactorEnrolment::Overdue(deadline));
+    }
+
+    #[test]
+    fn test_twofactor_reminder_due() {
+        let now = Utc::now().naive_utc();
+        let deadline = now + Duration::days(30);
+
+        // The first reminder is sent right away
+        assert!(twofactor_reminder_due(deadline, None, now));
+
+        // Then once half of the time left has passed
+        let reminded_at = now - Duration::days(10);
+        assert!(!twofactor_reminder_due(deadline, Some(reminded_at), now));
+        let reminded_at = now - Duration::days(30);
+        assert!(twofactor_reminder_due(deadline, Some(reminded_at), now));
+
+        // But never twice a day, even close to the deadline
+        let deadline = now + Duration::hours(2);
+        assert!(!twofactor_reminder_due(deadline, Some(now - Duration::hours(12)), now));
+        assert!(twofactor_reminder_due(deadline, Some(now - Duration::days(1)), now));
+    }
+}
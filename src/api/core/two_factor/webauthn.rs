@@ -20,23 +20,22 @@ pub fn routes() -> Vec<Route> {
     routes![get_webauthn, generate_webauthn_challenge, activate_webauthn, activate_webauthn_put, delete_webauthn,]
 }
 
-struct WebauthnConfig {
+pub struct WebauthnConfig {
     url: String,
     rpid: String,
 }
 
 impl WebauthnConfig {
-    fn load() -> Webauthn<Self> {
-        let domain = CONFIG.domain();
+    pub fn load() -> Webauthn<Self> {
         Webauthn::new(Self {
-            rpid: reqwest::Url::parse(&domain)
-                .map(|u| u.domain().map(str::to_owned))
-                .ok()
-                .flatten()
-                .unwrap_or_default(),
-            url: domain,
+            rpid: Self::relying_party_id(),
+            url: CONFIG.domain(),
         })
     }
+
+    pub fn relying_party_id() -> String {
+        reqwest::Url::parse(&CONFIG.domain()).map(|u| u.domain().map(str::to_owned)).ok().flatten().unwrap_or_default()
+    }
 }
 
 impl webauthn_rs::WebauthnConfig for WebauthnConfig {
@@ -141,7 +140,7 @@ struct EnableWebauthnData {
 // This is copied from RegisterPublicKeyCredential to change the Response objects casing
 #[derive(Debug, Deserialize)]
 #[allow(non_snake_case)]
-struct RegisterPublicKeyCredentialCopy {
+pub struct RegisterPublicKeyCredentialCopy {
     pub Id: String,
     pub RawId: Base64UrlSafeData,
     pub Response: AuthenticatorAttestationResponseRawCopy,
@@ -294,25 +293,9 @@ fn delete_webauthn(data: JsonUpcase<DeleteU2FData>, headers: Headers, conn: DbCo
         None => err!("Webauthn entry not found"),
     };
 
-    let removed_item = data.remove(item_pos);
+    data.remove(item_pos);
     tf.data = serde_json::to_string(&data)?;
     tf.save(&conn)?;
-    drop(tf);
-
-    // If entry is migrated from u2f, delete the u2f entry as well
-    if let Some(mut u2f) = TwoFactor::find_by_user_and_type(&headers.user.uuid, TwoFactorType::U2f as i32, &conn) {
-        use crate::api::core::two_factor::u2f::U2FRegistration;
-        let mut data: Vec<U2FRegistration> = match serde_json::from_str(&u2f.data) {
-            Ok(d) => d,
-            Err(_) => err!("Error parsing U2F data"),
-        };
-
-        data.retain(|r| r.reg.key_handle != removed_item.credential.cred_id);
-        let new_data_str = serde_json::to_string(&data)?;
-
-        u2f.data = new_data_str;
-        u2f.save(&conn)?;
-    }
 
     let keys_json: Vec<Value> = data.iter().map(WebauthnRegistration::to_json).collect();
 
@@ -368,8 +351,7 @@ pub fn validate_webauthn_login(user_uuid: &str, response: &str, conn: &DbConn) -
 
     let mut registrations = get_webauthn_registrations(user_uuid, conn)?.1;
 
-    // If the credential we received is migrated from U2F, enable the U2F compatibility
-    //let use_u2f = registrations.iter().any(|r| r.migrated && r.credential.cred_id == rsp.raw_id.0);
+    // The keys migrated from U2F sign with the AppID as relying party, which the AppID extension of the challenge allows
     let (cred_id, auth_data) = WebauthnConfig::load().authenticate_credential(&rsp, &state)?;
 
     for reg in &mut registrations {
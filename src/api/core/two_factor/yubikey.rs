@@ -1,3 +1,5 @@
+use data_encoding::HEXLOWER;
+use openssl::symm::{Cipher, Crypter, Mode};
 use rocket::Route;
 use rocket_contrib::json::Json;
 use serde_json::Value;
@@ -35,6 +37,28 @@ struct EnableYubikeyData {
 pub struct YubikeyMetadata {
     Keys: Vec<String>,
     pub Nfc: bool,
+    // The secrets of the keys, when the OTPs are validated by the server itself
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    OfflineKeys: Vec<OfflineYubikey>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[allow(non_snake_case)]
+struct OfflineYubikey {
+    PublicId: String,
+    // Hex encoded private ID and AES key, configured in the YubiKey
+    PrivateId: String,
+    Secret: String,
+    // Counters of the last accepted OTP, the next ones must be greater to prevent replays
+    UseCounter: u16,
+    SessionCounter: u8,
+}
+
+// The decrypted part of a YubiKey OTP
+struct OtpToken {
+    private_id: [u8; 6],
+    use_counter: u16,
+    session_counter: u8,
 }
 
 fn parse_yubikeys(data: &EnableYubikeyData) -> Vec<String> {
@@ -53,6 +77,18 @@ fn jsonify_yubikeys(yubikeys: Vec<String>) -> serde_json::Value {
     result
 }
 
+fn check_yubico_enabled() -> EmptyResult {
+    if !CONFIG._enable_yubico() {
+        err!("Yubico support is disabled");
+    }
+
+    // Without the Yubico servers, the keys are enrolled with their secret instead
+    if !CONFIG.yubico_offline() {
+        get_yubico_credentials()?;
+    }
+    Ok(())
+}
+
 fn get_yubico_credentials() -> Result<(String, String), Error> {
     if !CONFIG._enable_yubico() {
         err!("Yubico support is disabled");
@@ -77,10 +113,105 @@ fn verify_yubikey_otp(otp: String) -> EmptyResult {
     .and(Ok(()))
 }
 
+const MODHEX: &[u8; 16] = b"cbdefghijklnrtuv";
+
+fn decode_modhex(modhex: &str) -> Option<Vec<u8>> {
+    let nibbles: Option<Vec<u8>> =
+        modhex.bytes().map(|c| MODHEX.iter().position(|m| *m == c.to_ascii_lowercase()).map(|n| n as u8)).collect();
+    let nibbles = nibbles?;
+    if nibbles.len() % 2 != 0 {
+        return None;
+    }
+    Some(nibbles.chunks(2).map(|n| n[0] << 4 | n[1]).collect())
+}
+
+// CRC-16 (ISO 13239) of the YubiKey OTPs
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff_u16;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            let carry = crc & 1;
+            crc >>= 1;
+            if carry != 0 {
+                crc ^= 0x8408;
+            }
+        }
+    }
+    crc
+}
+
+/// Decrypts the token of a 44 characters OTP with the AES key of the YubiKey, and checks its CRC.
+fn decrypt_otp(otp: &str, secret: &[u8]) -> Result<OtpToken, Error> {
+    // Checked first, so that the OTP can be split by bytes
+    if !otp.is_ascii() {
+        err!("Invalid Yubikey OTP encoding");
+    }
+    if otp.len() != 44 || secret.len() != 16 {
+        err!("Invalid Yubikey OTP length");
+    }
+    let encrypted = decode_modhex(&otp[12..]).map_res("Invalid Yubikey OTP encoding")?;
+
+    let mut crypter = Crypter::new(Cipher::aes_128_ecb(), Mode::Decrypt, secret, None)?;
+    crypter.pad(false);
+    let mut token = vec![0u8; 32];
+    let mut count = crypter.update(&encrypted, &mut token)?;
+    count += crypter.finalize(&mut token[count..])?;
+    token.truncate(count);
+
+    // The CRC of the whole token, including its own CRC, is a constant residue
+    if token.len() != 16 || crc16(&token) != 0xf0b8 {
+        err!("Invalid Yubikey OTP")
+    }
+
+    let mut private_id = [0u8; 6];
+    private_id.copy_from_slice(&token[..6]);
+    Ok(OtpToken {
+        private_id,
+        use_counter: u16::from_le_bytes([token[6], token[7]]),
+        session_counter: token[11],
+    })
+}
+
+/// Validates an OTP with the secret of the YubiKey, and updates its counters so it can't be used again.
+fn verify_offline_otp(otp: &str, key: &mut OfflineYubikey) -> EmptyResult {
+    let secret = HEXLOWER.decode(key.Secret.as_bytes()).ok().map_res("Invalid Yubikey secret")?;
+    let token = decrypt_otp(otp, &secret)?;
+
+    if !crate::crypto::ct_eq(HEXLOWER.encode(&token.private_id), &key.PrivateId) {
+        err!("Invalid Yubikey OTP")
+    }
+    if (token.use_counter, token.session_counter) <= (key.UseCounter, key.SessionCounter) {
+        err!("Yubikey OTP was already used")
+    }
+
+    key.UseCounter = token.use_counter;
+    key.SessionCounter = token.session_counter;
+    Ok(())
+}
+
+/// Enrolls a key for the offline validation, from its AES key followed by an OTP, as `<AES key>:<OTP>`.
+fn parse_offline_yubikey(value: &str) -> Result<OfflineYubikey, Error> {
+    let (secret, otp) = match value.rsplit_once(':') {
+        Some((secret, otp)) => (secret.replace(char::is_whitespace, "").to_lowercase(), otp.trim()),
+        None => err!("The AES key of the Yubikey is needed to validate its OTPs, enter it as <AES key>:<OTP>"),
+    };
+    let secret_bytes = HEXLOWER.decode(secret.as_bytes()).ok().map_res("Invalid Yubikey AES key")?;
+    let token = decrypt_otp(otp, &secret_bytes).map_res("Invalid Yubikey OTP provided")?;
+
+    Ok(OfflineYubikey {
+        PublicId: otp[..12].to_string(),
+        PrivateId: HEXLOWER.encode(&token.private_id),
+        Secret: secret,
+        UseCounter: token.use_counter,
+        SessionCounter: token.session_counter,
+    })
+}
+
 #[post("/two-factor/get-yubikey", data = "<data>")]
 fn generate_yubikey(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
     // Make sure the credentials are set
-    get_yubico_credentials()?;
+    check_yubico_enabled()?;
 
     let data: PasswordData = data.into_inner().data;
     let user = headers.user;
@@ -136,21 +267,39 @@ fn activate_yubikey(data: JsonUpcase<EnableYubikeyData>, headers: Headers, conn:
         })));
     }
 
+    let old_offline_keys = match serde_json::from_str::<YubikeyMetadata>(&yubikey_data.data) {
+        Ok(metadata) => metadata.OfflineKeys,
+        Err(_) => Vec::new(),
+    };
+    let mut offline_keys = Vec::new();
+
     // Ensure they are valid OTPs
     for yubikey in &yubikeys {
         if yubikey.len() == 12 {
-            // YubiKey ID
+            // YubiKey ID, keep its secret if it already has one
+            offline_keys.extend(old_offline_keys.iter().find(|k| &k.PublicId == yubikey).cloned());
             continue;
         }
 
-        verify_yubikey_otp(yubikey.to_owned()).map_res("Invalid Yubikey OTP provided")?;
+        if CONFIG.yubico_offline() {
+            offline_keys.push(parse_offline_yubikey(yubikey)?);
+        } else {
+            verify_yubikey_otp(yubikey.to_owned()).map_res("Invalid Yubikey OTP provided")?;
+        }
     }
 
-    let yubikey_ids: Vec<String> = yubikeys.into_iter().map(|x| (&x[..12]).to_owned()).collect();
+    let yubikey_ids: Vec<String> = yubikeys
+        .into_iter()
+        .map(|x| match x.rsplit_once(':') {
+            Some((_, otp)) => otp.trim().chars().take(12).collect(),
+            None => x.chars().take(12).collect(),
+        })
+        .collect();
 
     let yubikey_metadata = YubikeyMetadata {
         Keys: yubikey_ids,
         Nfc: data.Nfc,
+        OfflineKeys: offline_keys,
     };
 
     yubikey_data.data = serde_json::to_string(&yubikey_metadata).unwrap();
@@ -172,18 +321,36 @@ fn activate_yubikey_put(data: JsonUpcase<EnableYubikeyData>, headers: Headers, c
     activate_yubikey(data, headers, conn)
 }
 
-pub fn validate_yubikey_login(response: &str, twofactor_data: &str) -> EmptyResult {
-    if response.len() != 44 {
+pub fn validate_yubikey_login(user_uuid: &str, response: &str, twofactor_data: &str, conn: &DbConn) -> EmptyResult {
+    if !response.is_ascii() || response.len() != 44 {
         err!("Invalid Yubikey OTP length");
     }
 
-    let yubikey_metadata: YubikeyMetadata = serde_json::from_str(twofactor_data).expect("Can't parse Yubikey Metadata");
+    let mut yubikey_metadata: YubikeyMetadata =
+        serde_json::from_str(twofactor_data).expect("Can't parse Yubikey Metadata");
     let response_id = &response[..12];
 
     if !yubikey_metadata.Keys.contains(&response_id.to_owned()) {
         err!("Given Yubikey is not registered");
     }
 
+    if CONFIG.yubico_offline() {
+        let key = match yubikey_metadata.OfflineKeys.iter_mut().find(|k| k.PublicId == response_id) {
+            Some(key) => key,
+            None => err!("Given Yubikey is not enrolled for the offline validation, it has to be added again"),
+        };
+        verify_offline_otp(response, key)?;
+
+        // Save the counters, so the OTP can't be replayed. The data is only replaced if it wasn't already by a
+        // concurrent login with the same OTP.
+        let mut twofactor = TwoFactor::find_by_user_and_type(user_uuid, TwoFactorType::YubiKey as i32, conn)
+            .map_res("Yubikey doesn't exist")?;
+        if !twofactor.replace_data(twofactor_data, serde_json::to_string(&yubikey_metadata)?, conn)? {
+            err!("Yubikey OTP was already used")
+        }
+        return Ok(());
+    }
+
     let result = verify_yubikey_otp(response.to_owned());
 
     match result {
@@ -191,3 +358,84 @@ pub fn validate_yubikey_login(response: &str, twofactor_data: &str) -> EmptyResu
         Err(_e) => err!("Failed to verify Yubikey against OTP server"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "ecde18dbe76fbd0c33330f1c354871db";
+    const PRIVATE_ID: [u8; 6] = [0x87, 0x92, 0xeb, 0xfe, 0x26, 0xcc];
+
+    fn encode_modhex(data: &[u8]) -> String {
+        data.iter().flat_map(|b| vec![MODHEX[(b >> 4) as usize] as char, MODHEX[(b & 0xf) as usize] as char]).collect()
+    }
+
+    // Generates an OTP like a YubiKey would
+    fn generate_otp(public_id: &str, secret: &str, use_counter: u16, session_counter: u8) -> String {
+        let mut token = [0u8; 16];
+        token[..6].copy_from_slice(&PRIVATE_ID);
+        token[6..8].copy_from_slice(&use_counter.to_le_bytes());
+        token[8..11].copy_from_slice(&[0x12, 0x34, 0x56]); // Timestamp
+        token[11] = session_counter;
+        token[12..14].copy_from_slice(&[0xab, 0xcd]); // Random
+        let crc = !crc16(&token[..14]);
+        token[14..].copy_from_slice(&crc.to_le_bytes());
+
+        let secret = HEXLOWER.decode(secret.as_bytes()).unwrap();
+        let mut crypter = Crypter::new(Cipher::aes_128_ecb(), Mode::Encrypt, &secret, None).unwrap();
+        crypter.pad(false);
+        let mut encrypted = vec![0u8; 32];
+        let count = crypter.update(&token, &mut encrypted).unwrap();
+        encrypted.truncate(count);
+
+        format!("{}{}", public_id, encode_modhex(&encrypted))
+    }
+
+    #[test]
+    fn test_offline_otp() {
+        let otp = generate_otp("vvcccccfhkdr", SECRET, 1, 0);
+        assert_eq!(otp.len(), 44);
+
+        let mut key = parse_offline_yubikey(&format!("{}:{}", SECRET, otp)).unwrap();
+        assert_eq!(key.PublicId, "vvcccccfhkdr");
+        assert_eq!(key.PrivateId, "8792ebfe26cc");
+
+        // The OTP used for the enrollment can't be used again
+        assert!(verify_offline_otp(&otp, &mut key).is_err());
+
+        // The next OTPs of the session and of the next sessions are valid once
+        let otp = generate_otp("vvcccccfhkdr", SECRET, 1, 1);
+        assert!(verify_offline_otp(&otp, &mut key).is_ok());
+        assert!(verify_offline_otp(&otp, &mut key).is_err());
+        assert!(verify_offline_otp(&generate_otp("vvcccccfhkdr", SECRET, 2, 0), &mut key).is_ok());
+        assert!(verify_offline_otp(&generate_otp("vvcccccfhkdr", SECRET, 1, 5), &mut key).is_err());
+        assert_eq!((key.UseCounter, key.SessionCounter), (2, 0));
+
+        // OTPs of another key are rejected
+        let other = generate_otp("vvcccccfhkdr", "00112233445566778899aabbccddeeff", 3, 0);
+        assert!(verify_offline_otp(&other, &mut key).is_err());
+        assert_eq!((key.UseCounter, key.SessionCounter), (2, 0));
+    }
+
+    #[test]
+    fn test_offline_enrollment_format() {
+        let otp = generate_otp("vvcccccfhkdr", SECRET, 1, 0);
+        assert!(parse_offline_yubikey(&otp).is_err());
+        assert!(parse_offline_yubikey(&format!("{}:{}", "00112233445566778899aabbccddeeff", otp)).is_err());
+
+        // The personalization tool shows the AES key as space separated bytes
+        let spaced = "ec de 18 db e7 6f bd 0c 33 33 0f 1c 35 48 71 DB";
+        assert!(parse_offline_yubikey(&format!("{}:{}", spaced, otp)).is_ok());
+    }
+
+    #[test]
+    fn test_non_ascii_otp() {
+        // 44 bytes, with a multi-byte character across the end of the public id
+        let otp = format!("vvcccccfhkd\u{e9}{}", "c".repeat(31));
+        assert_eq!(otp.len(), 44);
+
+        let secret = HEXLOWER.decode(SECRET.as_bytes()).unwrap();
+        assert!(decrypt_otp(&otp, &secret).is_err());
+        assert!(parse_offline_yubikey(&format!("{}:{}", SECRET, otp)).is_err());
+    }
+}
@@ -0,0 +1,541 @@
+//
+// Passkeys: discoverable WebAuthn credentials which log the user in without the master password
+//
+// The client encrypts the user key with a key derived from the PRF extension output of the
+// authenticator, so that the vault can be unlocked with the same passkey. The server never sees
+// the PRF output, it only stores the encrypted keys and returns them after a successful login.
+//
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::Utc;
+use data_encoding::BASE64URL_NOPAD;
+use once_cell::sync::Lazy;
+use rocket::Route;
+use rocket_contrib::json::Json;
+use serde_json::Value;
+use webauthn_rs::{
+    base64_data::Base64UrlSafeData,
+    proto::{Credential, CredentialID, PublicKeyCredential, RegisterPublicKeyCredential, UserVerificationPolicy},
+    RegistrationState,
+};
+
+use crate::{
+    api::{
+        core::two_factor::webauthn::{PublicKeyCredentialCopy, RegisterPublicKeyCredentialCopy, WebauthnConfig},
+        EmptyResult, JsonResult, JsonUpcase, PasswordData,
+    },
+    auth::{
+        decode_webauthn_login, decode_webauthn_register, encode_jwt, generate_webauthn_login_claims,
+        generate_webauthn_register_claims, Headers,
+    },
+    crypto,
+    db::{
+        models::{User, WebAuthnCredential},
+        DbConn,
+    },
+    error::Error,
+    util::UpCase,
+    CONFIG,
+};
+
+/// Max number of passkeys of a user.
+const MAX_CREDENTIALS: usize = 5;
+/// Time the client has to complete a ceremony, in milliseconds.
+const CHALLENGE_TIMEOUT: u64 = 60_000;
+
+/// Login challenges which were already used, with their expiration time, to reject replayed assertions.
+static USED_LOGIN_CHALLENGES: Lazy<Mutex<HashMap<String, i64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        get_webauthn_credentials,
+        post_attestation_options,
+        post_webauthn_credential,
+        post_assertion_options,
+        put_webauthn_credential,
+        delete_webauthn_credential,
+    ]
+}
+
+#[get("/webauthn")]
+fn get_webauthn_credentials(headers: Headers, conn: DbConn) -> Json<Value> {
+    let credentials_json: Vec<Value> =
+        WebAuthnCredential::find_by_user(&headers.user.uuid, &conn).iter().map(WebAuthnCredential::to_json).collect();
+
+    Json(json!({
+        "Data": credentials_json,
+        "Object": "list",
+        "ContinuationToken": null
+    }))
+}
+
+#[post("/webauthn/attestation-options", data = "<data>")]
+fn post_attestation_options(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+    check_domain()?;
+    if !headers.user.check_valid_password(&data.data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    let existing = WebAuthnCredential::find_by_user(&headers.user.uuid, &conn);
+    if existing.len() >= MAX_CREDENTIALS {
+        err!(format!("A user can't have more than {} passkeys", MAX_CREDENTIALS))
+    }
+
+    let exclude = existing.iter().filter_map(|c| BASE64URL_NOPAD.decode(c.credential_id.as_bytes()).ok()).collect();
+    let (options, state) = start_registration(&headers.user, exclude)?;
+    let claims = generate_webauthn_register_claims(headers.user.uuid, serde_json::to_string(&state)?);
+
+    Ok(Json(json!({
+        "Options": options,
+        "Token": encode_jwt(&claims),
+        "Object": "webauthnCredentialCreateOptions"
+    })))
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct WebAuthnCredentialData {
+    DeviceResponse: RegisterPublicKeyCredentialCopy,
+    Name: String,
+    Token: String,
+    #[serde(default)]
+    SupportsPrf: bool,
+    EncryptedUserKey: Option<String>,
+    EncryptedPublicKey: Option<String>,
+    EncryptedPrivateKey: Option<String>,
+}
+
+#[post("/webauthn", data = "<data>")]
+fn post_webauthn_credential(data: JsonUpcase<WebAuthnCredentialData>, headers: Headers, conn: DbConn) -> EmptyResult {
+    let data: WebAuthnCredentialData = data.into_inner().data;
+    let user = headers.user;
+
+    let claims = decode_webauthn_register(&data.Token)?;
+    if claims.sub != user.uuid {
+        err!("The token doesn't belong to this user")
+    }
+    if WebAuthnCredential::find_by_user(&user.uuid, &conn).len() >= MAX_CREDENTIALS {
+        err!(format!("A user can't have more than {} passkeys", MAX_CREDENTIALS))
+    }
+
+    let state: RegistrationState = serde_json::from_str(&claims.state)?;
+    let credential = finish_registration(&data.DeviceResponse.into(), &state)?;
+    let credential_id = BASE64URL_NOPAD.encode(&credential.cred_id);
+    if WebAuthnCredential::find_by_credential_id(&credential_id, &conn).is_some() {
+        err!("This passkey is already registered")
+    }
+
+    let mut webauthn_credential = WebAuthnCredential::new(
+        user.uuid,
+        data.Name,
+        credential_id,
+        serde_json::to_string(&credential)?,
+        data.SupportsPrf,
+    );
+    if data.SupportsPrf {
+        set_encrypted_keys(
+            &mut webauthn_credential,
+            data.EncryptedUserKey,
+            data.EncryptedPublicKey,
+            data.EncryptedPrivateKey,
+        )?;
+    }
+    webauthn_credential.save(&conn)
+}
+
+#[post("/webauthn/assertion-options", data = "<data>")]
+fn post_assertion_options(data: JsonUpcase<PasswordData>, headers: Headers) -> JsonResult {
+    check_domain()?;
+    if !headers.user.check_valid_password(&data.data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    generate_login_options(Some(headers.user.uuid))
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct UpdateWebAuthnKeysData {
+    DeviceResponse: PublicKeyCredentialCopy,
+    Token: String,
+    EncryptedUserKey: Option<String>,
+    EncryptedPublicKey: Option<String>,
+    EncryptedPrivateKey: Option<String>,
+}
+
+/// Sets the encrypted keys of a passkey, which has to be used to prove that the PRF output is the right one.
+#[put("/webauthn", data = "<data>")]
+fn put_webauthn_credential(data: JsonUpcase<UpdateWebAuthnKeysData>, headers: Headers, conn: DbConn) -> EmptyResult {
+    let data: UpdateWebAuthnKeysData = data.into_inner().data;
+
+    let (user, mut webauthn_credential) = validate_login(&data.Token, data.DeviceResponse.into(), &conn)?;
+    if user.uuid != headers.user.uuid {
+        err!("The passkey doesn't belong to this user")
+    }
+    if !webauthn_credential.supports_prf {
+        err!("This passkey doesn't support encryption")
+    }
+
+    set_encrypted_keys(
+        &mut webauthn_credential,
+        data.EncryptedUserKey,
+        data.EncryptedPublicKey,
+        data.EncryptedPrivateKey,
+    )?;
+    webauthn_credential.save(&conn)
+}
+
+#[post("/webauthn/<uuid>/delete", data = "<data>")]
+fn delete_webauthn_credential(
+    uuid: String,
+    data: JsonUpcase<PasswordData>,
+    headers: Headers,
+    conn: DbConn,
+) -> EmptyResult {
+    if !headers.user.check_valid_password(&data.data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    match WebAuthnCredential::find_by_uuid_and_user(&uuid, &headers.user.uuid, &conn) {
+        Some(webauthn_credential) => webauthn_credential.delete(&conn),
+        None => err!("Passkey not found"),
+    }
+}
+
+fn check_domain() -> EmptyResult {
+    if !CONFIG.domain_set() {
+        err!("`DOMAIN` environment variable is not set. Passkeys disabled")
+    }
+    Ok(())
+}
+
+fn set_encrypted_keys(
+    webauthn_credential: &mut WebAuthnCredential,
+    user_key: Option<String>,
+    public_key: Option<String>,
+    private_key: Option<String>,
+) -> EmptyResult {
+    match (user_key, public_key, private_key) {
+        (Some(user_key), Some(public_key), Some(private_key)) => {
+            webauthn_credential.encrypted_user_key = Some(user_key);
+            webauthn_credential.encrypted_public_key = Some(public_key);
+            webauthn_credential.encrypted_private_key = Some(private_key);
+            Ok(())
+        }
+        (None, None, None) => Ok(()),
+        _ => err!("The user key and both keys of the key pair are needed to enable encryption"),
+    }
+}
+
+/// Returns the options for a passkey login, along with the token which has to be sent back to validate it.
+/// When a user is given, the token can only be used by that user to update the keys of a passkey.
+pub fn generate_login_options(user_uuid: Option<String>) -> JsonResult {
+    check_domain()?;
+
+    let (options, challenge) = start_login();
+    let claims = generate_webauthn_login_claims(user_uuid, challenge);
+
+    Ok(Json(json!({
+        "Options": options,
+        "Token": encode_jwt(&claims),
+        "Object": "webAuthnLoginAssertionOptions"
+    })))
+}
+
+/// Validates the assertion of a passkey against the challenge in the token, returning the user it authenticates.
+pub fn validate_login(
+    token: &str,
+    response: PublicKeyCredential,
+    conn: &DbConn,
+) -> Result<(User, WebAuthnCredential), Error> {
+    let claims = decode_webauthn_login(token)?;
+    take_login_challenge(&claims.state, claims.exp)?;
+
+    let credential_id = BASE64URL_NOPAD.encode(&response.raw_id.0);
+    let mut webauthn_credential = match WebAuthnCredential::find_by_credential_id(&credential_id, conn) {
+        Some(webauthn_credential) => webauthn_credential,
+        None => err!("Passkey not found", format!("Credential id: {}", credential_id)),
+    };
+    if !claims.sub.is_empty() && claims.sub != webauthn_credential.user_uuid {
+        err!("The passkey doesn't belong to this user")
+    }
+
+    let user = match User::find_by_uuid(&webauthn_credential.user_uuid, conn) {
+        Some(user) => user,
+        None => err!("User not found"),
+    };
+
+    let credential: Credential = serde_json::from_str(&webauthn_credential.credential)?;
+    let credential = finish_login(&response, &claims.state, credential, &user.uuid)?;
+
+    webauthn_credential.credential = serde_json::to_string(&credential)?;
+    webauthn_credential.last_used_at = Some(Utc::now().naive_utc());
+    webauthn_credential.save(conn)?;
+
+    Ok((user, webauthn_credential))
+}
+
+/// The user of the passkey used for an assertion, before validating it.
+pub fn find_credential_user(response: &PublicKeyCredential, conn: &DbConn) -> Option<User> {
+    let credential_id = BASE64URL_NOPAD.encode(&response.raw_id.0);
+    let webauthn_credential = WebAuthnCredential::find_by_credential_id(&credential_id, conn)?;
+    User::find_by_uuid(&webauthn_credential.user_uuid, conn)
+}
+
+/// Parses the `deviceResponse` sent to the identity endpoint.
+pub fn parse_device_response(response: &str) -> Result<PublicKeyCredential, Error> {
+    let response: UpCase<PublicKeyCredentialCopy> = serde_json::from_str(response)?;
+    Ok(response.data.into())
+}
+
+/// Marks the login challenge as used, failing if it already was.
+fn take_login_challenge(challenge: &str, expiration: i64) -> EmptyResult {
+    let now = Utc::now().naive_utc().timestamp();
+    let mut used = USED_LOGIN_CHALLENGES.lock().unwrap();
+    used.retain(|_, exp| *exp >= now);
+
+    if used.insert(challenge.to_string(), expiration).is_some() {
+        err!("This login challenge has already been used")
+    }
+    Ok(())
+}
+
+fn start_registration(user: &User, exclude: Vec<CredentialID>) -> Result<(Value, RegistrationState), Error> {
+    let (challenge, state) = WebauthnConfig::load().generate_challenge_register_options(
+        user.uuid.as_bytes().to_vec(),
+        user.email.clone(),
+        user.name.clone(),
+        Some(exclude),
+        Some(UserVerificationPolicy::Required),
+        None,
+    )?;
+
+    // Passkeys have to be discoverable, as the user isn't known yet when logging in with them
+    let mut options = serde_json::to_value(challenge.public_key)?;
+    options["authenticatorSelection"] = json!({
+        "requireResidentKey": true,
+        "residentKey": "required",
+        "userVerification": "required"
+    });
+    Ok((options, state))
+}
+
+fn finish_registration(response: &RegisterPublicKeyCredential, state: &RegistrationState) -> Result<Credential, Error> {
+    let (credential, _data) = WebauthnConfig::load().register_credential(response, state, |_| Ok(false))?;
+    Ok(credential)
+}
+
+/// Returns the options for a login with a discoverable credential and the challenge to validate it.
+fn start_login() -> (Value, String) {
+    let challenge = BASE64URL_NOPAD.encode(&crypto::get_random(vec![0u8; 32]));
+    let options = json!({
+        "challenge": challenge,
+        "timeout": CHALLENGE_TIMEOUT,
+        "rpId": WebauthnConfig::relying_party_id(),
+        "allowCredentials": [],
+        "userVerification": "required"
+    });
+    (options, challenge)
+}
+
+/// Verifies the assertion with the stored credential, and returns it with the updated signature counter.
+fn finish_login(
+    response: &PublicKeyCredential,
+    challenge: &str,
+    credential: Credential,
+    user_uuid: &str,
+) -> Result<Credential, Error> {
+    match response.response.user_handle {
+        Some(ref user_handle) if user_handle.0 == user_uuid.as_bytes() => (),
+        _ => err!("The passkey doesn't belong to this user"),
+    }
+
+    let webauthn = WebauthnConfig::load();
+    let (_, state) = webauthn.generate_challenge_authenticate_options(vec![credential.clone()], None)?;
+
+    // Replace the newly generated challenge with the one sent to the client. There was no credential to
+    // generate the state with at that point, as the authenticator picks the credential on its own.
+    let mut state = serde_json::to_value(state)?;
+    if state.get("challenge").is_none() {
+        err!("Unexpected WebAuthn authentication state")
+    }
+    let challenge = BASE64URL_NOPAD.decode(challenge.as_bytes()).map_err(|_| Error::new("Invalid challenge", ""))?;
+    state["challenge"] = serde_json::to_value(Base64UrlSafeData(challenge))?;
+    state["policy"] = serde_json::to_value(UserVerificationPolicy::Required)?;
+
+    let (_, auth_data) = webauthn.authenticate_credential(response, &serde_json::from_value(state)?)?;
+
+    let mut credential = credential;
+    credential.counter = auth_data.counter;
+    Ok(credential)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        bn::BigNumContext,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::{PKey, Private},
+        sha::sha256,
+        sign::Signer,
+    };
+    use webauthn_rs::proto::{AuthenticatorAssertionResponseRaw, AuthenticatorAttestationResponseRaw};
+
+    /// A software authenticator with a single discoverable P-256 credential.
+    struct SoftAuthenticator {
+        key: PKey<Private>,
+        credential_id: Vec<u8>,
+        user_handle: Vec<u8>,
+        counter: u32,
+    }
+
+    fn cbor_header(major: u8, len: usize) -> Vec<u8> {
+        match len {
+            0..=23 => vec![major << 5 | len as u8],
+            24..=255 => vec![major << 5 | 24, len as u8],
+            _ => vec![major << 5 | 25, (len >> 8) as u8, len as u8],
+        }
+    }
+
+    fn cbor_bytes(data: &[u8]) -> Vec<u8> {
+        let mut encoded = cbor_header(2, data.len());
+        encoded.extend_from_slice(data);
+        encoded
+    }
+
+    fn cbor_text(text: &str) -> Vec<u8> {
+        let mut encoded = cbor_header(3, text.len());
+        encoded.extend_from_slice(text.as_bytes());
+        encoded
+    }
+
+    impl SoftAuthenticator {
+        fn new(user_handle: &[u8]) -> Self {
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+            Self {
+                key: PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap(),
+                credential_id: crypto::get_random(vec![0u8; 16]),
+                user_handle: user_handle.to_vec(),
+                counter: 0,
+            }
+        }
+
+        fn client_data(&self, type_: &str, challenge: &str) -> Vec<u8> {
+            json!({ "type": type_, "challenge": challenge, "origin": CONFIG.domain() }).to_string().into_bytes()
+        }
+
+        fn auth_data(&mut self, flags: u8) -> Vec<u8> {
+            self.counter += 1;
+            let mut data = sha256(WebauthnConfig::relying_party_id().as_bytes()).to_vec();
+            data.push(flags);
+            data.extend_from_slice(&self.counter.to_be_bytes());
+            data
+        }
+
+        fn cose_key(&self) -> Vec<u8> {
+            let ec_key = self.key.ec_key().unwrap();
+            let mut ctx = BigNumContext::new().unwrap();
+            let (mut x, mut y) = (openssl::bn::BigNum::new().unwrap(), openssl::bn::BigNum::new().unwrap());
+            ec_key.public_key().affine_coordinates_gfp(ec_key.group(), &mut x, &mut y, &mut ctx).unwrap();
+
+            // {1: 2 (EC2), 3: -7 (ES256), -1: 1 (P-256), -2: x, -3: y}
+            let mut key = vec![0xa5, 0x01, 0x02, 0x03, 0x26, 0x20, 0x01, 0x21];
+            key.extend(cbor_bytes(&x.to_vec_padded(32).unwrap()));
+            key.push(0x22);
+            key.extend(cbor_bytes(&y.to_vec_padded(32).unwrap()));
+            key
+        }
+
+        fn register(&mut self, challenge: &str) -> RegisterPublicKeyCredential {
+            // User present, user verified and attested credential data included
+            let mut auth_data = self.auth_data(0x45);
+            auth_data.extend_from_slice(&[0u8; 16]); // AAGUID
+            auth_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            auth_data.extend_from_slice(&self.credential_id);
+            auth_data.extend(self.cose_key());
+
+            let mut attestation_object = vec![0xa3];
+            attestation_object.extend(cbor_text("fmt"));
+            attestation_object.extend(cbor_text("none"));
+            attestation_object.extend(cbor_text("attStmt"));
+            attestation_object.push(0xa0);
+            attestation_object.extend(cbor_text("authData"));
+            attestation_object.extend(cbor_bytes(&auth_data));
+
+            RegisterPublicKeyCredential {
+                id: BASE64URL_NOPAD.encode(&self.credential_id),
+                raw_id: Base64UrlSafeData(self.credential_id.clone()),
+                response: AuthenticatorAttestationResponseRaw {
+                    attestation_object: Base64UrlSafeData(attestation_object),
+                    client_data_json: Base64UrlSafeData(self.client_data("webauthn.create", challenge)),
+                },
+                type_: "public-key".to_string(),
+            }
+        }
+
+        fn assert(&mut self, challenge: &str) -> PublicKeyCredential {
+            // User present and user verified
+            let auth_data = self.auth_data(0x05);
+            let client_data = self.client_data("webauthn.get", challenge);
+
+            let mut signer = Signer::new(MessageDigest::sha256(), &self.key).unwrap();
+            signer.update(&auth_data).unwrap();
+            signer.update(&sha256(&client_data)).unwrap();
+
+            PublicKeyCredential {
+                id: BASE64URL_NOPAD.encode(&self.credential_id),
+                raw_id: Base64UrlSafeData(self.credential_id.clone()),
+                response: AuthenticatorAssertionResponseRaw {
+                    authenticator_data: Base64UrlSafeData(auth_data),
+                    client_data_json: Base64UrlSafeData(client_data),
+                    signature: Base64UrlSafeData(signer.sign_to_vec().unwrap()),
+                    user_handle: Some(Base64UrlSafeData(self.user_handle.clone())),
+                },
+                extensions: None,
+                type_: "public-key".to_string(),
+            }
+        }
+    }
+
+    fn register(user: &User, authenticator: &mut SoftAuthenticator) -> Credential {
+        let (options, state) = start_registration(user, vec![]).unwrap();
+        assert_eq!(options["authenticatorSelection"]["residentKey"], "required");
+
+        let challenge = options["challenge"].as_str().unwrap();
+        finish_registration(&authenticator.register(challenge), &state).unwrap()
+    }
+
+    #[test]
+    fn test_passkey_login() {
+        let user = User::new("passkey@example.com".to_string());
+        let mut authenticator = SoftAuthenticator::new(user.uuid.as_bytes());
+        let credential = register(&user, &mut authenticator);
+        assert_eq!(credential.cred_id, authenticator.credential_id);
+
+        let (options, challenge) = start_login();
+        assert_eq!(options["allowCredentials"], json!([]));
+        let credential = finish_login(&authenticator.assert(&challenge), &challenge, credential, &user.uuid).unwrap();
+        assert_eq!(credential.counter, authenticator.counter);
+
+        // The assertion has to be for the challenge which was sent
+        let (_, other_challenge) = start_login();
+        assert!(
+            finish_login(&authenticator.assert(&other_challenge), &challenge, credential.clone(), &user.uuid).is_err()
+        );
+
+        // And the user handle has to match the owner of the credential
+        let other_user = User::new("other@example.com".to_string());
+        assert!(finish_login(&authenticator.assert(&challenge), &challenge, credential, &other_user.uuid).is_err());
+    }
+
+    #[test]
+    fn test_used_login_challenge() {
+        let (_, challenge) = start_login();
+        let expiration = Utc::now().naive_utc().timestamp() + 60;
+        assert!(take_login_challenge(&challenge, expiration).is_ok());
+        assert!(take_login_challenge(&challenge, expiration).is_err());
+    }
+}
@@ -1,19 +1,28 @@
 use std::{
     collections::HashMap,
-    fs::{create_dir_all, remove_file, symlink_metadata, File},
+    fs::{create_dir_all, read_dir, remove_file, symlink_metadata, File},
     io::prelude::*,
-    net::{IpAddr, ToSocketAddrs},
-    sync::{Arc, RwLock},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
     time::{Duration, SystemTime},
 };
 
 use once_cell::sync::Lazy;
 use regex::Regex;
-use reqwest::{blocking::Client, blocking::Response, header};
-use rocket::{http::ContentType, response::Content, Route};
+use reqwest::header;
+use rocket::{
+    http::ContentType,
+    response::{self, Content, Redirect, Responder},
+    Request, Route,
+};
 
 use crate::{
     error::Error,
+    http_client::{HttpClient, HttpResponse},
     util::{get_reqwest_client_builder, Cached},
     CONFIG,
 };
@@ -22,7 +31,7 @@ pub fn routes() -> Vec<Route> {
     routes![icon]
 }
 
-static CLIENT: Lazy<Client> = Lazy::new(|| {
+static CLIENT: Lazy<HttpClient> = Lazy::new(|| {
     // Generate the default headers
     let mut default_headers = header::HeaderMap::new();
     default_headers
@@ -33,13 +42,16 @@ static CLIENT: Lazy<Client> = Lazy::new(|| {
     default_headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("no-cache"));
     default_headers.insert(header::PRAGMA, header::HeaderValue::from_static("no-cache"));
 
-    // Reuse the client between requests
-    get_reqwest_client_builder()
-        .cookie_provider(Arc::new(Jar::default()))
-        .timeout(Duration::from_secs(CONFIG.icon_download_timeout()))
-        .default_headers(default_headers)
-        .build()
-        .expect("Failed to build icon client")
+    // Share the cookies between requests
+    let cookie_store = Arc::new(Jar::default());
+    HttpClient::new(move || {
+        get_reqwest_client_builder()
+            .cookie_provider(cookie_store.clone())
+            .timeout(Duration::from_secs(CONFIG.icon_download_timeout()))
+            .default_headers(default_headers.clone())
+    })
+    .block_non_global_ips(|| CONFIG.icon_blacklist_non_global_ips())
+    .block_hosts(is_domain_blacklisted)
 });
 
 // Build Regex only once since this takes a lot of time.
@@ -47,29 +59,86 @@ static ICON_REL_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)icon$|apple.*
 static ICON_REL_BLACKLIST: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)mask-icon").unwrap());
 static ICON_SIZE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?x)(\d+)\D*(\d+)").unwrap());
 
+// The icons are shown at 16-19px by the clients, at up to twice that on HiDPI screens.
+const PREFERRED_ICON_SIZE: u16 = 32;
+
 // Special HashMap which holds the user defined Regex to speedup matching the regex.
 static ICON_BLACKLIST_REGEX: Lazy<RwLock<HashMap<String, Regex>>> = Lazy::new(|| RwLock::new(HashMap::new()));
 
+// Last access time of the cached icons by file name, so the least recently used ones are evicted first.
+// Entries which weren't accessed since the server started use the access time of their file instead, which
+// most filesystems update at least daily, or their modification time.
+static ICON_ACCESS: Lazy<RwLock<HashMap<String, SystemTime>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+// Size of the icon cache in bytes, computed from the folder the first time it's needed.
+static ICON_CACHE_SIZE: Lazy<AtomicU64> =
+    Lazy::new(|| AtomicU64::new(list_cache_files().iter().map(|file| file.size).sum()));
+// Held while removing files from the icon cache, so the cleanups don't run concurrently.
+static ICON_CACHE_CLEANUP: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+// Whether the icon service may be asked for a domain, by domain, with the time it was checked. Checking
+// it resolves the domain, which would otherwise block every redirected icon request.
+static ICON_HOST_ALLOWED: Lazy<RwLock<HashMap<String, (bool, SystemTime)>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+const HOST_ALLOWED_TTL: Duration = Duration::from_secs(10 * 60);
+const MAX_HOST_ALLOWED_ENTRIES: usize = 10_000;
+
+enum IconResponse {
+    Icon(Cached<Content<Vec<u8>>>),
+    Redirect(Cached<Redirect>),
+}
+
+impl<'r> Responder<'r> for IconResponse {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        match self {
+            Self::Icon(icon) => icon.respond_to(req),
+            Self::Redirect(redirect) => redirect.respond_to(req),
+        }
+    }
+}
+
 #[get("/<domain>/icon.png")]
-fn icon(domain: String) -> Cached<Content<Vec<u8>>> {
+fn icon(domain: String) -> IconResponse {
     const FALLBACK_ICON: &[u8] = include_bytes!("../static/images/fallback-icon.png");
+    let fallback = || {
+        IconResponse::Icon(Cached::ttl(
+            Content(ContentType::new("image", "png"), FALLBACK_ICON.to_vec()),
+            CONFIG.icon_cache_negttl(),
+        ))
+    };
 
     if !is_valid_domain(&domain) {
         warn!("Invalid domain: {}", domain);
-        return Cached::ttl(
-            Content(ContentType::new("image", "png"), FALLBACK_ICON.to_vec()),
-            CONFIG.icon_cache_negttl(),
-        );
+        return fallback();
     }
 
-    match get_icon(&domain) {
-        Some((icon, icon_type)) => {
-            Cached::ttl(Content(ContentType::new("image", icon_type), icon), CONFIG.icon_cache_ttl())
+    // Send the clients straight to the external icon service, unless we proxy it
+    let service_url = CONFIG._icon_service_url();
+    if !service_url.is_empty() && !CONFIG.icon_service_proxy() {
+        // Don't leak the internal domains to the icon service either
+        if is_domain_blacklisted(&domain) || !is_host_allowed_cached(&domain) {
+            return fallback();
         }
-        _ => Cached::ttl(Content(ContentType::new("image", "png"), FALLBACK_ICON.to_vec()), CONFIG.icon_cache_negttl()),
+        return icon_redirect(service_url.replace("{}", &domain));
+    }
+
+    match get_icon(&domain) {
+        Some((icon, icon_type)) => IconResponse::Icon(Cached::ttl(
+            Content(ContentType::new("image", icon_type), icon),
+            CONFIG.icon_cache_ttl(),
+        )),
+        _ => fallback(),
     }
 }
 
+fn icon_redirect(url: String) -> IconResponse {
+    let redirect = match CONFIG.icon_redirect_code() {
+        301 => Redirect::moved(url),
+        307 => Redirect::temporary(url),
+        308 => Redirect::permanent(url),
+        _ => Redirect::found(url),
+    };
+    IconResponse::Redirect(Cached::ttl(redirect, CONFIG.icon_cache_ttl()))
+}
+
 /// Returns if the domain provided is valid or not.
 ///
 /// This does some manual checks and makes use of Url to do some basic checking.
@@ -107,152 +176,63 @@ fn is_valid_domain(domain: &str) -> bool {
     true
 }
 
-/// TODO: This is extracted from IpAddr::is_global, which is unstable:
-/// https://doc.rust-lang.org/nightly/std/net/enum.IpAddr.html#method.is_global
-/// Remove once https://github.com/rust-lang/rust/issues/27709 is merged
-#[allow(clippy::nonminimal_bool)]
-#[cfg(not(feature = "unstable"))]
-fn is_global(ip: IpAddr) -> bool {
-    match ip {
-        IpAddr::V4(ip) => {
-            // check if this address is 192.0.0.9 or 192.0.0.10. These addresses are the only two
-            // globally routable addresses in the 192.0.0.0/24 range.
-            if u32::from(ip) == 0xc0000009 || u32::from(ip) == 0xc000000a {
-                return true;
-            }
-            !ip.is_private()
-            && !ip.is_loopback()
-            && !ip.is_link_local()
-            && !ip.is_broadcast()
-            && !ip.is_documentation()
-            && !(ip.octets()[0] == 100 && (ip.octets()[1] & 0b1100_0000 == 0b0100_0000))
-            && !(ip.octets()[0] == 192 && ip.octets()[1] == 0 && ip.octets()[2] == 0)
-            && !(ip.octets()[0] & 240 == 240 && !ip.is_broadcast())
-            && !(ip.octets()[0] == 198 && (ip.octets()[1] & 0xfe) == 18)
-            // Make sure the address is not in 0.0.0.0/8
-            && ip.octets()[0] != 0
-        }
-        IpAddr::V6(ip) => {
-            if ip.is_multicast() && ip.segments()[0] & 0x000f == 14 {
-                true
-            } else {
-                !ip.is_multicast()
-                    && !ip.is_loopback()
-                    && !((ip.segments()[0] & 0xffc0) == 0xfe80)
-                    && !((ip.segments()[0] & 0xfe00) == 0xfc00)
-                    && !ip.is_unspecified()
-                    && !((ip.segments()[0] == 0x2001) && (ip.segments()[1] == 0xdb8))
-            }
-        }
-    }
-}
-
-#[cfg(feature = "unstable")]
-fn is_global(ip: IpAddr) -> bool {
-    ip.is_global()
-}
+/// Returns if the HTTP client would allow requests to the domain, reusing the result of a recent check.
+fn is_host_allowed_cached(domain: &str) -> bool {
+    let now = SystemTime::now();
+    let is_fresh = |checked: &SystemTime| now.duration_since(*checked).map_or(true, |age| age < HOST_ALLOWED_TTL);
 
-/// These are some tests to check that the implementations match
-/// The IPv4 can be all checked in 5 mins or so and they are correct as of nightly 2020-07-11
-/// The IPV6 can't be checked in a reasonable time, so we check  about ten billion random ones, so far correct
-/// Note that the is_global implementation is subject to change as new IP RFCs are created
-///
-/// To run while showing progress output:
-/// cargo test --features sqlite,unstable -- --nocapture --ignored
-#[cfg(test)]
-#[cfg(feature = "unstable")]
-mod tests {
-    use super::*;
-
-    #[test]
-    #[ignore]
-    fn test_ipv4_global() {
-        for a in 0..u8::MAX {
-            println!("Iter: {}/255", a);
-            for b in 0..u8::MAX {
-                for c in 0..u8::MAX {
-                    for d in 0..u8::MAX {
-                        let ip = IpAddr::V4(std::net::Ipv4Addr::new(a, b, c, d));
-                        assert_eq!(ip.is_global(), is_global(ip))
-                    }
-                }
-            }
+    if let Some((allowed, checked)) = ICON_HOST_ALLOWED.read().unwrap().get(domain) {
+        if is_fresh(checked) {
+            return *allowed;
         }
     }
 
-    #[test]
-    #[ignore]
-    fn test_ipv6_global() {
-        use ring::rand::{SecureRandom, SystemRandom};
-        let mut v = [0u8; 16];
-        let rand = SystemRandom::new();
-        for i in 0..1_000 {
-            println!("Iter: {}/1_000", i);
-            for _ in 0..10_000_000 {
-                rand.fill(&mut v).expect("Error generating random values");
-                let ip = IpAddr::V6(std::net::Ipv6Addr::new(
-                    (v[14] as u16) << 8 | v[15] as u16,
-                    (v[12] as u16) << 8 | v[13] as u16,
-                    (v[10] as u16) << 8 | v[11] as u16,
-                    (v[8] as u16) << 8 | v[9] as u16,
-                    (v[6] as u16) << 8 | v[7] as u16,
-                    (v[4] as u16) << 8 | v[5] as u16,
-                    (v[2] as u16) << 8 | v[3] as u16,
-                    (v[0] as u16) << 8 | v[1] as u16,
-                ));
-                assert_eq!(ip.is_global(), is_global(ip))
-            }
+    let allowed = CLIENT.is_host_allowed(domain);
+    let mut hosts = ICON_HOST_ALLOWED.write().unwrap();
+    if hosts.len() >= MAX_HOST_ALLOWED_ENTRIES {
+        hosts.retain(|_, (_, checked)| is_fresh(checked));
+        if hosts.len() >= MAX_HOST_ALLOWED_ENTRIES {
+            hosts.clear();
         }
     }
+    hosts.insert(domain.to_string(), (allowed, now));
+    allowed
 }
 
+/// Returns if the domain matches `ICON_BLACKLIST_REGEX`.
+/// The IPs the domains resolve to are checked by the HTTP client, right before connecting.
 fn is_domain_blacklisted(domain: &str) -> bool {
-    let mut is_blacklisted = CONFIG.icon_blacklist_non_global_ips()
-        && (domain, 0)
-            .to_socket_addrs()
-            .map(|x| {
-                for ip_port in x {
-                    if !is_global(ip_port.ip()) {
-                        warn!("IP {} for domain '{}' is not a global IP!", ip_port.ip(), domain);
-                        return true;
-                    }
-                }
-                false
-            })
-            .unwrap_or(false);
+    let mut is_blacklisted = false;
 
-    // Skip the regex check if the previous one is true already
-    if !is_blacklisted {
-        if let Some(blacklist) = CONFIG.icon_blacklist_regex() {
-            let mut regex_hashmap = ICON_BLACKLIST_REGEX.read().unwrap();
+    if let Some(blacklist) = CONFIG.icon_blacklist_regex() {
+        let mut regex_hashmap = ICON_BLACKLIST_REGEX.read().unwrap();
 
-            // Use the pre-generate Regex stored in a Lazy HashMap if there's one, else generate it.
-            let regex = if let Some(regex) = regex_hashmap.get(&blacklist) {
-                regex
-            } else {
-                drop(regex_hashmap);
+        // Use the pre-generate Regex stored in a Lazy HashMap if there's one, else generate it.
+        let regex = if let Some(regex) = regex_hashmap.get(&blacklist) {
+            regex
+        } else {
+            drop(regex_hashmap);
 
-                let mut regex_hashmap_write = ICON_BLACKLIST_REGEX.write().unwrap();
-                // Clear the current list if the previous key doesn't exists.
-                // To prevent growing of the HashMap after someone has changed it via the admin interface.
-                if regex_hashmap_write.len() >= 1 {
-                    regex_hashmap_write.clear();
-                }
+            let mut regex_hashmap_write = ICON_BLACKLIST_REGEX.write().unwrap();
+            // Clear the current list if the previous key doesn't exists.
+            // To prevent growing of the HashMap after someone has changed it via the admin interface.
+            if regex_hashmap_write.len() >= 1 {
+                regex_hashmap_write.clear();
+            }
 
-                // Generate the regex to store in too the Lazy Static HashMap.
-                let blacklist_regex = Regex::new(&blacklist).unwrap();
-                regex_hashmap_write.insert(blacklist.to_string(), blacklist_regex);
-                drop(regex_hashmap_write);
+            // Generate the regex to store in too the Lazy Static HashMap.
+            let blacklist_regex = Regex::new(&blacklist).unwrap();
+            regex_hashmap_write.insert(blacklist.to_string(), blacklist_regex);
+            drop(regex_hashmap_write);
 
-                regex_hashmap = ICON_BLACKLIST_REGEX.read().unwrap();
-                regex_hashmap.get(&blacklist).unwrap()
-            };
+            regex_hashmap = ICON_BLACKLIST_REGEX.read().unwrap();
+            regex_hashmap.get(&blacklist).unwrap()
+        };
 
-            // Use the pre-generate Regex stored in a Lazy HashMap.
-            if regex.is_match(domain) {
-                warn!("Blacklisted domain: {:#?} matched {:#?}", domain, blacklist);
-                is_blacklisted = true;
-            }
+        // Use the pre-generate Regex stored in a Lazy HashMap.
+        if regex.is_match(domain) {
+            warn!("Blacklisted domain: {:#?} matched {:#?}", domain, blacklist);
+            is_blacklisted = true;
         }
     }
 
@@ -268,6 +248,7 @@ fn get_icon(domain: &str) -> Option<(Vec<u8>, String)> {
     }
 
     if let Some(icon) = get_cached_icon(&path) {
+        touch_cached_file(&path);
         let icon_type = match get_icon_type(&icon) {
             Some(x) => x,
             _ => "x-icon",
@@ -280,10 +261,16 @@ fn get_icon(domain: &str) -> Option<(Vec<u8>, String)> {
     }
 
     // Get the icon, or None in case of error
-    match download_icon(domain) {
+    let result = if CONFIG._icon_service_url().is_empty() {
+        download_icon(domain)
+    } else {
+        download_service_icon(domain)
+    };
+
+    match result {
         Ok((icon, icon_type)) => {
             save_icon(&path, &icon);
-            Some((icon, icon_type.unwrap_or("x-icon").to_string()))
+            Some((icon, icon_type.to_string()))
         }
         Err(e) => {
             error!("Error downloading icon: {:?}", e);
@@ -344,6 +331,203 @@ fn icon_is_expired(path: &str) -> bool {
     expired.unwrap_or(true)
 }
 
+struct CachedFile {
+    path: PathBuf,
+    name: String,
+    size: u64,
+    modified: SystemTime,
+    accessed: SystemTime,
+}
+
+impl CachedFile {
+    fn is_miss(&self) -> bool {
+        self.name.ends_with(".miss")
+    }
+
+    fn last_access(&self, access: &HashMap<String, SystemTime>) -> SystemTime {
+        let recorded = self.accessed.max(self.modified);
+        access.get(&self.name).map_or(recorded, |accessed| recorded.max(*accessed))
+    }
+}
+
+fn list_cache_files() -> Vec<CachedFile> {
+    let entries = match read_dir(CONFIG.icon_cache_folder()) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let meta = entry.metadata().ok()?;
+            if !meta.is_file() {
+                return None;
+            }
+
+            let modified = meta.modified().ok()?;
+            Some(CachedFile {
+                path: entry.path(),
+                name: entry.file_name().to_string_lossy().into_owned(),
+                size: meta.len(),
+                modified,
+                accessed: meta.accessed().unwrap_or(modified),
+            })
+        })
+        .collect()
+}
+
+fn touch_cached_file(path: &str) {
+    if let Some(name) = Path::new(path).file_name() {
+        ICON_ACCESS.write().unwrap().insert(name.to_string_lossy().into_owned(), SystemTime::now());
+    }
+}
+
+fn remove_cached_file(file: &CachedFile) -> bool {
+    if let Err(e) = remove_file(&file.path) {
+        warn!("Could not remove {:?} from the icon cache: {:?}", file.path, e);
+        return false;
+    }
+
+    ICON_ACCESS.write().unwrap().remove(&file.name);
+    ICON_CACHE_SIZE
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |size| Some(size.saturating_sub(file.size)))
+        .ok();
+    true
+}
+
+/// Max size of the icon cache in bytes, or 0 if it's unlimited.
+fn icon_cache_max_size() -> u64 {
+    CONFIG.icon_cache_max_size().saturating_mul(1024 * 1024)
+}
+
+/// Evicts the least recently used entries in the background when the icon cache has grown past its max size.
+/// The folder is only scanned then, the size of the cache is kept up to date when saving and removing icons.
+fn enforce_icon_cache_size() {
+    let max_size = icon_cache_max_size();
+    if max_size == 0 || ICON_CACHE_SIZE.load(Ordering::Relaxed) <= max_size {
+        return;
+    }
+
+    let spawned = std::thread::Builder::new().name("icon-cache-eviction".into()).spawn(move || {
+        // Another thread is already cleaning up
+        if let Ok(_cleanup) = ICON_CACHE_CLEANUP.try_lock() {
+            let removed = evict_icons(max_size);
+            debug!("Evicted {} entries from the icon cache", removed);
+        }
+    });
+    if let Err(e) = spawned {
+        error!("Error spawning the icon cache eviction thread: {:?}", e);
+    }
+}
+
+/// Removes the least recently used entries until the icon cache is below 90% of `max_size`,
+/// so that it doesn't have to be done again on the next download. Returns the number of removed files.
+fn evict_icons(max_size: u64) -> usize {
+    let files = list_cache_files();
+    let size: u64 = files.iter().map(|file| file.size).sum();
+    ICON_CACHE_SIZE.store(size, Ordering::Relaxed);
+
+    let evicted = files_to_evict(files, &ICON_ACCESS.read().unwrap(), size, max_size);
+    evicted.iter().filter(|file| remove_cached_file(file)).count()
+}
+
+/// The least recently used files to remove, in that order, to bring a cache of `size` bytes below 90% of `max_size`.
+fn files_to_evict(
+    mut files: Vec<CachedFile>,
+    access: &HashMap<String, SystemTime>,
+    size: u64,
+    max_size: u64,
+) -> Vec<CachedFile> {
+    if size <= max_size {
+        return Vec::new();
+    }
+
+    files.sort_by_key(|file| file.last_access(access));
+    let target = max_size - max_size / 10;
+    let mut size = size;
+    files
+        .into_iter()
+        .take_while(|file| {
+            let keep_going = size > target;
+            size = size.saturating_sub(file.size);
+            keep_going
+        })
+        .collect()
+}
+
+/// Removes the expired icons and negative cache entries, then evicts the least recently used icons
+/// if the cache is still larger than `ICON_CACHE_MAX_SIZE`.
+pub fn purge_icon_cache() {
+    debug!("Purging the icon cache");
+    let _cleanup = ICON_CACHE_CLEANUP.lock().unwrap();
+
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for file in list_cache_files() {
+        let ttl = if file.is_miss() {
+            CONFIG.icon_cache_negttl()
+        } else {
+            CONFIG.icon_cache_ttl()
+        };
+        let expired = ttl > 0 && now.duration_since(file.modified).map_or(false, |age| age.as_secs() >= ttl);
+        if expired && remove_cached_file(&file) {
+            removed += 1;
+        }
+    }
+
+    let max_size = icon_cache_max_size();
+    if max_size > 0 {
+        removed += evict_icons(max_size);
+    }
+
+    if removed > 0 {
+        info!("Removed {} expired or least recently used entries from the icon cache", removed);
+    }
+}
+
+/// Removes the cached icon of a domain, or all of them when no domain is given.
+/// Returns the number of removed files.
+pub fn purge_cached_icons(domain: Option<&str>) -> Result<usize, Error> {
+    let files = list_cache_files();
+    let files: Vec<CachedFile> = match domain {
+        Some(domain) => {
+            let domain = domain.trim();
+            if !is_valid_domain(domain) {
+                err!("Invalid domain", domain)
+            }
+            let icon = format!("{}.png", domain);
+            let miss = format!("{}.miss", icon);
+            files.into_iter().filter(|file| file.name == icon || file.name == miss).collect()
+        }
+        None => files,
+    };
+
+    let _cleanup = ICON_CACHE_CLEANUP.lock().unwrap();
+    Ok(files.iter().filter(|file| remove_cached_file(file)).count())
+}
+
+pub struct IconCacheStats {
+    pub icons: usize,
+    pub misses: usize,
+    pub size: u64,
+    pub max_size: u64,
+    pub oldest_access: Option<SystemTime>,
+}
+
+pub fn icon_cache_stats() -> IconCacheStats {
+    let files = list_cache_files();
+    let access = ICON_ACCESS.read().unwrap();
+    let misses = files.iter().filter(|file| file.is_miss()).count();
+
+    IconCacheStats {
+        icons: files.len() - misses,
+        misses,
+        size: files.iter().map(|file| file.size).sum(),
+        max_size: icon_cache_max_size(),
+        oldest_access: files.iter().map(|file| file.last_access(&access)).min(),
+    }
+}
+
 struct Icon {
     priority: u8,
     href: String,
@@ -413,6 +597,7 @@ fn get_favicons_node(node: &std::rc::Rc<markup5ever_rcdom::Node>, icons: &mut Ve
             let mut has_rel = false;
             let mut href = None;
             let mut sizes = None;
+            let mut mime_type = None;
 
             let attrs = attrs.borrow();
             for attr in attrs.iter() {
@@ -426,13 +611,15 @@ fn get_favicons_node(node: &std::rc::Rc<markup5ever_rcdom::Node>, icons: &mut Ve
                     href = Some(attr_value);
                 } else if attr_name == "sizes" {
                     sizes = Some(attr_value);
+                } else if attr_name == "type" {
+                    mime_type = Some(attr_value);
                 }
             }
 
             if has_rel {
                 if let Some(inner_href) = href {
                     if let Ok(full_href) = url.join(inner_href).map(String::from) {
-                        let priority = get_icon_priority(&full_href, sizes);
+                        let priority = get_icon_priority(&full_href, sizes, mime_type);
                         icons.push(Icon::new(priority, full_href));
                     }
                 }
@@ -549,68 +736,95 @@ fn get_icon_url(domain: &str) -> Result<IconUrlResult, Error> {
     })
 }
 
-fn get_page(url: &str) -> Result<Response, Error> {
+fn get_page(url: &str) -> Result<HttpResponse, Error> {
     get_page_with_referer(url, "")
 }
 
-fn get_page_with_referer(url: &str, referer: &str) -> Result<Response, Error> {
+fn get_page_with_referer(url: &str, referer: &str) -> Result<HttpResponse, Error> {
     if is_domain_blacklisted(url::Url::parse(url).unwrap().host_str().unwrap_or_default()) {
         err!("Favicon rel linked to a blacklisted domain!");
     }
 
-    let mut client = CLIENT.get(url);
-    if !referer.is_empty() {
-        client = client.header("Referer", referer)
-    }
-
-    client.send()?.error_for_status().map_err(Into::into)
+    CLIENT
+        .send(reqwest::Method::GET, url, |request| {
+            if referer.is_empty() {
+                request
+            } else {
+                request.header("Referer", referer)
+            }
+        })?
+        .error_for_status()
 }
 
 /// Returns a Integer with the priority of the type of the icon which to prefer.
 /// The lower the number the better.
 ///
+/// Icons with a known size are ranked by how close they are to `PREFERRED_ICON_SIZE`, preferring larger icons
+/// which can be scaled down over smaller ones. Scalable (SVG) icons are ranked right after the ones of the exact size.
+///
 /// # Arguments
-/// * `href`  - A string which holds the href value or relative path.
-/// * `sizes` - The size of the icon if available as a <width>x<height> value like 32x32.
+/// * `href`      - A string which holds the href value or relative path.
+/// * `sizes`     - The sizes of the icon if available as space separated <width>x<height> values like "16x16 32x32", or "any".
+/// * `mime_type` - The MIME type of the icon if available, like image/png.
 ///
 /// # Example
 /// ```
-/// priority1 = get_icon_priority("http://example.com/path/to/a/favicon.png", "32x32");
-/// priority2 = get_icon_priority("https://example.com/path/to/a/favicon.ico", "");
+/// priority1 = get_icon_priority("http://example.com/path/to/a/favicon.png", Some("32x32"), None);
+/// priority2 = get_icon_priority("https://example.com/path/to/a/favicon.ico", None, None);
 /// ```
-fn get_icon_priority(href: &str, sizes: Option<&str>) -> u8 {
-    // Check if there is a dimension set
-    let (width, height) = parse_sizes(sizes);
-
-    // Check if there is a size given
-    if width != 0 && height != 0 {
-        // Only allow square dimensions
-        if width == height {
-            // Change priority by given size
-            if width == 32 {
-                1
-            } else if width == 64 {
-                2
-            } else if (24..=192).contains(&width) {
-                3
-            } else if width == 16 {
-                4
-            } else {
+fn get_icon_priority(href: &str, sizes: Option<&str>, mime_type: Option<&str>) -> u8 {
+    let href = href.to_lowercase();
+    let is_svg = mime_type.map_or(false, |t| t.eq_ignore_ascii_case("image/svg+xml"))
+        || href.ends_with(".svg")
+        || href.starts_with("data:image/svg+xml");
+
+    // An icon can be offered in several sizes, rank it by the best one
+    let size_priority = sizes.and_then(|sizes| {
+        sizes
+            .split_whitespace()
+            .map(|size| {
+                if size.eq_ignore_ascii_case("any") {
+                    return 2;
+                }
+                match parse_sizes(Some(size)) {
+                    (0, _) | (_, 0) => 30,
+                    // Only allow square dimensions
+                    (width, height) if width != height => 200,
+                    (width, _) => get_size_priority(width),
+                }
+            })
+            .min()
+    });
+
+    match size_priority {
+        Some(priority) if priority < 30 => priority,
+        // There are dimensions available, but the image is not a square
+        Some(200) => 200,
+        // Change priority by file type
+        _ => {
+            if is_svg {
                 5
+            } else if href.ends_with(".png") {
+                10
+            } else if href.ends_with(".jpg") || href.ends_with(".jpeg") {
+                20
+            } else {
+                30
             }
-        // There are dimensions available, but the image is not a square
-        } else {
-            200
         }
+    }
+}
+
+/// Returns the priority of a square icon of the given width, between 1 (the preferred size) and 29.
+fn get_size_priority(width: u16) -> u8 {
+    if width == PREFERRED_ICON_SIZE {
+        1
+    } else if width > PREFERRED_ICON_SIZE {
+        // 64 => 3, 96 => 4, 192 => 7, 512 => 17
+        (2 + (width - PREFERRED_ICON_SIZE) / 32).min(29) as u8
     } else {
-        // Change priority by file extension
-        if href.ends_with(".png") {
-            10
-        } else if href.ends_with(".jpg") || href.ends_with(".jpeg") {
-            20
-        } else {
-            30
-        }
+        // 24 => 12, 16 => 14
+        (10 + (PREFERRED_ICON_SIZE - width) / 4).min(29) as u8
     }
 }
 
@@ -645,16 +859,13 @@ fn parse_sizes(sizes: Option<&str>) -> (u16, u16) {
     (width, height)
 }
 
-fn download_icon(domain: &str) -> Result<(Vec<u8>, Option<&str>), Error> {
+fn download_icon(domain: &str) -> Result<(Vec<u8>, &'static str), Error> {
     if is_domain_blacklisted(domain) {
         err!("Domain is blacklisted", domain)
     }
 
     let icon_result = get_icon_url(domain)?;
 
-    let mut buffer = Vec::new();
-    let mut icon_type: Option<&str> = None;
-
     use data_url::DataUrl;
 
     for icon in icon_result.iconlist.iter().take(5) {
@@ -666,48 +877,72 @@ fn download_icon(domain: &str) -> Result<(Vec<u8>, Option<&str>), Error> {
                     // Also check if the size is atleast 67 bytes, which seems to be the smallest png i could create
                     if body.len() >= 67 {
                         // Check if the icon type is allowed, else try an icon from the list.
-                        icon_type = get_icon_type(&body);
-                        if icon_type.is_none() {
-                            debug!("Icon from {} data:image uri, is not a valid image type", domain);
-                            continue;
+                        match check_icon(body) {
+                            Some(checked) => {
+                                info!("Extracted icon from data:image uri for {}", domain);
+                                return Ok(checked);
+                            }
+                            None => debug!("Icon from {} data:image uri, is not a valid image type", domain),
                         }
-                        info!("Extracted icon from data:image uri for {}", domain);
-                        buffer = body;
-                        break;
                     }
                 }
                 _ => warn!("Extracted icon from data:image uri is invalid"),
             };
         } else {
             match get_page_with_referer(&icon.href, &icon_result.referer) {
-                Ok(mut res) => {
-                    res.copy_to(&mut buffer)?;
+                Ok(res) => {
+                    let buffer = res.bytes()?;
                     // Check if the icon type is allowed, else try an icon from the list.
-                    icon_type = get_icon_type(&buffer);
-                    if icon_type.is_none() {
-                        buffer.clear();
-                        debug!("Icon from {}, is not a valid image type", icon.href);
-                        continue;
+                    match check_icon(buffer) {
+                        Some(checked) => {
+                            info!("Downloaded icon from {}", icon.href);
+                            return Ok(checked);
+                        }
+                        None => debug!("Icon from {}, is not a valid image type", icon.href),
                     }
-                    info!("Downloaded icon from {}", icon.href);
-                    break;
                 }
                 _ => warn!("Download failed for {}", icon.href),
             };
         }
     }
 
-    if buffer.is_empty() {
-        err!("Empty response downloading icon")
+    err!("Empty response downloading icon")
+}
+
+/// Downloads the icon of the domain from the configured external icon service.
+fn download_service_icon(domain: &str) -> Result<(Vec<u8>, &'static str), Error> {
+    if is_domain_blacklisted(domain) || !CLIENT.is_host_allowed(domain) {
+        err!("Domain is blacklisted", domain)
     }
 
-    Ok((buffer, icon_type))
+    // The icon service is set by the admin, an internal one has to be in `HTTP_REQUEST_ALLOW_CIDRS`
+    let url = CONFIG._icon_service_url().replace("{}", domain);
+    let buffer = CLIENT.get(&url)?.error_for_status()?.bytes()?;
+    match check_icon(buffer) {
+        Some(icon) => {
+            info!("Downloaded icon for {} from the icon service", domain);
+            Ok(icon)
+        }
+        None => err!("The icon service returned an invalid image", url),
+    }
 }
 
 fn save_icon(path: &str, icon: &[u8]) {
+    // Compute the size of the cache without this file, and account for it once written.
+    // It might replace an expired one, which doesn't count anymore.
+    Lazy::force(&ICON_CACHE_SIZE);
+    let previous_size = symlink_metadata(path).map_or(0, |meta| meta.len());
+
     match File::create(path) {
         Ok(mut f) => {
             f.write_all(icon).expect("Error writing icon file");
+            touch_cached_file(path);
+            ICON_CACHE_SIZE
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |size| {
+                    Some(size.saturating_sub(previous_size).saturating_add(icon.len() as u64))
+                })
+                .ok();
+            enforce_icon_cache_size();
         }
         Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
             create_dir_all(&CONFIG.icon_cache_folder()).expect("Error creating icon cache");
@@ -726,10 +961,174 @@ fn get_icon_type(bytes: &[u8]) -> Option<&'static str> {
         [255, 216, 255, ..] => Some("jpeg"),
         [71, 73, 70, 56, ..] => Some("gif"),
         [66, 77, ..] => Some("bmp"),
+        _ if is_svg(bytes) => Some("svg+xml"),
         _ => None,
     }
 }
 
+/// Returns the icon and its type if it's an allowed image, with SVG icons sanitized.
+fn check_icon(icon: Vec<u8>) -> Option<(Vec<u8>, &'static str)> {
+    match get_icon_type(&icon)? {
+        "svg+xml" => Some((sanitize_svg(&icon)?, "svg+xml")),
+        icon_type => Some((icon, icon_type)),
+    }
+}
+
+/// SVG has no magic bytes, so look for an `<svg` tag near the start of what looks like an XML document.
+fn is_svg(bytes: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]).to_lowercase();
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+
+    (head.starts_with("<?xml")
+        || head.starts_with("<!--")
+        || head.starts_with("<!doctype svg")
+        || head.starts_with("<svg"))
+        && head.contains("<svg")
+}
+
+const SVG_NAMESPACE: &str = "http://www.w3.org/2000/svg";
+const XLINK_NAMESPACE: &str = "http://www.w3.org/1999/xlink";
+const XML_NAMESPACE: &str = "http://www.w3.org/XML/1998/namespace";
+
+/// Elements kept in sanitized SVG icons, anything else is dropped along with its children.
+/// This notably excludes `script`, `foreignObject`, `style`, `image`, `a` and the animation elements.
+const SVG_ALLOWED_ELEMENTS: &[&str] = &[
+    "svg",
+    "g",
+    "defs",
+    "symbol",
+    "use",
+    "title",
+    "desc",
+    "path",
+    "rect",
+    "circle",
+    "ellipse",
+    "line",
+    "polyline",
+    "polygon",
+    "text",
+    "tspan",
+    "linearGradient",
+    "radialGradient",
+    "stop",
+    "clipPath",
+    "mask",
+    "pattern",
+    "filter",
+    "feBlend",
+    "feColorMatrix",
+    "feComposite",
+    "feFlood",
+    "feGaussianBlur",
+    "feMerge",
+    "feMergeNode",
+    "feOffset",
+];
+
+/// Rebuilds the SVG document keeping only the allowed elements and the attributes which can't run scripts
+/// or load external resources. Returns None if there's no SVG image in the document.
+fn sanitize_svg(bytes: &[u8]) -> Option<Vec<u8>> {
+    use html5ever::tendril::TendrilSink;
+    let dom = html5ever::parse_document(markup5ever_rcdom::RcDom::default(), Default::default())
+        .from_utf8()
+        .read_from(&mut &bytes[..])
+        .ok()?;
+
+    let root = find_svg_root(&dom.document)?;
+    let mut sanitized = String::new();
+    write_svg_node(&root, &mut sanitized, true);
+    Some(sanitized.into_bytes())
+}
+
+fn find_svg_root(node: &std::rc::Rc<markup5ever_rcdom::Node>) -> Option<std::rc::Rc<markup5ever_rcdom::Node>> {
+    if let markup5ever_rcdom::NodeData::Element {
+        name,
+        ..
+    } = &node.data
+    {
+        if name.local.as_ref() == "svg" && name.ns.as_ref() == SVG_NAMESPACE {
+            return Some(node.clone());
+        }
+    }
+
+    node.children.borrow().iter().find_map(find_svg_root)
+}
+
+fn write_svg_node(node: &std::rc::Rc<markup5ever_rcdom::Node>, output: &mut String, is_root: bool) {
+    match &node.data {
+        markup5ever_rcdom::NodeData::Element {
+            name,
+            attrs,
+            ..
+        } => {
+            let element = name.local.as_ref();
+            if name.ns.as_ref() != SVG_NAMESPACE || !SVG_ALLOWED_ELEMENTS.contains(&element) {
+                return;
+            }
+
+            output.push('<');
+            output.push_str(element);
+            if is_root {
+                output.push_str(&format!(r#" xmlns="{}" xmlns:xlink="{}""#, SVG_NAMESPACE, XLINK_NAMESPACE));
+            }
+
+            for attr in attrs.borrow().iter() {
+                let local = attr.name.local.as_ref();
+                if !local.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                    || !is_safe_svg_attribute(local, &attr.value)
+                {
+                    continue;
+                }
+
+                // The namespace declarations are only added to the root
+                let attr_name = match attr.name.ns.as_ref() {
+                    "" => local.to_string(),
+                    XLINK_NAMESPACE => format!("xlink:{}", local),
+                    XML_NAMESPACE => format!("xml:{}", local),
+                    _ => continue,
+                };
+                output.push_str(&format!(r#" {}="{}""#, attr_name, escape_xml(&attr.value)));
+            }
+            output.push('>');
+
+            for child in node.children.borrow().iter() {
+                write_svg_node(child, output, false);
+            }
+
+            output.push_str("</");
+            output.push_str(element);
+            output.push('>');
+        }
+        markup5ever_rcdom::NodeData::Text {
+            contents,
+        } => output.push_str(&escape_xml(&contents.borrow())),
+        _ => {}
+    }
+}
+
+/// Event handlers can run scripts, and links or `url()` references which don't point inside the document
+/// can load external resources.
+fn is_safe_svg_attribute(name: &str, value: &str) -> bool {
+    if name.to_lowercase().starts_with("on") {
+        return false;
+    }
+    if name == "href" {
+        return value.trim_start().starts_with('#');
+    }
+
+    let value: String = value.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_lowercase();
+    const BLOCKED: &[&str] = &["javascript:", "vbscript:", "data:", "@import", "expression("];
+    !BLOCKED.iter().any(|blocked| value.contains(blocked))
+        && value
+            .match_indices("url(")
+            .all(|(idx, _)| value[idx + 4..].trim_start_matches(&['"', '\''][..]).starts_with('#'))
+}
+
+fn escape_xml(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
 /// This is an implementation of the default Cookie Jar from Reqwest and reqwest_cookie_store build by pfernie.
 /// The default cookie jar used by Reqwest keeps all the cookies based upon the Max-Age or Expires which could be a long time.
 /// That could be used for tracking, to prevent this we force the lifespan of the cookies to always be max two minutes.
@@ -775,3 +1174,100 @@ impl reqwest::cookie::CookieStore for Jar {
         header::HeaderValue::from_maybe_shared(Bytes::from(s)).ok()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_icon_priority() {
+        let priority = |href, sizes, mime_type| get_icon_priority(href, sizes, mime_type);
+
+        assert_eq!(priority("https://example.com/icon.png", Some("32x32"), None), 1);
+        assert_eq!(priority("https://example.com/icon.png", Some("16x16 32x32 48x48"), None), 1);
+        assert_eq!(priority("https://example.com/icon.svg", Some("any"), Some("image/svg+xml")), 2);
+        assert_eq!(priority("https://example.com/icon.png", Some("64x64"), None), 3);
+        assert_eq!(priority("https://example.com/icon.svg", None, None), 5);
+        assert_eq!(priority("https://example.com/icon.png", None, None), 10);
+        assert_eq!(priority("https://example.com/icon.png", Some("16x16"), None), 14);
+        assert_eq!(priority("https://example.com/icon.png", Some("32x16"), None), 200);
+
+        assert!(
+            priority("https://example.com/a.png", Some("192x192"), None)
+                < priority("https://example.com/b.png", Some("16x16"), None)
+        );
+    }
+
+    #[test]
+    fn test_icon_type() {
+        assert_eq!(get_icon_type(&[137, 80, 78, 71, 13, 10, 26, 10]), Some("png"));
+        assert_eq!(
+            get_icon_type(b"<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\"/>"),
+            Some("svg+xml")
+        );
+        assert_eq!(get_icon_type(b"\n  <svg viewBox=\"0 0 16 16\"></svg>"), Some("svg+xml"));
+        assert_eq!(get_icon_type(b"<!DOCTYPE html><html><body><svg></svg></body></html>"), None);
+    }
+
+    #[test]
+    fn test_sanitize_svg() {
+        let svg = br##"<?xml version="1.0" encoding="UTF-8"?>
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" viewBox="0 0 16 16" onload="alert(1)">
+<script>alert(2)</script><foreignObject><p>Hi</p></foreignObject>
+<defs><linearGradient id="a"><stop offset="0" stop-color="#fff"/></linearGradient></defs>
+<use xlink:href="#a"/><use xlink:href="https://example.com/sprite.svg#a"/>
+<rect fill="url(#a)" width="16" height="16"/><path style="fill: url('https://example.com/x')" d="M0 0h16"/>
+</svg>"##;
+        let sanitized = String::from_utf8(sanitize_svg(svg).unwrap()).unwrap();
+
+        assert!(sanitized.starts_with(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" viewBox="0 0 16 16">"#
+        ));
+        assert!(
+            sanitized
+                .contains(r##"<linearGradient id="a"><stop offset="0" stop-color="#fff"></stop></linearGradient>"##)
+        );
+        assert!(sanitized.contains(r##"<use xlink:href="#a"></use><use></use>"##));
+        assert!(
+            sanitized.contains(r##"<rect fill="url(#a)" width="16" height="16"></rect><path d="M0 0h16"></path>"##)
+        );
+        assert!(!sanitized.contains("alert"));
+        assert!(!sanitized.contains("foreignObject"));
+        assert!(!sanitized.contains("Hi"));
+
+        assert!(sanitize_svg(b"<html><body>No image</body></html>").is_none());
+    }
+
+    #[test]
+    fn test_files_to_evict() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let file = |name: &str, modified: u64, accessed: u64| CachedFile {
+            path: PathBuf::from(name),
+            name: String::from(name),
+            size: 100,
+            modified: start + Duration::from_secs(modified),
+            accessed: start + Duration::from_secs(accessed),
+        };
+        let files = || {
+            vec![
+                file("a.png", 10, 10),
+                file("b.png", 0, 0),
+                file("c.png", 20, 20),
+                file("d.png", 30, 30),
+                // Read after the restart, its file access time was updated
+                file("e.png", 5, 40),
+            ]
+        };
+
+        // Accessed since the server started
+        let mut access = HashMap::new();
+        access.insert(String::from("b.png"), start + Duration::from_secs(50));
+
+        let names = |evicted: Vec<CachedFile>| evicted.into_iter().map(|file| file.name).collect::<Vec<String>>();
+        assert!(files_to_evict(files(), &access, 500, 500).is_empty());
+        // Down to 90% of the max size, least recently used first
+        assert_eq!(names(files_to_evict(files(), &access, 500, 400)), vec!["a.png", "c.png"]);
+        assert_eq!(names(files_to_evict(files(), &access, 500, 200)), vec!["a.png", "c.png", "d.png", "e.png"]);
+        assert_eq!(names(files_to_evict(files(), &HashMap::new(), 500, 300)), vec!["b.png", "a.png", "c.png"]);
+    }
+}
@@ -1,5 +1,8 @@
-use chrono::Local;
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::{Duration, Local, NaiveDateTime, Utc};
 use num_traits::FromPrimitive;
+use once_cell::sync::Lazy;
 use rocket::{
     request::{Form, FormItems, FromForm},
     Route,
@@ -9,27 +12,42 @@ use serde_json::Value;
 
 use crate::{
     api::{
-        core::two_factor::{duo, email, email::EmailTokenData, yubikey},
+        core::{
+            two_factor::{self, duo, email, email::EmailTokenData, yubikey, TwoFactorEnrolment},
+            webauthn,
+        },
         ApiResult, EmptyResult, JsonResult,
     },
-    auth::ClientIp,
+    auth::{ClientIp, ClientLanguage, TWOFACTOR_SETUP_SCOPE},
     db::{models::*, DbConn},
     error::MapResult,
     mail, util, CONFIG,
 };
 
 pub fn routes() -> Vec<Route> {
-    routes![login]
+    routes![login, get_webauthn_login_options]
 }
 
 #[post("/connect/token", data = "<data>")]
-fn login(data: Form<ConnectData>, conn: DbConn, ip: ClientIp) -> JsonResult {
+fn login(data: Form<ConnectData>, conn: DbConn, ip: ClientIp, lang: ClientLanguage) -> JsonResult {
     let data: ConnectData = data.into_inner();
 
     match data.grant_type.as_ref() {
         "refresh_token" => {
             _check_is_some(&data.refresh_token, "refresh_token cannot be blank")?;
-            _refresh_login(data, conn)
+            _refresh_login(data, conn, &ip)
+        }
+        "password" if data.auth_request.is_some() => {
+            _check_is_some(&data.client_id, "client_id cannot be blank")?;
+            _check_is_some(&data.password, "password cannot be blank")?;
+            _check_is_some(&data.scope, "scope cannot be blank")?;
+            _check_is_some(&data.username, "username cannot be blank")?;
+
+            _check_is_some(&data.device_identifier, "device_identifier cannot be blank")?;
+            _check_is_some(&data.device_name, "device_name cannot be blank")?;
+            _check_is_some(&data.device_type, "device_type cannot be blank")?;
+
+            _auth_request_login(data, conn, &ip)
         }
         "password" => {
             _check_is_some(&data.client_id, "client_id cannot be blank")?;
@@ -41,27 +59,79 @@ fn login(data: Form<ConnectData>, conn: DbConn, ip: ClientIp) -> JsonResult {
             _check_is_some(&data.device_name, "device_name cannot be blank")?;
             _check_is_some(&data.device_type, "device_type cannot be blank")?;
 
-            _password_login(data, conn, &ip)
+            _password_login(data, conn, &ip, &lang)
+        }
+        "webauthn" => {
+            _check_is_some(&data.client_id, "client_id cannot be blank")?;
+            _check_is_some(&data.scope, "scope cannot be blank")?;
+            _check_is_some(&data.token, "token cannot be blank")?;
+            _check_is_some(&data.device_response, "device_response cannot be blank")?;
+
+            _check_is_some(&data.device_identifier, "device_identifier cannot be blank")?;
+            _check_is_some(&data.device_name, "device_name cannot be blank")?;
+            _check_is_some(&data.device_type, "device_type cannot be blank")?;
+
+            _webauthn_login(data, conn, &ip)
         }
         t => err!("Invalid type", t),
     }
 }
 
-fn _refresh_login(data: ConnectData, conn: DbConn) -> JsonResult {
+fn _refresh_login(data: ConnectData, conn: DbConn, ip: &ClientIp) -> JsonResult {
     // Extract token
     let token = data.refresh_token.unwrap();
 
     // Get device by refresh token
-    let mut device = Device::find_by_refresh_token(&token, &conn).map_res("Invalid refresh token")?;
+    let mut device = match Device::find_by_refresh_token(&token, &conn) {
+        Some(device) => device,
+        None => return _previous_refresh_token_login(&token, ip, &conn),
+    };
+
+    if device.is_refresh_token_expired() {
+        _record_login(&device.user_uuid, &device, ip, LoginMethod::RefreshToken, false, None, &conn);
+        err!("Session expired, log in again", format!("IP: {}. Device: {}.", ip.ip, device.uuid))
+    }
 
     // COMMON
-    let user = User::find_by_uuid(&device.user_uuid, &conn).unwrap();
+    let mut user = User::find_by_uuid(&device.user_uuid, &conn).unwrap();
     let orgs = UserOrganization::find_by_user(&user.uuid, &conn);
+    let scope = _session_scope(&mut user, &conn)?;
 
-    let (access_token, expires_in) = device.refresh_tokens(&user, orgs);
+    device.rotate_refresh_token();
+    let (access_token, expires_in) = device.refresh_tokens(&user, orgs, scope.clone());
 
-    device.save(&conn)?;
-    Ok(Json(json!({
+    // A concurrent request with the same token rotated it first, this one is handled as a use of the previous token
+    if !device.save_rotated_refresh_token(&token, &conn)? {
+        return _previous_refresh_token_login(&token, ip, &conn);
+    }
+    _record_login(&user.uuid, &device, ip, LoginMethod::RefreshToken, true, None, &conn);
+    Ok(Json(_refresh_response(&user, &device, access_token, expires_in, &scope)))
+}
+
+/// Handles a refresh token which was already replaced: it still works for a few seconds, returning the current
+/// one, so that concurrent refreshes of a client don't log it out. After that, the session is revoked.
+fn _previous_refresh_token_login(token: &str, ip: &ClientIp, conn: &DbConn) -> JsonResult {
+    let mut device = match Device::find_by_previous_refresh_token(token, conn) {
+        Some(device) => device,
+        None => err!("Invalid refresh token"),
+    };
+
+    if !device.is_previous_refresh_token_usable() {
+        _revoke_reused_session(device, ip, conn)?;
+        err!("Invalid refresh token")
+    }
+
+    let mut user = User::find_by_uuid(&device.user_uuid, conn).unwrap();
+    let orgs = UserOrganization::find_by_user(&user.uuid, conn);
+    let scope = _session_scope(&mut user, conn)?;
+
+    let (access_token, expires_in) = device.refresh_tokens(&user, orgs, scope.clone());
+    _record_login(&user.uuid, &device, ip, LoginMethod::RefreshToken, true, None, conn);
+    Ok(Json(_refresh_response(&user, &device, access_token, expires_in, &scope)))
+}
+
+fn _refresh_response(user: &User, device: &Device, access_token: String, expires_in: i64, scope: &[String]) -> Value {
+    json!({
         "access_token": access_token,
         "expires_in": expires_in,
         "token_type": "Bearer",
@@ -72,12 +142,45 @@ fn _refresh_login(data: ConnectData, conn: DbConn) -> JsonResult {
         "Kdf": user.client_kdf_type,
         "KdfIterations": user.client_kdf_iter,
         "ResetMasterPassword": false, // TODO: according to official server seems something like: user.password_hash.is_empty(), but would need testing
-        "scope": "api offline_access",
+        "scope": scope.join(" "),
         "unofficialServer": true,
-    })))
+    })
 }
 
-fn _password_login(data: ConnectData, conn: DbConn, ip: &ClientIp) -> JsonResult {
+/// The scope of the session, which only allows to set up two-step login if the user had to do it and the grace period is over.
+fn _session_scope(user: &mut User, conn: &DbConn) -> ApiResult<Vec<String>> {
+    match two_factor::update_twofactor_enrolment(user, conn)? {
+        TwoFactorEnrolment::Overdue(deadline) => {
+            info!("User {} didn't set up two-step login before {}, only allowing to set it up", user.email, deadline);
+            Ok(vec![TWOFACTOR_SETUP_SCOPE.into(), "offline_access".into()])
+        }
+        _ => Ok(vec!["api".into(), "offline_access".into()]),
+    }
+}
+
+/// A refresh token which was already replaced was used again, so either the client or someone else has a copy of it.
+/// As we can't tell which one is legitimate, the device is removed, which invalidates all its tokens.
+fn _revoke_reused_session(device: Device, ip: &ClientIp, conn: &DbConn) -> EmptyResult {
+    let user = User::find_by_uuid(&device.user_uuid, conn).map_res("User doesn't exist")?;
+    warn!("Reused refresh token of device {} of user {}, revoking its session. IP: {}", device.uuid, user.email, ip.ip);
+    _record_login(&user.uuid, &device, ip, LoginMethod::RefreshToken, false, None, conn);
+
+    if CONFIG.mail_enabled() {
+        if let Err(e) = mail::send_session_revoked(
+            &user.email,
+            user.language.as_deref(),
+            &ip.ip.to_string(),
+            &Local::now(),
+            &device.name,
+            conn,
+        ) {
+            error!("Error sending session revoked email: {:#?}", e);
+        }
+    }
+    device.delete(conn)
+}
+
+fn _password_login(data: ConnectData, conn: DbConn, ip: &ClientIp, lang: &ClientLanguage) -> JsonResult {
     // Validate scope
     let scope = data.scope.as_ref().unwrap();
     if scope != "api offline_access" {
@@ -86,14 +189,17 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: &ClientIp) -> JsonResult
 
     // Get the user
     let username = data.username.as_ref().unwrap();
-    let user = match User::find_by_mail(username, &conn) {
+    let mut user = match User::find_by_mail(username, &conn) {
         Some(user) => user,
         None => err!("Username or password is incorrect. Try again", format!("IP: {}. Username: {}.", ip.ip, username)),
     };
 
+    let (mut device, new_device) = get_device(&data, &conn, &user);
+
     // Check password
     let password = data.password.as_ref().unwrap();
     if !user.check_valid_password(password) {
+        _record_login(&user.uuid, &device, ip, LoginMethod::Password, false, None, &conn);
         err!("Username or password is incorrect. Try again", format!("IP: {}. Username: {}.", ip.ip, username))
     }
 
@@ -102,6 +208,14 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: &ClientIp) -> JsonResult
         err!("This user has been disabled", format!("IP: {}. Username: {}.", ip.ip, username))
     }
 
+    // Use the language of the client for the emails, until the user sets one in their profile
+    if user.language.is_none() && lang.language.is_some() {
+        user.language = lang.language.map(String::from);
+        if let Err(e) = user.save(&conn) {
+            error!("Error updating user: {:#?}", e);
+        }
+    }
+
     let now = Local::now();
 
     if user.verified_at.is_none() && CONFIG.mail_enabled() && CONFIG.signups_verify() {
@@ -114,7 +228,6 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: &ClientIp) -> JsonResult
             if resend_limit == 0 || user.login_verify_count < resend_limit {
                 // We want to send another email verification if we require signups to verify
                 // their email address, and we haven't sent them a reminder in a while...
-                let mut user = user;
                 user.last_verifying_at = Some(now);
                 user.login_verify_count += 1;
 
@@ -122,7 +235,7 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: &ClientIp) -> JsonResult
                     error!("Error updating user: {:#?}", e);
                 }
 
-                if let Err(e) = mail::send_verify_email(&user.email, &user.uuid) {
+                if let Err(e) = mail::send_verify_email(&user.email, user.language.as_deref(), &user.uuid, &conn) {
                     error!("Error auto-sending email verification email: {:#?}", e);
                 }
             }
@@ -132,24 +245,19 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: &ClientIp) -> JsonResult
         err!("Please verify your email before trying again.", format!("IP: {}. Username: {}.", ip.ip, username))
     }
 
-    let (mut device, new_device) = get_device(&data, &conn, &user);
+    let (twofactor_token, twofactor_type) = twofactor_auth(&user.uuid, &data, &mut device, ip, &conn)?;
 
-    let twofactor_token = twofactor_auth(&user.uuid, &data, &mut device, ip, &conn)?;
-
-    if CONFIG.mail_enabled() && new_device {
-        if let Err(e) = mail::send_new_device_logged_in(&user.email, &ip.ip.to_string(), &now, &device.name) {
-            error!("Error sending new device email: {:#?}", e);
-
-            if CONFIG.require_device_email() {
-                err!("Could not send login notification email. Please contact your administrator.")
-            }
-        }
+    if new_device {
+        _notify_new_device(&user, &device, ip, &conn)?;
     }
 
     // Common
     let orgs = UserOrganization::find_by_user(&user.uuid, &conn);
 
-    let (access_token, expires_in) = device.refresh_tokens(&user, orgs);
+    let scope = _session_scope(&mut user, &conn)?;
+
+    device.start_session();
+    let (access_token, expires_in) = device.refresh_tokens(&user, orgs, scope.clone());
     device.save(&conn)?;
 
     let mut result = json!({
@@ -164,7 +272,7 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: &ClientIp) -> JsonResult
         "Kdf": user.client_kdf_type,
         "KdfIterations": user.client_kdf_iter,
         "ResetMasterPassword": false,// TODO: Same as above
-        "scope": "api offline_access",
+        "scope": scope.join(" "),
         "unofficialServer": true,
     });
 
@@ -172,10 +280,269 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: &ClientIp) -> JsonResult
         result["TwoFactorToken"] = Value::String(token);
     }
 
+    _record_login(&user.uuid, &device, ip, LoginMethod::Password, true, twofactor_type, &conn);
     info!("User {} logged in successfully. IP: {}", username, ip.ip);
     Ok(Json(result))
 }
 
+fn _webauthn_login(data: ConnectData, conn: DbConn, ip: &ClientIp) -> JsonResult {
+    // Validate scope
+    let scope = data.scope.as_ref().unwrap();
+    if scope != "api offline_access" {
+        err!("Scope not supported")
+    }
+
+    // Get the user from the passkey, a verified passkey is enough on its own so there is no 2FA check
+    let response = webauthn::parse_device_response(data.device_response.as_ref().unwrap())?;
+    let credential_user = webauthn::find_credential_user(&response, &conn);
+    let (mut user, credential) = match webauthn::validate_login(data.token.as_ref().unwrap(), response, &conn) {
+        Ok(result) => result,
+        Err(e) => {
+            // A failed assertion of an existing passkey is a failed login of its user
+            if let Some(user) = credential_user {
+                let (device, _) = get_device(&data, &conn, &user);
+                _record_login(&user.uuid, &device, ip, LoginMethod::Passkey, false, None, &conn);
+            }
+            err!("Passkey login failed. Try again", format!("IP: {}. {:?}", ip.ip, e))
+        }
+    };
+
+    if !user.enabled {
+        err!("This user has been disabled", format!("IP: {}. Username: {}.", ip.ip, user.email))
+    }
+
+    if user.verified_at.is_none() && CONFIG.mail_enabled() && CONFIG.signups_verify() {
+        err!("Please verify your email before trying again.", format!("IP: {}. Username: {}.", ip.ip, user.email))
+    }
+
+    let (mut device, new_device) = get_device(&data, &conn, &user);
+    if new_device {
+        _notify_new_device(&user, &device, ip, &conn)?;
+    }
+
+    // Common
+    let orgs = UserOrganization::find_by_user(&user.uuid, &conn);
+
+    let scope = _session_scope(&mut user, &conn)?;
+
+    device.start_session();
+    let (access_token, expires_in) = device.refresh_tokens(&user, orgs, scope.clone());
+    device.save(&conn)?;
+
+    let mut result = json!({
+        "access_token": access_token,
+        "expires_in": expires_in,
+        "token_type": "Bearer",
+        "refresh_token": device.refresh_token,
+        "Key": user.akey,
+        "PrivateKey": user.private_key,
+
+        "Kdf": user.client_kdf_type,
+        "KdfIterations": user.client_kdf_iter,
+        "ResetMasterPassword": false,
+        "scope": scope.join(" "),
+        "unofficialServer": true,
+        "UserDecryptionOptions": {
+            "HasMasterPassword": true,
+            "Object": "userDecryptionOptions"
+        },
+    });
+
+    // Without the keys wrapped by the passkey, the vault has to be unlocked with the master password
+    if let (Some(user_key), Some(private_key)) = (credential.encrypted_user_key, credential.encrypted_private_key) {
+        result["UserDecryptionOptions"]["WebAuthnPrfOption"] = json!({
+            "EncryptedPrivateKey": private_key,
+            "EncryptedUserKey": user_key,
+        });
+    }
+
+    _record_login(&user.uuid, &device, ip, LoginMethod::Passkey, true, None, &conn);
+    info!("User {} logged in successfully with a passkey. IP: {}", user.email, ip.ip);
+    Ok(Json(result))
+}
+
+/// Login with device: the password is the access code of a login request which was approved by another device of the user.
+fn _auth_request_login(data: ConnectData, conn: DbConn, ip: &ClientIp) -> JsonResult {
+    // Validate scope
+    let scope = data.scope.as_ref().unwrap();
+    if scope != "api offline_access" {
+        err!("Scope not supported")
+    }
+
+    let username = data.username.as_ref().unwrap();
+    let mut user = match User::find_by_mail(username, &conn) {
+        Some(user) => user,
+        None => {
+            err!("Username or access code is incorrect. Try again", format!("IP: {}. Username: {}.", ip.ip, username))
+        }
+    };
+
+    let (mut device, new_device) = get_device(&data, &conn, &user);
+
+    let request_uuid = data.auth_request.as_ref().unwrap();
+    let mut auth_request = match AuthRequest::find_by_uuid_and_user(request_uuid, &user.uuid, &conn) {
+        Some(auth_request)
+            if auth_request.check_access_code(data.password.as_ref().unwrap())
+                && data.device_identifier.as_ref() == Some(&auth_request.request_device_identifier) =>
+        {
+            auth_request
+        }
+        _ => {
+            _record_login(&user.uuid, &device, ip, LoginMethod::AuthRequest, false, None, &conn);
+            err!("Username or access code is incorrect. Try again", format!("IP: {}. Username: {}.", ip.ip, username))
+        }
+    };
+
+    if !auth_request.is_usable() {
+        _record_login(&user.uuid, &device, ip, LoginMethod::AuthRequest, false, None, &conn);
+        err!("Login request wasn't approved or has expired", format!("IP: {}. Username: {}.", ip.ip, username))
+    }
+
+    if !user.enabled {
+        err!("This user has been disabled", format!("IP: {}. Username: {}.", ip.ip, username))
+    }
+
+    if user.verified_at.is_none() && CONFIG.mail_enabled() && CONFIG.signups_verify() {
+        err!("Please verify your email before trying again.", format!("IP: {}. Username: {}.", ip.ip, username))
+    }
+
+    // The request can only be used once, even by concurrent logins
+    if !auth_request.mark_used(&conn)? {
+        _record_login(&user.uuid, &device, ip, LoginMethod::AuthRequest, false, None, &conn);
+        err!("Login request wasn't approved or has expired", format!("IP: {}. Username: {}.", ip.ip, username))
+    }
+
+    // The request was approved from a logged in device, which already went through the two-step login
+    if new_device {
+        _notify_new_device(&user, &device, ip, &conn)?;
+    }
+
+    // Common
+    let orgs = UserOrganization::find_by_user(&user.uuid, &conn);
+
+    let scope = _session_scope(&mut user, &conn)?;
+
+    device.start_session();
+    let (access_token, expires_in) = device.refresh_tokens(&user, orgs, scope.clone());
+    device.save(&conn)?;
+
+    _record_login(&user.uuid, &device, ip, LoginMethod::AuthRequest, true, None, &conn);
+    info!("User {} logged in successfully with login request {}. IP: {}", username, auth_request.uuid, ip.ip);
+    Ok(Json(json!({
+        "access_token": access_token,
+        "expires_in": expires_in,
+        "token_type": "Bearer",
+        "refresh_token": device.refresh_token,
+        "Key": user.akey,
+        "PrivateKey": user.private_key,
+
+        "Kdf": user.client_kdf_type,
+        "KdfIterations": user.client_kdf_iter,
+        "ResetMasterPassword": false,
+        "scope": scope.join(" "),
+        "unofficialServer": true,
+    })))
+}
+
+/// Adds the attempt to the login history of the user, and warns them by email about a burst of failed attempts
+fn _record_login(
+    user_uuid: &str,
+    device: &Device,
+    ip: &ClientIp,
+    method: LoginMethod,
+    success: bool,
+    twofactor_type: Option<i32>,
+    conn: &DbConn,
+) {
+    let entry = LoginHistory::new(user_uuid.to_string(), device, ip.ip.to_string(), method, success, twofactor_type);
+    if let Err(e) = entry.save(conn) {
+        error!("Error saving login history: {:#?}", e);
+    }
+
+    if !success {
+        _check_login_failures(user_uuid, ip, conn);
+    }
+}
+
+/// When the users were last warned about failed logins, to only send one email per burst.
+static LOGIN_FAILURE_ALERTS: Lazy<Mutex<HashMap<String, NaiveDateTime>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Whether to warn a user about the failed logins since the start of the alert window. The count can jump past the
+/// threshold with concurrent attempts, so it's enough to reach it, as long as the user wasn't warned in the window.
+fn should_alert_login_failures(
+    failures: i64,
+    threshold: u32,
+    last_alert: Option<&NaiveDateTime>,
+    since: &NaiveDateTime,
+) -> bool {
+    threshold > 0 && failures >= threshold as i64 && last_alert.map_or(true, |alerted_at| alerted_at < since)
+}
+
+fn _check_login_failures(user_uuid: &str, ip: &ClientIp, conn: &DbConn) {
+    let threshold = CONFIG.login_failure_alert_threshold();
+    if threshold == 0 || !CONFIG.mail_enabled() {
+        return;
+    }
+
+    let now = Utc::now().naive_utc();
+    let since = now - Duration::minutes(CONFIG.login_failure_alert_window() as i64);
+    let failures = LoginHistory::count_failures_by_user_since(user_uuid, &since, conn);
+    {
+        let mut last_alerts = LOGIN_FAILURE_ALERTS.lock().unwrap();
+        if !should_alert_login_failures(failures, threshold, last_alerts.get(user_uuid), &since) {
+            return;
+        }
+        // Forget the alerts which don't limit the next ones anymore
+        last_alerts.retain(|_, alerted_at| *alerted_at >= since);
+        last_alerts.insert(user_uuid.to_string(), now);
+    }
+
+    if let Some(user) = User::find_by_uuid(user_uuid, conn) {
+        warn!(
+            "{} failed login attempts for user {} in the last {} minutes. IP: {}",
+            failures,
+            user.email,
+            CONFIG.login_failure_alert_window(),
+            ip.ip
+        );
+        if let Err(e) = mail::send_login_failures(
+            &user.email,
+            user.language.as_deref(),
+            failures,
+            &ip.ip.to_string(),
+            &Local::now(),
+            conn,
+        ) {
+            error!("Error sending failed logins email: {:#?}", e);
+        }
+    }
+}
+
+#[get("/accounts/webauthn/assertion-options")]
+fn get_webauthn_login_options() -> JsonResult {
+    webauthn::generate_login_options(None)
+}
+
+fn _notify_new_device(user: &User, device: &Device, ip: &ClientIp, conn: &DbConn) -> EmptyResult {
+    if CONFIG.mail_enabled() {
+        if let Err(e) = mail::send_new_device_logged_in(
+            &user.email,
+            user.language.as_deref(),
+            &ip.ip.to_string(),
+            &Local::now(),
+            &device.name,
+            conn,
+        ) {
+            error!("Error sending new device email: {:#?}", e);
+
+            if CONFIG.require_device_email() {
+                err!("Could not send login notification email. Please contact your administrator.")
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Retrieves an existing device or creates a new device from ConnectData and the User
 fn get_device(data: &ConnectData, conn: &DbConn, user: &User) -> (Device, bool) {
     // On iOS, device_type sends "iOS", on others it sends a number
@@ -211,15 +578,29 @@ fn twofactor_auth(
     device: &mut Device,
     ip: &ClientIp,
     conn: &DbConn,
-) -> ApiResult<Option<String>> {
+) -> ApiResult<(Option<String>, Option<i32>)> {
+    // The U2F keys are only used through WebAuthn. If they can't be converted, the other providers can still be used.
+    if let Err(e) = TwoFactor::migrate_u2f_to_webauthn_by_user(user_uuid, conn) {
+        error!("Error converting the U2F keys of user {} to WebAuthn: {:#?}", user_uuid, e);
+    }
     let twofactors = TwoFactor::find_by_user(user_uuid, conn);
 
+    // Members without personal twofactor are challenged with the Duo of their organization, if any
+    let org_duo = if twofactors.is_empty() {
+        Organization::find_with_duo_by_user(user_uuid, conn)
+    } else {
+        None
+    };
+
     // No twofactor token if twofactor is disabled
-    if twofactors.is_empty() {
-        return Ok(None);
+    if twofactors.is_empty() && org_duo.is_none() {
+        return Ok((None, None));
     }
 
-    let twofactor_ids: Vec<_> = twofactors.iter().map(|tf| tf.atype).collect();
+    let mut twofactor_ids: Vec<_> = twofactors.iter().map(|tf| tf.atype).collect();
+    if org_duo.is_some() {
+        twofactor_ids.push(TwoFactorType::OrganizationDuo as i32);
+    }
     let selected_id = data.two_factor_provider.unwrap_or(twofactor_ids[0]); // If we aren't given a two factor provider, asume the first one
 
     let twofactor_code = match data.two_factor_token {
@@ -233,26 +614,34 @@ fn twofactor_auth(
     use crate::crypto::ct_eq;
 
     let selected_data = _selected_data(selected_twofactor);
-    let mut remember = data.two_factor_remember.unwrap_or(0);
+    let remember = data.two_factor_remember.unwrap_or(0);
 
-    match TwoFactorType::from_i32(selected_id) {
+    let validation = match TwoFactorType::from_i32(selected_id) {
         Some(TwoFactorType::Authenticator) => {
-            _tf::authenticator::validate_totp_code_str(user_uuid, twofactor_code, &selected_data?, ip, conn)?
+            _tf::authenticator::validate_totp_code_str(user_uuid, twofactor_code, &selected_data?, ip, conn)
         }
-        Some(TwoFactorType::U2f) => _tf::u2f::validate_u2f_login(user_uuid, twofactor_code, conn)?,
-        Some(TwoFactorType::Webauthn) => _tf::webauthn::validate_webauthn_login(user_uuid, twofactor_code, conn)?,
-        Some(TwoFactorType::YubiKey) => _tf::yubikey::validate_yubikey_login(twofactor_code, &selected_data?)?,
-        Some(TwoFactorType::Duo) => {
-            _tf::duo::validate_duo_login(data.username.as_ref().unwrap(), twofactor_code, conn)?
+        Some(TwoFactorType::Webauthn) => _tf::webauthn::validate_webauthn_login(user_uuid, twofactor_code, conn),
+        Some(TwoFactorType::YubiKey) => {
+            _tf::yubikey::validate_yubikey_login(user_uuid, twofactor_code, &selected_data?, conn)
         }
+        Some(TwoFactorType::Duo) => _tf::duo::validate_duo_login(data.username.as_ref().unwrap(), twofactor_code, conn),
+        Some(TwoFactorType::OrganizationDuo) => match org_duo {
+            Some(ref org) => _tf::duo::validate_org_duo_login(data.username.as_ref().unwrap(), twofactor_code, org),
+            None => err!("Invalid two factor provider"),
+        },
         Some(TwoFactorType::Email) => {
-            _tf::email::validate_email_code_str(user_uuid, twofactor_code, &selected_data?, conn)?
+            _tf::email::validate_email_code_str(user_uuid, twofactor_code, &selected_data?, conn)
         }
 
         Some(TwoFactorType::Remember) => {
             match device.twofactor_remember {
-                Some(ref code) if !CONFIG.disable_2fa_remember() && ct_eq(code, twofactor_code) => {
-                    remember = 1; // Make sure we also return the token here, otherwise it will only remember the first time
+                Some(ref code)
+                    if !CONFIG.disable_2fa_remember()
+                        && !device.is_twofactor_remember_expired()
+                        && ct_eq(code, twofactor_code) =>
+                {
+                    // Return the same token, so it still expires at the end of the lifetime of the original one
+                    return Ok((Some(code.clone()), Some(selected_id)));
                 }
                 _ => {
                     err_json!(_json_err_twofactor(&twofactor_ids, user_uuid, conn)?, "2FA Remember token not provided")
@@ -260,13 +649,18 @@ fn twofactor_auth(
             }
         }
         _ => err!("Invalid two factor provider"),
+    };
+
+    if let Err(e) = validation {
+        _record_login(user_uuid, device, ip, LoginMethod::Password, false, Some(selected_id), conn);
+        return Err(e);
     }
 
     if !CONFIG.disable_2fa_remember() && remember == 1 {
-        Ok(Some(device.refresh_twofactor_remember()))
+        Ok((Some(device.refresh_twofactor_remember()), Some(selected_id)))
     } else {
         device.delete_twofactor_remember();
-        Ok(None)
+        Ok((None, Some(selected_id)))
     }
 }
 
@@ -275,8 +669,6 @@ fn _selected_data(tf: Option<TwoFactor>) -> ApiResult<String> {
 }
 
 fn _json_err_twofactor(providers: &[i32], user_uuid: &str, conn: &DbConn) -> ApiResult<Value> {
-    use crate::api::core::two_factor;
-
     let mut result = json!({
         "error" : "invalid_grant",
         "error_description" : "Two factor required.",
@@ -290,26 +682,6 @@ fn _json_err_twofactor(providers: &[i32], user_uuid: &str, conn: &DbConn) -> Api
         match TwoFactorType::from_i32(*provider) {
             Some(TwoFactorType::Authenticator) => { /* Nothing to do for TOTP */ }
 
-            Some(TwoFactorType::U2f) if CONFIG.domain_set() => {
-                let request = two_factor::u2f::generate_u2f_login(user_uuid, conn)?;
-                let mut challenge_list = Vec::new();
-
-                for key in request.registered_keys {
-                    challenge_list.push(json!({
-                        "appId": request.app_id,
-                        "challenge": request.challenge,
-                        "version": key.version,
-                        "keyHandle": key.key_handle,
-                    }));
-                }
-
-                let challenge_list_str = serde_json::to_string(&challenge_list).unwrap();
-
-                result["TwoFactorProviders2"][provider.to_string()] = json!({
-                    "Challenges": challenge_list_str,
-                });
-            }
-
             Some(TwoFactorType::Webauthn) if CONFIG.domain_set() => {
                 let request = two_factor::webauthn::generate_webauthn_login(user_uuid, conn)?;
                 result["TwoFactorProviders2"][provider.to_string()] = request.0;
@@ -329,6 +701,24 @@ fn _json_err_twofactor(providers: &[i32], user_uuid: &str, conn: &DbConn) -> Api
                 });
             }
 
+            Some(TwoFactorType::OrganizationDuo) => {
+                let email = match User::find_by_uuid(user_uuid, conn) {
+                    Some(u) => u.email,
+                    None => err!("User does not exist"),
+                };
+                let org = match Organization::find_with_duo_by_user(user_uuid, conn) {
+                    Some(org) => org,
+                    None => err!("Organization Duo is not configured"),
+                };
+
+                let (signature, host) = duo::generate_org_duo_signature(&email, &org)?;
+
+                result["TwoFactorProviders2"][provider.to_string()] = json!({
+                    "Host": host,
+                    "Signature": signature,
+                });
+            }
+
             Some(tf_type @ TwoFactorType::YubiKey) => {
                 let twofactor = match TwoFactor::find_by_user_and_type(user_uuid, tf_type as i32, conn) {
                     Some(tf) => tf,
@@ -372,7 +762,7 @@ fn _json_err_twofactor(providers: &[i32], user_uuid: &str, conn: &DbConn) -> Api
 #[derive(Debug, Clone, Default)]
 #[allow(non_snake_case)]
 struct ConnectData {
-    grant_type: String, // refresh_token, password
+    grant_type: String, // refresh_token, password, webauthn
 
     // Needed for grant_type="refresh_token"
     refresh_token: Option<String>,
@@ -392,6 +782,13 @@ struct ConnectData {
     two_factor_provider: Option<i32>,
     two_factor_token: Option<String>,
     two_factor_remember: Option<i32>,
+
+    // Needed for grant_type="webauthn"
+    token: Option<String>,
+    device_response: Option<String>,
+
+    // Needed for grant_type="password" with a login request approved by another device
+    auth_request: Option<String>,
 }
 
 impl<'f> FromForm<'f> for ConnectData {
@@ -418,6 +815,9 @@ impl<'f> FromForm<'f> for ConnectData {
                 "twofactorprovider" => form.two_factor_provider = value.parse().ok(),
                 "twofactortoken" => form.two_factor_token = Some(value),
                 "twofactorremember" => form.two_factor_remember = value.parse().ok(),
+                "token" => form.token = Some(value),
+                "deviceresponse" => form.device_response = Some(value),
+                "authrequest" => form.auth_request = Some(value),
                 key => warn!("Detected unexpected parameter during login: {}", key),
             }
         }
@@ -432,3 +832,25 @@ fn _check_is_some<T>(value: &Option<T>, msg: &str) -> EmptyResult {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_alert_login_failures() {
+        let since = Utc::now().naive_utc() - Duration::minutes(15);
+
+        assert!(!should_alert_login_failures(4, 5, None, &since));
+        assert!(should_alert_login_failures(5, 5, None, &since));
+        // Concurrent attempts can skip the threshold
+        assert!(should_alert_login_failures(7, 5, None, &since));
+        assert!(!should_alert_login_failures(10, 0, None, &since));
+
+        // Only once per window
+        let recent = since + Duration::minutes(5);
+        let old = since - Duration::minutes(5);
+        assert!(!should_alert_login_failures(6, 5, Some(&recent), &since));
+        assert!(should_alert_login_failures(6, 5, Some(&old), &since));
+    }
+}
@@ -3,20 +3,29 @@ pub mod core;
 mod icons;
 mod identity;
 mod notifications;
+mod scim;
 mod web;
 
 use rocket_contrib::json::Json;
 use serde_json::Value;
 
 pub use crate::api::{
+    admin::purge_admin_actions,
     admin::routes as admin_routes,
+    core::purge_auth_requests,
+    core::purge_deleted_users,
+    core::purge_incomplete_uploads,
+    core::purge_login_history,
     core::purge_sends,
     core::purge_trashed_ciphers,
     core::routes as core_routes,
+    core::two_factor::send_twofactor_reminders,
+    icons::purge_icon_cache,
     icons::routes as icons_routes,
     identity::routes as identity_routes,
     notifications::routes as notifications_routes,
     notifications::{start_notification_server, Notify, UpdateType},
+    scim::routes as scim_routes,
     web::routes as web_routes,
 };
 use crate::util;
@@ -65,7 +65,7 @@ use chashmap::CHashMap;
 use chrono::NaiveDateTime;
 use serde_json::from_str;
 
-use crate::db::models::{Cipher, Folder, User};
+use crate::db::models::{AuthRequest, Cipher, Folder, User};
 
 use rmpv::Value;
 
@@ -194,6 +194,9 @@ impl Handler for WsHandler {
             Ok(claims) => claims,
             Err(_) => return self.err("Invalid access token provided"),
         };
+        if !claims.scope.iter().any(|s| s == "api") {
+            return self.err("Two-step login has to be set up first");
+        }
 
         // Assign the user to the handler
         let user_uuid = claims.sub;
@@ -303,6 +306,19 @@ impl WebSocketUsers {
         self.send_update(&user.uuid, &data).ok();
     }
 
+    /// Asks the logged in devices of the user to approve or deny a login request, or tells them it was answered
+    pub fn send_auth_request(&self, ut: UpdateType, auth_request: &AuthRequest) {
+        let data = create_update(
+            vec![
+                ("Id".into(), auth_request.uuid.clone().into()),
+                ("UserId".into(), auth_request.user_uuid.clone().into()),
+            ],
+            ut,
+        );
+
+        self.send_update(&auth_request.user_uuid, &data).ok();
+    }
+
     pub fn send_folder_update(&self, ut: UpdateType, folder: &Folder) {
         let data = create_update(
             vec![
@@ -396,6 +412,9 @@ pub enum UpdateType {
     SyncSendUpdate = 13,
     SyncSendDelete = 14,
 
+    AuthRequest = 15,
+    AuthRequestResponse = 16,
+
     None = 100,
 }
 
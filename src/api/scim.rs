@@ -0,0 +1,999 @@
+//
+// SCIM 2.0 provisioning (RFC 7643 / RFC 7644)
+//
+// Users are mapped to organization memberships (the SCIM `id` is the `UserOrganization` uuid),
+// and Groups are mapped to the organization groups table.
+//
+use rocket::{
+    http::Status,
+    request::{FromRequest, LenientForm, Outcome, Request},
+    response::status::{Custom, NoContent},
+    Route,
+};
+use rocket_contrib::json::Json;
+use serde_json::Value;
+
+use crate::{
+    api::{ApiResult, JsonResult},
+    db::{models::*, DbConn},
+    error::Error,
+    mail,
+    util::format_date,
+    CONFIG,
+};
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        get_service_provider_config,
+        get_users,
+        get_user,
+        post_user,
+        put_user,
+        patch_user,
+        delete_user,
+        get_groups,
+        get_group,
+        post_group,
+        put_group,
+        patch_group,
+        delete_group,
+    ]
+}
+
+const SCHEMA_USER: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+const SCHEMA_GROUP: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+const SCHEMA_LIST: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+const SCHEMA_ERROR: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+const SCHEMA_SP_CONFIG: &str = "urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig";
+
+const MAX_RESULTS: usize = 200;
+
+//
+// Authentication
+//
+
+/// Request guard for the SCIM endpoints. The organization is taken from the path
+/// ("/v2/<org_id>/...") and the bearer token is checked against the organization's SCIM token.
+pub struct ScimHeaders {
+    pub org: Organization,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ScimHeaders {
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let org_id = match request.get_param::<String>(1) {
+            Some(Ok(org_id)) => org_id,
+            _ => err_handler!("Error getting the organization id"),
+        };
+
+        let token: &str = match request.headers().get_one("Authorization") {
+            Some(a) => match a.rsplit("Bearer ").next() {
+                Some(split) => split,
+                None => err_handler!("No SCIM token provided"),
+            },
+            None => err_handler!("No SCIM token provided"),
+        };
+
+        let conn = match request.guard::<DbConn>() {
+            Outcome::Success(conn) => conn,
+            _ => err_handler!("Error getting DB"),
+        };
+
+        let org = match Organization::find_by_uuid(&org_id, &conn) {
+            Some(org) => org,
+            None => err_handler!("Invalid organization"),
+        };
+
+        if !org.check_scim_token(token) {
+            err_handler!("Invalid SCIM token", format!("Organization: {}", org_id))
+        }
+
+        Outcome::Success(ScimHeaders {
+            org,
+        })
+    }
+}
+
+//
+// Request data
+//
+
+#[derive(FromForm)]
+struct ListQuery {
+    filter: Option<String>,
+    #[form(field = "startIndex")]
+    start_index: Option<usize>,
+    count: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct ScimEmail {
+    value: String,
+    primary: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ScimUserData {
+    userName: Option<String>,
+    externalId: Option<String>,
+    emails: Option<Vec<ScimEmail>>,
+    active: Option<bool>,
+}
+
+impl ScimUserData {
+    /// The primary email if one is flagged, otherwise the first email, otherwise the userName.
+    fn email(&self) -> Option<String> {
+        let emails = self.emails.as_deref().unwrap_or_default();
+        emails
+            .iter()
+            .find(|e| e.primary == Some(true))
+            .or_else(|| emails.first())
+            .map(|e| e.value.clone())
+            .or_else(|| self.userName.clone())
+            .map(|e| e.trim().to_lowercase())
+            .filter(|e| e.contains('@'))
+    }
+}
+
+#[derive(Deserialize)]
+struct ScimMember {
+    value: String,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ScimGroupData {
+    displayName: String,
+    externalId: Option<String>,
+    members: Option<Vec<ScimMember>>,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ScimPatchData {
+    Operations: Vec<ScimPatchOperation>,
+}
+
+#[derive(Deserialize)]
+struct ScimPatchOperation {
+    op: String,
+    path: Option<String>,
+    value: Option<Value>,
+}
+
+//
+// Helpers
+//
+
+fn scim_error(status: Status, scim_type: Option<&str>, detail: &str) -> Error {
+    let body = json!({
+        "schemas": [SCHEMA_ERROR],
+        "status": status.code.to_string(),
+        "scimType": scim_type,
+        "detail": detail,
+    });
+    error!("SCIM error: {}", detail);
+    Error::from((detail, body)).with_code(status.code)
+}
+
+fn not_found(resource: &str) -> Error {
+    scim_error(Status::NotFound, None, &format!("{} not found", resource))
+}
+
+/// Parses the `<attribute> eq "<value>"` filters that identity providers use to look up
+/// existing resources. The attribute is returned lowercased, as SCIM attribute names are case insensitive.
+fn parse_filter(filter: &str) -> ApiResult<(String, String)> {
+    let mut parts = filter.trim().splitn(3, ' ');
+
+    match (parts.next(), parts.next(), parts.next().map(str::trim)) {
+        (Some(attr), Some(op), Some(value))
+            if op.eq_ignore_ascii_case("eq") && value.len() >= 2 && value.starts_with('"') && value.ends_with('"') =>
+        {
+            Ok((attr.to_lowercase(), value[1..value.len() - 1].replace("\\\"", "\"")))
+        }
+        _ => Err(scim_error(Status::BadRequest, Some("invalidFilter"), "Only 'eq' filters are supported")),
+    }
+}
+
+/// Extracts the member id out of a `members[value eq "<id>"]` patch path.
+fn parse_member_path(path: &str) -> Option<String> {
+    let inner = path.trim().strip_prefix("members[")?.strip_suffix(']')?;
+    match parse_filter(inner) {
+        Ok((attr, value)) if attr == "value" => Some(value),
+        _ => None,
+    }
+}
+
+fn list_response(resources: Vec<Value>, query: &ListQuery) -> Value {
+    let total = resources.len();
+    let start_index = query.start_index.unwrap_or(1).max(1);
+    let count = query.count.unwrap_or(MAX_RESULTS).min(MAX_RESULTS);
+    let page: Vec<Value> = resources.into_iter().skip(start_index - 1).take(count).collect();
+
+    json!({
+        "schemas": [SCHEMA_LIST],
+        "totalResults": total,
+        "startIndex": start_index,
+        "itemsPerPage": page.len(),
+        "Resources": page,
+    })
+}
+
+fn user_to_scim(user_org: &UserOrganization, user: &User) -> Value {
+    json!({
+        "schemas": [SCHEMA_USER],
+        "id": user_org.uuid,
+        "externalId": user_org.external_id,
+        "userName": user.email,
+        "displayName": user.name,
+        "name": {
+            "formatted": user.name,
+        },
+        "emails": [{
+            "value": user.email,
+            "type": "work",
+            "primary": true,
+        }],
+        // Deleted users wait for the end of their grace period, they can't log in anymore
+        "active": user.deleted_at.is_none(),
+        "meta": {
+            "resourceType": "User",
+            "created": format_date(&user.created_at),
+            "lastModified": format_date(&user.updated_at),
+        },
+    })
+}
+
+fn group_to_scim(group: &Group, conn: &DbConn) -> Value {
+    let members: Vec<Value> = GroupUser::find_by_group(&group.uuid, conn)
+        .iter()
+        .filter_map(|gu| UserOrganization::find_by_uuid(&gu.users_organizations_uuid, conn))
+        .filter_map(|uo| User::find_by_uuid(&uo.user_uuid, conn).map(|u| (uo, u)))
+        .map(|(uo, u)| {
+            json!({
+                "value": uo.uuid,
+                "display": u.email,
+            })
+        })
+        .collect();
+
+    json!({
+        "schemas": [SCHEMA_GROUP],
+        "id": group.uuid,
+        "externalId": group.external_id,
+        "displayName": group.name,
+        "members": members,
+        "meta": {
+            "resourceType": "Group",
+            "created": format_date(&group.created_at),
+            "lastModified": format_date(&group.updated_at),
+        },
+    })
+}
+
+fn get_user_org_or_404(id: &str, org_id: &str, conn: &DbConn) -> ApiResult<(UserOrganization, User)> {
+    let user_org = UserOrganization::find_by_uuid_and_org(id, org_id, conn).ok_or_else(|| not_found("User"))?;
+    let user = User::find_by_uuid(&user_org.user_uuid, conn).ok_or_else(|| not_found("User"))?;
+    Ok((user_org, user))
+}
+
+fn get_group_or_404(id: &str, org_id: &str, conn: &DbConn) -> ApiResult<Group> {
+    Group::find_by_uuid_and_org(id, org_id, conn).ok_or_else(|| not_found("Group"))
+}
+
+/// Removes a user from the organization, refusing to remove the last owner.
+fn remove_user_org(user_org: UserOrganization, conn: &DbConn) -> ApiResult<()> {
+    if user_org.atype == UserOrgType::Owner {
+        let num_owners =
+            UserOrganization::find_by_org_and_type(&user_org.org_uuid, UserOrgType::Owner as i32, conn).len();
+        if num_owners <= 1 {
+            return Err(scim_error(Status::BadRequest, Some("mutability"), "Can't remove the last owner"));
+        }
+    }
+
+    user_org.delete(conn)
+}
+
+/// Adds the given membership ids to the group, ignoring ids that don't belong to the organization.
+fn add_group_members(group: &Group, members: &[ScimMember], conn: &DbConn) -> ApiResult<()> {
+    for member in members {
+        match UserOrganization::find_by_uuid_and_org(&member.value, &group.org_uuid, conn) {
+            Some(user_org) => GroupUser::save(&group.uuid, &user_org.uuid, conn)?,
+            None => warn!("SCIM: Ignoring unknown group member {}", member.value),
+        }
+    }
+    Ok(())
+}
+
+fn remove_group_member(group: &Group, member_id: &str, conn: &DbConn) -> ApiResult<()> {
+    GroupUser {
+        group_uuid: group.uuid.clone(),
+        users_organizations_uuid: member_id.to_string(),
+    }
+    .delete(conn)
+}
+
+fn parse_members(value: Option<Value>) -> ApiResult<Vec<ScimMember>> {
+    match value {
+        Some(v) => serde_json::from_value(v)
+            .map_err(|_| scim_error(Status::BadRequest, Some("invalidValue"), "Invalid members value")),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn user_matches_filter(user_org: &UserOrganization, user: &User, (attr, value): &(String, String)) -> bool {
+    match attr.as_str() {
+        "id" => &user_org.uuid == value,
+        "username" | "emails" | "emails.value" => user.email == value.to_lowercase(),
+        "externalid" => user_org.external_id.as_ref() == Some(value),
+        _ => false,
+    }
+}
+
+fn group_matches_filter(group: &Group, (attr, value): &(String, String)) -> bool {
+    match attr.as_str() {
+        "id" => &group.uuid == value,
+        "displayname" => &group.name == value,
+        "externalid" => group.external_id.as_ref() == Some(value),
+        _ => false,
+    }
+}
+
+/// Applies the operations of a user patch to the membership, returns whether the user was deactivated.
+fn patch_user_attributes(user_org: &mut UserOrganization, operations: Vec<ScimPatchOperation>) -> bool {
+    let mut deactivate = false;
+    for operation in operations {
+        let op = operation.op.to_lowercase();
+        if op != "replace" && op != "add" {
+            debug!("SCIM: Ignoring unsupported user patch operation '{}'", op);
+            continue;
+        }
+
+        // The attributes can either be given through the path, or as an object without path
+        let attributes = match (operation.path, operation.value) {
+            (Some(path), Some(value)) => json!({ path: value }),
+            (None, Some(value @ Value::Object(_))) => value,
+            _ => continue,
+        };
+
+        for (attr, value) in attributes.as_object().into_iter().flatten() {
+            match attr.to_lowercase().as_str() {
+                // Some providers send booleans as strings
+                "active" => deactivate = value == &json!(false) || value == &json!("False") || value == &json!("false"),
+                "externalid" => user_org.external_id = value.as_str().map(String::from),
+                other => debug!("SCIM: Ignoring unsupported user attribute '{}'", other),
+            }
+        }
+    }
+    deactivate
+}
+
+/// A change of the members of a group, requested by a patch operation.
+enum MembersChange {
+    Add(Vec<ScimMember>),
+    Replace(Vec<ScimMember>),
+    Remove(Vec<String>),
+    RemoveAll,
+}
+
+/// Applies the attribute changes of a group patch to the group, and returns the changes of its members,
+/// which have to be made in order.
+fn patch_group_attributes(group: &mut Group, operations: Vec<ScimPatchOperation>) -> ApiResult<Vec<MembersChange>> {
+    let mut changes = Vec::new();
+    for operation in operations {
+        let op = operation.op.to_lowercase();
+        let path = operation.path.as_deref().map(str::trim).unwrap_or_default();
+
+        match (op.as_str(), path.to_lowercase().as_str()) {
+            ("add", "members") => changes.push(MembersChange::Add(parse_members(operation.value)?)),
+            ("replace", "members") => changes.push(MembersChange::Replace(parse_members(operation.value)?)),
+            // Without a value, all the members are removed
+            ("remove", "members") if operation.value.is_none() => changes.push(MembersChange::RemoveAll),
+            ("remove", "members") => changes.push(MembersChange::Remove(
+                parse_members(operation.value)?.into_iter().map(|member| member.value).collect(),
+            )),
+            ("remove", _) if path.starts_with("members[") => match parse_member_path(path) {
+                Some(member_id) => changes.push(MembersChange::Remove(vec![member_id])),
+                None => return Err(scim_error(Status::BadRequest, Some("invalidPath"), "Invalid members path")),
+            },
+            ("replace", _) | ("add", _) => {
+                let attributes = match operation.value {
+                    Some(value @ Value::Object(_)) if path.is_empty() => value,
+                    Some(value) => json!({ path: value }),
+                    None => continue,
+                };
+
+                for (attr, value) in attributes.as_object().into_iter().flatten() {
+                    match attr.to_lowercase().as_str() {
+                        "displayname" => {
+                            if let Some(name) = value.as_str() {
+                                group.name = name.to_string();
+                            }
+                        }
+                        "externalid" => group.external_id = value.as_str().map(String::from),
+                        other => debug!("SCIM: Ignoring unsupported group attribute '{}'", other),
+                    }
+                }
+            }
+            _ => debug!("SCIM: Ignoring unsupported group patch operation '{}' on '{}'", op, path),
+        }
+    }
+    Ok(changes)
+}
+
+//
+// Service provider configuration
+//
+
+#[get("/v2/<_org_id>/ServiceProviderConfig")]
+fn get_service_provider_config(_org_id: String, _scim: ScimHeaders) -> Json<Value> {
+    Json(json!({
+        "schemas": [SCHEMA_SP_CONFIG],
+        "patch": { "supported": true },
+        "bulk": { "supported": false, "maxOperations": 0, "maxPayloadSize": 0 },
+        "filter": { "supported": true, "maxResults": MAX_RESULTS },
+        "changePassword": { "supported": false },
+        "sort": { "supported": false },
+        "etag": { "supported": false },
+        "authenticationSchemes": [{
+            "type": "oauthbearertoken",
+            "name": "OAuth Bearer Token",
+            "description": "Authentication using the organization SCIM token",
+            "primary": true,
+        }],
+    }))
+}
+
+//
+// Users
+//
+
+#[get("/v2/<org_id>/Users?<query..>")]
+fn get_users(org_id: String, query: LenientForm<ListQuery>, _scim: ScimHeaders, conn: DbConn) -> JsonResult {
+    let query = query.into_inner();
+    let filter = match query.filter {
+        Some(ref f) => Some(parse_filter(f)?),
+        None => None,
+    };
+
+    let users: Vec<Value> = UserOrganization::find_by_org(&org_id, &conn)
+        .into_iter()
+        .filter_map(|uo| User::find_by_uuid(&uo.user_uuid, &conn).map(|u| (uo, u)))
+        .filter(|(uo, u)| filter.as_ref().map_or(true, |filter| user_matches_filter(uo, u, filter)))
+        .map(|(uo, u)| user_to_scim(&uo, &u))
+        .collect();
+
+    Ok(Json(list_response(users, &query)))
+}
+
+#[get("/v2/<org_id>/Users/<id>")]
+fn get_user(org_id: String, id: String, _scim: ScimHeaders, conn: DbConn) -> JsonResult {
+    let (user_org, user) = get_user_org_or_404(&id, &org_id, &conn)?;
+    Ok(Json(user_to_scim(&user_org, &user)))
+}
+
+#[post("/v2/<org_id>/Users", data = "<data>")]
+fn post_user(
+    org_id: String,
+    data: Json<ScimUserData>,
+    scim: ScimHeaders,
+    conn: DbConn,
+) -> ApiResult<Custom<Json<Value>>> {
+    let data = data.into_inner();
+
+    let email = match data.email() {
+        Some(email) => email,
+        None => return Err(scim_error(Status::BadRequest, Some("invalidValue"), "A valid email is required")),
+    };
+
+    if UserOrganization::find_by_email_and_org(&email, &org_id, &conn).is_some() {
+        return Err(scim_error(Status::Conflict, Some("uniqueness"), "User already in organization"));
+    }
+
+    let mut user_org_status = if CONFIG.mail_enabled() {
+        UserOrgStatus::Invited as i32
+    } else {
+        UserOrgStatus::Accepted as i32 // Automatically mark user as accepted if no email invites
+    };
+
+    let user = match User::find_by_mail(&email, &conn) {
+        None => {
+            if !CONFIG.invitations_allowed() {
+                return Err(scim_error(Status::BadRequest, None, "Invitations are not allowed"));
+            }
+
+            if !CONFIG.is_email_domain_allowed(&email) {
+                return Err(scim_error(Status::BadRequest, None, "Email domain not eligible for invitations"));
+            }
+
+            if !CONFIG.mail_enabled() {
+                let invitation = Invitation::new(email.clone());
+                invitation.save(&conn)?;
+            }
+
+            let mut user = User::new(email.clone());
+            user.save(&conn)?;
+            user_org_status = UserOrgStatus::Invited as i32;
+            user
+        }
+        Some(user) => user,
+    };
+
+    let mut user_org = UserOrganization::new(user.uuid.clone(), org_id.clone());
+    user_org.status = user_org_status;
+    user_org.external_id = data.externalId;
+    user_org.save(&conn)?;
+
+    if CONFIG.mail_enabled() {
+        // The billing email receives the "invite accepted" notification, as there is no inviting user
+        mail::send_invite(
+            &email,
+            user.language.as_deref(),
+            &user.uuid,
+            Some(org_id),
+            Some(user_org.uuid.clone()),
+            &scim.org.name,
+            Some(scim.org.billing_email.clone()),
+            &conn,
+        )?;
+    }
+
+    Ok(Custom(Status::Created, Json(user_to_scim(&user_org, &user))))
+}
+
+#[put("/v2/<org_id>/Users/<id>", data = "<data>")]
+fn put_user(org_id: String, id: String, data: Json<ScimUserData>, _scim: ScimHeaders, conn: DbConn) -> JsonResult {
+    let data = data.into_inner();
+    let (mut user_org, user) = get_user_org_or_404(&id, &org_id, &conn)?;
+
+    if data.active == Some(false) {
+        let resource = user_to_scim(&user_org, &user);
+        remove_user_org(user_org, &conn)?;
+        return Ok(Json(resource));
+    }
+
+    user_org.external_id = data.externalId;
+    user_org.save(&conn)?;
+
+    Ok(Json(user_to_scim(&user_org, &user)))
+}
+
+#[patch("/v2/<org_id>/Users/<id>", data = "<data>")]
+fn patch_user(org_id: String, id: String, data: Json<ScimPatchData>, _scim: ScimHeaders, conn: DbConn) -> JsonResult {
+    let data = data.into_inner();
+    let (mut user_org, user) = get_user_org_or_404(&id, &org_id, &conn)?;
+
+    // There is no suspended state for memberships, deactivated users are removed from the organization,
+    // which matches how the Directory Connector import handles deleted users.
+    if patch_user_attributes(&mut user_org, data.Operations) {
+        let resource = user_to_scim(&user_org, &user);
+        remove_user_org(user_org, &conn)?;
+        return Ok(Json(resource));
+    }
+
+    user_org.save(&conn)?;
+    Ok(Json(user_to_scim(&user_org, &user)))
+}
+
+#[delete("/v2/<org_id>/Users/<id>")]
+fn delete_user(org_id: String, id: String, _scim: ScimHeaders, conn: DbConn) -> ApiResult<NoContent> {
+    let (user_org, _) = get_user_org_or_404(&id, &org_id, &conn)?;
+    remove_user_org(user_org, &conn)?;
+    Ok(NoContent)
+}
+
+//
+// Groups
+//
+
+#[get("/v2/<org_id>/Groups?<query..>")]
+fn get_groups(org_id: String, query: LenientForm<ListQuery>, _scim: ScimHeaders, conn: DbConn) -> JsonResult {
+    let query = query.into_inner();
+    let filter = match query.filter {
+        Some(ref f) => Some(parse_filter(f)?),
+        None => None,
+    };
+
+    let groups: Vec<Value> = Group::find_by_org(&org_id, &conn)
+        .iter()
+        .filter(|g| filter.as_ref().map_or(true, |filter| group_matches_filter(g, filter)))
+        .map(|g| group_to_scim(g, &conn))
+        .collect();
+
+    Ok(Json(list_response(groups, &query)))
+}
+
+#[get("/v2/<org_id>/Groups/<id>")]
+fn get_group(org_id: String, id: String, _scim: ScimHeaders, conn: DbConn) -> JsonResult {
+    let group = get_group_or_404(&id, &org_id, &conn)?;
+    Ok(Json(group_to_scim(&group, &conn)))
+}
+
+#[post("/v2/<org_id>/Groups", data = "<data>")]
+fn post_group(
+    org_id: String,
+    data: Json<ScimGroupData>,
+    _scim: ScimHeaders,
+    conn: DbConn,
+) -> ApiResult<Custom<Json<Value>>> {
+    let data = data.into_inner();
+
+    if Group::find_by_org(&org_id, &conn).iter().any(|g| g.name == data.displayName) {
+        return Err(scim_error(Status::Conflict, Some("uniqueness"), "Group already exists"));
+    }
+
+    let mut group = Group::new(org_id, data.displayName, data.externalId);
+    group.save(&conn)?;
+    add_group_members(&group, data.members.as_deref().unwrap_or_default(), &conn)?;
+
+    Ok(Custom(Status::Created, Json(group_to_scim(&group, &conn))))
+}
+
+#[put("/v2/<org_id>/Groups/<id>", data = "<data>")]
+fn put_group(org_id: String, id: String, data: Json<ScimGroupData>, _scim: ScimHeaders, conn: DbConn) -> JsonResult {
+    let data = data.into_inner();
+    let mut group = get_group_or_404(&id, &org_id, &conn)?;
+
+    group.name = data.displayName;
+    group.external_id = data.externalId;
+    group.save(&conn)?;
+
+    GroupUser::delete_all_by_group(&group.uuid, &conn)?;
+    add_group_members(&group, data.members.as_deref().unwrap_or_default(), &conn)?;
+
+    Ok(Json(group_to_scim(&group, &conn)))
+}
+
+#[patch("/v2/<org_id>/Groups/<id>", data = "<data>")]
+fn patch_group(org_id: String, id: String, data: Json<ScimPatchData>, _scim: ScimHeaders, conn: DbConn) -> JsonResult {
+    let data = data.into_inner();
+    let mut group = get_group_or_404(&id, &org_id, &conn)?;
+
+    let changes = patch_group_attributes(&mut group, data.Operations)?;
+    group.save(&conn)?;
+
+    for change in changes {
+        match change {
+            MembersChange::Add(members) => add_group_members(&group, &members, &conn)?,
+            MembersChange::Replace(members) => {
+                GroupUser::delete_all_by_group(&group.uuid, &conn)?;
+                add_group_members(&group, &members, &conn)?;
+            }
+            MembersChange::Remove(member_ids) => {
+                for member_id in member_ids {
+                    remove_group_member(&group, &member_id, &conn)?;
+                }
+            }
+            MembersChange::RemoveAll => GroupUser::delete_all_by_group(&group.uuid, &conn)?,
+        }
+    }
+
+    Ok(Json(group_to_scim(&group, &conn)))
+}
+
+#[delete("/v2/<org_id>/Groups/<id>")]
+fn delete_group(org_id: String, id: String, _scim: ScimHeaders, conn: DbConn) -> ApiResult<NoContent> {
+    let group = get_group_or_404(&id, &org_id, &conn)?;
+    group.delete(&conn)?;
+    Ok(NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!(
+            parse_filter(r#"userName eq "john@example.com""#).unwrap(),
+            ("username".to_string(), "john@example.com".to_string())
+        );
+        assert_eq!(
+            parse_filter(r#"displayName EQ "Sales \"EU\"""#).unwrap(),
+            ("displayname".to_string(), "Sales \"EU\"".to_string())
+        );
+        assert_eq!(parse_filter(r#"externalId eq "a b c""#).unwrap().1, "a b c");
+    }
+
+    #[test]
+    fn test_parse_filter_unsupported() {
+        assert!(parse_filter(r#"userName sw "john""#).is_err());
+        assert!(parse_filter("userName eq john").is_err());
+        assert!(parse_filter("userName").is_err());
+        assert!(parse_filter("").is_err());
+    }
+
+    #[test]
+    fn test_parse_member_path() {
+        assert_eq!(parse_member_path(r#"members[value eq "1234"]"#), Some("1234".to_string()));
+        assert_eq!(parse_member_path(r#"members[display eq "1234"]"#), None);
+        assert_eq!(parse_member_path("members"), None);
+    }
+
+    #[test]
+    fn test_list_response_paging() {
+        let resources: Vec<Value> = (0..5).map(|i| json!(i)).collect();
+        let query = ListQuery {
+            filter: None,
+            start_index: Some(2),
+            count: Some(2),
+        };
+
+        let response = list_response(resources, &query);
+        assert_eq!(response["totalResults"], 5);
+        assert_eq!(response["startIndex"], 2);
+        assert_eq!(response["itemsPerPage"], 2);
+        assert_eq!(response["Resources"], json!([1, 2]));
+    }
+
+    fn patch_operations(operations: Value) -> Vec<ScimPatchOperation> {
+        serde_json::from_value::<ScimPatchData>(json!({ "Operations": operations })).unwrap().Operations
+    }
+
+    #[test]
+    fn test_user_filter() {
+        let user = User::new(String::from("john@example.com"));
+        let mut user_org = UserOrganization::new(user.uuid.clone(), String::from("org"));
+        user_org.external_id = Some(String::from("ext-1"));
+
+        let filter = |f: &str| parse_filter(f).unwrap();
+        assert!(user_matches_filter(&user_org, &user, &filter(r#"userName eq "John@Example.com""#)));
+        assert!(user_matches_filter(&user_org, &user, &filter(r#"emails.value eq "john@example.com""#)));
+        assert!(user_matches_filter(&user_org, &user, &filter(r#"externalId eq "ext-1""#)));
+        assert!(user_matches_filter(&user_org, &user, &filter(&format!(r#"id eq "{}""#, user_org.uuid))));
+        assert!(!user_matches_filter(&user_org, &user, &filter(r#"userName eq "jane@example.com""#)));
+        assert!(!user_matches_filter(&user_org, &user, &filter(r#"displayName eq "john@example.com""#)));
+    }
+
+    #[test]
+    fn test_group_filter() {
+        let group = Group::new(String::from("org"), String::from("Sales"), Some(String::from("ext-1")));
+
+        let filter = |f: &str| parse_filter(f).unwrap();
+        assert!(group_matches_filter(&group, &filter(r#"displayName eq "Sales""#)));
+        assert!(group_matches_filter(&group, &filter(r#"externalId eq "ext-1""#)));
+        assert!(group_matches_filter(&group, &filter(&format!(r#"id eq "{}""#, group.uuid))));
+        assert!(!group_matches_filter(&group, &filter(r#"displayName eq "sales""#)));
+        assert!(!group_matches_filter(&group, &filter(r#"userName eq "Sales""#)));
+    }
+
+    #[test]
+    fn test_patch_user() {
+        let mut user_org = UserOrganization::new(String::from("user"), String::from("org"));
+
+        let operations = patch_operations(json!([
+            { "op": "Replace", "path": "externalId", "value": "ext-1" },
+            { "op": "remove", "path": "externalId" },
+        ]));
+        assert!(!patch_user_attributes(&mut user_org, operations));
+        assert_eq!(user_org.external_id, Some(String::from("ext-1")));
+
+        // Azure AD sends the attributes without path, and the booleans as strings
+        let operations = patch_operations(json!([{ "op": "replace", "value": { "active": "False" } }]));
+        assert!(patch_user_attributes(&mut user_org, operations));
+
+        let operations = patch_operations(json!([{ "op": "replace", "path": "active", "value": false }]));
+        assert!(patch_user_attributes(&mut user_org, operations));
+
+        let operations = patch_operations(json!([{ "op": "replace", "path": "active", "value": true }]));
+        assert!(!patch_user_attributes(&mut user_org, operations));
+    }
+
+    #[test]
+    fn test_patch_group() {
+        let mut group = Group::new(String::from("org"), String::from("Sales"), None);
+
+        let operations = patch_operations(json!([
+            { "op": "replace", "value": { "displayName": "Sales EU", "externalId": "ext-1" } },
+            { "op": "add", "path": "members", "value": [{ "value": "member-1" }, { "value": "member-2" }] },
+            { "op": "remove", "path": r#"members[value eq "member-1"]"# },
+            { "op": "remove", "path": "members", "value": [{ "value": "member-2" }] },
+            { "op": "replace", "path": "members", "value": [{ "value": "member-3" }] },
+            { "op": "remove", "path": "members" },
+        ]));
+        let changes = patch_group_attributes(&mut group, operations).unwrap();
+        assert_eq!(group.name, "Sales EU");
+        assert_eq!(group.external_id, Some(String::from("ext-1")));
+
+        assert_eq!(changes.len(), 5);
+        assert!(
+            matches!(&changes[0], MembersChange::Add(members) if members.len() == 2 && members[1].value == "member-2")
+        );
+        assert!(matches!(&changes[1], MembersChange::Remove(ids) if ids == &["member-1"]));
+        assert!(matches!(&changes[2], MembersChange::Remove(ids) if ids == &["member-2"]));
+        assert!(matches!(&changes[3], MembersChange::Replace(members) if members[0].value == "member-3"));
+        assert!(matches!(changes[4], MembersChange::RemoveAll));
+
+        let operations = patch_operations(json!([{ "op": "remove", "path": "members[display eq \"x\"]" }]));
+        assert!(patch_group_attributes(&mut group, operations).is_err());
+
+        let operations = patch_operations(json!([{ "op": "add", "path": "members", "value": "member-1" }]));
+        assert!(patch_group_attributes(&mut group, operations).is_err());
+    }
+
+    #[test]
+    fn test_user_data_email() {
+        let data: ScimUserData = serde_json::from_value(json!({
+            "userName": "login-name",
+            "emails": [{ "value": "other@example.com" }, { "value": "John@Example.com", "primary": true }],
+        }))
+        .unwrap();
+        assert_eq!(data.email(), Some("john@example.com".to_string()));
+
+        let data: ScimUserData = serde_json::from_value(json!({ "userName": "jane@example.com" })).unwrap();
+        assert_eq!(data.email(), Some("jane@example.com".to_string()));
+
+        let data: ScimUserData = serde_json::from_value(json!({ "userName": "jane" })).unwrap();
+        assert_eq!(data.email(), None);
+    }
+
+    //
+    // Endpoints, on a test database
+    //
+
+    #[cfg(sqlite)]
+    use crate::db::{test_pool, DbPool};
+
+    /// An organization with an owner and a member, returns the ids of the organization and of both memberships.
+    #[cfg(sqlite)]
+    fn scim_fixture(conn: &DbConn) -> (String, String, String) {
+        let org = Organization::new(String::from("Org"), String::from("billing@example.com"), None, None);
+        org.save(conn).unwrap();
+
+        let member = |email: &str, atype: UserOrgType, external_id: Option<&str>| {
+            let mut user = User::new(String::from(email));
+            user.save(conn).unwrap();
+            let mut user_org = UserOrganization::new(user.uuid, org.uuid.clone());
+            user_org.atype = atype as i32;
+            user_org.status = UserOrgStatus::Confirmed as i32;
+            user_org.external_id = external_id.map(String::from);
+            user_org.save(conn).unwrap();
+            user_org.uuid
+        };
+        let owner_id = member("owner@example.com", UserOrgType::Owner, None);
+        let member_id = member("member@example.com", UserOrgType::User, Some("ext-member"));
+
+        (org.uuid, owner_id, member_id)
+    }
+
+    #[cfg(sqlite)]
+    fn headers(org_id: &str, pool: &DbPool) -> ScimHeaders {
+        ScimHeaders {
+            org: Organization::find_by_uuid(org_id, &pool.get().unwrap()).unwrap(),
+        }
+    }
+
+    #[cfg(sqlite)]
+    fn list_query(filter: Option<&str>) -> LenientForm<ListQuery> {
+        LenientForm(ListQuery {
+            filter: filter.map(String::from),
+            start_index: None,
+            count: None,
+        })
+    }
+
+    #[cfg(sqlite)]
+    fn patch_data(operations: Value) -> Json<ScimPatchData> {
+        Json(serde_json::from_value(json!({ "Operations": operations })).unwrap())
+    }
+
+    #[test]
+    #[cfg(sqlite)]
+    fn test_users_endpoints() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (org_id, owner_id, member_id) = scim_fixture(&conn);
+        let list = |filter: Option<&str>| {
+            get_users(org_id.clone(), list_query(filter), headers(&org_id, &pool), pool.get().unwrap())
+        };
+
+        let users = list(None).unwrap().into_inner();
+        assert_eq!(users["schemas"], json!([SCHEMA_LIST]));
+        assert_eq!(users["totalResults"], 2);
+
+        let users = list(Some(r#"userName eq "Member@example.com""#)).unwrap().into_inner();
+        assert_eq!(users["totalResults"], 1);
+        assert_eq!(users["Resources"][0]["id"], member_id.as_str());
+        assert_eq!(users["Resources"][0]["externalId"], "ext-member");
+
+        let users = list(Some(r#"externalId eq "unknown""#)).unwrap().into_inner();
+        assert_eq!(users["totalResults"], 0);
+        assert!(list(Some(r#"userName co "member""#)).is_err());
+
+        // Clearing the external id is saved
+        let patch = patch_data(json!([{ "op": "replace", "path": "externalId", "value": null }]));
+        let user = patch_user(org_id.clone(), member_id.clone(), patch, headers(&org_id, &pool), pool.get().unwrap())
+            .unwrap()
+            .into_inner();
+        assert_eq!(user["externalId"], Value::Null);
+        assert_eq!(UserOrganization::find_by_uuid(&member_id, &conn).unwrap().external_id, None);
+
+        // Deactivated users are removed from the organization
+        let patch = patch_data(json!([{ "op": "replace", "value": { "active": false } }]));
+        let user = patch_user(org_id.clone(), member_id.clone(), patch, headers(&org_id, &pool), pool.get().unwrap())
+            .unwrap()
+            .into_inner();
+        assert_eq!(user["id"], member_id.as_str());
+        assert!(UserOrganization::find_by_uuid(&member_id, &conn).is_none());
+        assert!(get_user(org_id.clone(), member_id.clone(), headers(&org_id, &pool), pool.get().unwrap()).is_err());
+
+        // The last owner can't be removed
+        assert!(delete_user(org_id.clone(), owner_id.clone(), headers(&org_id, &pool), pool.get().unwrap()).is_err());
+        assert!(UserOrganization::find_by_uuid(&owner_id, &conn).is_some());
+        assert!(delete_user(org_id.clone(), member_id, headers(&org_id, &pool), pool.get().unwrap()).is_err());
+
+        // Memberships of other organizations aren't visible
+        let (other_org_id, other_owner_id, _) = scim_fixture(&conn);
+        assert!(
+            get_user(org_id.clone(), other_owner_id.clone(), headers(&org_id, &pool), pool.get().unwrap()).is_err()
+        );
+        assert!(delete_user(org_id.clone(), other_owner_id, headers(&org_id, &pool), pool.get().unwrap()).is_err());
+        assert_eq!(list(None).unwrap().into_inner()["totalResults"], 1);
+        assert_eq!(UserOrganization::find_by_org(&other_org_id, &conn).len(), 2);
+    }
+
+    #[test]
+    #[cfg(sqlite)]
+    fn test_groups_endpoints() {
+        let pool = test_pool();
+        let conn = pool.get().unwrap();
+        let (org_id, owner_id, member_id) = scim_fixture(&conn);
+        let members = |group_id: &str| -> Vec<String> {
+            let mut members: Vec<String> =
+                GroupUser::find_by_group(group_id, &conn).into_iter().map(|gu| gu.users_organizations_uuid).collect();
+            members.sort();
+            members
+        };
+
+        let data = Json(
+            serde_json::from_value(json!({
+                "displayName": "Sales",
+                "externalId": "ext-sales",
+                "members": [{ "value": member_id }, { "value": "unknown" }],
+            }))
+            .unwrap(),
+        );
+        let Custom(status, group) =
+            post_group(org_id.clone(), data, headers(&org_id, &pool), pool.get().unwrap()).unwrap();
+        assert_eq!(status, Status::Created);
+        let group_id = group["id"].as_str().unwrap().to_string();
+        assert_eq!(members(&group_id), vec![member_id.clone()]);
+
+        // Group names are unique
+        let data = Json(serde_json::from_value(json!({ "displayName": "Sales" })).unwrap());
+        assert!(post_group(org_id.clone(), data, headers(&org_id, &pool), pool.get().unwrap()).is_err());
+
+        let list = |filter: Option<&str>| {
+            get_groups(org_id.clone(), list_query(filter), headers(&org_id, &pool), pool.get().unwrap())
+                .unwrap()
+                .into_inner()
+        };
+        assert_eq!(list(None)["totalResults"], 1);
+        let groups = list(Some(r#"displayName eq "Sales""#));
+        assert_eq!(groups["Resources"][0]["id"], group_id.as_str());
+        assert_eq!(groups["Resources"][0]["members"][0]["value"], member_id.as_str());
+        assert_eq!(list(Some(r#"externalId eq "other""#))["totalResults"], 0);
+
+        let patch = patch_data(json!([
+            { "op": "add", "path": "members", "value": [{ "value": owner_id }] },
+            { "op": "remove", "path": format!(r#"members[value eq "{}"]"#, member_id) },
+            { "op": "replace", "path": "externalId", "value": null },
+        ]));
+        let group = patch_group(org_id.clone(), group_id.clone(), patch, headers(&org_id, &pool), pool.get().unwrap())
+            .unwrap()
+            .into_inner();
+        assert_eq!(group["externalId"], Value::Null);
+        assert_eq!(members(&group_id), vec![owner_id.clone()]);
+        assert_eq!(Group::find_by_uuid_and_org(&group_id, &org_id, &conn).unwrap().external_id, None);
+
+        let patch = patch_data(json!([{ "op": "remove", "path": "members" }]));
+        patch_group(org_id.clone(), group_id.clone(), patch, headers(&org_id, &pool), pool.get().unwrap()).unwrap();
+        assert!(members(&group_id).is_empty());
+
+        delete_group(org_id.clone(), group_id.clone(), headers(&org_id, &pool), pool.get().unwrap()).unwrap();
+        assert!(Group::find_by_uuid_and_org(&group_id, &org_id, &conn).is_none());
+        assert!(get_group(org_id.clone(), group_id, headers(&org_id, &pool), pool.get().unwrap()).is_err());
+    }
+}
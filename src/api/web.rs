@@ -14,9 +14,9 @@ pub fn routes() -> Vec<Route> {
     // If addding more routes here, consider also adding them to
     // crate::utils::LOGGED_ROUTES to make sure they appear in the log
     if CONFIG.web_vault_enabled() {
-        routes![web_index, app_id, web_files, attachments, alive, static_files]
+        routes![web_index, app_id, jwks, web_files, attachments, alive, static_files]
     } else {
-        routes![attachments, alive, static_files]
+        routes![jwks, attachments, alive, static_files]
     }
 }
 
@@ -54,6 +54,11 @@ fn app_id() -> Cached<Content<Json<Value>>> {
     ))
 }
 
+#[get("/.well-known/jwks.json")]
+fn jwks() -> Json<Value> {
+    Json(crate::jwt_keys::jwks())
+}
+
 #[get("/<p..>", rank = 10)] // Only match this if the other routes don't match
 fn web_files(p: PathBuf) -> Cached<Option<NamedFile>> {
     Cached::long(NamedFile::open(Path::new(&CONFIG.web_vault_folder()).join(p)).ok())
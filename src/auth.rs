@@ -5,20 +5,12 @@ use chrono::{Duration, Utc};
 use num_traits::FromPrimitive;
 use once_cell::sync::Lazy;
 
-use jsonwebtoken::{self, Algorithm, DecodingKey, EncodingKey, Header};
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
 
-use crate::{
-    error::{Error, MapResult},
-    util::read_file,
-    CONFIG,
-};
-
-const JWT_ALGORITHM: Algorithm = Algorithm::RS256;
+use crate::{error::Error, jwt_keys, CONFIG};
 
 pub static DEFAULT_VALIDITY: Lazy<Duration> = Lazy::new(|| Duration::hours(2));
-static JWT_HEADER: Lazy<Header> = Lazy::new(|| Header::new(JWT_ALGORITHM));
 
 pub static JWT_LOGIN_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|login", CONFIG.domain_origin()));
 static JWT_INVITE_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|invite", CONFIG.domain_origin()));
@@ -26,45 +18,23 @@ static JWT_DELETE_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|delete", CONFI
 static JWT_VERIFYEMAIL_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|verifyemail", CONFIG.domain_origin()));
 static JWT_ADMIN_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|admin", CONFIG.domain_origin()));
 static JWT_SEND_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|send", CONFIG.domain_origin()));
-
-static PRIVATE_RSA_KEY_VEC: Lazy<Vec<u8>> = Lazy::new(|| {
-    read_file(&CONFIG.private_rsa_key()).unwrap_or_else(|e| panic!("Error loading private RSA Key.\n{}", e))
-});
-static PRIVATE_RSA_KEY: Lazy<EncodingKey> = Lazy::new(|| {
-    EncodingKey::from_rsa_pem(&PRIVATE_RSA_KEY_VEC).unwrap_or_else(|e| panic!("Error decoding private RSA Key.\n{}", e))
-});
-static PUBLIC_RSA_KEY_VEC: Lazy<Vec<u8>> = Lazy::new(|| {
-    read_file(&CONFIG.public_rsa_key()).unwrap_or_else(|e| panic!("Error loading public RSA Key.\n{}", e))
-});
-static PUBLIC_RSA_KEY: Lazy<DecodingKey> = Lazy::new(|| {
-    DecodingKey::from_rsa_pem(&PUBLIC_RSA_KEY_VEC).unwrap_or_else(|e| panic!("Error decoding public RSA Key.\n{}", e))
-});
+static JWT_WEBAUTHN_REGISTER_ISSUER: Lazy<String> =
+    Lazy::new(|| format!("{}|webauthn_register", CONFIG.domain_origin()));
+static JWT_WEBAUTHN_LOGIN_ISSUER: Lazy<String> = Lazy::new(|| format!("{}|webauthn_login", CONFIG.domain_origin()));
 
 pub fn load_keys() {
-    Lazy::force(&PRIVATE_RSA_KEY);
-    Lazy::force(&PUBLIC_RSA_KEY);
+    jwt_keys::load_keys();
 }
 
 pub fn encode_jwt<T: Serialize>(claims: &T) -> String {
-    match jsonwebtoken::encode(&JWT_HEADER, claims, &PRIVATE_RSA_KEY) {
+    match jwt_keys::encode(claims) {
         Ok(token) => token,
-        Err(e) => panic!("Error encoding jwt {}", e),
+        Err(e) => panic!("Error encoding jwt {:?}", e),
     }
 }
 
 fn decode_jwt<T: DeserializeOwned>(token: &str, issuer: String) -> Result<T, Error> {
-    let validation = jsonwebtoken::Validation {
-        leeway: 30, // 30 seconds
-        validate_exp: true,
-        validate_nbf: true,
-        aud: None,
-        iss: Some(issuer),
-        sub: None,
-        algorithms: vec![JWT_ALGORITHM],
-    };
-
-    let token = token.replace(char::is_whitespace, "");
-    jsonwebtoken::decode(&token, &PUBLIC_RSA_KEY, &validation).map(|d| d.claims).map_res("Error decoding JWT")
+    jwt_keys::decode(token, &issuer)
 }
 
 pub fn decode_login(token: &str) -> Result<LoginJwtClaims, Error> {
@@ -91,6 +61,14 @@ pub fn decode_send(token: &str) -> Result<BasicJwtClaims, Error> {
     decode_jwt(token, JWT_SEND_ISSUER.to_string())
 }
 
+pub fn decode_webauthn_register(token: &str) -> Result<WebAuthnJwtClaims, Error> {
+    decode_jwt(token, JWT_WEBAUTHN_REGISTER_ISSUER.to_string())
+}
+
+pub fn decode_webauthn_login(token: &str) -> Result<WebAuthnJwtClaims, Error> {
+    decode_jwt(token, JWT_WEBAUTHN_LOGIN_ISSUER.to_string())
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginJwtClaims {
     // Not before
@@ -191,13 +169,13 @@ pub fn generate_verify_email_claims(uuid: String) -> BasicJwtClaims {
     }
 }
 
-pub fn generate_admin_claims() -> BasicJwtClaims {
+pub fn generate_admin_claims(sub: String) -> BasicJwtClaims {
     let time_now = Utc::now().naive_utc();
     BasicJwtClaims {
         nbf: time_now.timestamp(),
         exp: (time_now + Duration::minutes(20)).timestamp(),
         iss: JWT_ADMIN_ISSUER.to_string(),
-        sub: "admin_panel".to_string(),
+        sub,
     }
 }
 
@@ -211,13 +189,52 @@ pub fn generate_send_claims(send_id: &str, file_id: &str) -> BasicJwtClaims {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebAuthnJwtClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Subject, the uuid of the user, or empty for a passkey login
+    pub sub: String,
+
+    // The serialized state of the ceremony, which the client sends back along with the authenticator response
+    pub state: String,
+}
+
+pub fn generate_webauthn_register_claims(user_uuid: String, state: String) -> WebAuthnJwtClaims {
+    let time_now = Utc::now().naive_utc();
+    WebAuthnJwtClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + Duration::minutes(5)).timestamp(),
+        iss: JWT_WEBAUTHN_REGISTER_ISSUER.to_string(),
+        sub: user_uuid,
+        state,
+    }
+}
+
+pub fn generate_webauthn_login_claims(user_uuid: Option<String>, state: String) -> WebAuthnJwtClaims {
+    let time_now = Utc::now().naive_utc();
+    WebAuthnJwtClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + Duration::minutes(5)).timestamp(),
+        iss: JWT_WEBAUTHN_LOGIN_ISSUER.to_string(),
+        sub: user_uuid.unwrap_or_default(),
+        state,
+    }
+}
+
 //
 // Bearer token authentication
 //
 use rocket::request::{FromRequest, Outcome, Request};
 
 use crate::db::{
-    models::{CollectionUser, Device, User, UserOrgStatus, UserOrgType, UserOrganization, UserStampException},
+    models::{
+        CollectionUser, Device, ServerRole, User, UserOrgStatus, UserOrgType, UserOrganization, UserStampException,
+    },
     DbConn,
 };
 
@@ -265,6 +282,16 @@ impl<'a, 'r> FromRequest<'a, 'r> for Host {
     }
 }
 
+// Scope of the sessions of the users who didn't set up the two-step login required by `REQUIRE_2FA` in time,
+// instead of "api". Those sessions can only be used to set it up.
+pub const TWOFACTOR_SETUP_SCOPE: &str = "twofactor_setup";
+
+/// Sessions limited to setting up two-step login can only use its endpoints.
+fn is_scope_allowed(scope: &[String], uri_subpath: &str) -> bool {
+    scope.iter().any(|s| s == "api")
+        || (scope.iter().any(|s| s == TWOFACTOR_SETUP_SCOPE) && uri_subpath.starts_with("/api/two-factor"))
+}
+
 pub struct Headers {
     pub host: String,
     pub device: Device,
@@ -298,6 +325,13 @@ impl<'a, 'r> FromRequest<'a, 'r> for Headers {
             Err(_) => err_handler!("Invalid claim"),
         };
 
+        let uri_path = request.uri().path();
+        let uri_subpath = uri_path.strip_prefix(&CONFIG.domain_path()).unwrap_or(uri_path);
+        if !is_scope_allowed(&claims.scope, uri_subpath) {
+            error!(target: "auth", "Forbidden Error: Two-step login has to be set up first");
+            return Outcome::Failure((rocket::http::Status::Forbidden, "Two-step login has to be set up first"));
+        }
+
         let device_uuid = claims.device;
         let user_uuid = claims.sub;
 
@@ -626,6 +660,34 @@ impl<'a, 'r> FromRequest<'a, 'r> for OwnerHeaders {
     }
 }
 
+/// A user with a server role, who uses their login JWT to call the admin panel endpoints
+pub struct ServerRoleHeaders {
+    pub user: User,
+    pub role: ServerRole,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ServerRoleHeaders {
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        server_role_headers(request.guard::<Headers>())
+    }
+}
+
+fn server_role_headers(headers: Outcome<Headers, &'static str>) -> Outcome<ServerRoleHeaders, &'static str> {
+    match headers {
+        Outcome::Forward(_) => Outcome::Forward(()),
+        Outcome::Failure(f) => Outcome::Failure(f),
+        Outcome::Success(headers) => match headers.user.server_role() {
+            Some(role) if headers.user.enabled => Outcome::Success(ServerRoleHeaders {
+                user: headers.user,
+                role,
+            }),
+            _ => err_handler!("You need a server role to call this endpoint"),
+        },
+    }
+}
+
 //
 // Client IP address detection
 //
@@ -660,3 +722,92 @@ impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
         })
     }
 }
+
+//
+// Client language detection
+//
+pub struct ClientLanguage {
+    /// One of the languages we have email translations for, taken from the `Accept-Language` header
+    pub language: Option<&'static str>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientLanguage {
+    type Error = ();
+
+    fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let language = req.headers().get_one("Accept-Language").and_then(crate::mail::language_from_header);
+
+        Outcome::Success(ClientLanguage {
+            language,
+        })
+    }
+}
+
+//
+// Client device type, sent by the clients which aren't logged in yet
+//
+pub struct ClientDeviceType {
+    /// The `Device-Type` header, 14 (unknown browser) when it's missing
+    pub atype: i32,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientDeviceType {
+    type Error = ();
+
+    fn from_request(req: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        let atype = req.headers().get_one("Device-Type").and_then(|t| t.parse().ok()).unwrap_or(14);
+
+        Outcome::Success(ClientDeviceType {
+            atype,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_headers(server_role: Option<ServerRole>, enabled: bool) -> Outcome<Headers, &'static str> {
+        let mut user = User::new(String::from("user@example.com"));
+        user.server_role = server_role.map(|role| role as i32);
+        user.enabled = enabled;
+        let device = Device::new(String::from("device"), user.uuid.clone(), String::from("Firefox"), 10);
+
+        Outcome::Success(Headers {
+            host: String::from("https://vault.example.com"),
+            device,
+            user,
+        })
+    }
+
+    #[test]
+    fn test_server_role_headers() {
+        match server_role_headers(user_headers(Some(ServerRole::UserAdmin), true)) {
+            Outcome::Success(headers) => assert!(headers.role == ServerRole::UserAdmin),
+            _ => panic!("A user with a server role should be accepted"),
+        }
+
+        // Users without a role, or disabled, are rejected instead of falling back to the admin login
+        assert!(server_role_headers(user_headers(None, true)).is_failure());
+        assert!(server_role_headers(user_headers(Some(ServerRole::SuperAdmin), false)).is_failure());
+
+        // The outcome of the login guard is kept otherwise
+        assert!(server_role_headers(Outcome::Forward(())).is_forward());
+        let failure = Outcome::Failure((rocket::http::Status::Unauthorized, "Invalid claim"));
+        assert!(server_role_headers(failure).is_failure());
+    }
+
+    #[test]
+    fn test_is_scope_allowed() {
+        let api = vec![String::from("api"), String::from("offline_access")];
+        assert!(is_scope_allowed(&api, "/api/ciphers"));
+        assert!(is_scope_allowed(&api, "/api/two-factor/authenticator"));
+
+        let setup = vec![String::from(TWOFACTOR_SETUP_SCOPE), String::from("offline_access")];
+        assert!(is_scope_allowed(&setup, "/api/two-factor/authenticator"));
+        assert!(!is_scope_allowed(&setup, "/api/ciphers"));
+        assert!(!is_scope_allowed(&setup, "/api/sync"));
+
+        assert!(!is_scope_allowed(&[String::from("offline_access")], "/api/two-factor/authenticator"));
+    }
+}
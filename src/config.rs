@@ -308,10 +308,14 @@ make_config! {
         attachments_folder:     String, false,  auto,   |c| format!("{}/{}", c.data_folder, "attachments");
         /// Sends folder
         sends_folder:           String, false,  auto,   |c| format!("{}/{}", c.data_folder, "sends");
+        /// Mail folder |> Folder where emails are written when using the "file" mail transport
+        mail_folder:            String, false,  auto,   |c| format!("{}/{}", c.data_folder, "mail");
         /// Templates folder
         templates_folder:       String, false,  auto,   |c| format!("{}/{}", c.data_folder, "templates");
-        /// Session JWT key
+        /// Session JWT key |> RSA key used before the JWT key ring, it's imported into the ring on the first start
         rsa_key_filename:       String, false,  auto,   |c| format!("{}/{}", c.data_folder, "rsa_key");
+        /// JWT keys folder |> Key ring used to sign and verify the JWTs
+        jwt_keys_folder:        String, false,  auto,   |c| format!("{}/{}", c.data_folder, "jwt_keys");
         /// Web vault folder
         web_vault_folder:       String, false,  def,    "web-vault/".to_string();
     },
@@ -333,6 +337,33 @@ make_config! {
         /// Trash purge schedule |> Cron schedule of the job that checks for trashed items to delete permanently.
         /// Defaults to daily. Set blank to disable this job.
         trash_purge_schedule:   String, false,  def,    "0 5 0 * * *".to_string();
+        /// Upload purge schedule |> Cron schedule of the job that cleans up abandoned attachment uploads.
+        /// Defaults to hourly. Set blank to disable this job.
+        upload_purge_schedule:  String, false,  def,    "0 15 * * * *".to_string();
+        /// Icon cache purge schedule |> Cron schedule of the job that removes expired icons from the icon cache, and the
+        /// least recently used ones when it exceeds its max size. Defaults to daily. Set blank to disable this job.
+        icon_cache_purge_schedule: String, false, def,  "0 25 0 * * *".to_string();
+        /// 2FA reminder schedule |> Cron schedule of the job that reminds the users who have to set up two-step login
+        /// before the end of their grace period, more often as it gets closer. Set blank to disable this job.
+        twofactor_reminder_schedule: String, false, def, "0 0 9 * * *".to_string();
+        /// JWT key rotation schedule |> Cron schedule of the job that replaces the JWT signing key with a new one, and
+        /// removes the old keys whose grace period is over. Disabled by default. Set blank to disable this job.
+        jwt_key_rotation_schedule: String, false, def,  String::new();
+        /// Auth request purge schedule |> Cron schedule of the job that removes the expired login with device requests.
+        /// Defaults to hourly. Set blank to disable this job.
+        auth_request_purge_schedule: String, false, def, "0 35 * * * *".to_string();
+        /// Login history purge schedule |> Cron schedule of the job that removes the login history entries older than
+        /// the retention period. Defaults to daily. Set blank to disable this job.
+        login_history_purge_schedule: String, false, def, "0 45 0 * * *".to_string();
+        /// Deleted user purge schedule |> Cron schedule of the job that removes the deleted users whose grace period is over,
+        /// and reminds the others before it ends. Defaults to daily. Set blank to disable this job.
+        user_purge_schedule:    String, false,  def,    "0 55 0 * * *".to_string();
+        /// Failed email purge schedule |> Cron schedule of the job that removes the failed emails older than the retention period
+        /// from the mail queue. Defaults to hourly. Set blank to disable this job.
+        mail_queue_purge_schedule: String, false, def,  "0 50 * * * *".to_string();
+        /// Admin action purge schedule |> Cron schedule of the job that removes the admin actions older than the retention period.
+        /// Defaults to daily. Set blank to disable this job.
+        admin_action_purge_schedule: String, false, def, "0 30 0 * * *".to_string();
     },
 
     /// General settings
@@ -356,10 +387,13 @@ make_config! {
         /// HIBP Api Key |> HaveIBeenPwned API Key, request it here: https://haveibeenpwned.com/API/Key
         hibp_api_key:           Pass,   true,   option;
 
-        /// Per-user attachment storage limit (KB) |> Max kilobytes of attachment storage allowed per user. When this limit is reached, the user will not be allowed to upload further attachments.
+        /// Per-user attachment storage limit (KB) |> Max kilobytes of attachment storage allowed per user. When this limit is reached, the user will not be allowed to upload further attachments. Can be overridden per user in the users overview.
         user_attachment_limit:  i64,    true,   option;
-        /// Per-organization attachment storage limit (KB) |> Max kilobytes of attachment storage allowed per org. When this limit is reached, org members will not be allowed to upload further attachments for ciphers owned by that org.
+        /// Per-organization attachment storage limit (KB) |> Max kilobytes of attachment storage allowed per org. When this limit is reached, org members will not be allowed to upload further attachments for ciphers owned by that org. Can be overridden per organization in the organizations overview.
         org_attachment_limit:   i64,    true,   option;
+        /// Abandoned upload timeout (hours) |> Incomplete attachment uploads which haven't received any data in this
+        /// many hours are deleted. Resumable uploads can be continued until then.
+        attachment_upload_timeout: i64, true,   def,    24;
 
         /// Trash auto-delete days |> Number of days to wait before auto-deleting a trashed item.
         /// If unset, trashed items are not auto-deleted. This setting applies globally, so make
@@ -393,7 +427,15 @@ make_config! {
         /// provides unauthenticated access to potentially sensitive data.
         show_password_hint:     bool,   true,   def,    false;
 
-        /// Admin page token |> The token used to authenticate in this very same page. Changing it here won't deauthorize the current session
+        /// Require 2FA |> Users who have to set up two-step login, either 'all' or a list of comma-separated email domains.
+        /// Blank or 'none' means it's only required by the organization policies
+        require_2fa:            String, true,   def,    "".to_string();
+        /// 2FA grace period (days) |> Days the users have to set up two-step login once it's required. After that, they can
+        /// only log in to set it up
+        require_2fa_grace_period: u64,  true,   def,    7;
+
+        /// Admin page token |> The token used to authenticate in this very same page, either in plain text or as a PBKDF2 hash
+        /// generated with `vaultwarden hash`. Changing it here won't deauthorize the current session
         admin_token:            Pass,   true,   option;
 
         /// Invitation organization name |> Name shown in the invitation emails that don't come from a specific organization
@@ -407,10 +449,26 @@ make_config! {
         ip_header:              String, true,   def,    "X-Real-IP".to_string();
         /// Internal IP header property, used to avoid recomputing each time
         _ip_header_enabled:     bool,   false,  gen,    |c| &c.ip_header.trim().to_lowercase() != "none";
+        /// Icon service |> The predefined icon services are: internal, bitwarden, duckduckgo, google.
+        /// To use a custom icon service, set a URL template with exactly one instance of `{}`, which is replaced with the domain,
+        /// for example `https://icon.example.com/domain/{}`. `internal` refers to the built-in icon fetching implementation.
+        /// With an external service the clients are redirected to it, unless the icons are proxied
+        icon_service:           String, true,   def,    "internal".to_string();
+        /// Internal
+        _icon_service_url:      String, false,  gen,    |c| generate_icon_service_url(&c.icon_service);
+        /// Icon redirect code |> The HTTP status code to use for redirects to an external icon service.
+        /// The supported codes are 301 (legacy permanent), 302 (legacy temporary), 307 (temporary), and 308 (permanent)
+        icon_redirect_code:     u32,    true,   def,    302;
+        /// Proxy external icons |> Fetch the icons from the external icon service on the server and cache them,
+        /// instead of redirecting the clients to it. This keeps the clients' IP addresses hidden from the icon service
+        icon_service_proxy:     bool,   true,   def,    false;
         /// Positive icon cache expiry |> Number of seconds to consider that an already cached icon is fresh. After this period, the icon will be redownloaded
         icon_cache_ttl:         u64,    true,   def,    2_592_000;
         /// Negative icon cache expiry |> Number of seconds before trying to download an icon that failed again.
         icon_cache_negttl:      u64,    true,   def,    259_200;
+        /// Icon cache max size |> Max size of the icon cache in megabytes. When it's exceeded, the least recently used icons are removed.
+        /// Set to 0 to let it grow without limit
+        icon_cache_max_size:    u64,    true,   def,    100;
         /// Icon download timeout |> Number of seconds when to stop attempting to download an icon.
         icon_download_timeout:  u64,    true,   def,    10;
         /// Icon blacklist Regex |> Any domains or IPs that match this regex won't be fetched by the icon service.
@@ -420,9 +478,57 @@ make_config! {
         /// Usefull to secure your internal environment: See https://en.wikipedia.org/wiki/Reserved_IP_addresses for a list of IPs which it will block
         icon_blacklist_non_global_ips:  bool,   true,   def,    true;
 
+        /// Outbound request allowed CIDRs |> Comma-separated list of IP ranges which can be reached by the outbound requests
+        /// to user provided URLs (icons, Duo), even if they are not global IPs. For example an icon service in the local network
+        http_request_allow_cidrs: String, true, option;
+        /// Outbound request denied CIDRs |> Comma-separated list of IP ranges which can never be reached by the outbound requests
+        /// to user provided URLs, even if they are global IPs or allowed
+        http_request_deny_cidrs: String, true,  option;
+        /// Outbound request max redirects |> Max number of redirects followed by the outbound requests to user provided URLs
+        http_request_max_redirects: u32, true,  def,    5;
+        /// Outbound request max response size |> Max size in megabytes of the responses to the outbound requests to user provided URLs
+        http_request_max_size:  u64,    true,   def,    5;
+
+        /// JWT key algorithm |> Algorithm of the new JWT signing keys, either RS256, ES256 or EdDSA (Ed25519).
+        /// It only applies to the keys generated from then on, the current key is kept until the next rotation.
+        jwt_key_algorithm:      String, true,   def,    "RS256".to_string();
+        /// JWT key grace period |> Hours during which the JWTs signed with a rotated key are still accepted.
+        /// The invitation and account deletion links are valid for 5 days (120 hours)
+        jwt_key_grace_period:   u64,    true,   def,    120;
+
         /// Disable Two-Factor remember |> Enabling this would force the users to use a second factor to login every time.
         /// Note that the checkbox would still be present, but ignored.
         disable_2fa_remember:   bool,   true,   def,    false;
+        /// Two-Factor remember lifetime |> Days after which a remembered device has to use the second factor again
+        twofactor_remember_lifetime: u64, true, def,    30;
+
+        /// Session lifetime |> Days after which a device has to log in again, even if it's used every day
+        refresh_token_lifetime: u64,    true,   def,    90;
+        /// Session idle lifetime |> Days without any use after which a device has to log in again
+        refresh_token_idle_lifetime: u64, true, def,    30;
+
+        /// Login with device request lifetime |> Minutes during which a login request from a new device can be approved and used
+        auth_request_lifetime:  u64,    true,   def,    15;
+
+        /// Login history retention (days) |> Days during which the logins and failed login attempts are kept in the history of the users.
+        /// Set to 0 to keep them forever
+        login_history_days:     u64,    true,   def,    90;
+        /// Failed login alert threshold |> Number of failed login attempts in the alert window after which the user is warned by email.
+        /// Set to 0 to disable the alerts
+        login_failure_alert_threshold: u32, true, def,  5;
+        /// Failed login alert window (minutes) |> Period in which the failed login attempts are counted for the alert
+        login_failure_alert_window: u64, true,  def,    15;
+
+        /// Deleted user grace period (days) |> Days during which a deleted user is only disabled and keeps all their data,
+        /// so that an admin can restore the account. Set to 0 to remove the users and their data immediately
+        user_deletion_grace_days: u64,  true,   def,    30;
+        /// Deleted user purge reminder (days) |> Days before the end of the grace period when the deleted user is reminded
+        /// by email that their data will be removed. Set to 0 to disable the reminder
+        user_purge_reminder_days: u64,  true,   def,    7;
+
+        /// Admin action log retention (days) |> Days during which the actions done from the admin panel are kept in the action log.
+        /// Set to 0 to keep them forever
+        admin_actions_days:     u64,    true,   def,    365;
 
         /// Disable authenticator time drifted codes to be valid |> Enabling this only allows the current TOTP code to be valid
         /// TOTP codes of the previous and next 30 seconds will be invalid.
@@ -470,6 +576,9 @@ make_config! {
         yubico_secret_key:      Pass,   true,   option;
         /// Server
         yubico_server:          String, true,   option;
+        /// Offline validation |> Validate the OTPs with the AES key of each YubiKey instead of the Yubico servers.
+        /// The keys are added with their AES key followed by an OTP, as <AES key>:<OTP>
+        yubico_offline:         bool,   true,   def,    false;
     },
 
     /// Global Duo settings (Note that users can override them)
@@ -490,6 +599,8 @@ make_config! {
     smtp: _enable_smtp {
         /// Enabled
         _enable_smtp:                  bool,   true,   def,     true;
+        /// Transport |> How emails are sent. Possible values: "smtp", "sendmail" (pipe them to SENDMAIL_COMMAND) and "file" (write them as .eml files to MAIL_FOLDER, for testing)
+        mail_transport:                String, true,   def,     "smtp".to_string();
         /// Host
         smtp_host:                     String, true,   option;
         /// Enable Secure SMTP |> (Explicit) - Enabling this by default would use STARTTLS (Standard ports 587 or 25)
@@ -502,6 +613,12 @@ make_config! {
         smtp_from:                     String, true,   def,     String::new();
         /// From Name
         smtp_from_name:                String, true,   def,     "Vaultwarden".to_string();
+        /// Reply-To Address |> Address the replies to the emails are sent to, instead of the From address
+        smtp_reply_to:                 String, true,   option;
+        /// Message-ID domain |> Domain used in the Message-ID header of the emails. Defaults to the domain of the From address
+        smtp_message_id_domain:        String, true,   option;
+        /// Extra headers |> JSON object with static headers added to every email, like {"X-Mailer": "Vaultwarden"}
+        smtp_extra_headers:            String, true,   option;
         /// Username
         smtp_username:                 String, true,   option;
         /// Password
@@ -510,6 +627,13 @@ make_config! {
         smtp_auth_mechanism:           String, true,   option;
         /// SMTP connection timeout |> Number of seconds when to stop trying to connect to the SMTP server
         smtp_timeout:                  u64,    true,   def,     15;
+        /// Max delivery attempts |> Number of times the delivery of a queued email is attempted when the SMTP server reports a temporary failure, before it is marked as failed
+        smtp_queue_max_attempts:       u32,    true,   def,     8;
+        /// Retry delay |> Number of seconds to wait before the first retry of a queued email. The delay doubles after every failed attempt
+        smtp_queue_retry_delay:        u64,    true,   def,     60;
+        /// Failed email retention (days) |> Days after which the failed emails are removed from the queue, as their bodies contain the links and codes sent to the users.
+        /// Set to 0 to keep them until they are resent or discarded
+        smtp_queue_failed_retention:   u64,    true,   def,     7;
         /// Server name sent during HELO |> By default this value should be is on the machine's hostname, but might need to be changed in case it trips some anti-spam filters
         helo_name:                     String, true,   option;
         /// Enable SMTP debugging (Know the risks!) |> DANGEROUS: Enabling this will output very detailed SMTP messages. This could contain sensitive information like passwords and usernames! Only enable this during troubleshooting!
@@ -518,12 +642,20 @@ make_config! {
         smtp_accept_invalid_certs:     bool,   true,   def,     false;
         /// Accept Invalid Hostnames (Know the risks!) |> DANGEROUS: Allow invalid hostnames. This option introduces significant vulnerabilities to man-in-the-middle attacks!
         smtp_accept_invalid_hostnames: bool,   true,   def,     false;
+        /// Sendmail command |> Path to the sendmail compatible binary used by the "sendmail" mail transport. Only settable in the environment, as it runs a binary on the server
+        sendmail_command:              String, false,  def,     "sendmail".to_string();
+        /// DKIM selector |> Selector of the DNS record with the public key. Emails are signed when this and DKIM_PRIVATE_KEY_FILE are set
+        dkim_selector:                 String, true,   option;
+        /// DKIM domain |> Domain the emails are signed for. Defaults to the domain of the From address
+        dkim_domain:                   String, true,   option;
+        /// DKIM private key file |> Path to the RSA or Ed25519 private key in PEM format
+        dkim_private_key_file:         String, true,   option;
     },
 
     /// Email 2FA Settings
     email_2fa: _enable_email_2fa {
         /// Enabled |> Disabling will prevent users from setting up new email 2FA and using existing email 2FA configured
-        _enable_email_2fa:      bool,   true,   auto,    |c| c._enable_smtp && c.smtp_host.is_some();
+        _enable_email_2fa:      bool,   true,   auto,    |c| is_mail_configured(c);
         /// Email token size |> Number of digits in an email token (min: 6, max: 19). Note that the Bitwarden clients are hardcoded to mention 6 digit codes regardless of this setting.
         email_token_size:       u32,    true,   def,      6;
         /// Token expiration time |> Maximum time in seconds a token is valid. The time the user has to open email client and copy token.
@@ -566,6 +698,12 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
             println!("[WARNING] `ADMIN_TOKEN` is enabled but has an empty value, so the admin page will be disabled.");
             println!("[WARNING] To enable the admin page without a token, use `DISABLE_ADMIN_TOKEN`.");
         }
+
+        if token.starts_with(crate::crypto::PHC_PBKDF2_PREFIX)
+            && crate::crypto::verify_password_phc("", token).is_none()
+        {
+            err!("`ADMIN_TOKEN` is not a valid PBKDF2 hash, generate it again with `vaultwarden hash`")
+        }
     }
 
     if cfg._enable_duo
@@ -580,19 +718,50 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
     }
 
     if cfg._enable_smtp {
-        if cfg.smtp_host.is_some() == cfg.smtp_from.is_empty() {
-            err!("Both `SMTP_HOST` and `SMTP_FROM` need to be set for email support")
+        match cfg.mail_transport.as_str() {
+            "smtp" => {
+                if cfg.smtp_host.is_some() == cfg.smtp_from.is_empty() {
+                    err!("Both `SMTP_HOST` and `SMTP_FROM` need to be set for email support")
+                }
+            }
+            "sendmail" | "file" => {
+                if cfg.smtp_from.is_empty() {
+                    err!("`SMTP_FROM` needs to be set for email support")
+                }
+            }
+            _ => err!("`MAIL_TRANSPORT` must be one of `smtp`, `sendmail` or `file`"),
         }
 
-        if cfg.smtp_host.is_some() && !cfg.smtp_from.contains('@') {
+        if is_mail_configured(cfg) && !cfg.smtp_from.contains('@') {
             err!("SMTP_FROM does not contain a mandatory @ sign")
         }
 
+        if let Some(ref reply_to) = cfg.smtp_reply_to {
+            if !reply_to.contains('@') {
+                err!("`SMTP_REPLY_TO` does not contain a mandatory @ sign")
+            }
+        }
+
+        if let Some(ref extra_headers) = cfg.smtp_extra_headers {
+            crate::mail::parse_extra_headers(extra_headers)?;
+        }
+
+        if cfg.dkim_selector.is_some() != cfg.dkim_private_key_file.is_some() {
+            err!("Both `DKIM_SELECTOR` and `DKIM_PRIVATE_KEY_FILE` need to be set for DKIM signing")
+        }
+
+        if let (Some(selector), Some(key_file)) = (&cfg.dkim_selector, &cfg.dkim_private_key_file) {
+            let domain = cfg.dkim_domain.clone().unwrap_or_default();
+            if let Err(e) = crate::mail::DkimSigner::load(domain, selector.clone(), key_file) {
+                err!(format!("Error loading the DKIM private key `{}`: {:?}", key_file, e))
+            }
+        }
+
         if cfg.smtp_username.is_some() != cfg.smtp_password.is_some() {
             err!("Both `SMTP_USERNAME` and `SMTP_PASSWORD` need to be set to enable email authentication")
         }
 
-        if cfg._enable_email_2fa && (!cfg._enable_smtp || cfg.smtp_host.is_none()) {
+        if cfg._enable_email_2fa && !is_mail_configured(cfg) {
             err!("To enable email 2FA, SMTP must be configured")
         }
 
@@ -614,9 +783,83 @@ fn validate_config(cfg: &ConfigItems) -> Result<(), Error> {
         }
     }
 
+    // Check if the icon service is valid
+    let icon_service = cfg.icon_service.as_str();
+    match icon_service {
+        "internal" | "bitwarden" | "duckduckgo" | "google" => {}
+        _ => {
+            if !icon_service.starts_with("http://") && !icon_service.starts_with("https://") {
+                err!(format!("Icon service URL `{}` must start with \"http://\" or \"https://\"", icon_service))
+            }
+            match icon_service.matches("{}").count() {
+                1 => {} // nominal
+                0 => err!(format!("Icon service URL `{}` has no placeholder \"{{}}\"", icon_service)),
+                _ => err!(format!("Icon service URL `{}` has more than one placeholder \"{{}}\"", icon_service)),
+            }
+        }
+    }
+
+    if let Some(ref cidrs) = cfg.http_request_allow_cidrs {
+        if let Err(e) = crate::http_client::parse_cidr_list(cidrs) {
+            err!(format!("`HTTP_REQUEST_ALLOW_CIDRS` is invalid: {:?}", e))
+        }
+    }
+
+    if let Some(ref cidrs) = cfg.http_request_deny_cidrs {
+        if let Err(e) = crate::http_client::parse_cidr_list(cidrs) {
+            err!(format!("`HTTP_REQUEST_DENY_CIDRS` is invalid: {:?}", e))
+        }
+    }
+
+    match cfg.icon_redirect_code {
+        301 | 302 | 307 | 308 => {}
+        _ => err!("Only HTTP 301/302 and 307/308 redirects are supported"),
+    }
+
+    if crate::jwt_keys::KeyAlgorithm::from_str(&cfg.jwt_key_algorithm).is_none() {
+        err!("`JWT_KEY_ALGORITHM` must be RS256, ES256 or EdDSA")
+    }
+
+    // The login tokens are valid for 2 hours
+    if cfg.jwt_key_grace_period < 2 {
+        err!("`JWT_KEY_GRACE_PERIOD` must be at least 2 hours")
+    }
+
+    if cfg.login_failure_alert_threshold > 0 && cfg.login_failure_alert_window == 0 {
+        err!("`LOGIN_FAILURE_ALERT_WINDOW` must be at least 1 minute")
+    }
+
+    if cfg.auth_request_lifetime == 0 {
+        err!("`AUTH_REQUEST_LIFETIME` must be at least 1 minute")
+    }
+
+    if cfg.refresh_token_lifetime == 0 || cfg.refresh_token_idle_lifetime == 0 || cfg.twofactor_remember_lifetime == 0 {
+        err!(
+            "`REFRESH_TOKEN_LIFETIME`, `REFRESH_TOKEN_IDLE_LIFETIME` and `TWOFACTOR_REMEMBER_LIFETIME` must be at least 1 day"
+        )
+    }
+
     Ok(())
 }
 
+/// Returns the URL template of the icon service, with `{}` in place of the domain.
+/// The built-in implementation has an empty template.
+fn generate_icon_service_url(icon_service: &str) -> String {
+    match icon_service {
+        "internal" => "",
+        "bitwarden" => "https://icons.bitwarden.net/{}/icon.png",
+        "duckduckgo" => "https://icons.duckduckgo.com/ip3/{}.ico",
+        "google" => "https://www.google.com/s2/favicons?domain={}&sz=32",
+        _ => icon_service,
+    }
+    .to_string()
+}
+
+/// Email is enabled and the selected transport has everything it needs.
+fn is_mail_configured(cfg: &ConfigItems) -> bool {
+    cfg._enable_smtp && (cfg.smtp_host.is_some() || cfg.mail_transport != "smtp")
+}
+
 /// Extracts an RFC 6454 web origin from a URL.
 fn extract_url_origin(url: &str) -> String {
     match Url::parse(url) {
@@ -670,7 +913,10 @@ impl Config {
         //let builder = other.remove(&self.inner.read().unwrap()._env);
 
         // TODO: Remove values that are defaults, above only checks those set by env and not the defaults
-        let builder = other;
+        let mut builder = other;
+
+        // The sendmail command runs a binary on the server, so it can't be changed from the admin panel
+        builder.sendmail_command = self.inner.read().unwrap()._usr.sendmail_command.clone();
 
         // Serialize now before we consume the builder
         let config_str = serde_json::to_string_pretty(&builder)?;
@@ -734,6 +980,11 @@ impl Config {
         }
     }
 
+    /// Tests whether the specified user has to set up two-step login, because of the require_2fa setting.
+    pub fn is_2fa_required(&self, email: &str) -> bool {
+        is_2fa_required_by(&self.require_2fa(), email)
+    }
+
     /// Tests whether the specified user is allowed to create an organization.
     pub fn is_org_creation_allowed(&self, email: &str) -> bool {
         let users = self.org_creation_users();
@@ -773,12 +1024,9 @@ impl Config {
     pub fn private_rsa_key(&self) -> String {
         format!("{}.pem", CONFIG.rsa_key_filename())
     }
-    pub fn public_rsa_key(&self) -> String {
-        format!("{}.pub.pem", CONFIG.rsa_key_filename())
-    }
     pub fn mail_enabled(&self) -> bool {
         let inner = &self.inner.read().unwrap().config;
-        inner._enable_smtp && inner.smtp_host.is_some()
+        is_mail_configured(inner)
     }
 
     pub fn get_duo_akey(&self) -> String {
@@ -820,6 +1068,30 @@ impl Config {
             hb.render(name, data).map_err(Into::into)
         }
     }
+
+    /// Renders the `<name>.<lang><ext>` template when it exists, and `<name><ext>` otherwise.
+    pub fn render_localized_template<T: serde::ser::Serialize>(
+        &self,
+        name: &str,
+        ext: &str,
+        lang: Option<&str>,
+        data: &T,
+    ) -> Result<String, crate::error::Error> {
+        let render = |hb: &Handlebars<'_>| {
+            let localized = lang.map(|lang| format!("{}.{}{}", name, lang, ext));
+            match localized {
+                Some(localized) if hb.get_template(&localized).is_some() => hb.render(&localized, data),
+                _ => hb.render(&format!("{}{}", name, ext), data),
+            }
+        };
+
+        if CONFIG.reload_templates() {
+            warn!("RELOADING TEMPLATES");
+            render(&load_templates(CONFIG.templates_folder())).map_err(Into::into)
+        } else {
+            render(&CONFIG.inner.read().unwrap().templates).map_err(Into::into)
+        }
+    }
 }
 
 use handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderError, Renderable};
@@ -846,32 +1118,54 @@ where
         }};
     }
 
+    // Registers an email template along with its bundled translations
+    macro_rules! reg_email {
+        ($name:expr) => {{
+            reg!($name, ".html");
+            reg!(concat!($name, ".de"), ".html");
+            reg!(concat!($name, ".es"), ".html");
+            reg!(concat!($name, ".fr"), ".html");
+        }};
+    }
+
     // First register default templates here
     reg!("email/email_header");
     reg!("email/email_footer");
     reg!("email/email_footer_text");
 
-    reg!("email/change_email", ".html");
-    reg!("email/delete_account", ".html");
-    reg!("email/invite_accepted", ".html");
-    reg!("email/invite_confirmed", ".html");
-    reg!("email/new_device_logged_in", ".html");
-    reg!("email/pw_hint_none", ".html");
-    reg!("email/pw_hint_some", ".html");
-    reg!("email/send_2fa_removed_from_org", ".html");
-    reg!("email/send_org_invite", ".html");
-    reg!("email/twofactor_email", ".html");
-    reg!("email/verify_email", ".html");
-    reg!("email/welcome", ".html");
-    reg!("email/welcome_must_verify", ".html");
-    reg!("email/smtp_test", ".html");
+    reg_email!("email/account_deleted");
+    reg_email!("email/account_purge_reminder");
+    reg_email!("email/change_email");
+    reg_email!("email/delete_account");
+    reg_email!("email/invite_accepted");
+    reg_email!("email/invite_confirmed");
+    reg_email!("email/login_failures");
+    reg_email!("email/new_device_logged_in");
+    reg_email!("email/pw_hint_none");
+    reg_email!("email/pw_hint_some");
+    reg_email!("email/send_2fa_removed_from_org");
+    reg_email!("email/send_org_invite");
+    reg_email!("email/session_revoked");
+    reg_email!("email/storage_quota_warning");
+    reg_email!("email/twofactor_email");
+    reg_email!("email/twofactor_required");
+    reg_email!("email/verify_email");
+    reg_email!("email/welcome");
+    reg_email!("email/welcome_must_verify");
+    reg_email!("email/smtp_test");
+    reg!("email/admin_broadcast", ".html");
 
     reg!("admin/base");
     reg!("admin/login");
     reg!("admin/settings");
     reg!("admin/users");
     reg!("admin/organizations");
+    reg!("admin/storage");
+    reg!("admin/mail_queue");
+    reg!("admin/email_preview");
+    reg!("admin/broadcast");
     reg!("admin/diagnostics");
+    reg!("admin/admins");
 
     // And then load user templates to overwrite the defaults
     // Use .hbs extension for the files
@@ -920,3 +1214,35 @@ fn js_escape_helper<'reg, 'rc>(
     out.write(&escaped_value)?;
     Ok(())
 }
+
+fn is_2fa_required_by(required: &str, email: &str) -> bool {
+    if required.is_empty() || required == "none" {
+        false
+    } else if required == "all" {
+        true
+    } else {
+        let email_domain = match email.rsplit_once('@') {
+            Some((_, domain)) => domain.to_lowercase(),
+            None => return false,
+        };
+        required.split(',').any(|d| d.trim().to_lowercase() == email_domain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_2fa_required_by() {
+        assert!(!is_2fa_required_by("", "user@example.com"));
+        assert!(!is_2fa_required_by("none", "user@example.com"));
+        assert!(is_2fa_required_by("all", "user@example.com"));
+
+        assert!(is_2fa_required_by("example.com, Example.net", "user@example.net"));
+        assert!(is_2fa_required_by("example.com", "User@EXAMPLE.com"));
+        assert!(!is_2fa_required_by("example.com", "user@sub.example.com"));
+        assert!(!is_2fa_required_by("example.com", "user@example.org"));
+        assert!(!is_2fa_required_by("example.com", "example.com"));
+    }
+}
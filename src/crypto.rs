@@ -3,7 +3,7 @@
 //
 use std::num::NonZeroU32;
 
-use data_encoding::HEXLOWER;
+use data_encoding::{BASE64_NOPAD, HEXLOWER};
 use ring::{digest, hmac, pbkdf2};
 
 use crate::error::Error;
@@ -25,6 +25,38 @@ pub fn verify_password_hash(secret: &[u8], salt: &[u8], previous: &[u8], iterati
     pbkdf2::verify(DIGEST_ALG, iterations, salt, secret, previous).is_ok()
 }
 
+//
+// PBKDF2 hashes in the PHC string format, for the secrets which are only checked by the server
+//
+pub const PHC_PBKDF2_PREFIX: &str = "$pbkdf2-sha256$";
+const PHC_PBKDF2_ITERATIONS: u32 = 310_000;
+
+pub fn hash_password_phc(secret: &str) -> String {
+    let salt = get_random(vec![0u8; 16]);
+    let hash = hash_password(secret.as_bytes(), &salt, PHC_PBKDF2_ITERATIONS);
+
+    format!(
+        "{}{}${}${}",
+        PHC_PBKDF2_PREFIX,
+        PHC_PBKDF2_ITERATIONS,
+        BASE64_NOPAD.encode(&salt),
+        BASE64_NOPAD.encode(&hash)
+    )
+}
+
+/// Returns `None` if `phc` isn't a valid `$pbkdf2-sha256$<iterations>$<salt>$<hash>` string
+pub fn verify_password_phc(secret: &str, phc: &str) -> Option<bool> {
+    let mut parts = phc.strip_prefix(PHC_PBKDF2_PREFIX)?.split('$');
+    let iterations: u32 = parts.next()?.parse().ok().filter(|i| *i > 0)?;
+    let salt = BASE64_NOPAD.decode(parts.next()?.as_bytes()).ok()?;
+    let hash = BASE64_NOPAD.decode(parts.next()?.as_bytes()).ok()?;
+    if parts.next().is_some() || hash.len() != OUTPUT_LEN {
+        return None;
+    }
+
+    Some(verify_password_hash(secret.as_bytes(), &salt, &hash, iterations))
+}
+
 //
 // HMAC
 //
@@ -35,6 +67,28 @@ pub fn hmac_sign(key: &str, data: &str) -> String {
     HEXLOWER.encode(signature.as_ref())
 }
 
+//
+// SHA-256 digest
+//
+pub fn sha256_hex(data: &[u8]) -> String {
+    HEXLOWER.encode(digest::digest(&digest::SHA256, data).as_ref())
+}
+
+/// Same as `sha256_hex`, for data which might not fit in memory, like attachment files
+pub fn sha256_hex_reader<R: std::io::Read>(mut reader: R) -> std::io::Result<String> {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    let mut buffer = [0u8; 64 * 1024];
+
+    loop {
+        match reader.read(&mut buffer)? {
+            0 => break,
+            n => ctx.update(&buffer[..n]),
+        }
+    }
+
+    Ok(HEXLOWER.encode(ctx.finish().as_ref()))
+}
+
 //
 // Random values
 //
@@ -92,3 +146,19 @@ pub fn ct_eq<T: AsRef<[u8]>, U: AsRef<[u8]>>(a: T, b: U) -> bool {
 
     verify_slices_are_equal(a.as_ref(), b.as_ref()).is_ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_phc() {
+        let phc = hash_password_phc("correct horse battery staple");
+        assert!(phc.starts_with(PHC_PBKDF2_PREFIX));
+        assert_eq!(verify_password_phc("correct horse battery staple", &phc), Some(true));
+        assert_eq!(verify_password_phc("wrong", &phc), Some(false));
+
+        assert_eq!(verify_password_phc("secret", "secret"), None);
+        assert_eq!(verify_password_phc("secret", "$pbkdf2-sha256$0$c2FsdA$aGFzaA"), None);
+    }
+}
@@ -311,8 +311,28 @@ mod sqlite_migrations {
         embedded_migrations::run_with_output(&connection, &mut std::io::stdout())?;
         Ok(())
     }
+
+    /// Creates a database in a temporary file with all the migrations applied, for the tests which need one.
+    #[cfg(test)]
+    pub fn test_pool() -> super::DbPool {
+        use diesel::{
+            r2d2::{ConnectionManager, Pool},
+            Connection,
+        };
+
+        let path = std::env::temp_dir().join(format!("vaultwarden-test-{}.sqlite3", crate::util::get_uuid()));
+        let url = path.to_str().expect("Invalid temporary path").to_string();
+        let connection = diesel::sqlite::SqliteConnection::establish(&url).expect("Error creating test database");
+        embedded_migrations::run(&connection).expect("Error running migrations on the test database");
+
+        let pool = Pool::builder().max_size(4).build(ConnectionManager::new(&url)).expect("Error creating test pool");
+        super::DbPool::sqlite(pool)
+    }
 }
 
+#[cfg(all(test, sqlite))]
+pub use sqlite_migrations::test_pool;
+
 #[cfg(mysql)]
 mod mysql_migrations {
     #[allow(unused_imports)]
@@ -0,0 +1,218 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+use crate::api::EmptyResult;
+use crate::crypto;
+use crate::db::DbConn;
+use crate::error::MapResult;
+use crate::CONFIG;
+
+/// Hash of a random password, checked when logging in with an unknown name, so that it takes as long as with a known one.
+static DUMMY_PASSWORD_HASH: Lazy<String> = Lazy::new(|| crypto::hash_password_phc(&crate::util::get_uuid()));
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[table_name = "admins"]
+    #[changeset_options(treat_none_as_null="true")]
+    #[primary_key(uuid)]
+    pub struct Admin {
+        pub uuid: String,
+        pub name: String,
+        // PBKDF2 hash of the password, in the PHC string format
+        pub password_hash: String,
+        // Base32 encoded TOTP secret, if the admin set up TOTP
+        pub totp_secret: Option<String>,
+        // Last TOTP time step used to log in, to reject replayed codes
+        pub totp_last_used: i32,
+        pub created_at: NaiveDateTime,
+        pub last_login_at: Option<NaiveDateTime>,
+    }
+
+    #[derive(Identifiable, Queryable, Insertable)]
+    #[table_name = "admin_actions"]
+    #[primary_key(uuid)]
+    pub struct AdminAction {
+        pub uuid: String,
+        // None when the admin logged in with the admin token
+        pub admin_uuid: Option<String>,
+        pub admin_name: String,
+        pub action: String,
+        // The user, organization, setting... the action was done on
+        pub target: Option<String>,
+        pub ip: String,
+        pub created_at: NaiveDateTime,
+    }
+}
+
+/// Local methods
+impl Admin {
+    pub fn new(name: String, password: &str) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            name,
+            password_hash: crypto::hash_password_phc(password),
+            totp_secret: None,
+            totp_last_used: 0,
+            created_at: Utc::now().naive_utc(),
+            last_login_at: None,
+        }
+    }
+
+    pub fn check_valid_password(&self, password: &str) -> bool {
+        crypto::verify_password_phc(password, &self.password_hash).unwrap_or(false)
+    }
+
+    /// Spends the same time as `check_valid_password`, for when there is no admin to check the password of.
+    pub fn check_dummy_password(password: &str) {
+        crypto::verify_password_phc(password, &DUMMY_PASSWORD_HASH);
+    }
+
+    pub fn set_password(&mut self, password: &str) {
+        self.password_hash = crypto::hash_password_phc(password);
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Id": self.uuid,
+            "Name": self.name,
+            "TotpEnabled": self.totp_secret.is_some(),
+            "CreatedAt": format_date(&self.created_at),
+            "LastLoginAt": self.last_login_at.as_ref().map(format_date),
+        })
+    }
+}
+
+impl AdminAction {
+    pub fn new(
+        admin_uuid: Option<String>,
+        admin_name: String,
+        action: &str,
+        target: Option<String>,
+        ip: String,
+    ) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            admin_uuid,
+            admin_name,
+            action: action.to_string(),
+            target,
+            ip,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Id": self.uuid,
+            "AdminId": self.admin_uuid,
+            "AdminName": self.admin_name,
+            "Action": self.action,
+            "Target": self.target,
+            "IpAddress": self.ip,
+            "CreatedAt": format_date(&self.created_at),
+        })
+    }
+}
+
+/// Database methods
+impl Admin {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(admins::table)
+                    .values(AdminDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving admin")
+            }
+            postgresql {
+                let value = AdminDb::to_db(self);
+                diesel::insert_into(admins::table)
+                    .values(&value)
+                    .on_conflict(admins::uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving admin")
+            }
+        }
+    }
+
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(admins::table.filter(admins::uuid.eq(self.uuid)))
+                .execute(conn)
+                .map_res("Error deleting admin")
+        }}
+    }
+
+    pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            admins::table
+                .filter(admins::uuid.eq(uuid))
+                .first::<AdminDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub fn find_by_name(name: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            admins::table
+                .filter(admins::name.eq(name))
+                .first::<AdminDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub fn get_all(conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            admins::table
+                .order(admins::name.asc())
+                .load::<AdminDb>(conn)
+                .expect("Error loading admins")
+                .from_db()
+        }}
+    }
+}
+
+impl AdminAction {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::insert_into(admin_actions::table)
+                .values(AdminActionDb::to_db(self))
+                .execute(conn)
+                .map_res("Error saving admin action")
+        }}
+    }
+
+    pub fn purge_old(conn: &DbConn) -> EmptyResult {
+        if CONFIG.admin_actions_days() == 0 {
+            return Ok(());
+        }
+
+        let limit = Utc::now().naive_utc() - Duration::days(CONFIG.admin_actions_days() as i64);
+        db_run! { conn: {
+            diesel::delete(admin_actions::table.filter(admin_actions::created_at.lt(limit)))
+                .execute(conn)
+                .map_res("Error purging admin actions")
+        }}
+    }
+
+    /// The latest actions, newest first
+    pub fn find_latest(limit: i64, conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            admin_actions::table
+                .order(admin_actions::created_at.desc())
+                .limit(limit)
+                .load::<AdminActionDb>(conn)
+                .expect("Error loading admin actions")
+                .from_db()
+        }}
+    }
+}
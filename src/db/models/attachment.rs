@@ -1,5 +1,6 @@
 use std::io::ErrorKind;
 
+use chrono::{Duration, NaiveDateTime, Utc};
 use serde_json::Value;
 
 use super::Cipher;
@@ -18,6 +19,18 @@ db_object! {
         pub file_size: i32,
         pub akey: Option<String>,
     }
+
+    #[derive(Identifiable, Queryable, Insertable, Associations, AsChangeset)]
+    #[table_name = "attachment_uploads"]
+    #[changeset_options(treat_none_as_null="true")]
+    #[belongs_to(super::Attachment, foreign_key = "attachment_id")]
+    #[primary_key(attachment_id)]
+    pub struct AttachmentUpload {
+        pub attachment_id: String,
+        pub file_hash: Option<String>, // Hex encoded SHA-256, as declared by the client
+        pub created_at: NaiveDateTime,
+        pub updated_at: NaiveDateTime,
+    }
 }
 
 /// Local methods
@@ -36,6 +49,12 @@ impl Attachment {
         format!("{}/{}/{}", CONFIG.attachments_folder(), self.cipher_uuid, self.id)
     }
 
+    /// Path of the file where the data is written while the upload is in progress.
+    /// It is in the same folder as the final file, so that it can be atomically renamed once complete.
+    pub fn get_upload_path(&self) -> String {
+        format!("{}.part", self.get_file_path())
+    }
+
     pub fn get_url(&self, host: &str) -> String {
         format!("{}/attachments/{}/{}", host, self.cipher_uuid, self.id)
     }
@@ -46,7 +65,7 @@ impl Attachment {
             "Url": self.get_url(host),
             "FileName": self.file_name,
             "Size": self.file_size.to_string(),
-            "SizeName": crate::util::get_display_size(self.file_size),
+            "SizeName": crate::util::get_display_size(self.file_size as i64),
             "Key": self.akey,
             "Object": "attachment"
         })
@@ -93,6 +112,11 @@ impl Attachment {
     }
 
     pub fn delete(&self, conn: &DbConn) -> EmptyResult {
+        if let Some(upload) = AttachmentUpload::find_by_attachment(&self.id, conn) {
+            upload.delete(conn)?;
+            crate::util::delete_file(&self.get_upload_path()).ok();
+        }
+
         db_run! { conn: {
             crate::util::retry(
                 || diesel::delete(attachments::table.filter(attachments::id.eq(&self.id))).execute(conn),
@@ -133,6 +157,15 @@ impl Attachment {
         }}
     }
 
+    pub fn get_all(conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            attachments::table
+                .load::<AttachmentDb>(conn)
+                .expect("Error loading attachments")
+                .from_db()
+        }}
+    }
+
     pub fn find_by_cipher(cipher_uuid: &str, conn: &DbConn) -> Vec<Self> {
         db_run! { conn: {
             attachments::table
@@ -199,3 +232,86 @@ impl Attachment {
         }}
     }
 }
+
+impl AttachmentUpload {
+    pub fn new(attachment_id: String, file_hash: Option<String>) -> Self {
+        let now = Utc::now().naive_utc();
+
+        Self {
+            attachment_id,
+            file_hash: file_hash.map(|h| h.to_lowercase()),
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    /// Uploads which haven't received any data since this date are abandoned, see `ATTACHMENT_UPLOAD_TIMEOUT`.
+    pub fn stale_limit() -> NaiveDateTime {
+        Utc::now().naive_utc() - Duration::hours(CONFIG.attachment_upload_timeout())
+    }
+
+    pub fn is_stale(&self, limit: &NaiveDateTime) -> bool {
+        self.updated_at < *limit
+    }
+
+    pub fn save(&mut self, conn: &DbConn) -> EmptyResult {
+        self.updated_at = Utc::now().naive_utc();
+
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(attachment_uploads::table)
+                    .values(AttachmentUploadDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving attachment upload")
+            }
+            postgresql {
+                let value = AttachmentUploadDb::to_db(self);
+                diesel::insert_into(attachment_uploads::table)
+                    .values(&value)
+                    .on_conflict(attachment_uploads::attachment_id)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving attachment upload")
+            }
+        }
+    }
+
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(attachment_uploads::table.filter(attachment_uploads::attachment_id.eq(self.attachment_id)))
+                .execute(conn)
+                .map_res("Error deleting attachment upload")
+        }}
+    }
+
+    pub fn find_by_attachment(attachment_id: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            attachment_uploads::table
+                .filter(attachment_uploads::attachment_id.eq(attachment_id))
+                .first::<AttachmentUploadDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub fn find_all(conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            attachment_uploads::table
+                .load::<AttachmentUploadDb>(conn)
+                .expect("Error loading attachment uploads")
+                .from_db()
+        }}
+    }
+
+    /// Uploads which haven't received any data since `dt`.
+    pub fn find_stale(dt: &NaiveDateTime, conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            attachment_uploads::table
+                .filter(attachment_uploads::updated_at.lt(dt))
+                .load::<AttachmentUploadDb>(conn)
+                .expect("Error loading attachment uploads")
+                .from_db()
+        }}
+    }
+}
@@ -0,0 +1,275 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde_json::Value;
+
+use crate::api::{ApiResult, EmptyResult};
+use crate::crypto;
+use crate::db::DbConn;
+use crate::error::MapResult;
+use crate::CONFIG;
+
+use super::User;
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, Associations, AsChangeset)]
+    #[table_name = "auth_requests"]
+    #[changeset_options(treat_none_as_null="true")]
+    #[belongs_to(User, foreign_key = "user_uuid")]
+    #[primary_key(uuid)]
+    pub struct AuthRequest {
+        pub uuid: String,
+        pub user_uuid: String,
+
+        // The device which wants to log in, it doesn't exist in the devices table yet
+        pub request_device_identifier: String,
+        pub device_type: i32,
+        pub request_ip: String,
+
+        // The device which approved or denied the request
+        pub response_device_id: Option<String>,
+
+        // SHA-256 of the access code, which the requesting device uses as password to log in
+        pub access_code: String,
+        pub public_key: String,
+        // The user key, encrypted with the public key of the requesting device
+        pub enc_key: Option<String>,
+        pub master_password_hash: Option<String>,
+
+        // None while the request is pending
+        pub approved: Option<bool>,
+        pub creation_date: NaiveDateTime,
+        pub response_date: Option<NaiveDateTime>,
+        // Set once the requesting device has logged in, the request can't be used again
+        pub authentication_date: Option<NaiveDateTime>,
+    }
+}
+
+/// Local methods
+impl AuthRequest {
+    pub fn new(
+        user_uuid: String,
+        request_device_identifier: String,
+        device_type: i32,
+        request_ip: String,
+        access_code: &str,
+        public_key: String,
+    ) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            user_uuid,
+            request_device_identifier,
+            device_type,
+            request_ip,
+            response_device_id: None,
+            access_code: crypto::sha256_hex(access_code.as_bytes()),
+            public_key,
+            enc_key: None,
+            master_password_hash: None,
+            approved: None,
+            creation_date: Utc::now().naive_utc(),
+            response_date: None,
+            authentication_date: None,
+        }
+    }
+
+    pub fn check_access_code(&self, access_code: &str) -> bool {
+        crypto::ct_eq(crypto::sha256_hex(access_code.as_bytes()), &self.access_code)
+    }
+
+    pub fn expiration_date(&self) -> NaiveDateTime {
+        self.creation_date + Duration::minutes(CONFIG.auth_request_lifetime() as i64)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Utc::now().naive_utc() > self.expiration_date()
+    }
+
+    /// An approved request which wasn't used to log in yet
+    pub fn is_usable(&self) -> bool {
+        self.approved == Some(true) && self.authentication_date.is_none() && !self.is_expired()
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Id": self.uuid,
+            "PublicKey": self.public_key,
+            "RequestDeviceType": self.device_type,
+            "RequestIpAddress": self.request_ip,
+            "Key": self.enc_key,
+            "MasterPasswordHash": self.master_password_hash,
+            "CreationDate": format_date(&self.creation_date),
+            "RequestApproved": self.approved,
+            "ResponseDate": self.response_date.as_ref().map(format_date),
+            "Origin": CONFIG.domain_origin(),
+            "Object": "auth-request"
+        })
+    }
+}
+
+/// Database methods
+impl AuthRequest {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                match diesel::replace_into(auth_requests::table)
+                    .values(AuthRequestDb::to_db(self))
+                    .execute(conn)
+                {
+                    Ok(_) => Ok(()),
+                    // Record already exists and causes a Foreign Key Violation because replace_into() wants to delete the record first.
+                    Err(diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::ForeignKeyViolation, _)) => {
+                        diesel::update(auth_requests::table)
+                            .filter(auth_requests::uuid.eq(&self.uuid))
+                            .set(AuthRequestDb::to_db(self))
+                            .execute(conn)
+                            .map_res("Error saving auth request")
+                    }
+                    Err(e) => Err(e.into()),
+                }.map_res("Error saving auth request")
+            }
+            postgresql {
+                let value = AuthRequestDb::to_db(self);
+                diesel::insert_into(auth_requests::table)
+                    .values(&value)
+                    .on_conflict(auth_requests::uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving auth request")
+            }
+        }
+    }
+
+    /// Marks the request as used to log in, unless a concurrent login already did. Returns whether it was marked.
+    pub fn mark_used(&mut self, conn: &DbConn) -> ApiResult<bool> {
+        let now = Utc::now().naive_utc();
+        let marked = db_run! { conn: {
+            diesel::update(
+                auth_requests::table
+                    .filter(auth_requests::uuid.eq(&self.uuid))
+                    .filter(auth_requests::authentication_date.is_null()),
+            )
+            .set(auth_requests::authentication_date.eq(now))
+            .execute(conn)
+            .map(|rows| rows == 1)
+            .map_res("Error saving auth request")
+        }}?;
+
+        if marked {
+            self.authentication_date = Some(now);
+        }
+        Ok(marked)
+    }
+
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(auth_requests::table.filter(auth_requests::uuid.eq(self.uuid)))
+                .execute(conn)
+                .map_res("Error deleting auth request")
+        }}
+    }
+
+    pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            auth_requests::table
+                .filter(auth_requests::uuid.eq(uuid))
+                .first::<AuthRequestDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub fn find_by_uuid_and_user(uuid: &str, user_uuid: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            auth_requests::table
+                .filter(auth_requests::uuid.eq(uuid))
+                .filter(auth_requests::user_uuid.eq(user_uuid))
+                .first::<AuthRequestDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            auth_requests::table
+                .filter(auth_requests::user_uuid.eq(user_uuid))
+                .order(auth_requests::creation_date.desc())
+                .load::<AuthRequestDb>(conn)
+                .expect("Error loading auth requests")
+                .from_db()
+        }}
+    }
+
+    /// Removes the requests which can't be used anymore, the used ones included
+    pub fn purge_expired(conn: &DbConn) -> EmptyResult {
+        let limit = Utc::now().naive_utc() - Duration::minutes(CONFIG.auth_request_lifetime() as i64);
+        db_run! { conn: {
+            diesel::delete(auth_requests::table.filter(auth_requests::creation_date.lt(limit)))
+                .execute(conn)
+                .map_res("Error purging auth requests")
+        }}
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(auth_requests::table.filter(auth_requests::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting auth requests")
+        }}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth_request() -> AuthRequest {
+        AuthRequest::new(
+            String::from("user"),
+            String::from("device"),
+            10,
+            String::from("127.0.0.1"),
+            "access code",
+            String::from("public key"),
+        )
+    }
+
+    #[test]
+    fn test_check_access_code() {
+        let auth_request = auth_request();
+        assert!(auth_request.access_code != "access code");
+        assert!(auth_request.check_access_code("access code"));
+        assert!(!auth_request.check_access_code("other code"));
+        assert!(!auth_request.check_access_code(""));
+    }
+
+    #[test]
+    fn test_is_expired() {
+        let mut auth_request = auth_request();
+        assert!(!auth_request.is_expired());
+
+        auth_request.creation_date -= Duration::minutes(CONFIG.auth_request_lifetime() as i64 + 1);
+        assert!(auth_request.is_expired());
+    }
+
+    #[test]
+    fn test_is_usable() {
+        let mut auth_request = auth_request();
+        assert!(!auth_request.is_usable());
+
+        auth_request.approved = Some(false);
+        assert!(!auth_request.is_usable());
+
+        auth_request.approved = Some(true);
+        assert!(auth_request.is_usable());
+
+        auth_request.authentication_date = Some(Utc::now().naive_utc());
+        assert!(!auth_request.is_usable());
+
+        auth_request.authentication_date = None;
+        auth_request.creation_date -= Duration::minutes(CONFIG.auth_request_lifetime() as i64 + 1);
+        assert!(!auth_request.is_usable());
+    }
+}
@@ -0,0 +1,193 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable)]
+    #[table_name = "broadcasts"]
+    #[primary_key(uuid)]
+    pub struct Broadcast {
+        pub uuid: String,
+        pub subject: String,
+        pub body: String,
+        // Description of the selected recipients, shown in the admin panel
+        pub recipients: String,
+        pub created_at: NaiveDateTime,
+    }
+
+    #[derive(Identifiable, Queryable, Insertable)]
+    #[table_name = "broadcast_recipients"]
+    #[primary_key(broadcast_uuid, email)]
+    pub struct BroadcastRecipient {
+        pub broadcast_uuid: String,
+        pub email: String,
+        pub status: i32,
+        pub last_error: Option<String>,
+        pub updated_at: NaiveDateTime,
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum BroadcastStatus {
+    Pending = 0,
+    Sent = 1,
+    Failed = 2,
+}
+
+/// Local methods
+impl Broadcast {
+    pub fn new(subject: String, body: String, recipients: String) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            subject,
+            body,
+            recipients,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn to_json(&self, conn: &DbConn) -> Value {
+        use crate::util::format_date;
+
+        let count = |status: BroadcastStatus| BroadcastRecipient::count_by_status(&self.uuid, status, conn);
+
+        json!({
+            "Id": self.uuid,
+            "Subject": self.subject,
+            "Body": self.body,
+            "Recipients": self.recipients,
+            "CreatedAt": format_date(&self.created_at),
+            "PendingCount": count(BroadcastStatus::Pending),
+            "SentCount": count(BroadcastStatus::Sent),
+            "FailedCount": count(BroadcastStatus::Failed),
+        })
+    }
+}
+
+impl BroadcastRecipient {
+    pub fn new(broadcast_uuid: String, email: String) -> Self {
+        Self {
+            broadcast_uuid,
+            email,
+            status: BroadcastStatus::Pending as i32,
+            last_error: None,
+            updated_at: Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Email": self.email,
+            "Status": self.status,
+            "LastError": self.last_error,
+            "UpdatedAt": format_date(&self.updated_at),
+        })
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl Broadcast {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::insert_into(broadcasts::table)
+                .values(BroadcastDb::to_db(self))
+                .execute(conn)
+                .map_res("Error saving broadcast")
+        }}
+    }
+
+    pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            broadcasts::table
+                .filter(broadcasts::uuid.eq(uuid))
+                .first::<BroadcastDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub fn find_all(conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            broadcasts::table
+                .order(broadcasts::created_at.desc())
+                .load::<BroadcastDb>(conn)
+                .expect("Error loading broadcasts")
+                .from_db()
+        }}
+    }
+}
+
+impl BroadcastRecipient {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::insert_into(broadcast_recipients::table)
+                .values(BroadcastRecipientDb::to_db(self))
+                .execute(conn)
+                .map_res("Error saving broadcast recipient")
+        }}
+    }
+
+    pub fn insert_all(recipients: &[Self], conn: &DbConn) -> EmptyResult {
+        // Stays below the limit of 999 bind parameters of SQLite
+        for chunk in recipients.chunks(100) {
+            db_run! { conn: {
+                let values: Vec<BroadcastRecipientDb> = chunk.iter().map(BroadcastRecipientDb::to_db).collect();
+                diesel::insert_into(broadcast_recipients::table)
+                    .values(&values)
+                    .execute(conn)
+                    .map_res("Error saving broadcast recipients")?;
+            }}
+        }
+        Ok(())
+    }
+
+    pub fn update_status(
+        broadcast_uuid: &str,
+        email: &str,
+        status: BroadcastStatus,
+        last_error: Option<String>,
+        conn: &DbConn,
+    ) -> EmptyResult {
+        db_run! { conn: {
+            diesel::update(broadcast_recipients::table)
+                .filter(broadcast_recipients::broadcast_uuid.eq(broadcast_uuid))
+                .filter(broadcast_recipients::email.eq(email))
+                .set((
+                    broadcast_recipients::status.eq(status as i32),
+                    broadcast_recipients::last_error.eq(last_error),
+                    broadcast_recipients::updated_at.eq(Utc::now().naive_utc()),
+                ))
+                .execute(conn)
+                .map_res("Error updating broadcast recipient")
+        }}
+    }
+
+    pub fn find_by_broadcast(broadcast_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            broadcast_recipients::table
+                .filter(broadcast_recipients::broadcast_uuid.eq(broadcast_uuid))
+                .order(broadcast_recipients::email.asc())
+                .load::<BroadcastRecipientDb>(conn)
+                .expect("Error loading broadcast recipients")
+                .from_db()
+        }}
+    }
+
+    pub fn count_by_status(broadcast_uuid: &str, status: BroadcastStatus, conn: &DbConn) -> i64 {
+        db_run! { conn: {
+            broadcast_recipients::table
+                .filter(broadcast_recipients::broadcast_uuid.eq(broadcast_uuid))
+                .filter(broadcast_recipients::status.eq(status as i32))
+                .count()
+                .first::<i64>(conn)
+                .ok()
+                .unwrap_or(0)
+        }}
+    }
+}
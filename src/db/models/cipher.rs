@@ -42,6 +42,15 @@ db_object! {
     }
 }
 
+/// The owner of a cipher and its dates, without the encrypted data, for the reports which go over all the ciphers.
+pub struct CipherOwnership {
+    pub uuid: String,
+    pub user_uuid: Option<String>,
+    pub organization_uuid: Option<String>,
+    pub updated_at: NaiveDateTime,
+    pub deleted_at: Option<NaiveDateTime>,
+}
+
 #[allow(dead_code)]
 pub enum RepromptType {
     None = 0,
@@ -504,6 +513,32 @@ impl Cipher {
         }}
     }
 
+    pub fn get_all_ownerships(conn: &DbConn) -> Vec<CipherOwnership> {
+        let ciphers = db_run! {conn: {
+            ciphers::table
+                .select((
+                    ciphers::uuid,
+                    ciphers::user_uuid,
+                    ciphers::organization_uuid,
+                    ciphers::updated_at,
+                    ciphers::deleted_at,
+                ))
+                .load::<(String, Option<String>, Option<String>, NaiveDateTime, Option<NaiveDateTime>)>(conn)
+                .expect("Error loading ciphers")
+        }};
+
+        ciphers
+            .into_iter()
+            .map(|(uuid, user_uuid, organization_uuid, updated_at, deleted_at)| CipherOwnership {
+                uuid,
+                user_uuid,
+                organization_uuid,
+                updated_at,
+                deleted_at,
+            })
+            .collect()
+    }
+
     pub fn count_by_org(org_uuid: &str, conn: &DbConn) -> i64 {
         db_run! {conn: {
             ciphers::table
@@ -524,6 +559,20 @@ impl Cipher {
         }}
     }
 
+    /// Find all ciphers which belong to an organization that no longer exists.
+    pub fn find_without_organization(conn: &DbConn) -> Vec<Self> {
+        db_run! {conn: {
+            ciphers::table
+                .left_join(organizations::table.on(
+                    ciphers::organization_uuid.eq(organizations::uuid.nullable())
+                ))
+                .filter(ciphers::organization_uuid.is_not_null())
+                .filter(organizations::uuid.is_null())
+                .select(ciphers::all_columns)
+                .load::<CipherDb>(conn).expect("Error loading ciphers").from_db()
+        }}
+    }
+
     /// Find all ciphers that were deleted before the specified datetime.
     pub fn find_deleted_before(dt: &NaiveDateTime, conn: &DbConn) -> Vec<Self> {
         db_run! {conn: {
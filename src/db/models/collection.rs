@@ -374,6 +374,24 @@ impl CollectionUser {
         }}
     }
 
+    /// Find all entries of users which are no longer members of the organization of the collection.
+    pub fn find_without_membership(conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            users_collections::table
+                .inner_join(collections::table.on(collections::uuid.eq(users_collections::collection_uuid)))
+                .left_join(users_organizations::table.on(
+                    users_organizations::user_uuid.eq(users_collections::user_uuid).and(
+                        users_organizations::org_uuid.eq(collections::org_uuid)
+                    )
+                ))
+                .filter(users_organizations::uuid.is_null())
+                .select(users_collections::all_columns)
+                .load::<CollectionUserDb>(conn)
+                .expect("Error loading users_collections")
+                .from_db()
+        }}
+    }
+
     pub fn delete_all_by_collection(collection_uuid: &str, conn: &DbConn) -> EmptyResult {
         CollectionUser::find_by_collection(collection_uuid, conn).iter().for_each(|collection| {
             User::update_uuid_revision(&collection.user_uuid, conn);
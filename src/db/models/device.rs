@@ -1,4 +1,6 @@
-use chrono::{NaiveDateTime, Utc};
+use std::collections::HashMap;
+
+use chrono::{Duration, NaiveDateTime, Utc};
 
 use super::User;
 use crate::CONFIG;
@@ -22,13 +24,21 @@ db_object! {
         pub push_token: Option<String>,
 
         pub refresh_token: String,
+        // The refresh token replaced by the current one, presenting it again means that one of them was stolen
+        pub previous_refresh_token: Option<String>,
+        pub refresh_token_issued_at: Option<NaiveDateTime>,
+        // Login which started the current chain of refresh tokens
+        pub session_started_at: Option<NaiveDateTime>,
 
         pub twofactor_remember: Option<String>,
+        pub twofactor_remember_issued_at: Option<NaiveDateTime>,
     }
 }
 
 /// Local methods
 impl Device {
+    const REFRESH_TOKEN_REUSE_GRACE_SECONDS: i64 = 30;
+
     pub fn new(uuid: String, user_uuid: String, name: String, atype: i32) -> Self {
         let now = Utc::now().naive_utc();
 
@@ -43,7 +53,12 @@ impl Device {
 
             push_token: None,
             refresh_token: String::new(),
+            previous_refresh_token: None,
+            refresh_token_issued_at: None,
+            session_started_at: None,
+
             twofactor_remember: None,
+            twofactor_remember_issued_at: None,
         }
     }
 
@@ -53,23 +68,75 @@ impl Device {
 
         let twofactor_remember = BASE64.encode(&crypto::get_random(vec![0u8; 180]));
         self.twofactor_remember = Some(twofactor_remember.clone());
+        self.twofactor_remember_issued_at = Some(Utc::now().naive_utc());
 
         twofactor_remember
     }
 
     pub fn delete_twofactor_remember(&mut self) {
         self.twofactor_remember = None;
+        self.twofactor_remember_issued_at = None;
     }
 
-    pub fn refresh_tokens(&mut self, user: &super::User, orgs: Vec<super::UserOrganization>) -> (String, i64) {
-        // If there is no refresh token, we create one
-        if self.refresh_token.is_empty() {
-            use crate::crypto;
-            use data_encoding::BASE64URL;
+    pub fn is_twofactor_remember_expired(&self) -> bool {
+        // Tokens remembered before the expiration was added don't have an issue date, they can't be older than the device
+        let issued_at = self.twofactor_remember_issued_at.unwrap_or(self.created_at);
+        let lifetime = Duration::days(CONFIG.twofactor_remember_lifetime() as i64);
+        issued_at + lifetime < Utc::now().naive_utc()
+    }
 
-            self.refresh_token = BASE64URL.encode(&crypto::get_random_64());
+    /// Starts a new chain of refresh tokens, after the user logged in.
+    pub fn start_session(&mut self) {
+        let now = Utc::now().naive_utc();
+        self.refresh_token = Self::generate_refresh_token();
+        self.previous_refresh_token = None;
+        self.refresh_token_issued_at = Some(now);
+        self.session_started_at = Some(now);
+    }
+
+    /// Replaces the refresh token after it was used, the chain keeps the start date of the session.
+    pub fn rotate_refresh_token(&mut self) {
+        let refresh_token = std::mem::replace(&mut self.refresh_token, Self::generate_refresh_token());
+        self.previous_refresh_token = Some(refresh_token);
+        self.refresh_token_issued_at = Some(Utc::now().naive_utc());
+    }
+
+    /// Whether the refresh token replaced by the current one can still be used, as clients can refresh
+    /// several times at once with the same token. After this, using it means that it was stolen.
+    pub fn is_previous_refresh_token_usable(&self) -> bool {
+        match self.refresh_token_issued_at {
+            Some(issued_at) => {
+                self.previous_refresh_token.is_some()
+                    && issued_at + Duration::seconds(Self::REFRESH_TOKEN_REUSE_GRACE_SECONDS) >= Utc::now().naive_utc()
+            }
+            None => false,
         }
+    }
 
+    /// Whether the session is over, because it's too old or because the refresh token wasn't used for too long.
+    pub fn is_refresh_token_expired(&self) -> bool {
+        let now = Utc::now().naive_utc();
+        // Devices which logged in before the rotation was added are handled as if the session started at their last use
+        let issued_at = self.refresh_token_issued_at.unwrap_or(self.updated_at);
+        let started_at = self.session_started_at.unwrap_or(issued_at);
+
+        started_at + Duration::days(CONFIG.refresh_token_lifetime() as i64) < now
+            || issued_at + Duration::days(CONFIG.refresh_token_idle_lifetime() as i64) < now
+    }
+
+    fn generate_refresh_token() -> String {
+        use crate::crypto;
+        use data_encoding::BASE64URL;
+
+        BASE64URL.encode(&crypto::get_random_64())
+    }
+
+    pub fn refresh_tokens(
+        &mut self,
+        user: &super::User,
+        orgs: Vec<super::UserOrganization>,
+        scope: Vec<String>,
+    ) -> (String, i64) {
         // Update the expiration of the device and the last update date
         let time_now = Utc::now().naive_utc();
         self.updated_at = time_now;
@@ -99,7 +166,7 @@ impl Device {
 
             sstamp: user.security_stamp.to_string(),
             device: self.uuid.to_string(),
-            scope: vec!["api".into(), "offline_access".into()],
+            scope,
             amr: vec!["Application".into()],
         };
 
@@ -109,7 +176,7 @@ impl Device {
 
 use crate::db::DbConn;
 
-use crate::api::EmptyResult;
+use crate::api::{ApiResult, EmptyResult};
 use crate::error::MapResult;
 
 /// Database methods
@@ -134,6 +201,27 @@ impl Device {
         }
     }
 
+    /// Saves a refresh token rotated with `rotate_refresh_token`, unless the previous one was already rotated
+    /// by a concurrent request. Returns whether it was saved.
+    pub fn save_rotated_refresh_token(&self, previous_refresh_token: &str, conn: &DbConn) -> ApiResult<bool> {
+        db_run! { conn: {
+            diesel::update(
+                devices::table
+                    .filter(devices::uuid.eq(&self.uuid))
+                    .filter(devices::refresh_token.eq(previous_refresh_token)),
+            )
+            .set((
+                devices::refresh_token.eq(&self.refresh_token),
+                devices::previous_refresh_token.eq(&self.previous_refresh_token),
+                devices::refresh_token_issued_at.eq(&self.refresh_token_issued_at),
+                devices::updated_at.eq(&self.updated_at),
+            ))
+            .execute(conn)
+            .map(|rows| rows == 1)
+            .map_res("Error saving device")
+        }}
+    }
+
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
         db_run! { conn: {
             diesel::delete(devices::table.filter(devices::uuid.eq(self.uuid)))
@@ -169,6 +257,16 @@ impl Device {
         }}
     }
 
+    pub fn find_by_previous_refresh_token(refresh_token: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            devices::table
+                .filter(devices::previous_refresh_token.eq(refresh_token))
+                .first::<DeviceDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
     pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
         db_run! { conn: {
             devices::table
@@ -189,4 +287,62 @@ impl Device {
                 .from_db()
         }}
     }
+
+    /// Returns the last time each user was active on any of their devices.
+    pub fn find_last_active_by_users(conn: &DbConn) -> HashMap<String, NaiveDateTime> {
+        let last_active: Vec<(String, Option<NaiveDateTime>)> = db_run! { conn: {
+            devices::table
+                .group_by(devices::user_uuid)
+                .select((devices::user_uuid, diesel::dsl::max(devices::updated_at)))
+                .load::<(String, Option<NaiveDateTime>)>(conn)
+                .expect("Error loading devices")
+        }};
+        last_active.into_iter().filter_map(|(user_uuid, updated_at)| Some((user_uuid, updated_at?))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate_refresh_token() {
+        let mut device = Device::new(String::from("device"), String::from("user"), String::from("Firefox"), 10);
+        device.start_session();
+        let first_token = device.refresh_token.clone();
+        assert!(device.previous_refresh_token.is_none());
+        assert!(!device.is_previous_refresh_token_usable());
+
+        device.rotate_refresh_token();
+        assert!(device.refresh_token != first_token);
+        assert_eq!(device.previous_refresh_token, Some(first_token));
+        assert!(device.is_previous_refresh_token_usable());
+
+        // Once the grace period is over, reusing the previous token is handled as a theft
+        let issued_at = Utc::now().naive_utc() - Duration::seconds(Device::REFRESH_TOKEN_REUSE_GRACE_SECONDS + 1);
+        device.refresh_token_issued_at = Some(issued_at);
+        assert!(!device.is_previous_refresh_token_usable());
+    }
+
+    #[test]
+    fn test_is_refresh_token_expired() {
+        let mut device = Device::new(String::from("device"), String::from("user"), String::from("Firefox"), 10);
+        device.start_session();
+        assert!(!device.is_refresh_token_expired());
+
+        let now = Utc::now().naive_utc();
+        device.refresh_token_issued_at = Some(now - Duration::days(CONFIG.refresh_token_idle_lifetime() as i64 + 1));
+        assert!(device.is_refresh_token_expired());
+
+        device.rotate_refresh_token();
+        assert!(!device.is_refresh_token_expired());
+        device.session_started_at = Some(now - Duration::days(CONFIG.refresh_token_lifetime() as i64 + 1));
+        assert!(device.is_refresh_token_expired());
+
+        // Devices from before the rotation was added use their last update
+        device.refresh_token_issued_at = None;
+        device.session_started_at = None;
+        device.updated_at = now;
+        assert!(!device.is_refresh_token_expired());
+    }
 }
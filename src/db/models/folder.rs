@@ -215,4 +215,17 @@ impl FolderCipher {
                 .from_db()
         }}
     }
+
+    /// Find all entries which reference a folder that no longer exists.
+    pub fn find_without_folder(conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            folders_ciphers::table
+                .left_join(folders::table.on(folders::uuid.eq(folders_ciphers::folder_uuid)))
+                .filter(folders::uuid.is_null())
+                .select(folders_ciphers::all_columns)
+                .load::<FolderCipherDb>(conn)
+                .expect("Error loading folders")
+                .from_db()
+        }}
+    }
 }
@@ -0,0 +1,190 @@
+use chrono::{NaiveDateTime, Utc};
+
+use super::{Organization, UserOrganization};
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, Associations, AsChangeset)]
+    #[table_name = "groups"]
+    #[changeset_options(treat_none_as_null="true")]
+    #[belongs_to(Organization, foreign_key = "org_uuid")]
+    #[primary_key(uuid)]
+    pub struct Group {
+        pub uuid: String,
+        pub org_uuid: String,
+        pub name: String,
+        pub external_id: Option<String>,
+        pub created_at: NaiveDateTime,
+        pub updated_at: NaiveDateTime,
+    }
+
+    #[derive(Identifiable, Queryable, Insertable, Associations)]
+    #[table_name = "groups_users"]
+    #[belongs_to(Group, foreign_key = "group_uuid")]
+    #[belongs_to(UserOrganization, foreign_key = "users_organizations_uuid")]
+    #[primary_key(group_uuid, users_organizations_uuid)]
+    pub struct GroupUser {
+        pub group_uuid: String,
+        pub users_organizations_uuid: String,
+    }
+}
+
+/// Local methods
+impl Group {
+    pub fn new(org_uuid: String, name: String, external_id: Option<String>) -> Self {
+        let now = Utc::now().naive_utc();
+
+        Self {
+            uuid: crate::util::get_uuid(),
+            org_uuid,
+            name,
+            external_id,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+
+/// Database methods
+impl Group {
+    pub fn save(&mut self, conn: &DbConn) -> EmptyResult {
+        self.updated_at = Utc::now().naive_utc();
+
+        db_run! { conn:
+            sqlite, mysql {
+                match diesel::replace_into(groups::table)
+                    .values(GroupDb::to_db(self))
+                    .execute(conn)
+                {
+                    Ok(_) => Ok(()),
+                    // Record already exists and causes a Foreign Key Violation because replace_into() wants to delete the record first.
+                    Err(diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::ForeignKeyViolation, _)) => {
+                        diesel::update(groups::table)
+                            .filter(groups::uuid.eq(&self.uuid))
+                            .set(GroupDb::to_db(self))
+                            .execute(conn)
+                            .map_res("Error saving group")
+                    }
+                    Err(e) => Err(e.into()),
+                }.map_res("Error saving group")
+            }
+            postgresql {
+                let value = GroupDb::to_db(self);
+                diesel::insert_into(groups::table)
+                    .values(&value)
+                    .on_conflict(groups::uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving group")
+            }
+        }
+    }
+
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        GroupUser::delete_all_by_group(&self.uuid, conn)?;
+
+        db_run! { conn: {
+            diesel::delete(groups::table.filter(groups::uuid.eq(self.uuid)))
+                .execute(conn)
+                .map_res("Error deleting group")
+        }}
+    }
+
+    pub fn delete_all_by_organization(org_uuid: &str, conn: &DbConn) -> EmptyResult {
+        for group in Self::find_by_org(org_uuid, conn) {
+            group.delete(conn)?;
+        }
+        Ok(())
+    }
+
+    pub fn find_by_uuid_and_org(uuid: &str, org_uuid: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            groups::table
+                .filter(groups::uuid.eq(uuid))
+                .filter(groups::org_uuid.eq(org_uuid))
+                .first::<GroupDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub fn find_by_org(org_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            groups::table
+                .filter(groups::org_uuid.eq(org_uuid))
+                .load::<GroupDb>(conn)
+                .expect("Error loading groups")
+                .from_db()
+        }}
+    }
+}
+
+impl GroupUser {
+    pub fn save(group_uuid: &str, users_organizations_uuid: &str, conn: &DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(groups_users::table)
+                    .values((
+                        groups_users::group_uuid.eq(group_uuid),
+                        groups_users::users_organizations_uuid.eq(users_organizations_uuid),
+                    ))
+                    .execute(conn)
+                    .map_res("Error adding user to group")
+            }
+            postgresql {
+                diesel::insert_into(groups_users::table)
+                    .values((
+                        groups_users::group_uuid.eq(group_uuid),
+                        groups_users::users_organizations_uuid.eq(users_organizations_uuid),
+                    ))
+                    .on_conflict((groups_users::group_uuid, groups_users::users_organizations_uuid))
+                    .do_nothing()
+                    .execute(conn)
+                    .map_res("Error adding user to group")
+            }
+        }
+    }
+
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(
+                groups_users::table
+                    .filter(groups_users::group_uuid.eq(&self.group_uuid))
+                    .filter(groups_users::users_organizations_uuid.eq(&self.users_organizations_uuid)),
+            )
+            .execute(conn)
+            .map_res("Error removing user from group")
+        }}
+    }
+
+    pub fn find_by_group(group_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            groups_users::table
+                .filter(groups_users::group_uuid.eq(group_uuid))
+                .load::<GroupUserDb>(conn)
+                .expect("Error loading group users")
+                .from_db()
+        }}
+    }
+
+    pub fn delete_all_by_group(group_uuid: &str, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(groups_users::table.filter(groups_users::group_uuid.eq(group_uuid)))
+                .execute(conn)
+                .map_res("Error removing users from group")
+        }}
+    }
+
+    pub fn delete_all_by_user_org(users_organizations_uuid: &str, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(groups_users::table.filter(groups_users::users_organizations_uuid.eq(users_organizations_uuid)))
+                .execute(conn)
+                .map_res("Error removing user from groups")
+        }}
+    }
+}
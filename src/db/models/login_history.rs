@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde_json::Value;
+
+use crate::api::EmptyResult;
+use crate::db::DbConn;
+use crate::error::MapResult;
+use crate::CONFIG;
+
+use super::{Device, User};
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, Associations)]
+    #[table_name = "login_history"]
+    #[belongs_to(User, foreign_key = "user_uuid")]
+    #[primary_key(uuid)]
+    pub struct LoginHistory {
+        pub uuid: String,
+        pub user_uuid: String,
+        // Copied from the device, which may not exist for a failed login or could be deleted later
+        pub device_uuid: String,
+        pub device_name: String,
+        pub device_type: i32,
+        pub ip: String,
+        pub method: i32,
+        pub success: bool,
+        // The provider used for the two-step login, if any
+        pub twofactor_type: Option<i32>,
+        pub created_at: NaiveDateTime,
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum LoginMethod {
+    Password = 0,
+    RefreshToken = 1,
+    Passkey = 2,
+    AuthRequest = 3,
+}
+
+/// Local methods
+impl LoginHistory {
+    pub fn new(
+        user_uuid: String,
+        device: &Device,
+        ip: String,
+        method: LoginMethod,
+        success: bool,
+        twofactor_type: Option<i32>,
+    ) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            user_uuid,
+            device_uuid: device.uuid.clone(),
+            device_name: device.name.clone(),
+            device_type: device.atype,
+            ip,
+            method: method as i32,
+            success,
+            twofactor_type,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Id": self.uuid,
+            "DeviceId": self.device_uuid,
+            "DeviceName": self.device_name,
+            "DeviceType": self.device_type,
+            "IpAddress": self.ip,
+            "Method": self.method,
+            "Success": self.success,
+            "TwoFactorProvider": self.twofactor_type,
+            "Date": format_date(&self.created_at),
+            "Object": "loginHistory"
+        })
+    }
+}
+
+/// Database methods
+impl LoginHistory {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::insert_into(login_history::table)
+                .values(LoginHistoryDb::to_db(self))
+                .execute(conn)
+                .map_res("Error saving login history")
+        }}
+    }
+
+    /// The latest entries of the user, newest first
+    pub fn find_by_user(user_uuid: &str, limit: i64, conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            login_history::table
+                .filter(login_history::user_uuid.eq(user_uuid))
+                .order(login_history::created_at.desc())
+                .limit(limit)
+                .load::<LoginHistoryDb>(conn)
+                .expect("Error loading login history")
+                .from_db()
+        }}
+    }
+
+    /// The last successful login of every user who has one, token refreshes excluded, by user
+    pub fn find_last_logins(conn: &DbConn) -> HashMap<String, Self> {
+        let last_dates: HashMap<String, NaiveDateTime> = db_run! { conn: {
+            login_history::table
+                .filter(login_history::success.eq(true))
+                .filter(login_history::method.ne(LoginMethod::RefreshToken as i32))
+                .group_by(login_history::user_uuid)
+                .select((login_history::user_uuid, diesel::dsl::max(login_history::created_at)))
+                .load::<(String, Option<NaiveDateTime>)>(conn)
+                .expect("Error loading last logins")
+        }}
+        .into_iter()
+        .filter_map(|(user_uuid, date)| Some((user_uuid, date?)))
+        .collect();
+
+        // Then the entries themselves, for their details. The dates are sent in chunks, as the number of
+        // parameters of a query is limited.
+        let dates: Vec<NaiveDateTime> = last_dates.values().copied().collect();
+        let mut last_logins = HashMap::new();
+        for dates in dates.chunks(500) {
+            let entries: Vec<Self> = db_run! { conn: {
+                login_history::table
+                    .filter(login_history::success.eq(true))
+                    .filter(login_history::method.ne(LoginMethod::RefreshToken as i32))
+                    .filter(login_history::created_at.eq_any(dates))
+                    .load::<LoginHistoryDb>(conn)
+                    .expect("Error loading last logins")
+                    .from_db()
+            }};
+
+            for entry in entries {
+                if last_dates.get(&entry.user_uuid) == Some(&entry.created_at) {
+                    last_logins.insert(entry.user_uuid.clone(), entry);
+                }
+            }
+        }
+        last_logins
+    }
+
+    pub fn count_failures_by_user_since(user_uuid: &str, since: &NaiveDateTime, conn: &DbConn) -> i64 {
+        db_run! { conn: {
+            login_history::table
+                .filter(login_history::user_uuid.eq(user_uuid))
+                .filter(login_history::success.eq(false))
+                .filter(login_history::created_at.ge(since))
+                .count()
+                .first(conn)
+                .unwrap_or(0)
+        }}
+    }
+
+    /// Removes the entries older than `LOGIN_HISTORY_DAYS`
+    pub fn purge_old(conn: &DbConn) -> EmptyResult {
+        if CONFIG.login_history_days() == 0 {
+            return Ok(());
+        }
+
+        let limit = Utc::now().naive_utc() - Duration::days(CONFIG.login_history_days() as i64);
+        db_run! { conn: {
+            diesel::delete(login_history::table.filter(login_history::created_at.lt(limit)))
+                .execute(conn)
+                .map_res("Error purging login history")
+        }}
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(login_history::table.filter(login_history::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting login history")
+        }}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_history_new() {
+        let device = Device::new(String::from("device"), String::from("user"), String::from("Firefox"), 10);
+        let entry = LoginHistory::new(
+            String::from("user"),
+            &device,
+            String::from("192.0.2.1"),
+            LoginMethod::Passkey,
+            false,
+            None,
+        );
+
+        assert_eq!(entry.device_uuid, "device");
+        assert_eq!(entry.device_name, "Firefox");
+        assert_eq!(entry.device_type, 10);
+        assert_eq!(entry.method, LoginMethod::Passkey as i32);
+
+        let json = entry.to_json();
+        assert_eq!(json["IpAddress"], "192.0.2.1");
+        assert_eq!(json["Success"], false);
+        assert_eq!(json["Method"], 2);
+        assert_eq!(json["Object"], "loginHistory");
+    }
+}
@@ -0,0 +1,173 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde_json::Value;
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
+    #[table_name = "mail_queue"]
+    #[changeset_options(treat_none_as_null="true")]
+    #[primary_key(uuid)]
+    pub struct QueuedMail {
+        pub uuid: String,
+        pub recipient: String,
+        pub subject: String,
+        pub body_html: String,
+        pub body_text: String,
+        pub status: i32,
+        pub attempts: i32,
+        pub last_error: Option<String>,
+        pub created_at: NaiveDateTime,
+        pub next_attempt_at: NaiveDateTime,
+        // Set for the emails of an admin broadcast, whose delivery status is tracked per recipient
+        pub broadcast_uuid: Option<String>,
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum MailStatus {
+    Pending = 0,
+    // Permanently failed, or out of retries. These are kept until resent or discarded from the admin panel,
+    // or until they are purged after SMTP_QUEUE_FAILED_RETENTION days.
+    Failed = 1,
+}
+
+/// Local methods
+impl QueuedMail {
+    pub fn new(recipient: String, subject: String, body_html: String, body_text: String) -> Self {
+        let now = Utc::now().naive_utc();
+
+        Self {
+            uuid: crate::util::get_uuid(),
+            recipient,
+            subject,
+            body_html,
+            body_text,
+            status: MailStatus::Pending as i32,
+            attempts: 0,
+            last_error: None,
+            created_at: now,
+            next_attempt_at: now,
+            broadcast_uuid: None,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Id": self.uuid,
+            "Recipient": self.recipient,
+            "Subject": self.subject,
+            "Status": self.status,
+            "Attempts": self.attempts,
+            "LastError": self.last_error,
+            "CreatedAt": format_date(&self.created_at),
+            "NextAttemptAt": format_date(&self.next_attempt_at),
+        })
+    }
+}
+
+use crate::db::DbConn;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+use crate::CONFIG;
+
+/// Database methods
+impl QueuedMail {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                diesel::replace_into(mail_queue::table)
+                    .values(QueuedMailDb::to_db(self))
+                    .execute(conn)
+                    .map_res("Error saving queued mail")
+            }
+            postgresql {
+                let value = QueuedMailDb::to_db(self);
+                diesel::insert_into(mail_queue::table)
+                    .values(&value)
+                    .on_conflict(mail_queue::uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving queued mail")
+            }
+        }
+    }
+
+    /// Inserts new emails in batches, for the broadcasts sent to many users at once.
+    pub fn insert_all(mails: &[Self], conn: &DbConn) -> EmptyResult {
+        // Stays below the limit of 999 bind parameters of SQLite
+        for chunk in mails.chunks(50) {
+            db_run! { conn: {
+                let values: Vec<QueuedMailDb> = chunk.iter().map(QueuedMailDb::to_db).collect();
+                diesel::insert_into(mail_queue::table)
+                    .values(&values)
+                    .execute(conn)
+                    .map_res("Error saving queued mails")?;
+            }}
+        }
+        Ok(())
+    }
+
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(mail_queue::table.filter(mail_queue::uuid.eq(self.uuid)))
+                .execute(conn)
+                .map_res("Error deleting queued mail")
+        }}
+    }
+
+    /// Deletes the failed emails created before the retention period.
+    pub fn purge_failed(conn: &DbConn) -> EmptyResult {
+        if CONFIG.smtp_queue_failed_retention() == 0 {
+            return Ok(());
+        }
+
+        let limit = Utc::now().naive_utc() - Duration::days(CONFIG.smtp_queue_failed_retention() as i64);
+        db_run! { conn: {
+            diesel::delete(
+                mail_queue::table
+                    .filter(mail_queue::status.eq(MailStatus::Failed as i32))
+                    .filter(mail_queue::created_at.lt(limit)),
+            )
+            .execute(conn)
+            .map_res("Error purging failed emails")
+        }}
+    }
+
+    pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            mail_queue::table
+                .filter(mail_queue::uuid.eq(uuid))
+                .first::<QueuedMailDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub fn find_all(conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            mail_queue::table
+                .order(mail_queue::created_at.desc())
+                .load::<QueuedMailDb>(conn)
+                .expect("Error loading queued mails")
+                .from_db()
+        }}
+    }
+
+    /// Find pending mails which are due for a delivery attempt, the oldest first.
+    pub fn find_due(limit: i64, conn: &DbConn) -> Vec<Self> {
+        let now = Utc::now().naive_utc();
+        db_run! { conn: {
+            mail_queue::table
+                .filter(mail_queue::status.eq(MailStatus::Pending as i32))
+                .filter(mail_queue::next_attempt_at.le(now))
+                .order(mail_queue::next_attempt_at.asc())
+                .limit(limit)
+                .load::<QueuedMailDb>(conn)
+                .expect("Error loading queued mails")
+                .from_db()
+        }}
+    }
+}
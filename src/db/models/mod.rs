@@ -1,23 +1,37 @@
+mod admin;
 mod attachment;
+mod auth_request;
+mod broadcast;
 mod cipher;
 mod collection;
 mod device;
 mod favorite;
 mod folder;
+mod group;
+mod login_history;
+mod mail_queue;
 mod org_policy;
 mod organization;
 mod send;
 mod two_factor;
 mod user;
+mod webauthn_credential;
 
-pub use self::attachment::Attachment;
-pub use self::cipher::Cipher;
+pub use self::admin::{Admin, AdminAction};
+pub use self::attachment::{Attachment, AttachmentUpload};
+pub use self::auth_request::AuthRequest;
+pub use self::broadcast::{Broadcast, BroadcastRecipient, BroadcastStatus};
+pub use self::cipher::{Cipher, CipherOwnership};
 pub use self::collection::{Collection, CollectionCipher, CollectionUser};
 pub use self::device::Device;
 pub use self::favorite::Favorite;
 pub use self::folder::{Folder, FolderCipher};
+pub use self::group::{Group, GroupUser};
+pub use self::login_history::{LoginHistory, LoginMethod};
+pub use self::mail_queue::{MailStatus, QueuedMail};
 pub use self::org_policy::{OrgPolicy, OrgPolicyType};
 pub use self::organization::{Organization, UserOrgStatus, UserOrgType, UserOrganization};
 pub use self::send::{Send, SendType};
 pub use self::two_factor::{TwoFactor, TwoFactorType};
-pub use self::user::{Invitation, User, UserStampException};
+pub use self::user::{Invitation, ServerRole, User, UserStampException};
+pub use self::webauthn_credential::WebAuthnCredential;
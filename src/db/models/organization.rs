@@ -2,11 +2,13 @@ use num_traits::FromPrimitive;
 use serde_json::Value;
 use std::cmp::Ordering;
 
-use super::{CollectionUser, OrgPolicy, OrgPolicyType, User};
+use super::{CollectionUser, GroupUser, OrgPolicy, OrgPolicyType, User};
+use crate::CONFIG;
 
 db_object! {
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
     #[table_name = "organizations"]
+    #[changeset_options(treat_none_as_null="true")]
     #[primary_key(uuid)]
     pub struct Organization {
         pub uuid: String,
@@ -14,10 +16,15 @@ db_object! {
         pub billing_email: String,
         pub private_key: Option<String>,
         pub public_key: Option<String>,
+        pub scim_token: Option<String>,
+        pub attachment_limit: Option<i64>,
+        // JSON with the Duo credentials of the organization, used for the members without personal 2FA
+        pub duo_data: Option<String>,
     }
 
     #[derive(Identifiable, Queryable, Insertable, AsChangeset)]
     #[table_name = "users_organizations"]
+    #[changeset_options(treat_none_as_null="true")]
     #[primary_key(uuid)]
     pub struct UserOrganization {
         pub uuid: String,
@@ -28,6 +35,7 @@ db_object! {
         pub akey: String,
         pub status: i32,
         pub atype: i32,
+        pub external_id: Option<String>,
     }
 }
 
@@ -131,6 +139,29 @@ impl Organization {
             billing_email,
             private_key,
             public_key,
+            scim_token: None,
+            attachment_limit: None,
+            duo_data: None,
+        }
+    }
+
+    /// The attachment storage limit (KB) of this organization, falling back to the global `org_attachment_limit`.
+    pub fn effective_attachment_limit(&self) -> Option<i64> {
+        self.attachment_limit.or_else(|| CONFIG.org_attachment_limit())
+    }
+
+    /// Generates a new SCIM token, storing only its hash. The plain token is returned
+    /// so it can be shown once to the organization owner.
+    pub fn regenerate_scim_token(&mut self) -> String {
+        let token = crate::crypto::generate_id(32);
+        self.scim_token = Some(crate::crypto::sha256_hex(token.as_bytes()));
+        token
+    }
+
+    pub fn check_scim_token(&self, token: &str) -> bool {
+        match self.scim_token {
+            Some(ref hash) => crate::crypto::ct_eq(hash, crate::crypto::sha256_hex(token.as_bytes())),
+            None => false,
         }
     }
 
@@ -182,6 +213,7 @@ impl UserOrganization {
             akey: String::new(),
             status: UserOrgStatus::Accepted as i32,
             atype: UserOrgType::User as i32,
+            external_id: None,
         }
     }
 }
@@ -231,10 +263,11 @@ impl Organization {
     }
 
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
-        use super::{Cipher, Collection};
+        use super::{Cipher, Collection, Group};
 
         Cipher::delete_all_by_organization(&self.uuid, conn)?;
         Collection::delete_all_by_organization(&self.uuid, conn)?;
+        Group::delete_all_by_organization(&self.uuid, conn)?;
         UserOrganization::delete_all_by_organization(&self.uuid, conn)?;
         OrgPolicy::delete_all_by_organization(&self.uuid, conn)?;
 
@@ -259,6 +292,25 @@ impl Organization {
             organizations::table.load::<OrganizationDb>(conn).expect("Error loading organizations").from_db()
         }}
     }
+
+    /// The first organization with Duo configured of which the user is a confirmed member.
+    pub fn find_with_duo_by_user(user_uuid: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            organizations::table
+                .inner_join(
+                    users_organizations::table.on(
+                        users_organizations::org_uuid.eq(organizations::uuid)
+                            .and(users_organizations::user_uuid.eq(user_uuid))
+                    )
+                )
+                .filter(users_organizations::status.eq(UserOrgStatus::Confirmed as i32))
+                .filter(organizations::duo_data.is_not_null())
+                .order(organizations::uuid)
+                .select(organizations::all_columns)
+                .first::<OrganizationDb>(conn)
+                .ok().from_db()
+        }}
+    }
 }
 
 impl UserOrganization {
@@ -412,6 +464,7 @@ impl UserOrganization {
         User::update_uuid_revision(&self.user_uuid, conn);
 
         CollectionUser::delete_all_by_user_and_org(&self.user_uuid, &self.org_uuid, conn)?;
+        GroupUser::delete_all_by_user_org(&self.uuid, conn)?;
 
         db_run! { conn: {
             diesel::delete(users_organizations::table.filter(users_organizations::uuid.eq(self.uuid)))
@@ -119,6 +119,25 @@ impl Send {
         None
     }
 
+    /// Size in bytes of the file of a file send, as stored in its data.
+    pub fn file_size(&self) -> Option<i64> {
+        if self.atype != SendType::File as i32 {
+            return None;
+        }
+
+        let data: Value = serde_json::from_str(&self.data).ok()?;
+        match &data["Size"] {
+            Value::Number(n) => n.as_i64(),
+            Value::String(s) => s.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the send is past its expiration or deletion date.
+    pub fn is_expired(&self, now: &NaiveDateTime) -> bool {
+        self.deletion_date <= *now || self.expiration_date.map_or(false, |exp| exp <= *now)
+    }
+
     pub fn to_json(&self) -> Value {
         use crate::util::format_date;
         use data_encoding::BASE64URL_NOPAD;
@@ -285,6 +304,11 @@ impl Send {
         }}
     }
 
+    /// Total size of the files of the sends of the user. The sizes are only stored in the data of the sends.
+    pub fn size_by_user(user_uuid: &str, conn: &DbConn) -> i64 {
+        Self::find_by_user(user_uuid, conn).iter().filter_map(Self::file_size).sum()
+    }
+
     pub fn find_by_org(org_uuid: &str, conn: &DbConn) -> Vec<Self> {
         db_run! {conn: {
             sends::table
@@ -293,6 +317,21 @@ impl Send {
         }}
     }
 
+    pub fn get_all(conn: &DbConn) -> Vec<Self> {
+        db_run! {conn: {
+            sends::table
+                .load::<SendDb>(conn).expect("Error loading sends").from_db()
+        }}
+    }
+
+    pub fn find_by_type(atype: SendType, conn: &DbConn) -> Vec<Self> {
+        db_run! {conn: {
+            sends::table
+                .filter(sends::atype.eq(atype as i32))
+                .load::<SendDb>(conn).expect("Error loading sends").from_db()
+        }}
+    }
+
     pub fn find_by_past_deletion_date(conn: &DbConn) -> Vec<Self> {
         let now = Utc::now().naive_utc();
         db_run! {conn: {
@@ -1,6 +1,6 @@
 use serde_json::Value;
 
-use crate::api::EmptyResult;
+use crate::api::{ApiResult, EmptyResult};
 use crate::db::DbConn;
 use crate::error::MapResult;
 
@@ -112,6 +112,27 @@ impl TwoFactor {
         }
     }
 
+    /// Replaces the data only if it's still `previous_data`, so that concurrent logins can't both use the same
+    /// one-time state. Returns whether it was replaced.
+    pub fn replace_data(&mut self, previous_data: &str, data: String, conn: &DbConn) -> ApiResult<bool> {
+        let replaced = db_run! { conn: {
+            diesel::update(
+                twofactor::table
+                    .filter(twofactor::uuid.eq(&self.uuid))
+                    .filter(twofactor::data.eq(previous_data)),
+            )
+            .set(twofactor::data.eq(&data))
+            .execute(conn)
+            .map(|rows| rows == 1)
+            .map_res("Error saving twofactor")
+        }}?;
+
+        if replaced {
+            self.data = data;
+        }
+        Ok(replaced)
+    }
+
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
         db_run! { conn: {
             diesel::delete(twofactor::table.filter(twofactor::uuid.eq(self.uuid)))
@@ -150,6 +171,7 @@ impl TwoFactor {
         }}
     }
 
+    /// Converts the U2F registrations of all the users to WebAuthn credentials.
     pub fn migrate_u2f_to_webauthn(conn: &DbConn) -> EmptyResult {
         let u2f_factors = db_run! { conn: {
             twofactor::table
@@ -159,63 +181,145 @@ impl TwoFactor {
                 .from_db()
         }};
 
-        use crate::api::core::two_factor::u2f::U2FRegistration;
+        for u2f in u2f_factors {
+            let user_uuid = u2f.user_uuid.clone();
+            if let Err(e) = Self::migrate_u2f_factor(u2f, conn) {
+                error!("Error converting the U2F keys of user {} to WebAuthn: {:#?}", user_uuid, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Converts the U2F registrations of a user, which could have come back with a database backup for example.
+    pub fn migrate_u2f_to_webauthn_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        match Self::find_by_user_and_type(user_uuid, TwoFactorType::U2f as i32, conn) {
+            Some(u2f) => Self::migrate_u2f_factor(u2f, conn),
+            None => Ok(()),
+        }
+    }
+
+    // The U2F keys keep working with WebAuthn thanks to the AppID extension, so the U2F registrations are removed
+    // once converted, and the logins only go through WebAuthn.
+    fn migrate_u2f_factor(u2f: TwoFactor, conn: &DbConn) -> EmptyResult {
+        use crate::api::core::two_factor::u2f::parse_u2f_registrations;
         use crate::api::core::two_factor::webauthn::{get_webauthn_registrations, WebauthnRegistration};
-        use std::convert::TryInto;
         use webauthn_rs::proto::*;
 
-        for mut u2f in u2f_factors {
-            let mut regs: Vec<U2FRegistration> = serde_json::from_str(&u2f.data)?;
-            // If there are no registrations or they are migrated (we do the migration in batch so we can consider them all migrated when the first one is)
-            if regs.is_empty() || regs[0].migrated == Some(true) {
+        let regs = parse_u2f_registrations(&u2f.data)?;
+        let (_, mut webauthn_regs) = get_webauthn_registrations(&u2f.user_uuid, conn)?;
+        let mut converted = 0;
+        let mut failed = 0;
+
+        for reg in regs {
+            // Already converted by a previous version, which kept the U2F registrations
+            if webauthn_regs.iter().any(|r| r.credential.cred_id == reg.reg.key_handle) {
                 continue;
             }
 
-            let (_, mut webauthn_regs) = get_webauthn_registrations(&u2f.user_uuid, conn)?;
+            let key = match u2f_public_key_to_cose(&reg.reg.pub_key) {
+                Some(key) => key,
+                None => {
+                    warn!("Invalid U2F public key of user {}, the key '{}' is not converted", u2f.user_uuid, reg.name);
+                    failed += 1;
+                    continue;
+                }
+            };
 
-            // If the user already has webauthn registrations saved, don't overwrite them
-            if !webauthn_regs.is_empty() {
-                continue;
-            }
+            // The clients show up to 5 keys, so use a free slot if the id is already taken by a WebAuthn key
+            let id = if webauthn_regs.iter().any(|r| r.id == reg.id) {
+                (1..).find(|id| !webauthn_regs.iter().any(|r| r.id == *id)).unwrap()
+            } else {
+                reg.id
+            };
 
-            for reg in &mut regs {
-                let x: [u8; 32] = reg.reg.pub_key[1..33].try_into().unwrap();
-                let y: [u8; 32] = reg.reg.pub_key[33..65].try_into().unwrap();
-
-                let key = COSEKey {
-                    type_: COSEAlgorithm::ES256,
-                    key: COSEKeyType::EC_EC2(COSEEC2Key {
-                        curve: ECDSACurve::SECP256R1,
-                        x,
-                        y,
-                    }),
-                };
-
-                let new_reg = WebauthnRegistration {
-                    id: reg.id,
-                    migrated: true,
-                    name: reg.name.clone(),
-                    credential: Credential {
-                        counter: reg.counter,
-                        verified: false,
-                        cred: key,
-                        cred_id: reg.reg.key_handle.clone(),
-                        registration_policy: UserVerificationPolicy::Discouraged,
-                    },
-                };
-
-                webauthn_regs.push(new_reg);
-
-                reg.migrated = Some(true);
-            }
+            webauthn_regs.push(WebauthnRegistration {
+                id,
+                migrated: true,
+                name: reg.name,
+                credential: Credential {
+                    counter: reg.counter,
+                    verified: false,
+                    cred: key,
+                    cred_id: reg.reg.key_handle,
+                    registration_policy: UserVerificationPolicy::Discouraged,
+                },
+            });
+            converted += 1;
+        }
 
-            u2f.data = serde_json::to_string(&regs)?;
-            u2f.save(conn)?;
+        // Keep the U2F keys when none of them could be converted, it may be the only second factor of the user
+        if converted == 0 && failed > 0 {
+            error!("None of the U2F keys of user {} could be converted to WebAuthn, they are kept", u2f.user_uuid);
+            return Ok(());
+        }
 
+        if converted > 0 {
             TwoFactor::new(u2f.user_uuid.clone(), TwoFactorType::Webauthn, serde_json::to_string(&webauthn_regs)?)
                 .save(conn)?;
+            info!("Converted {} U2F key(s) of user {} to WebAuthn", converted, u2f.user_uuid);
         }
+        u2f.delete(conn)
+    }
+}
 
-        Ok(())
+/// Converts the public key of a U2F registration, an uncompressed P-256 point (0x04 | x | y), to its COSE form.
+fn u2f_public_key_to_cose(pub_key: &[u8]) -> Option<webauthn_rs::proto::COSEKey> {
+    use std::convert::TryInto;
+    use webauthn_rs::proto::*;
+
+    if pub_key.len() != 65 || pub_key[0] != 0x04 {
+        return None;
+    }
+
+    Some(COSEKey {
+        type_: COSEAlgorithm::ES256,
+        key: COSEKeyType::EC_EC2(COSEEC2Key {
+            curve: ECDSACurve::SECP256R1,
+            x: pub_key[1..33].try_into().ok()?,
+            y: pub_key[33..65].try_into().ok()?,
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        bn::{BigNum, BigNumContext},
+        ec::{EcGroup, EcKey, PointConversionForm},
+        ecdsa::EcdsaSig,
+        nid::Nid,
+        sha::sha256,
+    };
+    use webauthn_rs::proto::{COSEAlgorithm, COSEKeyType, ECDSACurve};
+
+    #[test]
+    fn test_u2f_public_key_to_cose() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let mut ctx = BigNumContext::new().unwrap();
+        let pub_key = key.public_key().to_bytes(&group, PointConversionForm::UNCOMPRESSED, &mut ctx).unwrap();
+
+        let cose = u2f_public_key_to_cose(&pub_key).unwrap();
+        assert!(matches!(cose.type_, COSEAlgorithm::ES256));
+        let ec2 = match cose.key {
+            COSEKeyType::EC_EC2(ec2) => ec2,
+            _ => panic!("The U2F key should be converted to an EC2 key"),
+        };
+        assert!(matches!(ec2.curve, ECDSACurve::SECP256R1));
+
+        // A signature of the U2F key still verifies with the converted key
+        let converted = EcKey::from_public_key_affine_coordinates(
+            &group,
+            &BigNum::from_slice(&ec2.x).unwrap(),
+            &BigNum::from_slice(&ec2.y).unwrap(),
+        )
+        .unwrap();
+        let digest = sha256(b"signed data");
+        let signature = EcdsaSig::sign(&digest, &key).unwrap();
+        assert!(signature.verify(&digest, &converted).unwrap());
+
+        assert!(u2f_public_key_to_cose(&pub_key[..64]).is_none());
+        assert!(u2f_public_key_to_cose(&[0u8; 65]).is_none());
     }
 }
@@ -44,6 +44,23 @@ db_object! {
 
         pub client_kdf_type: i32,
         pub client_kdf_iter: i32,
+
+        pub attachment_limit: Option<i64>,
+
+        // Language of the emails sent to this user, when they have a translation
+        pub language: Option<String>,
+
+        // When `REQUIRE_2FA` started to apply to this user, the grace period to set up 2FA starts then
+        pub twofactor_required_at: Option<NaiveDateTime>,
+        // Last reminder to set up 2FA, the reminders get closer as the deadline approaches
+        pub twofactor_reminded_at: Option<NaiveDateTime>,
+
+        // Role of the user in the admin panel, see `ServerRole`
+        pub server_role: Option<i32>,
+
+        // Deleted users are kept disabled during `USER_DELETION_GRACE_DAYS` before being purged, see `soft_delete`
+        pub deleted_at: Option<NaiveDateTime>,
+        pub purge_reminded: bool,
     }
 
 
@@ -61,6 +78,37 @@ enum UserStatus {
     _Disabled = 2,
 }
 
+/// Server level roles, which let users use the admin panel endpoints with their login.
+/// The variants are ordered by access level, each role can do everything the previous ones can.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, num_derive::FromPrimitive)]
+pub enum ServerRole {
+    /// Can view everything, but not change anything
+    Auditor = 0,
+    /// Can also manage the users: invite, disable, delete, remove their 2FA...
+    UserAdmin = 1,
+    /// Can do everything the admin token can
+    SuperAdmin = 2,
+}
+
+impl ServerRole {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "0" | "Auditor" => Some(ServerRole::Auditor),
+            "1" | "UserAdmin" => Some(ServerRole::UserAdmin),
+            "2" | "SuperAdmin" => Some(ServerRole::SuperAdmin),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ServerRole::Auditor => "Auditor",
+            ServerRole::UserAdmin => "User Admin",
+            ServerRole::SuperAdmin => "Super Admin",
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UserStampException {
     pub routes: Vec<String>,
@@ -110,6 +158,18 @@ impl User {
 
             client_kdf_type: Self::CLIENT_KDF_TYPE_DEFAULT,
             client_kdf_iter: Self::CLIENT_KDF_ITER_DEFAULT,
+
+            attachment_limit: None,
+
+            language: None,
+
+            twofactor_required_at: None,
+            twofactor_reminded_at: None,
+
+            server_role: None,
+
+            deleted_at: None,
+            purge_reminded: false,
         }
     }
 
@@ -174,9 +234,42 @@ impl User {
     pub fn reset_stamp_exception(&mut self) {
         self.stamp_exception = None;
     }
+
+    /// The date until which the user can log in without two-step login, when it's required by `REQUIRE_2FA`.
+    pub fn twofactor_deadline(&self) -> Option<NaiveDateTime> {
+        let grace_period = Duration::days(CONFIG.require_2fa_grace_period() as i64);
+        self.twofactor_required_at.map(|required_at| required_at + grace_period)
+    }
+
+    /// The role of the user in the admin panel, if any.
+    pub fn server_role(&self) -> Option<ServerRole> {
+        use num_traits::FromPrimitive;
+        self.server_role.and_then(ServerRole::from_i32)
+    }
+
+    /// The date when a deleted user and all their data are removed, `None` if the user isn't deleted.
+    pub fn purge_date(&self) -> Option<NaiveDateTime> {
+        let grace_period = Duration::days(CONFIG.user_deletion_grace_days() as i64);
+        self.deleted_at.map(|deleted_at| deleted_at + grace_period)
+    }
+
+    /// Restores a deleted user. The user is enabled again, even if it had been disabled before the deletion.
+    pub fn restore(&mut self) {
+        self.enabled = true;
+        self.deleted_at = None;
+        self.purge_reminded = false;
+    }
+
+    /// The attachment storage limit (KB) of this user, falling back to the global `user_attachment_limit`.
+    pub fn effective_attachment_limit(&self) -> Option<i64> {
+        self.attachment_limit.or_else(|| CONFIG.user_attachment_limit())
+    }
 }
 
-use super::{Cipher, Device, Favorite, Folder, Send, TwoFactor, UserOrgType, UserOrganization};
+use super::{
+    AuthRequest, Cipher, Device, Favorite, Folder, LoginHistory, Send, TwoFactor, UserOrgType, UserOrganization,
+    WebAuthnCredential,
+};
 use crate::db::DbConn;
 
 use crate::api::EmptyResult;
@@ -252,7 +345,7 @@ impl User {
         }
     }
 
-    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+    fn check_can_delete(&self, conn: &DbConn) -> EmptyResult {
         for user_org in UserOrganization::find_by_user(&self.uuid, conn) {
             if user_org.atype == UserOrgType::Owner {
                 let owner_type = UserOrgType::Owner as i32;
@@ -261,6 +354,24 @@ impl User {
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Marks the user as deleted: the user is disabled and logged out, but all their data is kept until the
+    /// end of the grace period, when `delete` removes it.
+    pub fn soft_delete(&mut self, conn: &DbConn) -> EmptyResult {
+        self.check_can_delete(conn)?;
+
+        Device::delete_all_by_user(&self.uuid, conn)?;
+        self.reset_security_stamp();
+        self.enabled = false;
+        self.deleted_at = Some(Utc::now().naive_utc());
+        self.purge_reminded = false;
+        self.save(conn)
+    }
+
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        self.check_can_delete(conn)?;
 
         Send::delete_all_by_user(&self.uuid, conn)?;
         UserOrganization::delete_all_by_user(&self.uuid, conn)?;
@@ -269,6 +380,9 @@ impl User {
         Folder::delete_all_by_user(&self.uuid, conn)?;
         Device::delete_all_by_user(&self.uuid, conn)?;
         TwoFactor::delete_all_by_user(&self.uuid, conn)?;
+        WebAuthnCredential::delete_all_by_user(&self.uuid, conn)?;
+        AuthRequest::delete_all_by_user(&self.uuid, conn)?;
+        LoginHistory::delete_all_by_user(&self.uuid, conn)?;
         Invitation::take(&self.email, conn); // Delete invitation if any
 
         db_run! {conn: {
@@ -337,6 +451,16 @@ impl User {
         }}
     }
 
+    pub fn find_deleted(conn: &DbConn) -> Vec<Self> {
+        db_run! {conn: {
+            users::table
+                .filter(users::deleted_at.is_not_null())
+                .load::<UserDb>(conn)
+                .expect("Error loading deleted users")
+                .from_db()
+        }}
+    }
+
     pub fn last_active(&self, conn: &DbConn) -> Option<NaiveDateTime> {
         match Device::find_latest_active_by_user(&self.uuid, conn) {
             Some(device) => Some(device.updated_at),
@@ -403,3 +527,25 @@ impl Invitation {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_role_order() {
+        assert!(ServerRole::Auditor < ServerRole::UserAdmin);
+        assert!(ServerRole::UserAdmin < ServerRole::SuperAdmin);
+
+        let mut user = User::new(String::from("user@example.com"));
+        assert!(user.server_role().is_none());
+        user.server_role = Some(ServerRole::UserAdmin as i32);
+        assert!(user.server_role() == Some(ServerRole::UserAdmin));
+        user.server_role = Some(3);
+        assert!(user.server_role().is_none());
+
+        assert!(ServerRole::from_str("SuperAdmin") == Some(ServerRole::SuperAdmin));
+        assert!(ServerRole::from_str("0") == Some(ServerRole::Auditor));
+        assert!(ServerRole::from_str("Owner").is_none());
+    }
+}
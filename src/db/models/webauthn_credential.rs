@@ -0,0 +1,180 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use crate::api::EmptyResult;
+use crate::db::DbConn;
+use crate::error::MapResult;
+
+use super::User;
+
+db_object! {
+    #[derive(Identifiable, Queryable, Insertable, Associations, AsChangeset)]
+    #[table_name = "webauthn_credentials"]
+    #[changeset_options(treat_none_as_null="true")]
+    #[belongs_to(User, foreign_key = "user_uuid")]
+    #[primary_key(uuid)]
+    pub struct WebAuthnCredential {
+        pub uuid: String,
+        pub user_uuid: String,
+        pub name: String,
+        // Base64url encoded id of the credential, used to find it from the assertion of the authenticator
+        pub credential_id: String,
+        // JSON serialized webauthn_rs Credential, with the public key and the signature counter
+        pub credential: String,
+        pub supports_prf: bool,
+        // The user key and the key pair, encrypted with the key derived from the PRF output of the authenticator
+        pub encrypted_user_key: Option<String>,
+        pub encrypted_public_key: Option<String>,
+        pub encrypted_private_key: Option<String>,
+        pub created_at: NaiveDateTime,
+        pub last_used_at: Option<NaiveDateTime>,
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum WebAuthnPrfStatus {
+    Enabled = 0,
+    Supported = 1,
+    Unsupported = 2,
+}
+
+/// Local methods
+impl WebAuthnCredential {
+    pub fn new(user_uuid: String, name: String, credential_id: String, credential: String, supports_prf: bool) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            user_uuid,
+            name,
+            credential_id,
+            credential,
+            supports_prf,
+            encrypted_user_key: None,
+            encrypted_public_key: None,
+            encrypted_private_key: None,
+            created_at: Utc::now().naive_utc(),
+            last_used_at: None,
+        }
+    }
+
+    pub fn prf_status(&self) -> WebAuthnPrfStatus {
+        if !self.supports_prf {
+            WebAuthnPrfStatus::Unsupported
+        } else if self.encrypted_user_key.is_some() {
+            WebAuthnPrfStatus::Enabled
+        } else {
+            WebAuthnPrfStatus::Supported
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Id": self.uuid,
+            "Name": self.name,
+            "PrfStatus": self.prf_status() as i32,
+            "EncryptedUserKey": self.encrypted_user_key,
+            "EncryptedPublicKey": self.encrypted_public_key,
+            "CreationDate": format_date(&self.created_at),
+            "LastUsedDate": self.last_used_at.as_ref().map(format_date),
+            "Object": "webauthnCredential"
+        })
+    }
+}
+
+/// Database methods
+impl WebAuthnCredential {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn:
+            sqlite, mysql {
+                match diesel::replace_into(webauthn_credentials::table)
+                    .values(WebAuthnCredentialDb::to_db(self))
+                    .execute(conn)
+                {
+                    Ok(_) => Ok(()),
+                    // Record already exists and causes a Foreign Key Violation because replace_into() wants to delete the record first.
+                    Err(diesel::result::Error::DatabaseError(diesel::result::DatabaseErrorKind::ForeignKeyViolation, _)) => {
+                        diesel::update(webauthn_credentials::table)
+                            .filter(webauthn_credentials::uuid.eq(&self.uuid))
+                            .set(WebAuthnCredentialDb::to_db(self))
+                            .execute(conn)
+                            .map_res("Error saving webauthn credential")
+                    }
+                    Err(e) => Err(e.into()),
+                }.map_res("Error saving webauthn credential")
+            }
+            postgresql {
+                let value = WebAuthnCredentialDb::to_db(self);
+                diesel::insert_into(webauthn_credentials::table)
+                    .values(&value)
+                    .on_conflict(webauthn_credentials::uuid)
+                    .do_update()
+                    .set(&value)
+                    .execute(conn)
+                    .map_res("Error saving webauthn credential")
+            }
+        }
+    }
+
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(webauthn_credentials::table.filter(webauthn_credentials::uuid.eq(self.uuid)))
+                .execute(conn)
+                .map_res("Error deleting webauthn credential")
+        }}
+    }
+
+    pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        db_run! { conn: {
+            webauthn_credentials::table
+                .filter(webauthn_credentials::user_uuid.eq(user_uuid))
+                .order(webauthn_credentials::created_at.asc())
+                .load::<WebAuthnCredentialDb>(conn)
+                .expect("Error loading webauthn credentials")
+                .from_db()
+        }}
+    }
+
+    pub fn find_by_uuid_and_user(uuid: &str, user_uuid: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            webauthn_credentials::table
+                .filter(webauthn_credentials::uuid.eq(uuid))
+                .filter(webauthn_credentials::user_uuid.eq(user_uuid))
+                .first::<WebAuthnCredentialDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    pub fn find_by_credential_id(credential_id: &str, conn: &DbConn) -> Option<Self> {
+        db_run! { conn: {
+            webauthn_credentials::table
+                .filter(webauthn_credentials::credential_id.eq(credential_id))
+                .first::<WebAuthnCredentialDb>(conn)
+                .ok()
+                .from_db()
+        }}
+    }
+
+    /// The encrypted keys are wrapping the old user key after a key rotation, so they can't be used anymore.
+    pub fn clear_encrypted_keys_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::update(webauthn_credentials::table.filter(webauthn_credentials::user_uuid.eq(user_uuid)))
+                .set((
+                    webauthn_credentials::encrypted_user_key.eq(None::<String>),
+                    webauthn_credentials::encrypted_public_key.eq(None::<String>),
+                    webauthn_credentials::encrypted_private_key.eq(None::<String>),
+                ))
+                .execute(conn)
+                .map_res("Error clearing webauthn credential keys")
+        }}
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        db_run! { conn: {
+            diesel::delete(webauthn_credentials::table.filter(webauthn_credentials::user_uuid.eq(user_uuid)))
+                .execute(conn)
+                .map_res("Error deleting webauthn credentials")
+        }}
+    }
+}
@@ -1,3 +1,36 @@
+table! {
+    admin_actions (uuid) {
+        uuid -> Text,
+        admin_uuid -> Nullable<Text>,
+        admin_name -> Text,
+        action -> Text,
+        target -> Nullable<Text>,
+        ip -> Text,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    admins (uuid) {
+        uuid -> Text,
+        name -> Text,
+        password_hash -> Text,
+        totp_secret -> Nullable<Text>,
+        totp_last_used -> Integer,
+        created_at -> Datetime,
+        last_login_at -> Nullable<Datetime>,
+    }
+}
+
+table! {
+    attachment_uploads (attachment_id) {
+        attachment_id -> Text,
+        file_hash -> Nullable<Text>,
+        created_at -> Datetime,
+        updated_at -> Datetime,
+    }
+}
+
 table! {
     attachments (id) {
         id -> Text,
@@ -8,6 +41,45 @@ table! {
     }
 }
 
+table! {
+    auth_requests (uuid) {
+        uuid -> Text,
+        user_uuid -> Text,
+        request_device_identifier -> Text,
+        device_type -> Integer,
+        request_ip -> Text,
+        response_device_id -> Nullable<Text>,
+        access_code -> Text,
+        public_key -> Text,
+        enc_key -> Nullable<Text>,
+        master_password_hash -> Nullable<Text>,
+        approved -> Nullable<Bool>,
+        creation_date -> Datetime,
+        response_date -> Nullable<Datetime>,
+        authentication_date -> Nullable<Datetime>,
+    }
+}
+
+table! {
+    broadcast_recipients (broadcast_uuid, email) {
+        broadcast_uuid -> Text,
+        email -> Text,
+        status -> Integer,
+        last_error -> Nullable<Text>,
+        updated_at -> Datetime,
+    }
+}
+
+table! {
+    broadcasts (uuid) {
+        uuid -> Text,
+        subject -> Text,
+        body -> Text,
+        recipients -> Text,
+        created_at -> Datetime,
+    }
+}
+
 table! {
     ciphers (uuid) {
         uuid -> Text,
@@ -51,7 +123,11 @@ table! {
         atype -> Integer,
         push_token -> Nullable<Text>,
         refresh_token -> Text,
+        previous_refresh_token -> Nullable<Text>,
+        refresh_token_issued_at -> Nullable<Datetime>,
+        session_started_at -> Nullable<Datetime>,
         twofactor_remember -> Nullable<Text>,
+        twofactor_remember_issued_at -> Nullable<Datetime>,
     }
 }
 
@@ -79,12 +155,61 @@ table! {
     }
 }
 
+table! {
+    groups (uuid) {
+        uuid -> Text,
+        org_uuid -> Text,
+        name -> Text,
+        external_id -> Nullable<Text>,
+        created_at -> Datetime,
+        updated_at -> Datetime,
+    }
+}
+
+table! {
+    groups_users (group_uuid, users_organizations_uuid) {
+        group_uuid -> Text,
+        users_organizations_uuid -> Text,
+    }
+}
+
 table! {
     invitations (email) {
         email -> Text,
     }
 }
 
+table! {
+    login_history (uuid) {
+        uuid -> Text,
+        user_uuid -> Text,
+        device_uuid -> Text,
+        device_name -> Text,
+        device_type -> Integer,
+        ip -> Text,
+        method -> Integer,
+        success -> Bool,
+        twofactor_type -> Nullable<Integer>,
+        created_at -> Datetime,
+    }
+}
+
+table! {
+    mail_queue (uuid) {
+        uuid -> Text,
+        recipient -> Text,
+        subject -> Text,
+        body_html -> Text,
+        body_text -> Text,
+        status -> Integer,
+        attempts -> Integer,
+        last_error -> Nullable<Text>,
+        created_at -> Datetime,
+        next_attempt_at -> Datetime,
+        broadcast_uuid -> Nullable<Text>,
+    }
+}
+
 table! {
     org_policies (uuid) {
         uuid -> Text,
@@ -102,6 +227,9 @@ table! {
         billing_email -> Text,
         private_key -> Nullable<Text>,
         public_key -> Nullable<Text>,
+        scim_token -> Nullable<Text>,
+        attachment_limit -> Nullable<BigInt>,
+        duo_data -> Nullable<Text>,
     }
 }
 
@@ -168,6 +296,13 @@ table! {
         excluded_globals -> Text,
         client_kdf_type -> Integer,
         client_kdf_iter -> Integer,
+        attachment_limit -> Nullable<BigInt>,
+        language -> Nullable<Text>,
+        twofactor_required_at -> Nullable<Datetime>,
+        twofactor_reminded_at -> Nullable<Datetime>,
+        server_role -> Nullable<Integer>,
+        deleted_at -> Nullable<Datetime>,
+        purge_reminded -> Bool,
     }
 }
 
@@ -189,10 +324,30 @@ table! {
         akey -> Text,
         status -> Integer,
         atype -> Integer,
+        external_id -> Nullable<Text>,
+    }
+}
+
+table! {
+    webauthn_credentials (uuid) {
+        uuid -> Text,
+        user_uuid -> Text,
+        name -> Text,
+        credential_id -> Text,
+        credential -> Text,
+        supports_prf -> Bool,
+        encrypted_user_key -> Nullable<Text>,
+        encrypted_public_key -> Nullable<Text>,
+        encrypted_private_key -> Nullable<Text>,
+        created_at -> Datetime,
+        last_used_at -> Nullable<Datetime>,
     }
 }
 
+joinable!(attachment_uploads -> attachments (attachment_id));
 joinable!(attachments -> ciphers (cipher_uuid));
+joinable!(auth_requests -> users (user_uuid));
+joinable!(broadcast_recipients -> broadcasts (broadcast_uuid));
 joinable!(ciphers -> organizations (organization_uuid));
 joinable!(ciphers -> users (user_uuid));
 joinable!(ciphers_collections -> ciphers (cipher_uuid));
@@ -202,6 +357,10 @@ joinable!(devices -> users (user_uuid));
 joinable!(folders -> users (user_uuid));
 joinable!(folders_ciphers -> ciphers (cipher_uuid));
 joinable!(folders_ciphers -> folders (folder_uuid));
+joinable!(groups -> organizations (org_uuid));
+joinable!(groups_users -> groups (group_uuid));
+joinable!(groups_users -> users_organizations (users_organizations_uuid));
+joinable!(login_history -> users (user_uuid));
 joinable!(org_policies -> organizations (org_uuid));
 joinable!(sends -> organizations (organization_uuid));
 joinable!(sends -> users (user_uuid));
@@ -210,16 +369,27 @@ joinable!(users_collections -> collections (collection_uuid));
 joinable!(users_collections -> users (user_uuid));
 joinable!(users_organizations -> organizations (org_uuid));
 joinable!(users_organizations -> users (user_uuid));
+joinable!(webauthn_credentials -> users (user_uuid));
 
 allow_tables_to_appear_in_same_query!(
+    admin_actions,
+    admins,
+    attachment_uploads,
     attachments,
+    auth_requests,
+    broadcast_recipients,
+    broadcasts,
     ciphers,
     ciphers_collections,
     collections,
     devices,
     folders,
     folders_ciphers,
+    groups,
+    groups_users,
     invitations,
+    login_history,
+    mail_queue,
     org_policies,
     organizations,
     sends,
@@ -227,4 +397,5 @@ allow_tables_to_appear_in_same_query!(
     users,
     users_collections,
     users_organizations,
+    webauthn_credentials,
 );
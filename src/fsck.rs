@@ -0,0 +1,386 @@
+//
+// Data consistency checker
+//
+// Looks for files in the attachments and sends folders which aren't referenced from the database,
+// and for database rows which point to files, folders, memberships or organizations that no longer exist.
+// It can be run with `vaultwarden fsck [--repair]` or from the diagnostics page of the admin panel.
+//
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use serde_json::Value;
+
+use crate::{
+    db::{models::*, DbConn},
+    util, CONFIG,
+};
+
+/// Files modified more recently than this are skipped, as they may belong to an upload in progress.
+const MIN_FILE_AGE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    OrphanedCipher,
+    OrphanedFolderCipher,
+    OrphanedCollectionUser,
+    DanglingAttachment,
+    OrphanedAttachmentFile,
+    DanglingSend,
+    OrphanedSendFile,
+}
+
+impl fmt::Display for IssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            IssueKind::OrphanedCipher => "Cipher of a deleted organization",
+            IssueKind::OrphanedFolderCipher => "Folder entry of a deleted folder",
+            IssueKind::OrphanedCollectionUser => "Collection access of a removed member",
+            IssueKind::DanglingAttachment => "Attachment without data",
+            IssueKind::OrphanedAttachmentFile => "Unreferenced attachment file",
+            IssueKind::DanglingSend => "File send without data",
+            IssueKind::OrphanedSendFile => "Unreferenced send file",
+        };
+        f.write_str(description)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub target: String,
+    pub repaired: bool,
+}
+
+#[derive(Default, Serialize)]
+pub struct FsckReport {
+    pub issues: Vec<Issue>,
+    pub warnings: Vec<String>,
+}
+
+impl FsckReport {
+    fn add(&mut self, kind: IssueKind, target: String, result: Option<Result<(), String>>) {
+        let repaired = match result {
+            Some(Ok(())) => true,
+            Some(Err(e)) => {
+                error!("Error repairing {} '{}': {}", kind.to_string().to_lowercase(), target, e);
+                false
+            }
+            None => false,
+        };
+        self.issues.push(Issue {
+            kind,
+            target,
+            repaired,
+        });
+    }
+
+    fn warn(&mut self, warning: String) {
+        warn!("{}", warning);
+        self.warnings.push(warning);
+    }
+
+    pub fn unrepaired_count(&self) -> usize {
+        self.issues.iter().filter(|i| !i.repaired).count()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "issues": self.issues,
+            "warnings": self.warnings,
+            "issue_count": self.issues.len(),
+            "unrepaired_count": self.unrepaired_count(),
+        })
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for issue in &self.issues {
+            let status = if issue.repaired {
+                "repaired"
+            } else {
+                "found"
+            };
+            writeln!(f, "[{}] {}: {}", status, issue.kind, issue.target)?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "[warning] {}", warning)?;
+        }
+        write!(f, "{} issue(s) found, {} left unrepaired", self.issues.len(), self.unrepaired_count())
+    }
+}
+
+/// Runs all the checks. When `repair` is false, nothing is modified.
+pub fn run(repair: bool, conn: &DbConn) -> FsckReport {
+    let mut report = FsckReport::default();
+
+    // Ciphers are checked first, as deleting them also removes their attachments and folder entries
+    check_ciphers(&mut report, repair, conn);
+    check_folders_ciphers(&mut report, repair, conn);
+    check_users_collections(&mut report, repair, conn);
+    check_attachments(&mut report, repair, conn);
+    check_sends(&mut report, repair, conn);
+
+    report
+}
+
+fn repair_with<F: FnOnce() -> Result<(), E>, E: fmt::Debug>(repair: bool, f: F) -> Option<Result<(), String>> {
+    if repair {
+        Some(f().map_err(|e| format!("{:?}", e)))
+    } else {
+        None
+    }
+}
+
+fn check_ciphers(report: &mut FsckReport, repair: bool, conn: &DbConn) {
+    for cipher in Cipher::find_without_organization(conn) {
+        let target =
+            format!("{} (organization {})", cipher.uuid, cipher.organization_uuid.as_deref().unwrap_or_default());
+        let result = repair_with(repair, || cipher.delete(conn));
+        report.add(IssueKind::OrphanedCipher, target, result);
+    }
+}
+
+fn check_folders_ciphers(report: &mut FsckReport, repair: bool, conn: &DbConn) {
+    for folder_cipher in FolderCipher::find_without_folder(conn) {
+        let target = format!("cipher {} in folder {}", folder_cipher.cipher_uuid, folder_cipher.folder_uuid);
+        let result = repair_with(repair, || folder_cipher.delete(conn));
+        report.add(IssueKind::OrphanedFolderCipher, target, result);
+    }
+}
+
+fn check_users_collections(report: &mut FsckReport, repair: bool, conn: &DbConn) {
+    for col_user in CollectionUser::find_without_membership(conn) {
+        let target = format!("user {} in collection {}", col_user.user_uuid, col_user.collection_uuid);
+        let result = repair_with(repair, || col_user.delete(conn));
+        report.add(IssueKind::OrphanedCollectionUser, target, result);
+    }
+}
+
+fn check_attachments(report: &mut FsckReport, repair: bool, conn: &DbConn) {
+    let pending: HashSet<String> = AttachmentUpload::find_all(conn).into_iter().map(|u| u.attachment_id).collect();
+    let attachments: HashMap<String, Attachment> =
+        Attachment::get_all(conn).into_iter().map(|a| (a.id.clone(), a)).collect();
+
+    // Attachment rows whose data was never uploaded, or was removed from disk.
+    // When the folder is missing or empty it is most likely not mounted, so the rows are kept.
+    let dangling: Vec<&Attachment> =
+        attachments.values().filter(|a| !pending.contains(&a.id) && !Path::new(&a.get_file_path()).exists()).collect();
+    let repair_dangling = repair && has_attachment_files(&CONFIG.attachments_folder());
+    if repair && !repair_dangling && !dangling.is_empty() {
+        report.warn(format!(
+            "The attachments folder '{}' is missing or empty, attachments without data were not removed",
+            CONFIG.attachments_folder()
+        ));
+    }
+
+    for attachment in dangling {
+        let target = format!("{} (cipher {})", attachment.id, attachment.cipher_uuid);
+        let result = repair_with(repair_dangling, || {
+            attachment.delete(conn)?;
+            if let Some(cipher) = Cipher::find_by_uuid(&attachment.cipher_uuid, conn) {
+                cipher.update_users_revision(conn);
+            }
+            Ok::<(), crate::Error>(())
+        });
+        report.add(IssueKind::DanglingAttachment, target, result);
+    }
+
+    for (cipher_uuid, path) in list_files(&CONFIG.attachments_folder()) {
+        let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        if !is_attachment_file_referenced(&cipher_uuid, file_name, &attachments, &pending) {
+            check_orphaned_file(report, IssueKind::OrphanedAttachmentFile, &path, repair);
+        }
+    }
+}
+
+/// Whether `folder` contains any attachment files. A missing or empty folder usually means that the volume
+/// isn't mounted, in which case attachments whose file is missing must not be removed.
+pub fn has_attachment_files(folder: &str) -> bool {
+    let entries = match fs::read_dir(folder) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries
+        .filter_map(Result::ok)
+        .filter_map(|dir| fs::read_dir(dir.path()).ok())
+        .any(|mut dir_entries| dir_entries.any(|file| file.map_or(false, |f| f.path().is_file())))
+}
+
+/// Files are stored as `<attachments_folder>/<cipher_uuid>/<attachment_id>`, with a `.part` suffix while uploading.
+fn is_attachment_file_referenced(
+    cipher_uuid: &str,
+    file_name: &str,
+    attachments: &HashMap<String, Attachment>,
+    pending: &HashSet<String>,
+) -> bool {
+    let attachment_id = match file_name.strip_suffix(".part") {
+        Some(id) if pending.contains(id) => id,
+        Some(_) => return false,
+        None => file_name,
+    };
+    attachments.get(attachment_id).map_or(false, |a| a.cipher_uuid == cipher_uuid)
+}
+
+fn check_sends(report: &mut FsckReport, repair: bool, conn: &DbConn) {
+    let mut files: HashMap<String, String> = HashMap::new();
+
+    for send in Send::find_by_type(SendType::File, conn) {
+        let file_id = serde_json::from_str::<Value>(&send.data)
+            .ok()
+            .and_then(|d| d["Id"].as_str().map(String::from))
+            .unwrap_or_default();
+
+        let path = Path::new(&CONFIG.sends_folder()).join(&send.uuid).join(&file_id);
+        if !file_id.is_empty() && path.is_file() {
+            files.insert(send.uuid, file_id);
+            continue;
+        }
+
+        let target = send.uuid.clone();
+        let result = repair_with(repair, || send.delete(conn));
+        report.add(IssueKind::DanglingSend, target, result);
+    }
+
+    // Files are stored as `<sends_folder>/<send_uuid>/<file_id>`
+    for (send_uuid, path) in list_files(&CONFIG.sends_folder()) {
+        let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        if files.get(&send_uuid).map_or(false, |id| id == file_name) {
+            continue;
+        }
+
+        check_orphaned_file(report, IssueKind::OrphanedSendFile, &path, repair);
+    }
+}
+
+fn check_orphaned_file(report: &mut FsckReport, kind: IssueKind, path: &Path, repair: bool) {
+    let recent = fs::metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .map_or(true, |age| age < MIN_FILE_AGE);
+    if !recent {
+        remove_orphaned_file(report, kind, path, repair);
+    }
+}
+
+fn remove_orphaned_file(report: &mut FsckReport, kind: IssueKind, path: &Path, repair: bool) {
+    let path = path.to_string_lossy().into_owned();
+    let result = repair_with(repair, || util::delete_file(&path));
+    report.add(kind, path, result);
+}
+
+/// Lists all the files one level deep inside `folder`, along with the name of the folder that contains them.
+fn list_files(folder: &str) -> Vec<(String, std::path::PathBuf)> {
+    let mut files = Vec::new();
+
+    let entries = match fs::read_dir(folder) {
+        Ok(entries) => entries,
+        Err(_) => return files, // The folder is only created once the first file is stored
+    };
+
+    for dir in entries.filter_map(Result::ok) {
+        let dir_name = dir.file_name().to_string_lossy().into_owned();
+        if let Ok(dir_entries) = fs::read_dir(dir.path()) {
+            for file in dir_entries.filter_map(Result::ok) {
+                if file.path().is_file() {
+                    files.push((dir_name.clone(), file.path()));
+                }
+            }
+        }
+    }
+
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attachment_file_referenced() {
+        let attachment = Attachment::new(String::from("att"), String::from("cipher"), String::from("file"), 10, None);
+        let attachments: HashMap<String, Attachment> = vec![(attachment.id.clone(), attachment)].into_iter().collect();
+        let mut pending = HashSet::new();
+
+        assert!(is_attachment_file_referenced("cipher", "att", &attachments, &pending));
+        assert!(!is_attachment_file_referenced("other-cipher", "att", &attachments, &pending));
+        assert!(!is_attachment_file_referenced("cipher", "unknown", &attachments, &pending));
+
+        // Partial files are only kept while their upload is pending
+        assert!(!is_attachment_file_referenced("cipher", "att.part", &attachments, &pending));
+        pending.insert(String::from("att"));
+        assert!(is_attachment_file_referenced("cipher", "att.part", &attachments, &pending));
+        assert!(!is_attachment_file_referenced("other-cipher", "att.part", &attachments, &pending));
+    }
+
+    #[test]
+    fn test_orphaned_files() {
+        let folder = std::env::temp_dir().join(format!("vaultwarden-fsck-{}", crate::util::get_uuid()));
+        fs::create_dir_all(folder.join("cipher")).unwrap();
+        let path = folder.join("cipher").join("att");
+        fs::write(&path, b"data").unwrap();
+
+        let files = list_files(folder.to_str().unwrap());
+        assert_eq!(files, vec![(String::from("cipher"), path.clone())]);
+
+        // Files which may belong to an upload in progress are skipped
+        let mut report = FsckReport::default();
+        check_orphaned_file(&mut report, IssueKind::OrphanedAttachmentFile, &path, true);
+        assert!(report.issues.is_empty());
+        assert!(path.exists());
+
+        // Only reported without repair
+        remove_orphaned_file(&mut report, IssueKind::OrphanedAttachmentFile, &path, false);
+        assert_eq!(report.unrepaired_count(), 1);
+        assert!(path.exists());
+
+        // Removed with repair, along with its empty folder
+        let mut report = FsckReport::default();
+        remove_orphaned_file(&mut report, IssueKind::OrphanedAttachmentFile, &path, true);
+        assert_eq!(report.issues.len(), 1);
+        assert_eq!(report.unrepaired_count(), 0);
+        assert!(!path.exists());
+        assert!(!folder.join("cipher").exists());
+        assert_eq!(report.to_json()["issue_count"], 1);
+
+        fs::remove_dir_all(&folder).ok();
+    }
+
+    #[test]
+    fn test_has_attachment_files() {
+        let folder = std::env::temp_dir().join(format!("vaultwarden-fsck-{}", crate::util::get_uuid()));
+        let folder_str = folder.to_str().unwrap();
+        assert!(!has_attachment_files(folder_str));
+
+        fs::create_dir_all(folder.join("cipher")).unwrap();
+        assert!(!has_attachment_files(folder_str));
+
+        fs::write(folder.join("cipher").join("att"), b"data").unwrap();
+        assert!(has_attachment_files(folder_str));
+
+        fs::remove_dir_all(&folder).ok();
+    }
+
+    #[test]
+    fn test_report_repair_results() {
+        let mut report = FsckReport::default();
+        report.add(IssueKind::OrphanedCipher, String::from("a"), repair_with(false, || Ok::<(), String>(())));
+        report.add(IssueKind::OrphanedCipher, String::from("b"), repair_with(true, || Ok::<(), String>(())));
+        report.add(IssueKind::OrphanedCipher, String::from("c"), repair_with(true, || Err(String::from("failed"))));
+
+        let repaired: Vec<bool> = report.issues.iter().map(|i| i.repaired).collect();
+        assert_eq!(repaired, vec![false, true, false]);
+        assert_eq!(report.unrepaired_count(), 2);
+        assert!(report.to_string().ends_with("3 issue(s) found, 2 left unrepaired"));
+
+        report.warn(String::from("folder missing"));
+        assert!(report.to_string().contains("[warning] folder missing\n"));
+        assert_eq!(report.to_json()["warnings"][0], "folder missing");
+    }
+}
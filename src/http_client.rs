@@ -0,0 +1,563 @@
+//
+// Outbound HTTP requests to URLs which come from users or remote sites
+//
+// Resolving a domain and checking its IP before handing the URL to reqwest isn't enough: reqwest
+// resolves it again when connecting (DNS rebinding), and follows redirects to any host. So here
+// the host of every hop is resolved and checked against the network policy, the connection is
+// pinned to the checked address, and the redirects are followed manually.
+//
+// Note that when an HTTP proxy is configured, the proxy resolves the hosts itself.
+//
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use once_cell::sync::Lazy;
+use reqwest::{
+    blocking::{Client, ClientBuilder, RequestBuilder, Response},
+    header::{self, HeaderMap},
+    redirect::Policy,
+    Method, StatusCode, Url,
+};
+
+use crate::{error::Error, CONFIG};
+
+/// Number of requests blocked by the network policy since the server started.
+static BLOCKED_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+pub fn blocked_requests() -> u64 {
+    BLOCKED_REQUESTS.load(Ordering::Relaxed)
+}
+
+/// Max number of reqwest clients kept by an `HttpClient`, one for every host and address they are pinned to.
+const MAX_CACHED_CLIENTS: usize = 64;
+
+pub struct HttpClient {
+    builder: Box<dyn Fn() -> ClientBuilder + Send + Sync>,
+    block_non_global_ips: fn() -> bool,
+    is_host_blocked: Option<fn(&str) -> bool>,
+    // The clients are reused, as building one sets up the TLS configuration and a new connection pool.
+    // Pinning a host to an address can only be done when building a client, so there is one for each of them.
+    clients: Mutex<HashMap<Option<(String, SocketAddr)>, Client>>,
+}
+
+/// Why a URL wasn't checked successfully.
+enum CheckError {
+    /// Blocked by the network policy
+    Blocked(&'static str),
+    Failed(String),
+}
+
+impl HttpClient {
+    /// Creates a client from the given reqwest client builder, which is called when a new host is requested.
+    /// Its redirect policy is replaced, as the redirects are followed by this client.
+    pub fn new<F>(builder: F) -> Self
+    where
+        F: Fn() -> ClientBuilder + Send + Sync + 'static,
+    {
+        Self {
+            builder: Box::new(builder),
+            block_non_global_ips: || true,
+            is_host_blocked: None,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets whether the IPs which aren't global are blocked, unless they are in `HTTP_REQUEST_ALLOW_CIDRS`.
+    /// This is checked on every request, so it can follow a setting. Defaults to true.
+    pub fn block_non_global_ips(mut self, block_non_global_ips: fn() -> bool) -> Self {
+        self.block_non_global_ips = block_non_global_ips;
+        self
+    }
+
+    /// Sets an additional check of the host of every hop.
+    pub fn block_hosts(mut self, is_host_blocked: fn(&str) -> bool) -> Self {
+        self.is_host_blocked = Some(is_host_blocked);
+        self
+    }
+
+    pub fn get(&self, url: &str) -> Result<HttpResponse, Error> {
+        self.send(Method::GET, url, |request| request)
+    }
+
+    /// Sends the request, following the redirects. The request is set up by `configure` on every hop,
+    /// though the credentials are removed when redirected to another host.
+    pub fn send<F>(&self, method: Method, url: &str, configure: F) -> Result<HttpResponse, Error>
+    where
+        F: Fn(RequestBuilder) -> RequestBuilder,
+    {
+        let first_url = match Url::parse(url) {
+            Ok(url) => url,
+            Err(e) => err!(format!("Invalid URL {}: {}", url, e)),
+        };
+        let mut url = first_url.clone();
+        let mut method = method;
+        let mut redirects = 0;
+
+        loop {
+            let client = self.client(self.check_url(&url)?)?;
+
+            let mut request = configure(client.request(method.clone(), url.clone())).build()?;
+            if !keeps_credentials(&first_url, &url) {
+                for name in &[header::AUTHORIZATION, header::COOKIE, header::PROXY_AUTHORIZATION] {
+                    request.headers_mut().remove(name);
+                }
+            }
+
+            let response = client.execute(request)?;
+            let location = match redirect_location(&url, response.status(), response.headers()) {
+                Some(location) => location,
+                None => return HttpResponse::new(response),
+            };
+
+            redirects += 1;
+            if redirects > CONFIG.http_request_max_redirects() {
+                err!(format!("Too many redirects requesting {}", first_url))
+            }
+
+            // Like browsers, change to GET except for the redirects which require keeping the method
+            let status = response.status();
+            if status != StatusCode::TEMPORARY_REDIRECT
+                && status != StatusCode::PERMANENT_REDIRECT
+                && method != Method::HEAD
+            {
+                method = Method::GET;
+            }
+            debug!("Following redirect from {} to {}", url, location);
+            url = location;
+        }
+    }
+
+    /// The reqwest client to use for a request, pinned to the given host and address if any.
+    fn client(&self, pinned: Option<(String, SocketAddr)>) -> Result<Client, Error> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get(&pinned) {
+            return Ok(client.clone());
+        }
+
+        let mut builder = (self.builder)().redirect(Policy::none());
+        if let Some((ref host, addr)) = pinned {
+            builder = builder.resolve(host, addr);
+        }
+        let client = builder.build()?;
+
+        if clients.len() >= MAX_CACHED_CLIENTS {
+            clients.clear();
+        }
+        clients.insert(pinned, client.clone());
+        Ok(client)
+    }
+
+    /// Checks the scheme and the host of the URL against the network policy, blocked requests are counted and logged.
+    /// Returns the domain and the checked address to connect to, if the host isn't an IP.
+    fn check_url(&self, url: &Url) -> Result<Option<(String, SocketAddr)>, Error> {
+        match self.check_url_policy(url) {
+            Ok(pinned) => Ok(pinned),
+            Err(CheckError::Blocked(reason)) => {
+                BLOCKED_REQUESTS.fetch_add(1, Ordering::Relaxed);
+                warn!("Blocked outbound request to {}: {}", url, reason);
+                let msg = format!("Request to {} blocked: {}", url, reason);
+                Err(Error::new(msg.clone(), msg))
+            }
+            Err(CheckError::Failed(msg)) => err!(msg.as_str()),
+        }
+    }
+
+    fn check_url_policy(&self, url: &Url) -> Result<Option<(String, SocketAddr)>, CheckError> {
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(CheckError::Blocked("unsupported scheme"));
+        }
+
+        let host = match url.host_str() {
+            Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => return Err(CheckError::Blocked("missing host")),
+        };
+        if self.is_host_blocked.map_or(false, |is_host_blocked| is_host_blocked(host)) {
+            return Err(CheckError::Blocked("blacklisted host"));
+        }
+
+        let port = url.port_or_known_default().unwrap_or(80);
+        if let Ok(ip) = IpAddr::from_str(host) {
+            check_ip(ip, (self.block_non_global_ips)()).map_err(CheckError::Blocked)?;
+            return Ok(None);
+        }
+
+        let addrs: Vec<SocketAddr> = match (host, port).to_socket_addrs() {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => return Err(CheckError::Failed(format!("Error resolving {}: {}", host, e))),
+        };
+
+        // Every address is checked, the domain shouldn't resolve to an internal one at all
+        let block_non_global_ips = (self.block_non_global_ips)();
+        for addr in &addrs {
+            check_ip(addr.ip(), block_non_global_ips).map_err(CheckError::Blocked)?;
+        }
+
+        match addrs.into_iter().next() {
+            Some(addr) => Ok(Some((host.to_string(), addr))),
+            None => Err(CheckError::Failed(format!("{} didn't resolve to any address", host))),
+        }
+    }
+
+    /// Returns if requests to the host would be allowed, without sending any. It isn't counted as a blocked request.
+    pub fn is_host_allowed(&self, host: &str) -> bool {
+        match Url::parse(&format!("https://{}", host)) {
+            Ok(url) => self.check_url_policy(&url).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// The credentials set up for the first URL are only sent to the same host, and not over plain HTTP
+/// if the first URL was HTTPS.
+fn keeps_credentials(first_url: &Url, url: &Url) -> bool {
+    url.host_str() == first_url.host_str() && !(first_url.scheme() == "https" && url.scheme() == "http")
+}
+
+fn redirect_location(url: &Url, status: StatusCode, headers: &HeaderMap) -> Option<Url> {
+    if !status.is_redirection() || status == StatusCode::NOT_MODIFIED {
+        return None;
+    }
+    let location = headers.get(header::LOCATION)?.to_str().ok()?;
+    url.join(location).ok()
+}
+
+/// A parsed CIDR list setting, along with the value it was parsed from, so that it's only parsed again when the config changes.
+type CidrListCache = Lazy<Mutex<(Option<String>, Vec<IpNetwork>)>>;
+
+static DENY_CIDRS: CidrListCache = Lazy::new(|| Mutex::new((None, Vec::new())));
+static ALLOW_CIDRS: CidrListCache = Lazy::new(|| Mutex::new((None, Vec::new())));
+
+fn cidr_list_contains(cache: &CidrListCache, list: Option<String>, ip: IpAddr) -> bool {
+    let mut cache = cache.lock().unwrap();
+    if cache.0 != list {
+        // The settings are validated when they are set
+        let networks = list.as_deref().map(|list| parse_cidr_list(list).unwrap_or_default()).unwrap_or_default();
+        *cache = (list, networks);
+    }
+    cache.1.iter().any(|net| net.contains(ip))
+}
+
+/// Checks the IP against the deny and allow lists, and then whether it's global.
+fn check_ip(ip: IpAddr, block_non_global_ips: bool) -> Result<(), &'static str> {
+    let ip = canonical_ip(ip);
+
+    if cidr_list_contains(&DENY_CIDRS, CONFIG.http_request_deny_cidrs(), ip) {
+        return Err("IP in HTTP_REQUEST_DENY_CIDRS");
+    }
+    if cidr_list_contains(&ALLOW_CIDRS, CONFIG.http_request_allow_cidrs(), ip) {
+        return Ok(());
+    }
+    if block_non_global_ips && !is_global(ip) {
+        return Err("IP is not global");
+    }
+    Ok(())
+}
+
+/// IPv4-mapped IPv6 addresses (::ffff:a.b.c.d) reach the IPv4 host, so they are checked as such.
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => IpAddr::V4(Ipv4Addr::from(((hi as u32) << 16) | lo as u32)),
+            _ => ip,
+        },
+        ip => ip,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = match IpAddr::from_str(addr.trim()) {
+            Ok(addr) => canonical_ip(addr),
+            Err(_) => err!(format!("Invalid IP address in CIDR `{}`", s)),
+        };
+        let max_prefix = if addr.is_ipv4() {
+            32
+        } else {
+            128
+        };
+        let prefix = match prefix.map(|p| p.trim().parse::<u8>()) {
+            None => max_prefix,
+            Some(Ok(prefix)) if prefix <= max_prefix => prefix,
+            Some(_) => err!(format!("Invalid prefix length in CIDR `{}`", s)),
+        };
+
+        Ok(Self {
+            addr,
+            prefix,
+        })
+    }
+}
+
+/// Parses a comma separated list of CIDR ranges, like `10.0.0.0/8, fd00::/8`. A single IP is a range by itself.
+pub fn parse_cidr_list(list: &str) -> Result<Vec<IpNetwork>, Error> {
+    list.split(',').map(str::trim).filter(|cidr| !cidr.is_empty()).map(IpNetwork::from_str).collect()
+}
+
+/// A response whose body can't be read past `HTTP_REQUEST_MAX_SIZE`.
+pub struct HttpResponse {
+    response: Response,
+    read: u64,
+    max_size: u64,
+}
+
+impl HttpResponse {
+    fn new(response: Response) -> Result<Self, Error> {
+        let max_size = CONFIG.http_request_max_size().saturating_mul(1024 * 1024);
+        if response.content_length().map_or(false, |len| len > max_size) {
+            err!(format!("The response from {} exceeds the max size", response.url()))
+        }
+
+        Ok(Self {
+            response,
+            read: 0,
+            max_size,
+        })
+    }
+
+    pub fn url(&self) -> &Url {
+        self.response.url()
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.response.status()
+    }
+
+    pub fn error_for_status(self) -> Result<Self, Error> {
+        match self.response.error_for_status_ref() {
+            Ok(_) => Ok(self),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn bytes(mut self) -> Result<Vec<u8>, Error> {
+        let mut buffer = Vec::new();
+        self.read_to_end(&mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+impl Read for HttpResponse {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.response.read(buf)?;
+        self.read += read as u64;
+        if self.read > self.max_size {
+            return Err(io::Error::new(io::ErrorKind::Other, "The response exceeds the max size"));
+        }
+        Ok(read)
+    }
+}
+
+/// TODO: This is extracted from IpAddr::is_global, which is unstable:
+/// https://doc.rust-lang.org/nightly/std/net/enum.IpAddr.html#method.is_global
+/// Remove once https://github.com/rust-lang/rust/issues/27709 is merged
+#[allow(clippy::nonminimal_bool)]
+#[cfg(not(feature = "unstable"))]
+pub fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            // check if this address is 192.0.0.9 or 192.0.0.10. These addresses are the only two
+            // globally routable addresses in the 192.0.0.0/24 range.
+            if u32::from(ip) == 0xc0000009 || u32::from(ip) == 0xc000000a {
+                return true;
+            }
+            !ip.is_private()
+            && !ip.is_loopback()
+            && !ip.is_link_local()
+            && !ip.is_broadcast()
+            && !ip.is_documentation()
+            && !(ip.octets()[0] == 100 && (ip.octets()[1] & 0b1100_0000 == 0b0100_0000))
+            && !(ip.octets()[0] == 192 && ip.octets()[1] == 0 && ip.octets()[2] == 0)
+            && !(ip.octets()[0] & 240 == 240 && !ip.is_broadcast())
+            && !(ip.octets()[0] == 198 && (ip.octets()[1] & 0xfe) == 18)
+            // Make sure the address is not in 0.0.0.0/8
+            && ip.octets()[0] != 0
+        }
+        IpAddr::V6(ip) => {
+            if ip.is_multicast() && ip.segments()[0] & 0x000f == 14 {
+                true
+            } else {
+                !ip.is_multicast()
+                    && !ip.is_loopback()
+                    && !((ip.segments()[0] & 0xffc0) == 0xfe80)
+                    && !((ip.segments()[0] & 0xfe00) == 0xfc00)
+                    && !ip.is_unspecified()
+                    && !((ip.segments()[0] == 0x2001) && (ip.segments()[1] == 0xdb8))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "unstable")]
+pub fn is_global(ip: IpAddr) -> bool {
+    ip.is_global()
+}
+
+/// These are some tests to check that the implementations match
+/// The IPv4 can be all checked in 5 mins or so and they are correct as of nightly 2020-07-11
+/// The IPV6 can't be checked in a reasonable time, so we check  about ten billion random ones, so far correct
+/// Note that the is_global implementation is subject to change as new IP RFCs are created
+///
+/// To run while showing progress output:
+/// cargo test --features sqlite,unstable -- --nocapture --ignored
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore]
+    #[cfg(feature = "unstable")]
+    fn test_ipv4_global() {
+        for a in 0..u8::MAX {
+            println!("Iter: {}/255", a);
+            for b in 0..u8::MAX {
+                for c in 0..u8::MAX {
+                    for d in 0..u8::MAX {
+                        let ip = IpAddr::V4(std::net::Ipv4Addr::new(a, b, c, d));
+                        assert_eq!(ip.is_global(), is_global(ip))
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[ignore]
+    #[cfg(feature = "unstable")]
+    fn test_ipv6_global() {
+        use ring::rand::{SecureRandom, SystemRandom};
+        let mut v = [0u8; 16];
+        let rand = SystemRandom::new();
+        for i in 0..1_000 {
+            println!("Iter: {}/1_000", i);
+            for _ in 0..10_000_000 {
+                rand.fill(&mut v).expect("Error generating random values");
+                let ip = IpAddr::V6(std::net::Ipv6Addr::new(
+                    (v[14] as u16) << 8 | v[15] as u16,
+                    (v[12] as u16) << 8 | v[13] as u16,
+                    (v[10] as u16) << 8 | v[11] as u16,
+                    (v[8] as u16) << 8 | v[9] as u16,
+                    (v[6] as u16) << 8 | v[7] as u16,
+                    (v[4] as u16) << 8 | v[5] as u16,
+                    (v[2] as u16) << 8 | v[3] as u16,
+                    (v[0] as u16) << 8 | v[1] as u16,
+                ));
+                assert_eq!(ip.is_global(), is_global(ip))
+            }
+        }
+    }
+
+    #[test]
+    fn test_cidr() {
+        let ip = |s: &str| IpAddr::from_str(s).unwrap();
+        let nets = parse_cidr_list("10.0.0.0/8, 192.168.1.1 ,fd00::/8").unwrap();
+        assert_eq!(nets.len(), 3);
+
+        assert!(nets[0].contains(ip("10.1.2.3")));
+        assert!(!nets[0].contains(ip("11.0.0.1")));
+        assert!(nets[1].contains(ip("192.168.1.1")));
+        assert!(!nets[1].contains(ip("192.168.1.2")));
+        assert!(nets[2].contains(ip("fd12:3456::1")));
+        assert!(!nets[2].contains(ip("10.1.2.3")));
+
+        assert!(IpNetwork::from_str("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+        assert!(IpNetwork::from_str("10.0.0.0/33").is_err());
+        assert!(IpNetwork::from_str("example.com/8").is_err());
+    }
+
+    #[test]
+    fn test_canonical_ip() {
+        let ip = |s: &str| IpAddr::from_str(s).unwrap();
+        assert_eq!(canonical_ip(ip("::ffff:169.254.169.254")), ip("169.254.169.254"));
+        assert_eq!(canonical_ip(ip("::1")), ip("::1"));
+        assert!(!is_global(canonical_ip(ip("::ffff:127.0.0.1"))));
+    }
+
+    #[test]
+    fn test_check_url() {
+        let client = HttpClient::new(ClientBuilder::new);
+        let check = |client: &HttpClient, url: &str| client.check_url(&Url::parse(url).unwrap()).is_ok();
+
+        assert!(check(&client, "https://8.8.8.8/"));
+        assert!(check(&client, "http://[2001:4860:4860::8888]:8080/"));
+
+        assert!(!check(&client, "http://127.0.0.1/"));
+        assert!(!check(&client, "http://[::1]/"));
+        assert!(!check(&client, "http://169.254.169.254/latest/meta-data/"));
+        assert!(!check(&client, "http://10.0.0.1:8080/"));
+        assert!(!check(&client, "http://[fd00::1]/"));
+        assert!(!check(&client, "http://0.0.0.0/"));
+        // IPv4-mapped IPv6, and the other notations of IPv4 addresses that the URL parser accepts
+        assert!(!check(&client, "http://[::ffff:127.0.0.1]/"));
+        assert!(!check(&client, "http://2130706433/"));
+        assert!(!check(&client, "http://0x7f.1/"));
+        assert!(!check(&client, "ftp://8.8.8.8/"));
+
+        let client =
+            HttpClient::new(ClientBuilder::new).block_non_global_ips(|| false).block_hosts(|host| host == "8.8.8.8");
+        assert!(check(&client, "http://127.0.0.1/"));
+        assert!(!check(&client, "https://8.8.8.8/"));
+
+        // Checking a host doesn't count as a blocked request
+        let blocked = blocked_requests();
+        assert!(!client.is_host_allowed("8.8.8.8"));
+        assert!(client.is_host_allowed("127.0.0.1"));
+        assert_eq!(blocked_requests(), blocked);
+    }
+
+    #[test]
+    fn test_keeps_credentials() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        assert!(keeps_credentials(&url("https://example.com/a"), &url("https://example.com/b")));
+        assert!(keeps_credentials(&url("http://example.com/a"), &url("https://example.com/b")));
+        assert!(!keeps_credentials(&url("https://example.com/a"), &url("https://example.org/a")));
+        assert!(!keeps_credentials(&url("https://example.com/a"), &url("http://example.com/a")));
+    }
+
+    #[test]
+    fn test_redirect_location() {
+        let url = Url::parse("https://example.com/a/b").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::LOCATION, header::HeaderValue::from_static("/c"));
+
+        assert_eq!(redirect_location(&url, StatusCode::FOUND, &headers).unwrap().as_str(), "https://example.com/c");
+        assert!(redirect_location(&url, StatusCode::OK, &headers).is_none());
+        assert!(redirect_location(&url, StatusCode::NOT_MODIFIED, &headers).is_none());
+    }
+}
@@ -0,0 +1,521 @@
+//
+// JWT signing key ring
+//
+// Tokens are signed with the newest key of the ring, and carry its id in the `kid` header. After a rotation the
+// previous keys are kept to verify the tokens they signed until the grace period is over, so rotating doesn't log
+// anyone out. The public keys are published as a JWK Set (RFC 7517) in `/.well-known/jwks.json`.
+//
+// Every key is stored in `JWT_KEYS_FOLDER` as `<kid>.pem`, with the metadata of the ring in `keys.json`. The key
+// ids are the JWK thumbprints (RFC 7638) of the public keys.
+//
+use std::{
+    fs,
+    sync::{RwLock, RwLockReadGuard},
+};
+
+use chrono::Utc;
+use data_encoding::BASE64URL_NOPAD;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use once_cell::sync::Lazy;
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey},
+    nid::Nid,
+    pkey::{Id, PKey, Private},
+    rsa::Rsa,
+    sha::sha256,
+    sign::{Signer, Verifier},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::{
+    api::EmptyResult,
+    error::{Error, MapResult},
+    util, CONFIG,
+};
+
+/// Allowed clock difference when validating the time claims, in seconds.
+const LEEWAY: i64 = 30;
+/// Min time between two reloads of the ring from disk, to pick up the rotations done by other instances.
+const RELOAD_INTERVAL: i64 = 60;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyAlgorithm {
+    #[serde(rename = "RS256")]
+    Rs256,
+    #[serde(rename = "ES256")]
+    Es256,
+    #[serde(rename = "EdDSA")]
+    EdDsa,
+}
+
+impl KeyAlgorithm {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "RS256" => Some(Self::Rs256),
+            "ES256" => Some(Self::Es256),
+            "EdDSA" => Some(Self::EdDsa),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Rs256 => "RS256",
+            Self::Es256 => "ES256",
+            Self::EdDsa => "EdDSA",
+        }
+    }
+
+    fn of_key(key: &PKey<Private>) -> Result<Self, Error> {
+        match key.id() {
+            Id::RSA => Ok(Self::Rs256),
+            Id::EC => Ok(Self::Es256),
+            Id::ED25519 => Ok(Self::EdDsa),
+            _ => err!("JWT keys must be RSA, P-256 or Ed25519 keys"),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyMetadata {
+    pub kid: String,
+    pub algorithm: KeyAlgorithm,
+    pub created_at: i64,
+    // When a newer key replaced it for signing, the key is removed once the grace period is over
+    pub retired_at: Option<i64>,
+}
+
+struct JwtKey {
+    meta: KeyMetadata,
+    private_key: PKey<Private>,
+    // Ed25519 isn't supported by jsonwebtoken, those tokens are signed and verified with openssl directly
+    encoding_key: Option<EncodingKey>,
+    decoding_key: Option<DecodingKey<'static>>,
+    jwk: Value,
+}
+
+impl JwtKey {
+    fn new(meta: KeyMetadata, private_key: PKey<Private>) -> Result<Self, Error> {
+        let (encoding_key, decoding_key) = match meta.algorithm {
+            KeyAlgorithm::Rs256 => {
+                let rsa = private_key.rsa()?;
+                (
+                    Some(EncodingKey::from_rsa_pem(&rsa.private_key_to_pem()?)?),
+                    Some(DecodingKey::from_rsa_pem(&rsa.public_key_to_pem()?)?.into_static()),
+                )
+            }
+            KeyAlgorithm::Es256 => (
+                Some(EncodingKey::from_ec_pem(&private_key.private_key_to_pem_pkcs8()?)?),
+                Some(DecodingKey::from_ec_pem(&private_key.public_key_to_pem()?)?.into_static()),
+            ),
+            KeyAlgorithm::EdDsa => (None, None),
+        };
+
+        let mut jwk = public_jwk(&private_key)?;
+        jwk["kid"] = Value::String(meta.kid.clone());
+        jwk["alg"] = Value::String(meta.algorithm.as_str().to_string());
+        jwk["use"] = Value::String("sig".to_string());
+
+        Ok(Self {
+            meta,
+            private_key,
+            encoding_key,
+            decoding_key,
+            jwk,
+        })
+    }
+
+    fn generate(algorithm: KeyAlgorithm) -> Result<Self, Error> {
+        let private_key = match algorithm {
+            KeyAlgorithm::Rs256 => PKey::from_rsa(Rsa::generate(2048)?)?,
+            KeyAlgorithm::Es256 => {
+                PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?)?)?
+            }
+            KeyAlgorithm::EdDsa => PKey::generate_ed25519()?,
+        };
+        Self::from_private_key(private_key)
+    }
+
+    fn from_private_key(private_key: PKey<Private>) -> Result<Self, Error> {
+        let meta = KeyMetadata {
+            kid: thumbprint(&public_jwk(&private_key)?),
+            algorithm: KeyAlgorithm::of_key(&private_key)?,
+            created_at: Utc::now().timestamp(),
+            retired_at: None,
+        };
+        Self::new(meta, private_key)
+    }
+
+    fn is_expired(&self, now: i64) -> bool {
+        match self.meta.retired_at {
+            Some(retired_at) => retired_at + CONFIG.jwt_key_grace_period() as i64 * 3600 < now,
+            None => false,
+        }
+    }
+
+    fn sign<T: Serialize>(&self, claims: &T) -> Result<String, Error> {
+        if let Some(ref encoding_key) = self.encoding_key {
+            let mut header = Header::new(self.jwt_algorithm());
+            header.kid = Some(self.meta.kid.clone());
+            return jsonwebtoken::encode(&header, claims, encoding_key).map_res("Error encoding JWT");
+        }
+
+        let header = json!({ "typ": "JWT", "alg": self.meta.algorithm.as_str(), "kid": self.meta.kid });
+        let signing_input = format!(
+            "{}.{}",
+            BASE64URL_NOPAD.encode(header.to_string().as_bytes()),
+            BASE64URL_NOPAD.encode(&serde_json::to_vec(claims)?)
+        );
+        let signature = Signer::new_without_digest(&self.private_key)?.sign_oneshot_to_vec(signing_input.as_bytes())?;
+        Ok(format!("{}.{}", signing_input, BASE64URL_NOPAD.encode(&signature)))
+    }
+
+    fn verify<T: DeserializeOwned>(&self, token: &str, issuer: &str) -> Result<T, Error> {
+        if let Some(ref decoding_key) = self.decoding_key {
+            let validation = Validation {
+                leeway: LEEWAY as u64,
+                validate_exp: true,
+                validate_nbf: true,
+                aud: None,
+                iss: Some(issuer.to_string()),
+                sub: None,
+                algorithms: vec![self.jwt_algorithm()],
+            };
+            return jsonwebtoken::decode(token, decoding_key, &validation)
+                .map(|d| d.claims)
+                .map_res("Error decoding JWT");
+        }
+
+        let (signing_input, signature) = token.rsplit_once('.').map_res("Invalid JWT format")?;
+        let signature = BASE64URL_NOPAD.decode(signature.as_bytes()).ok().map_res("Invalid JWT signature encoding")?;
+        if !Verifier::new_without_digest(&self.private_key)?.verify_oneshot(&signature, signing_input.as_bytes())? {
+            err!("Error decoding JWT", "Invalid signature")
+        }
+
+        let payload = signing_input.split('.').nth(1).map_res("Invalid JWT format")?;
+        let payload = BASE64URL_NOPAD.decode(payload.as_bytes()).ok().map_res("Invalid JWT payload encoding")?;
+        let claims: Value = serde_json::from_slice(&payload)?;
+        validate_claims(&claims, issuer, Utc::now().timestamp())?;
+        Ok(serde_json::from_value(claims)?)
+    }
+
+    fn jwt_algorithm(&self) -> Algorithm {
+        match self.meta.algorithm {
+            KeyAlgorithm::Es256 => Algorithm::ES256,
+            _ => Algorithm::RS256,
+        }
+    }
+}
+
+/// Checks the time and issuer claims, the same way jsonwebtoken does for the tokens it verifies.
+fn validate_claims(claims: &Value, issuer: &str, now: i64) -> EmptyResult {
+    match claims["exp"].as_i64() {
+        Some(exp) if exp >= now - LEEWAY => (),
+        _ => err!("Error decoding JWT", "Expired token"),
+    }
+    if let Some(nbf) = claims["nbf"].as_i64() {
+        if nbf > now + LEEWAY {
+            err!("Error decoding JWT", "Token not valid yet")
+        }
+    }
+    if claims["iss"].as_str() != Some(issuer) {
+        err!("Error decoding JWT", "Invalid issuer")
+    }
+    Ok(())
+}
+
+/// The members of the public JWK which are used to compute the thumbprint.
+fn public_jwk(key: &PKey<Private>) -> Result<Value, Error> {
+    let b64 = |bytes: &[u8]| BASE64URL_NOPAD.encode(bytes);
+
+    Ok(match key.id() {
+        Id::RSA => {
+            let rsa = key.rsa()?;
+            json!({ "kty": "RSA", "n": b64(&rsa.n().to_vec()), "e": b64(&rsa.e().to_vec()) })
+        }
+        Id::EC => {
+            let ec_key = key.ec_key()?;
+            let (mut x, mut y) = (BigNum::new()?, BigNum::new()?);
+            ec_key.public_key().affine_coordinates_gfp(ec_key.group(), &mut x, &mut y, &mut BigNumContext::new()?)?;
+            json!({ "kty": "EC", "crv": "P-256", "x": b64(&x.to_vec_padded(32)?), "y": b64(&y.to_vec_padded(32)?) })
+        }
+        Id::ED25519 => {
+            // The raw public key is the end of the SubjectPublicKeyInfo
+            let der = key.public_key_to_der()?;
+            json!({ "kty": "OKP", "crv": "Ed25519", "x": b64(&der[der.len() - 32..]) })
+        }
+        _ => err!("JWT keys must be RSA, P-256 or Ed25519 keys"),
+    })
+}
+
+/// JWK thumbprint (RFC 7638): the hash of the required members, in lexicographic order and without whitespace.
+fn thumbprint(jwk: &Value) -> String {
+    let members: &[&str] = match jwk["kty"].as_str() {
+        Some("RSA") => &["e", "kty", "n"],
+        Some("EC") => &["crv", "kty", "x", "y"],
+        _ => &["crv", "kty", "x"],
+    };
+    let fields: Vec<String> = members.iter().map(|m| format!("\"{}\":{}", m, jwk[m])).collect();
+    BASE64URL_NOPAD.encode(&sha256(format!("{{{}}}", fields.join(",")).as_bytes()))
+}
+
+struct KeyRing {
+    // Sorted from newest to oldest, the first one is used for signing
+    keys: Vec<JwtKey>,
+    loaded_at: i64,
+}
+
+static KEY_RING: Lazy<RwLock<KeyRing>> =
+    Lazy::new(|| RwLock::new(KeyRing::load().unwrap_or_else(|e| panic!("Error loading the JWT keys.\n{:?}", e))));
+
+fn metadata_path() -> String {
+    format!("{}/keys.json", CONFIG.jwt_keys_folder())
+}
+
+fn key_path(kid: &str) -> String {
+    format!("{}/{}.pem", CONFIG.jwt_keys_folder(), kid)
+}
+
+impl KeyRing {
+    fn load() -> Result<Self, Error> {
+        fs::create_dir_all(CONFIG.jwt_keys_folder())?;
+
+        let metadata: Vec<KeyMetadata> = match util::read_file_string(&metadata_path()) {
+            Ok(metadata) => serde_json::from_str(&metadata)?,
+            Err(_) => Vec::new(),
+        };
+
+        let mut keys = Vec::with_capacity(metadata.len());
+        for meta in metadata {
+            let private_key = PKey::private_key_from_pem(&util::read_file(&key_path(&meta.kid))?)?;
+            keys.push(JwtKey::new(meta, private_key)?);
+        }
+
+        let mut ring = Self {
+            keys,
+            loaded_at: Utc::now().timestamp(),
+        };
+
+        if ring.keys.is_empty() {
+            // The key used before the key ring existed, so that the tokens it signed stay valid
+            let key = if util::file_exists(&CONFIG.private_rsa_key()) {
+                info!("Importing the RSA key into the JWT key ring");
+                JwtKey::from_private_key(PKey::private_key_from_pem(&util::read_file(&CONFIG.private_rsa_key())?)?)?
+            } else {
+                JwtKey::generate(jwt_key_algorithm())?
+            };
+            ring.add(key)?;
+        }
+
+        ring.keys.sort_by(|a, b| b.meta.created_at.cmp(&a.meta.created_at));
+        Ok(ring)
+    }
+
+    fn save_metadata(&self) -> EmptyResult {
+        let metadata: Vec<&KeyMetadata> = self.keys.iter().map(|k| &k.meta).collect();
+        util::write_file(&metadata_path(), serde_json::to_string_pretty(&metadata)?.as_bytes())
+    }
+
+    fn add(&mut self, key: JwtKey) -> EmptyResult {
+        util::write_file(&key_path(&key.meta.kid), &key.private_key.private_key_to_pem_pkcs8()?)?;
+
+        let now = Utc::now().timestamp();
+        for old_key in &mut self.keys {
+            old_key.meta.retired_at.get_or_insert(now);
+        }
+        self.keys.insert(0, key);
+        self.save_metadata()
+    }
+
+    /// Removes the retired keys whose grace period is over.
+    fn prune(&mut self) -> Result<usize, Error> {
+        let now = Utc::now().timestamp();
+        let (expired, keys): (Vec<JwtKey>, Vec<JwtKey>) = self.keys.drain(..).partition(|k| k.is_expired(now));
+        self.keys = keys;
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        self.save_metadata()?;
+        for key in &expired {
+            if let Err(e) = fs::remove_file(key_path(&key.meta.kid)) {
+                warn!("Error removing the JWT key {}: {:?}", key.meta.kid, e);
+            }
+        }
+        Ok(expired.len())
+    }
+
+    fn signing_key(&self) -> &JwtKey {
+        &self.keys[0]
+    }
+
+    /// The keys which can have signed the token: the one with the given id, or all the keys of the algorithm for
+    /// the tokens signed before the key ring existed.
+    fn verification_keys<'a>(&'a self, kid: Option<&'a str>, alg: &'a str) -> impl Iterator<Item = &'a JwtKey> + 'a {
+        let now = Utc::now().timestamp();
+        self.keys.iter().filter(move |k| {
+            let matches = match kid {
+                Some(kid) => k.meta.kid == kid,
+                None => k.meta.algorithm.as_str() == alg,
+            };
+            matches && !k.is_expired(now)
+        })
+    }
+}
+
+fn read_ring() -> RwLockReadGuard<'static, KeyRing> {
+    KEY_RING.read().unwrap()
+}
+
+fn jwt_key_algorithm() -> KeyAlgorithm {
+    KeyAlgorithm::from_str(&CONFIG.jwt_key_algorithm()).unwrap_or(KeyAlgorithm::Rs256)
+}
+
+pub fn load_keys() {
+    Lazy::force(&KEY_RING);
+}
+
+pub fn encode<T: Serialize>(claims: &T) -> Result<String, Error> {
+    read_ring().signing_key().sign(claims)
+}
+
+pub fn decode<T: DeserializeOwned>(token: &str, issuer: &str) -> Result<T, Error> {
+    let token = token.replace(char::is_whitespace, "");
+
+    let header = token.split('.').next().unwrap_or_default();
+    let header = BASE64URL_NOPAD.decode(header.as_bytes()).ok().map_res("Invalid JWT header encoding")?;
+    let header: Value = serde_json::from_slice(&header)?;
+    let kid = header["kid"].as_str();
+    let alg = header["alg"].as_str().unwrap_or_default();
+
+    // The key could have been added by another instance sharing the data folder
+    if let Some(kid) = kid {
+        let reload = {
+            let ring = read_ring();
+            !ring.keys.iter().any(|k| k.meta.kid == kid) && ring.loaded_at + RELOAD_INTERVAL < Utc::now().timestamp()
+        };
+        if reload {
+            reload_keys()?;
+        }
+    }
+
+    let ring = read_ring();
+    let mut result = Err(Error::new("Error decoding JWT", "No key found to verify the token"));
+    for key in ring.verification_keys(kid, alg) {
+        result = key.verify(&token, issuer);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+fn reload_keys() -> EmptyResult {
+    let ring = KeyRing::load()?;
+    *KEY_RING.write().unwrap() = ring;
+    Ok(())
+}
+
+/// Generates a new signing key with the configured algorithm. The previous keys keep verifying the tokens they
+/// signed until the grace period is over.
+pub fn rotate_keys() -> Result<String, Error> {
+    let key = JwtKey::generate(jwt_key_algorithm())?;
+    let kid = key.meta.kid.clone();
+
+    let mut ring = KEY_RING.write().unwrap();
+    ring.add(key)?;
+    ring.prune()?;
+    Ok(kid)
+}
+
+pub fn rotate_keys_job() {
+    match rotate_keys() {
+        Ok(kid) => info!("Rotated the JWT signing key, the new key is {}", kid),
+        Err(e) => error!("Error rotating the JWT signing key: {:#?}", e),
+    }
+}
+
+/// The public keys of the ring, as a JWK Set.
+pub fn jwks() -> Value {
+    let ring = read_ring();
+    let now = Utc::now().timestamp();
+    let keys: Vec<&Value> = ring.keys.iter().filter(|k| !k.is_expired(now)).map(|k| &k.jwk).collect();
+    json!({ "keys": keys })
+}
+
+pub fn key_metadata() -> Vec<KeyMetadata> {
+    read_ring().keys.iter().map(|k| k.meta.clone()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct TestClaims {
+        nbf: i64,
+        exp: i64,
+        iss: String,
+        sub: String,
+    }
+
+    fn test_claims(exp_offset: i64) -> TestClaims {
+        let now = Utc::now().timestamp();
+        TestClaims {
+            nbf: now,
+            exp: now + exp_offset,
+            iss: "test|issuer".to_string(),
+            sub: "subject".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        for algorithm in &[KeyAlgorithm::Rs256, KeyAlgorithm::Es256, KeyAlgorithm::EdDsa] {
+            let key = JwtKey::generate(*algorithm).unwrap();
+            let other_key = JwtKey::generate(*algorithm).unwrap();
+            let claims = test_claims(60);
+
+            let token = key.sign(&claims).unwrap();
+            let header: Value =
+                serde_json::from_slice(&BASE64URL_NOPAD.decode(token.split('.').next().unwrap().as_bytes()).unwrap())
+                    .unwrap();
+            assert_eq!(header["kid"], key.meta.kid.as_str());
+            assert_eq!(header["alg"], algorithm.as_str());
+
+            assert_eq!(key.verify::<TestClaims>(&token, "test|issuer").unwrap(), claims);
+            assert!(key.verify::<TestClaims>(&token, "other|issuer").is_err());
+            assert!(other_key.verify::<TestClaims>(&token, "test|issuer").is_err());
+
+            let expired = key.sign(&test_claims(-120)).unwrap();
+            assert!(key.verify::<TestClaims>(&expired, "test|issuer").is_err());
+        }
+    }
+
+    #[test]
+    fn test_thumbprint() {
+        // Example from RFC 7638, 3.1
+        let jwk = json!({
+            "kty": "RSA",
+            "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+            "e": "AQAB",
+            "alg": "RS256",
+            "kid": "2011-04-29"
+        });
+        assert_eq!(thumbprint(&jwk), "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs");
+    }
+
+    #[test]
+    fn test_validate_claims() {
+        let now = Utc::now().timestamp();
+        let claims = json!({ "nbf": now, "exp": now + 60, "iss": "test|issuer" });
+        assert!(validate_claims(&claims, "test|issuer", now).is_ok());
+        assert!(validate_claims(&claims, "test|issuer", now + 60 + LEEWAY).is_ok());
+        assert!(validate_claims(&claims, "test|issuer", now + 61 + LEEWAY).is_err());
+        assert!(validate_claims(&claims, "test|issuer", now - 1 - LEEWAY).is_err());
+        assert!(validate_claims(&claims, "other|issuer", now).is_err());
+        assert!(validate_claims(&json!({ "iss": "test|issuer" }), "test|issuer", now).is_err());
+    }
+}
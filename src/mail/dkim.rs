@@ -0,0 +1,256 @@
+//
+// DKIM signing (RFC 6376) of outgoing emails
+//
+// Messages are signed with the relaxed/relaxed canonicalization, using either an RSA (rsa-sha256)
+// or an Ed25519 (ed25519-sha256, RFC 8463) private key in PEM format.
+//
+use std::sync::{Arc, Mutex};
+
+use data_encoding::BASE64;
+use once_cell::sync::Lazy;
+use openssl::{
+    hash::MessageDigest,
+    pkey::{Id, PKey, Private},
+    sha::sha256,
+    sign::Signer,
+};
+
+use crate::{
+    error::{Error, MapResult},
+    util, CONFIG,
+};
+
+/// Headers included in the signature, when present in the message.
+const SIGNED_HEADERS: &[&str] =
+    &["from", "reply-to", "to", "subject", "date", "message-id", "mime-version", "content-type"];
+
+/// The domain, selector and key file of a signer.
+type DkimSettings = (String, String, String);
+/// The configured signer, along with the settings it was loaded from, so that the key is only read again when the
+/// config changes.
+static SIGNER: Lazy<Mutex<Option<(DkimSettings, Arc<DkimSigner>)>>> = Lazy::new(|| Mutex::new(None));
+
+pub struct DkimSigner {
+    domain: String,
+    selector: String,
+    key: PKey<Private>,
+}
+
+impl DkimSigner {
+    /// Returns the signer configured with the `DKIM_*` options, or `None` when signing is disabled.
+    pub fn configured() -> Result<Option<Arc<Self>>, Error> {
+        let (selector, key_file) = match (CONFIG.dkim_selector(), CONFIG.dkim_private_key_file()) {
+            (Some(selector), Some(key_file)) => (selector, key_file),
+            _ => return Ok(None),
+        };
+        let domain = match CONFIG.dkim_domain() {
+            Some(domain) => domain,
+            None => super::address_domain(&CONFIG.smtp_from()).map_res("`SMTP_FROM` has no domain to sign with")?,
+        };
+
+        let settings = (domain, selector, key_file);
+        let mut cache = SIGNER.lock().unwrap();
+        if let Some((cached_settings, signer)) = cache.as_ref() {
+            if *cached_settings == settings {
+                return Ok(Some(Arc::clone(signer)));
+            }
+        }
+
+        let signer = Arc::new(Self::load(settings.0.clone(), settings.1.clone(), &settings.2)?);
+        *cache = Some((settings, Arc::clone(&signer)));
+        Ok(Some(signer))
+    }
+
+    pub fn load(domain: String, selector: String, key_file: &str) -> Result<Self, Error> {
+        let key = PKey::private_key_from_pem(&util::read_file(key_file)?)?;
+        if key.id() != Id::RSA && key.id() != Id::ED25519 {
+            err!("The DKIM private key must be an RSA or Ed25519 key")
+        }
+
+        Ok(Self {
+            domain,
+            selector,
+            key,
+        })
+    }
+
+    fn algorithm(&self) -> &'static str {
+        if self.key.id() == Id::ED25519 {
+            "ed25519-sha256"
+        } else {
+            "rsa-sha256"
+        }
+    }
+
+    /// Returns the message with a `DKIM-Signature` header prepended.
+    pub fn sign(&self, message: &[u8], timestamp: i64) -> Result<Vec<u8>, Error> {
+        let message = std::str::from_utf8(message).ok().map_res("Email is not valid UTF-8")?;
+        let (headers, body) = match message.find("\r\n\r\n") {
+            Some(idx) => (&message[..idx + 2], &message[idx + 4..]),
+            None => (message, ""),
+        };
+
+        let headers = parse_headers(headers);
+        let signed: Vec<&(String, String)> = SIGNED_HEADERS
+            .iter()
+            .filter_map(|name| headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)))
+            .collect();
+
+        let body_hash = BASE64.encode(&sha256(canonicalize_body(body).as_bytes()));
+        let header_list: Vec<String> = signed.iter().map(|(name, _)| name.to_lowercase()).collect();
+        let value = format!(
+            "v=1; a={}; c=relaxed/relaxed; d={}; s={}; t={}; h={}; bh={}; b=",
+            self.algorithm(),
+            self.domain,
+            self.selector,
+            timestamp,
+            header_list.join(":"),
+            body_hash
+        );
+
+        // The signature covers the signed headers followed by the DKIM-Signature header itself, with an empty b= tag
+        let mut data = String::new();
+        for (name, header_value) in signed {
+            data.push_str(&canonicalize_header(name, header_value));
+            data.push_str("\r\n");
+        }
+        data.push_str(&canonicalize_header("DKIM-Signature", &value));
+
+        let signature = if self.key.id() == Id::ED25519 {
+            Signer::new_without_digest(&self.key)?.sign_oneshot_to_vec(&sha256(data.as_bytes()))?
+        } else {
+            let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+            signer.update(data.as_bytes())?;
+            signer.sign_to_vec()?
+        };
+
+        let mut signed_message = format!("DKIM-Signature: {}{}\r\n", value, BASE64.encode(&signature)).into_bytes();
+        signed_message.extend_from_slice(message.as_bytes());
+        Ok(signed_message)
+    }
+}
+
+/// Splits the header section in (name, value) pairs, keeping the folding of the values.
+fn parse_headers(headers: &str) -> Vec<(String, String)> {
+    let mut parsed: Vec<(String, String)> = Vec::new();
+    for line in headers.split("\r\n").filter(|l| !l.is_empty()) {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some((_, value)) = parsed.last_mut() {
+                value.push_str("\r\n");
+                value.push_str(line);
+            }
+        } else if let Some(idx) = line.find(':') {
+            parsed.push((line[..idx].to_string(), line[idx + 1..].to_string()));
+        }
+    }
+    parsed
+}
+
+/// Replaces every run of spaces and tabs with a single space.
+fn collapse_whitespace(s: &str) -> String {
+    let mut collapsed = String::with_capacity(s.len());
+    let mut in_whitespace = false;
+    for c in s.chars() {
+        if c == ' ' || c == '\t' {
+            if !in_whitespace {
+                collapsed.push(' ');
+            }
+            in_whitespace = true;
+        } else {
+            collapsed.push(c);
+            in_whitespace = false;
+        }
+    }
+    collapsed
+}
+
+/// Relaxed header canonicalization (RFC 6376, 3.4.2), without the trailing CRLF.
+fn canonicalize_header(name: &str, value: &str) -> String {
+    let value = collapse_whitespace(&value.replace("\r\n", ""));
+    format!("{}:{}", name.trim().to_lowercase(), value.trim())
+}
+
+/// Relaxed body canonicalization (RFC 6376, 3.4.4).
+fn canonicalize_body(body: &str) -> String {
+    let mut lines: Vec<String> = body.split("\r\n").map(|l| collapse_whitespace(l).trim_end().to_string()).collect();
+    while lines.last().map_or(false, String::is_empty) {
+        lines.pop();
+    }
+
+    if lines.is_empty() {
+        String::new()
+    } else {
+        lines.join("\r\n") + "\r\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{rsa::Rsa, sign::Verifier};
+
+    #[test]
+    fn test_canonicalization() {
+        // Examples from RFC 6376, 3.4.5
+        let headers = parse_headers("A: X\r\nB : Y\t\r\n\tZ  \r\n");
+        let canonicalized: Vec<String> = headers.iter().map(|(n, v)| canonicalize_header(n, v)).collect();
+        assert_eq!(canonicalized, vec!["a:X", "b:Y Z"]);
+
+        assert_eq!(canonicalize_body(" C \r\nD \t E\r\n\r\n\r\n"), " C\r\nD E\r\n");
+        assert_eq!(canonicalize_body("\r\n\r\n"), "");
+    }
+
+    #[test]
+    fn test_sign() {
+        let rsa = Rsa::generate(2048).unwrap();
+        let signer = DkimSigner {
+            domain: "example.com".to_string(),
+            selector: "mail".to_string(),
+            key: PKey::from_rsa(rsa).unwrap(),
+        };
+
+        let message =
+            "From: Vaultwarden <vaultwarden@example.com>\r\nTo: user@example.com\r\nSubject: Test\r\n\r\nTest\r\n";
+        let signed = String::from_utf8(signer.sign(message.as_bytes(), 1_600_000_000).unwrap()).unwrap();
+        assert!(signed.ends_with(message));
+
+        let header = signed.lines().next().unwrap();
+        assert!(header.starts_with("DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed; d=example.com; s=mail;"));
+        assert!(header.contains("h=from:to:subject;"));
+
+        let (unsigned, signature) = header.split_at(header.rfind("; b=").unwrap() + 4);
+        let data = format!(
+            "from:Vaultwarden <vaultwarden@example.com>\r\nto:user@example.com\r\nsubject:Test\r\n{}",
+            canonicalize_header("DKIM-Signature", unsigned.trim_start_matches("DKIM-Signature:"))
+        );
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &signer.key).unwrap();
+        verifier.update(data.as_bytes()).unwrap();
+        assert!(verifier.verify(&BASE64.decode(signature.as_bytes()).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_sign_ed25519() {
+        let signer = DkimSigner {
+            domain: "example.com".to_string(),
+            selector: "mail".to_string(),
+            key: PKey::generate_ed25519().unwrap(),
+        };
+
+        let message = "From: vaultwarden@example.com\r\nTo: user@example.com\r\nSubject: Test\r\n\r\nTest\r\n";
+        let signed = String::from_utf8(signer.sign(message.as_bytes(), 1_600_000_000).unwrap()).unwrap();
+        assert!(signed.ends_with(message));
+
+        let header = signed.lines().next().unwrap();
+        assert!(header.starts_with("DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/relaxed; d=example.com; s=mail;"));
+
+        // RFC 8463: the Ed25519 signature is computed over the SHA-256 hash of the signed data
+        let (unsigned, signature) = header.split_at(header.rfind("; b=").unwrap() + 4);
+        let data = format!(
+            "from:vaultwarden@example.com\r\nto:user@example.com\r\nsubject:Test\r\n{}",
+            canonicalize_header("DKIM-Signature", unsigned.trim_start_matches("DKIM-Signature:"))
+        );
+        let mut verifier = Verifier::new_without_digest(&signer.key).unwrap();
+        let signature = BASE64.decode(signature.as_bytes()).unwrap();
+        assert!(verifier.verify_oneshot(&signature, &sha256(data.as_bytes())).unwrap());
+    }
+}
@@ -0,0 +1,785 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use chrono::{DateTime, Local, NaiveDateTime, Utc};
+use handlebars::Handlebars;
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+
+use lettre::{
+    message::{header, Mailbox, Message, MultiPart, SinglePart},
+    Address,
+};
+
+use crate::{
+    api::EmptyResult,
+    auth::{encode_jwt, generate_delete_claims, generate_invite_claims, generate_verify_email_claims},
+    db::{
+        models::{Broadcast, BroadcastRecipient, BroadcastStatus, QueuedMail, User},
+        DbConn,
+    },
+    error::Error,
+    CONFIG,
+};
+
+mod dkim;
+mod queue;
+mod transport;
+
+pub use dkim::DkimSigner;
+pub use queue::{purge_failed_mails, start_queue_worker, wake_queue_worker};
+use transport::MailTransport;
+
+/// Templates of the emails sent by the server, without the `email/` prefix.
+pub const EMAIL_TEMPLATES: &[&str] = &[
+    "account_deleted",
+    "account_purge_reminder",
+    "change_email",
+    "delete_account",
+    "invite_accepted",
+    "invite_confirmed",
+    "login_failures",
+    "new_device_logged_in",
+    "pw_hint_none",
+    "pw_hint_some",
+    "send_2fa_removed_from_org",
+    "send_org_invite",
+    "session_revoked",
+    "smtp_test",
+    "storage_quota_warning",
+    "twofactor_email",
+    "twofactor_required",
+    "verify_email",
+    "welcome",
+    "welcome_must_verify",
+];
+
+/// Languages with bundled translations of the email templates, English being the default.
+pub const EMAIL_LANGUAGES: &[&str] = &["en", "de", "es", "fr"];
+
+/// Maps a culture like `de-DE` to one of the `EMAIL_LANGUAGES`.
+pub fn email_language(culture: &str) -> Option<&'static str> {
+    let primary = culture.trim().split(|c| c == '-' || c == '_').next()?.to_lowercase();
+    EMAIL_LANGUAGES.iter().find(|lang| **lang == primary).copied()
+}
+
+/// Maps the culture sent with a profile update to one of the `EMAIL_LANGUAGES`.
+/// The official clients always send `en-US`, regardless of the language they are displayed in,
+/// so that one doesn't replace the language picked up from the `Accept-Language` header at login.
+pub fn profile_email_language(culture: &str) -> Option<&'static str> {
+    if culture == "en-US" {
+        return None;
+    }
+    email_language(culture)
+}
+
+/// Picks the preferred language of an `Accept-Language` header which is one of the `EMAIL_LANGUAGES`.
+pub fn language_from_header(header: &str) -> Option<&'static str> {
+    let mut languages: Vec<(&str, f32)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let culture = parts.next()?.trim();
+            let quality =
+                parts.find_map(|p| p.trim().strip_prefix("q=")).map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            Some((culture, quality))
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect();
+
+    // The sort is stable, so languages with the same quality keep their order
+    languages.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    languages.into_iter().find_map(|(culture, _)| email_language(culture))
+}
+
+/// Renders the template in the language of the recipient, falling back to English when there's no translation.
+fn get_text(
+    template_name: &'static str,
+    lang: Option<&str>,
+    data: serde_json::Value,
+) -> Result<(String, String, String), Error> {
+    render_text(template_name, lang, &data)
+}
+
+/// Renders the subject and both bodies of an email, used for the preview in the admin panel.
+pub fn render_text(
+    template_name: &str,
+    lang: Option<&str>,
+    data: &serde_json::Value,
+) -> Result<(String, String, String), Error> {
+    let (subject_html, body_html) = get_template(template_name, ".html", lang, data)?;
+    let (_subject_text, body_text) = get_template(template_name, "", lang, data)?;
+    Ok((subject_html, body_html, body_text))
+}
+
+fn get_template(
+    template_name: &str,
+    ext: &str,
+    lang: Option<&str>,
+    data: &serde_json::Value,
+) -> Result<(String, String), Error> {
+    let text = CONFIG.render_localized_template(template_name, ext, lang, data)?;
+    let mut text_split = text.split("<!---------------->");
+
+    let subject = match text_split.next() {
+        Some(s) => s.trim().to_string(),
+        None => err!("Template doesn't contain subject"),
+    };
+
+    let body = match text_split.next() {
+        Some(s) => s.trim().to_string(),
+        None => err!("Template doesn't contain body"),
+    };
+
+    Ok((subject, body))
+}
+
+pub fn send_password_hint(address: &str, lang: Option<&str>, hint: Option<String>, conn: &DbConn) -> EmptyResult {
+    let template_name = if hint.is_some() {
+        "email/pw_hint_some"
+    } else {
+        "email/pw_hint_none"
+    };
+
+    let (subject, body_html, body_text) =
+        get_text(template_name, lang, json!({ "hint": hint, "url": CONFIG.domain() }))?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_delete_account(address: &str, lang: Option<&str>, uuid: &str, conn: &DbConn) -> EmptyResult {
+    let claims = generate_delete_claims(uuid.to_string());
+    let delete_token = encode_jwt(&claims);
+
+    let (subject, body_html, body_text) = get_text(
+        "email/delete_account",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+            "user_id": uuid,
+            "email": percent_encode(address.as_bytes(), NON_ALPHANUMERIC).to_string(),
+            "token": delete_token,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_verify_email(address: &str, lang: Option<&str>, uuid: &str, conn: &DbConn) -> EmptyResult {
+    let claims = generate_verify_email_claims(uuid.to_string());
+    let verify_email_token = encode_jwt(&claims);
+
+    let (subject, body_html, body_text) = get_text(
+        "email/verify_email",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+            "user_id": uuid,
+            "email": percent_encode(address.as_bytes(), NON_ALPHANUMERIC).to_string(),
+            "token": verify_email_token,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_welcome(address: &str, lang: Option<&str>, conn: &DbConn) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/welcome",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_welcome_must_verify(address: &str, lang: Option<&str>, uuid: &str, conn: &DbConn) -> EmptyResult {
+    let claims = generate_verify_email_claims(uuid.to_string());
+    let verify_email_token = encode_jwt(&claims);
+
+    let (subject, body_html, body_text) = get_text(
+        "email/welcome_must_verify",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+            "user_id": uuid,
+            "token": verify_email_token,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_2fa_removed_from_org(address: &str, lang: Option<&str>, org_name: &str, conn: &DbConn) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/send_2fa_removed_from_org",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+            "org_name": org_name,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_invite(
+    address: &str,
+    lang: Option<&str>,
+    uuid: &str,
+    org_id: Option<String>,
+    org_user_id: Option<String>,
+    org_name: &str,
+    invited_by_email: Option<String>,
+    conn: &DbConn,
+) -> EmptyResult {
+    let claims = generate_invite_claims(
+        uuid.to_string(),
+        String::from(address),
+        org_id.clone(),
+        org_user_id.clone(),
+        invited_by_email,
+    );
+    let invite_token = encode_jwt(&claims);
+
+    let (subject, body_html, body_text) = get_text(
+        "email/send_org_invite",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+            "org_id": org_id.as_deref().unwrap_or("_"),
+            "org_user_id": org_user_id.as_deref().unwrap_or("_"),
+            "email": percent_encode(address.as_bytes(), NON_ALPHANUMERIC).to_string(),
+            "org_name": org_name,
+            "token": invite_token,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_invite_accepted(
+    new_user_email: &str,
+    address: &str,
+    lang: Option<&str>,
+    org_name: &str,
+    conn: &DbConn,
+) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/invite_accepted",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+            "email": new_user_email,
+            "org_name": org_name,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_invite_confirmed(address: &str, lang: Option<&str>, org_name: &str, conn: &DbConn) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/invite_confirmed",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+            "org_name": org_name,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_new_device_logged_in(
+    address: &str,
+    lang: Option<&str>,
+    ip: &str,
+    dt: &DateTime<Local>,
+    device: &str,
+    conn: &DbConn,
+) -> EmptyResult {
+    use crate::util::upcase_first;
+    let device = upcase_first(device);
+
+    let fmt = "%A, %B %_d, %Y at %r %Z";
+    let (subject, body_html, body_text) = get_text(
+        "email/new_device_logged_in",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+            "ip": ip,
+            "device": device,
+            "datetime": crate::util::format_datetime_local(dt, fmt),
+        }),
+    )?;
+
+    // Logins are blocked when this email can't be sent, so wait for the SMTP server to accept it
+    if CONFIG.require_device_email() {
+        send_email_now(address, &subject, body_html, body_text, conn)
+    } else {
+        send_email(address, &subject, body_html, body_text, conn)
+    }
+}
+
+pub fn send_session_revoked(
+    address: &str,
+    lang: Option<&str>,
+    ip: &str,
+    dt: &DateTime<Local>,
+    device: &str,
+    conn: &DbConn,
+) -> EmptyResult {
+    use crate::util::upcase_first;
+    let device = upcase_first(device);
+
+    let fmt = "%A, %B %_d, %Y at %r %Z";
+    let (subject, body_html, body_text) = get_text(
+        "email/session_revoked",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+            "ip": ip,
+            "device": device,
+            "datetime": crate::util::format_datetime_local(dt, fmt),
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_login_failures(
+    address: &str,
+    lang: Option<&str>,
+    count: i64,
+    ip: &str,
+    dt: &DateTime<Local>,
+    conn: &DbConn,
+) -> EmptyResult {
+    let fmt = "%A, %B %_d, %Y at %r %Z";
+    let (subject, body_html, body_text) = get_text(
+        "email/login_failures",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+            "count": count,
+            "minutes": CONFIG.login_failure_alert_window(),
+            "ip": ip,
+            "datetime": crate::util::format_datetime_local(dt, fmt),
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_token(address: &str, lang: Option<&str>, token: &str, conn: &DbConn) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/twofactor_email",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+            "token": token,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_twofactor_required(
+    address: &str,
+    lang: Option<&str>,
+    deadline: &NaiveDateTime,
+    conn: &DbConn,
+) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/twofactor_required",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+            "deadline": crate::util::format_naive_datetime_local(deadline, "%A, %B %_d, %Y at %r %Z"),
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_account_deleted(
+    address: &str,
+    lang: Option<&str>,
+    purge_date: &NaiveDateTime,
+    conn: &DbConn,
+) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/account_deleted",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+            "email": address,
+            "purge_date": crate::util::format_naive_datetime_local(purge_date, "%A, %B %_d, %Y at %r %Z"),
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_account_purge_reminder(
+    address: &str,
+    lang: Option<&str>,
+    purge_date: &NaiveDateTime,
+    conn: &DbConn,
+) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/account_purge_reminder",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+            "email": address,
+            "purge_date": crate::util::format_naive_datetime_local(purge_date, "%A, %B %_d, %Y at %r %Z"),
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_change_email(address: &str, lang: Option<&str>, token: &str, conn: &DbConn) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/change_email",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+            "token": token,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_storage_quota_warning(
+    address: &str,
+    lang: Option<&str>,
+    name: &str,
+    percent: i64,
+    used: &str,
+    limit: &str,
+    conn: &DbConn,
+) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/storage_quota_warning",
+        lang,
+        json!({
+            "url": CONFIG.domain(),
+            "name": name,
+            "percent": percent,
+            "limit_reached": percent >= 100,
+            "used": used,
+            "limit": limit,
+        }),
+    )?;
+
+    send_email(address, &subject, body_html, body_text, conn)
+}
+
+pub fn send_test(address: &str) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/smtp_test",
+        None,
+        json!({
+            "url": CONFIG.domain(),
+        }),
+    )?;
+
+    // Not queued, so that the result can be shown to the admin
+    let address = normalize_address(address)?;
+    deliver(&address, &subject, body_html, body_text).map_err(DeliveryError::into_error)
+}
+
+/// Renders an admin broadcast for one user. The subject and the body are Handlebars templates of plain text,
+/// which can use the `name` and `email` of the user and the `url` of the server.
+pub fn render_broadcast(subject: &str, body: &str, user: &User) -> Result<(String, String, String), Error> {
+    let (subject, body, body_html) = render_broadcast_text(subject, body, user, &CONFIG.domain())?;
+
+    render_text(
+        "email/admin_broadcast",
+        None,
+        &json!({
+            "subject": subject,
+            "body": body,
+            "body_html": body_html,
+        }),
+    )
+}
+
+/// Renders the subject, the text body and the HTML body of a broadcast for one user.
+fn render_broadcast_text(subject: &str, body: &str, user: &User, url: &str) -> Result<(String, String, String), Error> {
+    let data = json!({
+        "url": url,
+        "name": user.name,
+        "email": user.email,
+    });
+
+    let mut hb = Handlebars::new();
+    hb.set_strict_mode(true);
+    // Rendered as plain text, the body is escaped below for the HTML version
+    hb.register_escape_fn(handlebars::no_escape);
+    let subject = hb.render_template(subject, &data)?;
+    let body = hb.render_template(body, &data)?;
+    let body_html = handlebars::html_escape(&body).replace('\n', "<br>\n");
+    Ok((subject, body, body_html))
+}
+
+/// Queues the broadcast to every user, keeping track of the delivery to each of them.
+pub fn send_broadcast(broadcast: &Broadcast, users: &[User], conn: &DbConn) -> EmptyResult {
+    broadcast.save(conn)?;
+
+    let mut recipients = Vec::with_capacity(users.len());
+    let mut mails = Vec::with_capacity(users.len());
+    for user in users {
+        // Recipients are matched with the queued emails by address, so use the normalized one
+        let address = normalize_address(&user.email).unwrap_or_else(|_| user.email.clone());
+        let mut recipient = BroadcastRecipient::new(broadcast.uuid.clone(), address.clone());
+
+        let result = render_broadcast(&broadcast.subject, &broadcast.body, user).and_then(|(subject, html, text)| {
+            let mut mail = QueuedMail::new(normalize_address(&address)?, subject, html, text);
+            mail.broadcast_uuid = Some(broadcast.uuid.clone());
+            Ok(mail)
+        });
+
+        match result {
+            Ok(mail) => mails.push(mail),
+            Err(e) => {
+                warn!("Error sending broadcast {} to {}: {:?}", broadcast.uuid, address, e);
+                recipient.status = BroadcastStatus::Failed as i32;
+                recipient.last_error = Some(format!("{:?}", e));
+            }
+        }
+        recipients.push(recipient);
+    }
+
+    // The recipients have to exist before the worker sends the emails and updates their status
+    BroadcastRecipient::insert_all(&recipients, conn)?;
+    queue::enqueue_all(mails, conn)
+}
+
+/// Queues an email to be sent in the background.
+fn send_email(address: &str, subject: &str, body_html: String, body_text: String, conn: &DbConn) -> EmptyResult {
+    let address = normalize_address(address)?;
+    queue::enqueue(QueuedMail::new(address, subject.to_string(), body_html, body_text), conn)
+}
+
+/// Sends an email right away, for when the caller needs to know whether it was accepted by the SMTP server.
+/// If the delivery fails with a temporary error, the email is queued to be retried later.
+fn send_email_now(address: &str, subject: &str, body_html: String, body_text: String, conn: &DbConn) -> EmptyResult {
+    let address = normalize_address(address)?;
+    match deliver(&address, subject, body_html.clone(), body_text.clone()) {
+        Ok(()) => Ok(()),
+        Err(DeliveryError::Transient(e)) => {
+            warn!("Queuing email to be retried later: {:?}", e);
+            queue::enqueue(QueuedMail::new(address, subject.to_string(), body_html, body_text), conn)
+        }
+        Err(DeliveryError::Permanent(e)) => Err(e),
+    }
+}
+
+/// Converts the domain of the address to its ASCII representation.
+fn normalize_address(address: &str) -> Result<String, Error> {
+    let address_split: Vec<&str> = address.rsplitn(2, '@').collect();
+    if address_split.len() != 2 {
+        err!("Invalid email address (no @)");
+    }
+
+    let domain_puny = match idna::domain_to_ascii_strict(address_split[0]) {
+        Ok(d) => d,
+        Err(_) => err!("Can't convert email domain to ASCII representation"),
+    };
+
+    let address = format!("{}@{}", address_split[1], domain_puny);
+    Address::from_str(&address)?;
+    Ok(address)
+}
+
+pub enum DeliveryError {
+    /// The delivery may succeed when retried later, like after 4xx responses, timeouts or connection errors.
+    Transient(Error),
+    Permanent(Error),
+}
+
+impl DeliveryError {
+    pub fn into_error(self) -> Error {
+        match self {
+            DeliveryError::Transient(e) | DeliveryError::Permanent(e) => e,
+        }
+    }
+}
+
+/// Headers which are set from the config or the message itself, and can't be added with `SMTP_EXTRA_HEADERS`.
+const RESERVED_HEADERS: &[&str] = &[
+    "from",
+    "sender",
+    "reply-to",
+    "to",
+    "cc",
+    "bcc",
+    "subject",
+    "date",
+    "message-id",
+    "mime-version",
+    "content-type",
+    "content-transfer-encoding",
+    "dkim-signature",
+];
+
+/// Parses `SMTP_EXTRA_HEADERS`, a JSON object with the names and values of the headers.
+pub fn parse_extra_headers(json: &str) -> Result<Vec<(String, String)>, Error> {
+    let headers: BTreeMap<String, String> = match serde_json::from_str(json) {
+        Ok(headers) => headers,
+        Err(_) => err!("`SMTP_EXTRA_HEADERS` must be a JSON object with string values"),
+    };
+
+    for (name, value) in &headers {
+        // Printable ASCII without the colon (RFC 5322, 2.2)
+        if name.is_empty() || !name.bytes().all(|b| (33..=126).contains(&b) && b != b':') {
+            err!(format!("Invalid header name in `SMTP_EXTRA_HEADERS`: {}", name))
+        }
+        if RESERVED_HEADERS.contains(&name.to_lowercase().as_str()) {
+            err!(format!("The {} header can't be set with `SMTP_EXTRA_HEADERS`", name))
+        }
+        // Also prevents header injection with CR/LF
+        if !value.bytes().all(|b| b == b'\t' || (32..=126).contains(&b)) {
+            err!(format!("The value of the {} header in `SMTP_EXTRA_HEADERS` must be printable ASCII", name))
+        }
+    }
+
+    Ok(headers.into_iter().collect())
+}
+
+/// The domain part of an email address.
+fn address_domain(address: &str) -> Option<String> {
+    match address.rsplit_once('@') {
+        Some((_, domain)) if !domain.is_empty() => Some(domain.to_string()),
+        _ => None,
+    }
+}
+
+fn build_message(address: &str, subject: &str, body_html: String, body_text: String) -> Result<Message, Error> {
+    let html = SinglePart::builder()
+        // We force Base64 encoding because in the past we had issues with different encodings.
+        .header(header::ContentTransferEncoding::Base64)
+        .header(header::ContentType::TEXT_HTML)
+        .body(body_html);
+
+    let text = SinglePart::builder()
+        // We force Base64 encoding because in the past we had issues with different encodings.
+        .header(header::ContentTransferEncoding::Base64)
+        .header(header::ContentType::TEXT_PLAIN)
+        .body(body_text);
+
+    let smtp_from = &CONFIG.smtp_from();
+    let message_id_domain = CONFIG
+        .smtp_message_id_domain()
+        .or_else(|| address_domain(smtp_from))
+        .unwrap_or_else(|| String::from("localhost"));
+
+    let mut email = Message::builder()
+        .message_id(Some(format!("<{}@{}>", crate::util::get_uuid(), message_id_domain)))
+        .to(Mailbox::new(None, Address::from_str(address)?))
+        .from(Mailbox::new(Some(CONFIG.smtp_from_name()), Address::from_str(smtp_from)?))
+        .subject(subject);
+
+    if let Some(reply_to) = CONFIG.smtp_reply_to() {
+        email = email.reply_to(Mailbox::new(None, Address::from_str(&reply_to)?));
+    }
+
+    Ok(email.multipart(MultiPart::alternative().singlepart(text).singlepart(html))?)
+}
+
+/// Serializes the email, adding the extra headers and the DKIM signature when configured.
+fn format_message(email: &Message) -> Result<Vec<u8>, Error> {
+    let mut formatted = Vec::new();
+    if let Some(extra_headers) = CONFIG.smtp_extra_headers() {
+        for (name, value) in parse_extra_headers(&extra_headers)? {
+            formatted.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+    }
+    formatted.extend_from_slice(&email.formatted());
+
+    match DkimSigner::configured()? {
+        Some(signer) => signer.sign(&formatted, Utc::now().timestamp()),
+        None => Ok(formatted),
+    }
+}
+
+/// Builds the email and sends it with the configured transport.
+fn deliver(address: &str, subject: &str, body_html: String, body_text: String) -> Result<(), DeliveryError> {
+    let email = build_message(address, subject, body_html, body_text).map_err(DeliveryError::Permanent)?;
+    let formatted = format_message(&email).map_err(DeliveryError::Permanent)?;
+    transport::configured().send_message(email.envelope(), &formatted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_broadcast_text() {
+        let mut user = User::new(String::from("user@example.com"));
+        user.name = String::from("Tom & Jerry");
+
+        let (subject, body, body_html) = render_broadcast_text(
+            "Hello {{name}}",
+            "Your account {{email}}\n<b>{{url}}</b>",
+            &user,
+            "https://vault.example.com",
+        )
+        .unwrap();
+        assert_eq!(subject, "Hello Tom & Jerry");
+        // Plain text, only the HTML version is escaped
+        assert_eq!(body, "Your account user@example.com\n<b>https://vault.example.com</b>");
+        assert_eq!(body_html, "Your account user@example.com<br>\n&lt;b&gt;https://vault.example.com&lt;/b&gt;");
+
+        // Unknown variables are refused instead of being left empty
+        assert!(render_broadcast_text("Hello {{nmae}}", "Body", &user, "https://vault.example.com").is_err());
+    }
+
+    #[test]
+    fn test_email_language() {
+        assert_eq!(email_language("de-DE"), Some("de"));
+        assert_eq!(email_language("FR_ca"), Some("fr"));
+        assert_eq!(email_language("es"), Some("es"));
+        assert_eq!(email_language("en-US"), Some("en"));
+        assert_eq!(email_language("it-IT"), None);
+        assert_eq!(email_language(""), None);
+
+        // The default culture of the official clients keeps the language of the user
+        assert_eq!(profile_email_language("en-US"), None);
+        assert_eq!(profile_email_language("en-GB"), Some("en"));
+        assert_eq!(profile_email_language("de-DE"), Some("de"));
+    }
+
+    #[test]
+    fn test_language_from_header() {
+        assert_eq!(language_from_header("de-DE,de;q=0.9,en;q=0.8"), Some("de"));
+        assert_eq!(language_from_header("it-IT, fr;q=0.5, en;q=0.7"), Some("en"));
+        assert_eq!(language_from_header("es;q=0, fr"), Some("fr"));
+        assert_eq!(language_from_header("fr;q=abc, es;q=0.2"), Some("es"));
+        assert_eq!(language_from_header("*"), None);
+    }
+
+    #[test]
+    fn test_parse_extra_headers() {
+        let headers = parse_extra_headers(r#"{"X-Mailer": "Vaultwarden", "Precedence": "bulk"}"#).unwrap();
+        assert_eq!(headers[0], ("Precedence".to_string(), "bulk".to_string()));
+        assert_eq!(headers[1], ("X-Mailer".to_string(), "Vaultwarden".to_string()));
+
+        assert!(parse_extra_headers(r#"{"Subject": "Spam"}"#).is_err());
+        assert!(parse_extra_headers(r#"{"X-Test": "a\r\nBcc: user@example.com"}"#).is_err());
+        assert!(parse_extra_headers(r#"{"X Test": "a"}"#).is_err());
+        assert!(parse_extra_headers(r#"["X-Test"]"#).is_err());
+    }
+
+    #[test]
+    fn test_address_domain() {
+        assert_eq!(address_domain("vaultwarden@example.com"), Some("example.com".to_string()));
+        assert_eq!(address_domain("vaultwarden"), None);
+        assert_eq!(address_domain("vaultwarden@"), None);
+    }
+}
@@ -0,0 +1,195 @@
+//
+// Persistent queue of outgoing emails
+//
+// Emails are stored in the `mail_queue` table and sent by a background worker, so that a slow or
+// unreachable SMTP server doesn't stall the requests which send them. Deliveries which fail with a
+// temporary error are retried with an exponential backoff, the others are kept as failed until an
+// admin resends or discards them, or until they're purged after the retention period. Sent emails are
+// deleted right away, so their bodies aren't kept.
+//
+use std::{
+    sync::{
+        mpsc::{channel, Sender},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use chrono::Utc;
+use once_cell::sync::OnceCell;
+
+use super::{deliver, DeliveryError};
+use crate::{
+    api::EmptyResult,
+    db::{
+        models::{BroadcastRecipient, BroadcastStatus, MailStatus, QueuedMail},
+        DbConn, DbPool,
+    },
+    CONFIG,
+};
+
+/// How often the worker checks for due emails when it isn't woken up by a new one.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Max number of emails loaded at once by the worker, which keeps sending batches while they're full.
+const BATCH_SIZE: i64 = 50;
+/// Upper bound of the delay between retries.
+const MAX_RETRY_DELAY: u64 = 24 * 60 * 60;
+
+struct MailQueue {
+    wakeup: Mutex<Sender<()>>,
+}
+
+static QUEUE: OnceCell<MailQueue> = OnceCell::new();
+
+/// Starts the background worker. Until this is called, emails are sent directly.
+pub fn start_queue_worker(pool: DbPool) {
+    let (sender, receiver) = channel();
+    let queue = MailQueue {
+        wakeup: Mutex::new(sender),
+    };
+    if QUEUE.set(queue).is_err() {
+        return;
+    }
+
+    thread::Builder::new()
+        .name("mail-queue".to_string())
+        .spawn(move || {
+            loop {
+                if CONFIG.mail_enabled() {
+                    match pool.get() {
+                        // A full batch means that more emails may be due, like after a broadcast
+                        Ok(conn) => while send_due(&conn) {},
+                        Err(e) => error!("Failed to get DB connection while sending queued emails: {:?}", e),
+                    }
+                }
+
+                // Either a new email was queued, or it's time to check for retries
+                receiver.recv_timeout(POLL_INTERVAL).ok();
+            }
+        })
+        .expect("Error spawning mail queue thread");
+}
+
+/// Makes the worker check for due emails right away.
+pub fn wake_queue_worker() {
+    if let Some(queue) = QUEUE.get() {
+        if let Ok(sender) = queue.wakeup.lock() {
+            sender.send(()).ok();
+        }
+    }
+}
+
+/// Queues an email with the connection of the caller, then wakes the worker.
+pub fn enqueue(mail: QueuedMail, conn: &DbConn) -> EmptyResult {
+    if QUEUE.get().is_none() {
+        return deliver(&mail.recipient, &mail.subject, mail.body_html, mail.body_text)
+            .map_err(DeliveryError::into_error);
+    }
+
+    mail.save(conn)?;
+    wake_queue_worker();
+    Ok(())
+}
+
+/// Queues many emails at once with the connection of the caller, then wakes the worker.
+pub fn enqueue_all(mails: Vec<QueuedMail>, conn: &DbConn) -> EmptyResult {
+    if QUEUE.get().is_none() {
+        for mail in mails {
+            enqueue(mail, conn)?;
+        }
+        return Ok(());
+    }
+
+    QueuedMail::insert_all(&mails, conn)?;
+    wake_queue_worker();
+    Ok(())
+}
+
+/// Removes the failed emails older than the retention period, so that the links and codes they contain aren't kept forever.
+pub fn purge_failed_mails(pool: DbPool) {
+    debug!("Purging failed emails");
+    if let Ok(conn) = pool.get() {
+        if let Err(e) = QueuedMail::purge_failed(&conn) {
+            error!("Error purging failed emails: {:#?}", e);
+        }
+    } else {
+        error!("Failed to get DB connection while purging failed emails")
+    }
+}
+
+/// Sends one batch of due emails, and returns whether another batch should be sent right away.
+/// That's the case when the batch was full and every email could be updated, otherwise the same emails
+/// could be loaded again.
+fn send_due(conn: &DbConn) -> bool {
+    let mails = QueuedMail::find_due(BATCH_SIZE, conn);
+    let full_batch = mails.len() as i64 == BATCH_SIZE;
+    let mut updated = true;
+
+    for mut mail in mails {
+        let result = deliver(&mail.recipient, &mail.subject, mail.body_html.clone(), mail.body_text.clone());
+        mail.attempts += 1;
+
+        let result = match result {
+            Ok(()) => {
+                debug!("Sent queued email {} after {} attempt(s)", mail.uuid, mail.attempts);
+                update_broadcast(&mail, BroadcastStatus::Sent, None, conn);
+                mail.delete(conn)
+            }
+            Err(DeliveryError::Transient(e)) if (mail.attempts as u32) < CONFIG.smtp_queue_max_attempts() => {
+                let delay = retry_delay(mail.attempts, CONFIG.smtp_queue_retry_delay());
+                warn!("Error sending queued email {}, retrying in {} seconds: {:?}", mail.uuid, delay, e);
+                mail.last_error = Some(format!("{:?}", e));
+                update_broadcast(&mail, BroadcastStatus::Pending, mail.last_error.clone(), conn);
+                mail.next_attempt_at = Utc::now().naive_utc() + chrono::Duration::seconds(delay as i64);
+                mail.save(conn)
+            }
+            Err(e) => {
+                let e = e.into_error();
+                error!(
+                    "Error sending queued email {}, giving up after {} attempt(s): {:?}",
+                    mail.uuid, mail.attempts, e
+                );
+                mail.last_error = Some(format!("{:?}", e));
+                mail.status = MailStatus::Failed as i32;
+                update_broadcast(&mail, BroadcastStatus::Failed, mail.last_error.clone(), conn);
+                mail.save(conn)
+            }
+        };
+
+        if let Err(e) = result {
+            error!("Error updating the mail queue: {:#?}", e);
+            updated = false;
+        }
+    }
+
+    full_batch && updated
+}
+
+fn update_broadcast(mail: &QueuedMail, status: BroadcastStatus, last_error: Option<String>, conn: &DbConn) {
+    if let Some(ref broadcast_uuid) = mail.broadcast_uuid {
+        if let Err(e) = BroadcastRecipient::update_status(broadcast_uuid, &mail.recipient, status, last_error, conn) {
+            error!("Error updating the status of broadcast {}: {:#?}", broadcast_uuid, e);
+        }
+    }
+}
+
+/// Seconds to wait after the given number of failed attempts, doubling the base delay every time.
+fn retry_delay(attempts: i32, base: u64) -> u64 {
+    let exponent = (attempts.max(1) - 1).min(32) as u32;
+    base.saturating_mul(2u64.saturating_pow(exponent)).min(MAX_RETRY_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1, 60), 60);
+        assert_eq!(retry_delay(2, 60), 120);
+        assert_eq!(retry_delay(5, 60), 960);
+        assert_eq!(retry_delay(30, 60), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(0, 0), 0);
+    }
+}
@@ -0,0 +1,251 @@
+//
+// Mail transports
+//
+// Emails can be sent to an SMTP server, piped to a sendmail compatible binary to relay them through
+// the local MTA, or written as .eml files to a folder, which is useful for testing.
+//
+use std::time::Duration;
+
+use lettre::{
+    address::Envelope,
+    transport::smtp::authentication::{Credentials, Mechanism as SmtpAuthMechanism},
+    transport::smtp::client::{Tls, TlsParameters},
+    transport::smtp::extension::ClientId,
+    FileTransport, SendmailTransport, SmtpTransport, Transport,
+};
+
+use super::DeliveryError;
+use crate::{error::Error, CONFIG};
+
+pub trait MailTransport {
+    /// Sends an already formatted email, so that the DKIM signature of the exact bytes stays valid.
+    fn send_message(&self, envelope: &Envelope, email: &[u8]) -> Result<(), DeliveryError>;
+}
+
+/// Returns the transport selected with `MAIL_TRANSPORT`.
+pub fn configured() -> Box<dyn MailTransport> {
+    match CONFIG.mail_transport().as_str() {
+        "sendmail" => Box::new(SendmailTransport::new_with_command(CONFIG.sendmail_command())),
+        "file" => {
+            let folder = CONFIG.mail_folder();
+            if let Err(e) = std::fs::create_dir_all(&folder) {
+                error!("Error creating mail folder '{}': {:?}", folder, e);
+            }
+            Box::new(FileTransport::new(folder))
+        }
+        _ => Box::new(smtp_transport()),
+    }
+}
+
+fn error(msg: String) -> Error {
+    Error::new(msg.clone(), msg)
+}
+
+fn smtp_transport() -> SmtpTransport {
+    let host = CONFIG.smtp_host().unwrap();
+
+    let smtp_client = SmtpTransport::builder_dangerous(host.as_str())
+        .port(CONFIG.smtp_port())
+        .timeout(Some(Duration::from_secs(CONFIG.smtp_timeout())));
+
+    // Determine security
+    let smtp_client = if CONFIG.smtp_ssl() || CONFIG.smtp_explicit_tls() {
+        let mut tls_parameters = TlsParameters::builder(host);
+        if CONFIG.smtp_accept_invalid_hostnames() {
+            tls_parameters = tls_parameters.dangerous_accept_invalid_hostnames(true);
+        }
+        if CONFIG.smtp_accept_invalid_certs() {
+            tls_parameters = tls_parameters.dangerous_accept_invalid_certs(true);
+        }
+        let tls_parameters = tls_parameters.build().unwrap();
+
+        if CONFIG.smtp_explicit_tls() {
+            smtp_client.tls(Tls::Wrapper(tls_parameters))
+        } else {
+            smtp_client.tls(Tls::Required(tls_parameters))
+        }
+    } else {
+        smtp_client
+    };
+
+    let smtp_client = match (CONFIG.smtp_username(), CONFIG.smtp_password()) {
+        (Some(user), Some(pass)) => smtp_client.credentials(Credentials::new(user, pass)),
+        _ => smtp_client,
+    };
+
+    let smtp_client = match CONFIG.helo_name() {
+        Some(helo_name) => smtp_client.hello_name(ClientId::Domain(helo_name)),
+        None => smtp_client,
+    };
+
+    let smtp_client = match CONFIG.smtp_auth_mechanism() {
+        Some(mechanism) => {
+            let allowed_mechanisms = [SmtpAuthMechanism::Plain, SmtpAuthMechanism::Login, SmtpAuthMechanism::Xoauth2];
+            let mut selected_mechanisms = vec![];
+            for wanted_mechanism in mechanism.split(',') {
+                for m in &allowed_mechanisms {
+                    if m.to_string().to_lowercase()
+                        == wanted_mechanism.trim_matches(|c| c == '"' || c == '\'' || c == ' ').to_lowercase()
+                    {
+                        selected_mechanisms.push(*m);
+                    }
+                }
+            }
+
+            if !selected_mechanisms.is_empty() {
+                smtp_client.authentication(selected_mechanisms)
+            } else {
+                // Only show a warning, and return without setting an actual authentication mechanism
+                warn!("No valid SMTP Auth mechanism found for '{}', using default values", mechanism);
+                smtp_client
+            }
+        }
+        _ => smtp_client,
+    };
+
+    smtp_client.build()
+}
+
+impl MailTransport for SmtpTransport {
+    fn send_message(&self, envelope: &Envelope, email: &[u8]) -> Result<(), DeliveryError> {
+        match Transport::send_raw(self, envelope, email) {
+            Ok(_) => Ok(()),
+            // Match some common errors and make them more user friendly
+            Err(e) => {
+                if e.is_client() {
+                    Err(DeliveryError::Permanent(error(format!("SMTP Client error: {}", e))))
+                } else if e.is_permanent() {
+                    Err(DeliveryError::Permanent(error(format!("SMTP 5xx error: {:?}", e))))
+                } else if e.is_transient() {
+                    Err(DeliveryError::Transient(error(format!("SMTP 4xx error: {:?}", e))))
+                } else if e.is_timeout() {
+                    Err(DeliveryError::Transient(error(format!("SMTP timeout error: {:?}", e))))
+                } else {
+                    // Connection and TLS errors are usually temporary
+                    Err(DeliveryError::Transient(e.into()))
+                }
+            }
+        }
+    }
+}
+
+impl MailTransport for SendmailTransport {
+    fn send_message(&self, envelope: &Envelope, email: &[u8]) -> Result<(), DeliveryError> {
+        // The local MTA may be restarting or its queue may be full, so failures are retried
+        Transport::send_raw(self, envelope, email)
+            .map_err(|e| DeliveryError::Transient(error(format!("Sendmail error: {}", e))))
+    }
+}
+
+impl MailTransport for FileTransport {
+    fn send_message(&self, envelope: &Envelope, email: &[u8]) -> Result<(), DeliveryError> {
+        Transport::send_raw(self, envelope, email)
+            .map(|_| ())
+            .map_err(|e| DeliveryError::Transient(error(format!("Error writing email to file: {}", e))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lettre::Message;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    /// Starts an SMTP server which accepts a single connection and answers `RCPT` commands with `rcpt_reply`.
+    fn fake_smtp_server(rcpt_reply: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+
+            let mut in_data = false;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                let command = line.get(..4).unwrap_or_default().to_ascii_uppercase();
+                let reply = if in_data {
+                    in_data = line != ".\r\n";
+                    if in_data {
+                        None
+                    } else {
+                        Some("250 Queued")
+                    }
+                } else {
+                    match command.as_str() {
+                        "EHLO" | "HELO" => Some("250 localhost"),
+                        "RCPT" => Some(rcpt_reply),
+                        "DATA" => {
+                            in_data = true;
+                            Some("354 Go ahead")
+                        }
+                        "QUIT" => Some("221 Bye"),
+                        _ => Some("250 OK"),
+                    }
+                };
+
+                if let Some(reply) = reply {
+                    if writer.write_all(format!("{}\r\n", reply).as_bytes()).is_err() {
+                        break;
+                    }
+                }
+                line.clear();
+            }
+        });
+
+        port
+    }
+
+    fn test_message() -> Message {
+        Message::builder()
+            .to("user@example.com".parse().unwrap())
+            .from("vaultwarden@example.com".parse().unwrap())
+            .subject("Test")
+            .body(String::from("Test"))
+            .unwrap()
+    }
+
+    fn send_to_fake_server(rcpt_reply: &'static str) -> Result<(), DeliveryError> {
+        let port = fake_smtp_server(rcpt_reply);
+        let transport = SmtpTransport::builder_dangerous("127.0.0.1").port(port).build();
+        let email = test_message();
+
+        transport.send_message(email.envelope(), &email.formatted())
+    }
+
+    #[test]
+    fn test_send_accepted() {
+        assert!(send_to_fake_server("250 OK").is_ok());
+    }
+
+    #[test]
+    fn test_send_transient_error() {
+        assert!(matches!(send_to_fake_server("450 Mailbox busy"), Err(DeliveryError::Transient(_))));
+    }
+
+    #[test]
+    fn test_send_permanent_error() {
+        assert!(matches!(send_to_fake_server("550 No such user"), Err(DeliveryError::Permanent(_))));
+    }
+
+    #[test]
+    fn test_send_to_file() {
+        let folder = std::env::temp_dir().join(crate::util::get_uuid());
+        std::fs::create_dir_all(&folder).unwrap();
+
+        let email = test_message();
+        let result = FileTransport::new(&folder).send_message(email.envelope(), &email.formatted());
+        let files: Vec<_> = std::fs::read_dir(&folder).unwrap().filter_map(Result::ok).collect();
+        std::fs::remove_dir_all(&folder).ok();
+
+        assert!(result.is_ok());
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path().extension().and_then(|e| e.to_str()), Some("eml"));
+    }
+}
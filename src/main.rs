@@ -27,6 +27,9 @@ mod config;
 mod crypto;
 #[macro_use]
 mod db;
+mod fsck;
+mod http_client;
+mod jwt_keys;
 mod mail;
 mod util;
 
@@ -45,16 +48,14 @@ fn main() {
     let extra_debug = matches!(level, LF::Trace | LF::Debug);
 
     check_data_folder();
-    check_rsa_keys().unwrap_or_else(|_| {
-        error!("Error creating keys, exiting...");
-        exit(1);
-    });
+    auth::load_keys();
     check_web_vault();
 
     create_icon_cache_folder();
 
     let pool = create_db_pool();
     schedule_jobs(pool.clone());
+    mail::start_queue_worker(pool.clone());
     crate::db::models::TwoFactor::migrate_u2f_to_webauthn(&pool.get().unwrap()).unwrap();
 
     launch_rocket(pool, extra_debug); // Blocks until program termination.
@@ -64,11 +65,17 @@ const HELP: &str = "\
         Alternative implementation of the Bitwarden server API written in Rust
 
         USAGE:
-            vaultwarden
+            vaultwarden [FLAGS] [SUBCOMMAND]
 
         FLAGS:
             -h, --help       Prints help information
             -v, --version    Prints the app version
+
+        SUBCOMMANDS:
+            fsck [--repair]  Checks the database and the attachments and sends folders for
+                             orphaned files and dangling records, and removes them with --repair
+            hash             Reads a secret from the standard input and prints its PBKDF2 hash,
+                             which can be used as ADMIN_TOKEN
 ";
 
 fn parse_args() {
@@ -83,6 +90,57 @@ fn parse_args() {
         println!("vaultwarden {}", option_env!("BWRS_VERSION").unwrap_or(NO_VERSION));
         exit(0);
     }
+
+    match pargs.subcommand() {
+        Ok(Some(command)) if command == "fsck" => run_fsck(pargs.contains("--repair")),
+        Ok(Some(command)) if command == "hash" => hash_secret(),
+        Ok(Some(command)) => {
+            println!("Unknown subcommand '{}', use --help to see the available options", command);
+            exit(1);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            println!("Error parsing arguments: {}", e);
+            exit(1);
+        }
+    }
+}
+
+fn hash_secret() {
+    let mut secret = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut secret) {
+        println!("Error reading the secret: {}", e);
+        exit(1);
+    }
+
+    let secret = secret.trim_end_matches(&['\r', '\n'][..]);
+    if secret.is_empty() {
+        println!("The secret can't be empty");
+        exit(1);
+    }
+
+    println!("{}", crypto::hash_password_phc(secret));
+    exit(0);
+}
+
+fn run_fsck(repair: bool) {
+    init_logging(log::LevelFilter::from_str(&CONFIG.log_level()).expect("Valid log level")).ok();
+    check_data_folder();
+
+    let pool = create_db_pool();
+    let conn = pool.get().unwrap_or_else(|e| {
+        error!("Error getting DB connection: {:?}", e);
+        exit(1);
+    });
+
+    let report = fsck::run(repair, &conn);
+    println!("{}", report);
+
+    exit(if report.unrepaired_count() == 0 {
+        0
+    } else {
+        2
+    });
 }
 
 fn launch_info() {
@@ -244,31 +302,6 @@ fn check_data_folder() {
     }
 }
 
-fn check_rsa_keys() -> Result<(), crate::error::Error> {
-    // If the RSA keys don't exist, try to create them
-    let priv_path = CONFIG.private_rsa_key();
-    let pub_path = CONFIG.public_rsa_key();
-
-    if !util::file_exists(&priv_path) {
-        let rsa_key = openssl::rsa::Rsa::generate(2048)?;
-
-        let priv_key = rsa_key.private_key_to_pem()?;
-        crate::util::write_file(&priv_path, &priv_key)?;
-        info!("Private key created correctly.");
-    }
-
-    if !util::file_exists(&pub_path) {
-        let rsa_key = openssl::rsa::Rsa::private_key_from_pem(&util::read_file(&priv_path)?)?;
-
-        let pub_key = rsa_key.public_key_to_pem()?;
-        crate::util::write_file(&pub_path, &pub_key)?;
-        info!("Public key created correctly.");
-    }
-
-    auth::load_keys();
-    Ok(())
-}
-
 fn check_web_vault() {
     if !CONFIG.web_vault_enabled() {
         return;
@@ -309,6 +342,7 @@ fn launch_rocket(pool: db::DbPool, extra_debug: bool) {
         .mount(&[basepath, "/identity"].concat(), api::identity_routes())
         .mount(&[basepath, "/icons"].concat(), api::icons_routes())
         .mount(&[basepath, "/notifications"].concat(), api::notifications_routes())
+        .mount(&[basepath, "/scim"].concat(), api::scim_routes())
         .manage(pool)
         .manage(api::start_notification_server())
         .attach(util::AppHeaders())
@@ -345,6 +379,69 @@ fn schedule_jobs(pool: db::DbPool) {
                 }));
             }
 
+            // Purge abandoned attachment uploads.
+            if !CONFIG.upload_purge_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.upload_purge_schedule().parse().unwrap(), || {
+                    api::purge_incomplete_uploads(pool.clone());
+                }));
+            }
+
+            // Purge the login with device requests which have expired.
+            if !CONFIG.auth_request_purge_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.auth_request_purge_schedule().parse().unwrap(), || {
+                    api::purge_auth_requests(pool.clone());
+                }));
+            }
+
+            // Purge the login history entries older than the retention period.
+            if !CONFIG.login_history_purge_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.login_history_purge_schedule().parse().unwrap(), || {
+                    api::purge_login_history(pool.clone());
+                }));
+            }
+
+            // Purge the deleted users whose grace period is over, and remind the others before it ends.
+            if !CONFIG.user_purge_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.user_purge_schedule().parse().unwrap(), || {
+                    api::purge_deleted_users(pool.clone());
+                }));
+            }
+
+            // Purge the admin actions older than the retention period.
+            if !CONFIG.admin_action_purge_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.admin_action_purge_schedule().parse().unwrap(), || {
+                    api::purge_admin_actions(pool.clone());
+                }));
+            }
+
+            // Purge the failed emails older than the retention period from the mail queue.
+            if !CONFIG.mail_queue_purge_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.mail_queue_purge_schedule().parse().unwrap(), || {
+                    mail::purge_failed_mails(pool.clone());
+                }));
+            }
+
+            // Remind the users who have to set up two-step login before the end of their grace period.
+            if !CONFIG.twofactor_reminder_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.twofactor_reminder_schedule().parse().unwrap(), || {
+                    api::send_twofactor_reminders(pool.clone());
+                }));
+            }
+
+            // Rotate the JWT signing key, and remove the old keys whose grace period is over.
+            if !CONFIG.jwt_key_rotation_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.jwt_key_rotation_schedule().parse().unwrap(), || {
+                    jwt_keys::rotate_keys_job();
+                }));
+            }
+
+            // Purge expired icons, and the least recently used ones if the icon cache is too large.
+            if !CONFIG.icon_cache_purge_schedule().is_empty() {
+                sched.add(Job::new(CONFIG.icon_cache_purge_schedule().parse().unwrap(), || {
+                    api::purge_icon_cache();
+                }));
+            }
+
             // Periodically check for jobs to run. We probably won't need any
             // jobs that run more often than once a minute, so a default poll
             // interval of 30 seconds should be sufficient. Users who want to
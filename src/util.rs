@@ -161,8 +161,8 @@ impl<'r> FromParam<'r> for SafeString {
 
 // Log all the routes from the main paths list, and the attachments endpoint
 // Effectively ignores, any static file route, and the alive endpoint
-const LOGGED_ROUTES: [&str; 6] =
-    ["/api", "/admin", "/identity", "/icons", "/notifications/hub/negotiate", "/attachments"];
+const LOGGED_ROUTES: [&str; 7] =
+    ["/api", "/admin", "/identity", "/icons", "/notifications/hub/negotiate", "/attachments", "/scim"];
 
 // Boolean is extra debug, when true, we ignore the whitelist above and also print the mounts
 pub struct BetterLogging(pub bool);
@@ -284,8 +284,8 @@ pub fn delete_file(path: &str) -> IOResult<()> {
 
 const UNITS: [&str; 6] = ["bytes", "KB", "MB", "GB", "TB", "PB"];
 
-pub fn get_display_size(size: i32) -> String {
-    let mut size: f64 = size.into();
+pub fn get_display_size(size: i64) -> String {
+    let mut size = size as f64;
     let mut unit_counter = 0;
 
     loop {